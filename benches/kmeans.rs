@@ -0,0 +1,73 @@
+#![feature(test)]
+
+extern crate test;
+
+use ndarray::{Array2, Axis};
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use rand_xorshift::XorShiftRng;
+use test::Bencher;
+
+use reductive::kmeans::{
+    AssignmentKernel, KMeansIteration, KMeansWithCentroids, NIterationsCondition,
+};
+
+fn random_matrix(rows: usize, cols: usize) -> Array2<f32> {
+    let mut rng = XorShiftRng::seed_from_u64(42);
+    Array2::from_shape_fn((rows, cols), |_| rng.sample(StandardNormal))
+}
+
+fn bench_kmeans_iteration(bencher: &mut Bencher, n_dims: usize) {
+    let data = random_matrix(10_000, n_dims);
+    let centroids = data.select(Axis(0), &(0..256).collect::<Vec<_>>());
+
+    bencher.iter(|| {
+        let mut iter_centroids = centroids.clone();
+        data.kmeans_iteration(Axis(0), iter_centroids.view_mut())
+    })
+}
+
+fn bench_kmeans_kernel(bencher: &mut Bencher, n_dims: usize, kernel: AssignmentKernel) {
+    let data = random_matrix(10_000, n_dims);
+    let centroids = data.select(Axis(0), &(0..256).collect::<Vec<_>>());
+
+    bencher.iter(|| {
+        let mut iter_centroids = centroids.clone();
+        data.kmeans_with_centroids_using(
+            Axis(0),
+            iter_centroids.view_mut(),
+            NIterationsCondition(2),
+            kernel,
+        )
+    })
+}
+
+#[bench]
+fn kmeans_iteration_96d(bencher: &mut Bencher) {
+    bench_kmeans_iteration(bencher, 96);
+}
+
+#[bench]
+fn kmeans_iteration_768d(bencher: &mut Bencher) {
+    bench_kmeans_iteration(bencher, 768);
+}
+
+#[bench]
+fn kmeans_blocked_96d(bencher: &mut Bencher) {
+    bench_kmeans_kernel(bencher, 96, AssignmentKernel::Blocked);
+}
+
+#[bench]
+fn kmeans_early_abandon_96d(bencher: &mut Bencher) {
+    bench_kmeans_kernel(bencher, 96, AssignmentKernel::EarlyAbandon);
+}
+
+#[bench]
+fn kmeans_blocked_768d(bencher: &mut Bencher) {
+    bench_kmeans_kernel(bencher, 768, AssignmentKernel::Blocked);
+}
+
+#[bench]
+fn kmeans_early_abandon_768d(bencher: &mut Bencher) {
+    bench_kmeans_kernel(bencher, 768, AssignmentKernel::EarlyAbandon);
+}
@@ -0,0 +1,338 @@
+//! Quantize word embeddings and evaluate the quality of the reconstructions.
+//!
+//! Usage: embeddings [EMBEDDINGS [ANALOGIES]]
+//!
+//! EMBEDDINGS is a file in the finalfusion text format, which can be
+//! created with `finalfusion convert -t textdims`. Each line contains a
+//! word followed by its embedding; an optional first line contains the
+//! vocabulary size and the number of dimensions. ANALOGIES is a file
+//! with analogies of the form `a b c d` (*a* is to *b* as *c* is to
+//! *d*), one per line. Lines starting with `:` are ignored, so the
+//! word2vec analogy data set can be used as-is.
+//!
+//! Without arguments, a small synthetic data set is used. Its words are
+//! combinations of concepts and attributes, so that it has analogies by
+//! construction.
+//!
+//! The embeddings are quantized with optimized product quantization
+//! when the `opq-train` feature is enabled, otherwise with product
+//! quantization. The recommended settings for embeddings are used:
+//! subquantizers of 4 dimensions and 8-bit codes when there are enough
+//! words to train 256 centroids per subquantizer. The program reports
+//! the overlap of the 10 nearest neighbors of each word and analogy
+//! accuracy, both for the original embeddings and the reconstructions.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+use ndarray::{Array2, ArrayView2};
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use rand_xorshift::XorShiftRng;
+use reductive::metrics::neighbor_overlap;
+use reductive::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+const USAGE: &str = "Usage: embeddings [EMBEDDINGS [ANALOGIES]]";
+
+/// Minimum number of training instances per centroid.
+const MIN_INSTANCES_PER_CENTROID: usize = 39;
+
+/// Number of neighbors for the neighbor overlap.
+const N_NEIGHBORS: usize = 10;
+
+struct Embeddings {
+    words: Vec<String>,
+    indices: HashMap<String, usize>,
+    matrix: Array2<f32>,
+}
+
+impl Embeddings {
+    fn new(words: Vec<String>, mut matrix: Array2<f32>) -> Self {
+        // Embeddings are compared by cosine similarity, normalize them.
+        for mut embedding in matrix.outer_iter_mut() {
+            let norm = embedding.dot(&embedding).sqrt();
+            if norm > 0. {
+                embedding /= norm;
+            }
+        }
+
+        let indices = words
+            .iter()
+            .enumerate()
+            .map(|(idx, word)| (word.clone(), idx))
+            .collect();
+
+        Embeddings {
+            words,
+            indices,
+            matrix,
+        }
+    }
+
+    fn read_text(path: &str) -> Result<Self, String> {
+        let read = File::open(path).map_err(|err| format!("Cannot open {}: {}", path, err))?;
+
+        let mut words = Vec::new();
+        let mut values = Vec::new();
+        let mut n_dims = None;
+        for (idx, line) in BufReader::new(read).lines().enumerate() {
+            let line = line.map_err(|err| format!("Cannot read {}: {}", path, err))?;
+            let mut parts = line.split_whitespace();
+            let word = match parts.next() {
+                Some(word) => word,
+                None => continue,
+            };
+
+            let embedding = parts
+                .map(str::parse)
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|err| format!("Invalid embedding on line {}: {}", idx + 1, err))?;
+
+            // Skip the header of the textdims format.
+            if idx == 0 && embedding.len() == 1 && word.parse::<usize>().is_ok() {
+                continue;
+            }
+
+            match n_dims {
+                None => n_dims = Some(embedding.len()),
+                Some(n_dims) if n_dims != embedding.len() => {
+                    return Err(format!(
+                        "Embedding on line {} has {} dimensions, expected {}",
+                        idx + 1,
+                        embedding.len(),
+                        n_dims
+                    ))
+                }
+                _ => (),
+            }
+
+            words.push(word.to_string());
+            values.extend(embedding);
+        }
+
+        let n_dims = n_dims.ok_or_else(|| format!("{} does not contain embeddings", path))?;
+        let matrix = Array2::from_shape_vec((words.len(), n_dims), values)
+            .expect("Incorrect embedding matrix shape");
+
+        Ok(Self::new(words, matrix))
+    }
+}
+
+/// Generate embeddings for words that combine a concept and an attribute.
+///
+/// Returns the embeddings and analogies between the words.
+fn synthetic_embeddings() -> (Embeddings, Vec<[String; 4]>) {
+    const N_CONCEPTS: usize = 200;
+    const N_ATTRIBUTES: usize = 5;
+    const N_DIMS: usize = 64;
+
+    let mut rng = XorShiftRng::seed_from_u64(42);
+    let mut random_vectors = |n: usize, scale: f32| {
+        Array2::from_shape_fn((n, N_DIMS), |_| {
+            scale * rng.sample::<f32, _>(StandardNormal)
+        })
+    };
+    let concepts = random_vectors(N_CONCEPTS, 1.);
+    let attributes = random_vectors(N_ATTRIBUTES, 1.);
+    let noise = random_vectors(N_CONCEPTS * N_ATTRIBUTES, 0.1);
+
+    let word = |concept: usize, attribute: usize| format!("c{}_a{}", concept, attribute);
+    let mut words = Vec::new();
+    let mut matrix = Array2::zeros((N_CONCEPTS * N_ATTRIBUTES, N_DIMS));
+    for concept in 0..N_CONCEPTS {
+        for attribute in 0..N_ATTRIBUTES {
+            let idx = words.len();
+            let embedding = &concepts.row(concept) + &attributes.row(attribute) + noise.row(idx);
+            matrix.row_mut(idx).assign(&embedding);
+            words.push(word(concept, attribute));
+        }
+    }
+
+    let mut analogies = Vec::new();
+    for concept in (0..N_CONCEPTS).step_by(10) {
+        for other in (5..N_CONCEPTS).step_by(20) {
+            analogies.push([
+                word(concept, 0),
+                word(concept, 1),
+                word(other, 0),
+                word(other, 1),
+            ]);
+        }
+    }
+
+    (Embeddings::new(words, matrix), analogies)
+}
+
+fn read_analogies(path: &str) -> Result<Vec<[String; 4]>, String> {
+    let read = File::open(path).map_err(|err| format!("Cannot open {}: {}", path, err))?;
+
+    let mut analogies = Vec::new();
+    for (idx, line) in BufReader::new(read).lines().enumerate() {
+        let line = line.map_err(|err| format!("Cannot read {}: {}", path, err))?;
+        if line.starts_with(':') || line.trim().is_empty() {
+            continue;
+        }
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            [a, b, c, d] => {
+                analogies.push([a.to_string(), b.to_string(), c.to_string(), d.to_string()])
+            }
+            _ => return Err(format!("Invalid analogy on line {}", idx + 1)),
+        }
+    }
+
+    Ok(analogies)
+}
+
+/// Compute the fraction of analogies that is answered correctly.
+///
+/// The answer to *a : b :: c : ?* is the word that is most similar to
+/// *b - a + c*, excluding *a*, *b*, and *c*. Analogies with unknown
+/// words are skipped.
+fn analogy_accuracy(
+    embeddings: &Embeddings,
+    matrix: ArrayView2<f32>,
+    analogies: &[[String; 4]],
+) -> f32 {
+    let mut n_correct = 0;
+    let mut n_total = 0;
+    for analogy in analogies {
+        let indices = match analogy
+            .iter()
+            .map(|word| embeddings.indices.get(word).copied())
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(indices) => indices,
+            None => continue,
+        };
+
+        let query = &matrix.row(indices[1]) - &matrix.row(indices[0]) + matrix.row(indices[2]);
+        let similarities = matrix.dot(&query);
+        let answer = similarities
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !indices[..3].contains(idx))
+            .fold((0, f32::NEG_INFINITY), |best, (idx, &sim)| {
+                if sim > best.1 {
+                    (idx, sim)
+                } else {
+                    best
+                }
+            })
+            .0;
+
+        n_total += 1;
+        if answer == indices[3] {
+            n_correct += 1;
+        }
+    }
+
+    if n_total == 0 {
+        return f32::NAN;
+    }
+
+    n_correct as f32 / n_total as f32
+}
+
+/// Pick the subquantizer settings for the embeddings.
+fn quantizer_settings(n_words: usize, n_dims: usize) -> (usize, u32) {
+    let sq_dims = [4, 2, 1]
+        .iter()
+        .copied()
+        .find(|&sq_dims| n_dims % sq_dims == 0)
+        .unwrap();
+
+    // Use fewer bits if there are not enough words for 8-bit codes.
+    let max_centroids = (n_words / MIN_INSTANCES_PER_CENTROID).max(2);
+    let n_bits = (usize::BITS - 1 - max_centroids.leading_zeros()).min(8);
+
+    (n_dims / sq_dims, n_bits)
+}
+
+#[cfg(feature = "opq-train")]
+fn train(n_subquantizers: usize, n_bits: u32, instances: ArrayView2<f32>) -> PQ<f32> {
+    use reductive::pq::OPQ;
+
+    OPQ::train_pq_using(
+        n_subquantizers,
+        n_bits,
+        20,
+        1,
+        instances,
+        XorShiftRng::seed_from_u64(42),
+    )
+}
+
+#[cfg(not(feature = "opq-train"))]
+fn train(n_subquantizers: usize, n_bits: u32, instances: ArrayView2<f32>) -> PQ<f32> {
+    PQ::train_pq_using(
+        n_subquantizers,
+        n_bits,
+        20,
+        1,
+        instances,
+        XorShiftRng::seed_from_u64(42),
+    )
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let data = match args.as_slice() {
+        [] => Ok(synthetic_embeddings()),
+        [embeddings] => {
+            Embeddings::read_text(embeddings).map(|embeddings| (embeddings, Vec::new()))
+        }
+        [embeddings, analogies] => Embeddings::read_text(embeddings)
+            .and_then(|embeddings| Ok((embeddings, read_analogies(analogies)?))),
+        _ => Err(USAGE.to_string()),
+    };
+    let (embeddings, analogies) = data.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let (n_words, n_dims) = embeddings.matrix.dim();
+    if n_words <= N_NEIGHBORS {
+        eprintln!("At least {} words are required", N_NEIGHBORS + 1);
+        process::exit(1);
+    }
+
+    let (n_subquantizers, n_bits) = quantizer_settings(n_words, n_dims);
+    println!(
+        "{} words, {} dimensions, {} subquantizers, {} bits",
+        n_words, n_dims, n_subquantizers, n_bits
+    );
+
+    let pq = train(n_subquantizers, n_bits, embeddings.matrix.view());
+    let quantized: Array2<u8> = pq.quantize_batch(embeddings.matrix.view());
+    let reconstructions = Embeddings::new(
+        embeddings.words.clone(),
+        pq.reconstruct_batch(quantized.view()),
+    );
+
+    let original_size = embeddings.matrix.len() * std::mem::size_of::<f32>();
+    let quantized_size = quantized.len() + pq.subquantizers().len() * std::mem::size_of::<f32>();
+    println!(
+        "Size: {} bytes, quantized: {} bytes (including codebooks)",
+        original_size, quantized_size
+    );
+
+    let overlap: f32 = neighbor_overlap(
+        embeddings.matrix.view(),
+        reconstructions.matrix.view(),
+        N_NEIGHBORS,
+    );
+    println!("{}-nearest neighbor overlap: {:.3}", N_NEIGHBORS, overlap);
+
+    if !analogies.is_empty() {
+        println!(
+            "Analogy accuracy: {:.3} (original), {:.3} (quantized)",
+            analogy_accuracy(&embeddings, embeddings.matrix.view(), &analogies),
+            analogy_accuracy(&embeddings, reconstructions.matrix.view(), &analogies)
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Measure quantization and ADC search throughput.
+//!
+//! Usage: throughput [N_VECTORS] [N_DIMS] [N_SUBQUANTIZERS] [N_BITS] [N_QUERIES]
+//!
+//! A product quantizer is trained on synthetic Gaussian data of the
+//! given shape. Then the throughput of quantizing the data and of
+//! searching the quantized data with asymmetric distance computation
+//! (ADC) is printed.
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+
+use ndarray::{Array2, ArrayView2, Axis};
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use rand_xorshift::XorShiftRng;
+use reductive::pq::{QuantizeVector, TrainPQ, PQ};
+
+const USAGE: &str = "Usage: throughput [N_VECTORS] [N_DIMS] [N_SUBQUANTIZERS] [N_BITS] [N_QUERIES]";
+
+struct Config {
+    n_vectors: usize,
+    n_dims: usize,
+    n_subquantizers: usize,
+    n_bits: u32,
+    n_queries: usize,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let args = env::args().skip(1).collect::<Vec<_>>();
+        if args.len() > 5 {
+            return Err(USAGE.to_string());
+        }
+
+        let arg = |idx: usize, default: usize| -> Result<usize, String> {
+            match args.get(idx) {
+                Some(arg) => arg
+                    .parse()
+                    .map_err(|err| format!("Cannot parse argument '{}': {}", arg, err)),
+                None => Ok(default),
+            }
+        };
+
+        let config = Config {
+            n_vectors: arg(0, 100_000)?,
+            n_dims: arg(1, 128)?,
+            n_subquantizers: arg(2, 16)?,
+            n_bits: arg(3, 8)? as u32,
+            n_queries: arg(4, 100)?,
+        };
+
+        if config.n_subquantizers == 0 || config.n_dims % config.n_subquantizers != 0 {
+            return Err(format!(
+                "The number of dimensions ({}) should be a multiple of the number of subquantizers ({})",
+                config.n_dims, config.n_subquantizers
+            ));
+        }
+
+        if config.n_bits == 0 || config.n_bits > 16 {
+            return Err(format!(
+                "The number of bits should be in [1, 16], got: {}",
+                config.n_bits
+            ));
+        }
+
+        if config.n_vectors < 1 << config.n_bits {
+            return Err(format!(
+                "At least {} vectors are required to train {}-bit subquantizers",
+                1 << config.n_bits,
+                config.n_bits
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+fn random_matrix(rng: &mut impl Rng, rows: usize, cols: usize) -> Array2<f32> {
+    Array2::from_shape_fn((rows, cols), |_| rng.sample(StandardNormal))
+}
+
+/// Search the quantized vectors for the nearest neighbor of each query.
+fn adc_search(pq: &PQ<f32>, queries: ArrayView2<f32>, quantized: ArrayView2<u16>) -> Vec<usize> {
+    let tables = pq.adc_tables_batch(queries);
+    tables
+        .outer_iter()
+        .map(|table| nearest_neighbor(table, quantized))
+        .collect()
+}
+
+fn nearest_neighbor(table: ArrayView2<f32>, quantized: ArrayView2<u16>) -> usize {
+    let mut best = (0, f32::INFINITY);
+    for (idx, codes) in quantized.outer_iter().enumerate() {
+        let dist = codes
+            .iter()
+            .zip(table.outer_iter())
+            .map(|(&code, sq_table)| sq_table[code as usize])
+            .sum::<f32>();
+        if dist < best.1 {
+            best = (idx, dist);
+        }
+    }
+
+    best.0
+}
+
+fn report(label: &str, n_vectors: usize, n_bytes: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "{:<14} {:>10.3} s {:>14.0} vectors/s {:>8.3} GB/s",
+        label,
+        secs,
+        n_vectors as f64 / secs,
+        n_bytes as f64 / secs / 1e9
+    );
+}
+
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let mut rng = XorShiftRng::seed_from_u64(42);
+    let data = random_matrix(&mut rng, config.n_vectors, config.n_dims);
+    let queries = random_matrix(&mut rng, config.n_queries, config.n_dims);
+
+    println!(
+        "{} vectors, {} dimensions, {} subquantizers, {} bits, {} queries",
+        config.n_vectors, config.n_dims, config.n_subquantizers, config.n_bits, config.n_queries
+    );
+
+    let start = Instant::now();
+    let pq = PQ::train_pq_using(
+        config.n_subquantizers,
+        config.n_bits,
+        10,
+        1,
+        data.view(),
+        XorShiftRng::seed_from_u64(42),
+    );
+    println!("Training took {:.3} s", start.elapsed().as_secs_f64());
+
+    let start = Instant::now();
+    let quantized: Array2<u16> = pq.quantize_batch(data.view());
+    report(
+        "quantization",
+        config.n_vectors,
+        data.len() * std::mem::size_of::<f32>(),
+        start.elapsed(),
+    );
+
+    // Every query scans all quantized vectors.
+    let start = Instant::now();
+    let neighbors = adc_search(&pq, queries.view(), quantized.view());
+    let n_scanned = config.n_queries * config.n_vectors;
+    report(
+        "ADC search",
+        n_scanned,
+        config.n_queries * quantized.len() * std::mem::size_of::<u16>(),
+        start.elapsed(),
+    );
+    assert_eq!(neighbors.len(), config.n_queries);
+
+    println!(
+        "Codebooks: {} bytes, codes: {} bytes/vector",
+        pq.subquantizers().len() * std::mem::size_of::<f32>(),
+        quantized.len_of(Axis(1)) * std::mem::size_of::<u16>()
+    );
+}
@@ -0,0 +1,144 @@
+//! Zero-copy Arrow inputs.
+//!
+//! The functions in this module view Arrow arrays as instance matrices
+//! without copying the data. The resulting views can be passed directly
+//! to training (e.g. `TrainPQ::train_pq`) and quantization (e.g.
+//! `QuantizeVector::quantize_batch`).
+
+use arrow_array::{Array, FixedSizeListArray, Float32Array};
+use ndarray::ArrayView2;
+
+use crate::error::Error;
+
+/// View a list array of `f32` vectors as an instance matrix.
+///
+/// Each list of the array becomes a row of the matrix. The array and
+/// its values must not contain nulls.
+pub fn fixed_size_list_view(array: &FixedSizeListArray) -> Result<ArrayView2<f32>, Error> {
+    if array.null_count() != 0 {
+        return Err(Error::InvalidArrowArray(
+            "list array contains null vectors".to_string(),
+        ));
+    }
+
+    let values = array
+        .values()
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .ok_or_else(|| {
+            Error::InvalidArrowArray(format!(
+                "expected list values of type Float32, got: {}",
+                array.value_type()
+            ))
+        })?;
+
+    matrix_view(values, array.len(), array.value_length() as usize)
+}
+
+/// View a flat `f32` array as an instance matrix with `n_dims` columns.
+///
+/// The array is interpreted in row-major order. Its length must be a
+/// multiple of `n_dims` and it must not contain nulls.
+pub fn float32_view(array: &Float32Array, n_dims: usize) -> Result<ArrayView2<f32>, Error> {
+    assert!(n_dims > 0, "Number of dimensions should be non-zero");
+
+    if array.len() % n_dims != 0 {
+        return Err(Error::InvalidArrowArray(format!(
+            "array length {} is not a multiple of the number of dimensions {}",
+            array.len(),
+            n_dims
+        )));
+    }
+
+    matrix_view(array, array.len() / n_dims, n_dims)
+}
+
+fn matrix_view(
+    values: &Float32Array,
+    n_rows: usize,
+    n_dims: usize,
+) -> Result<ArrayView2<f32>, Error> {
+    if values.null_count() != 0 {
+        return Err(Error::InvalidArrowArray(
+            "array contains null values".to_string(),
+        ));
+    }
+
+    ArrayView2::from_shape((n_rows, n_dims), values.values())
+        .map_err(|err| Error::InvalidArrowArray(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::types::Float32Type;
+    use arrow_array::{Array, FixedSizeListArray, Float32Array};
+    use ndarray::{array, s};
+
+    use super::{fixed_size_list_view, float32_view};
+    use crate::error::Error;
+    use crate::pq::{QuantizeVector, PQ};
+
+    #[test]
+    fn view_fixed_size_list() {
+        let list = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![
+                Some(vec![Some(0.), Some(2.), Some(0.), Some(-0.5)]),
+                Some(vec![Some(1.), Some(-0.2), Some(0.), Some(0.5)]),
+                Some(vec![Some(0.5), Some(0.5), Some(1.), Some(1.)]),
+            ],
+            4,
+        );
+
+        let instances = array![
+            [0f32, 2., 0., -0.5],
+            [1., -0.2, 0., 0.5],
+            [0.5, 0.5, 1., 1.]
+        ];
+        let view = fixed_size_list_view(&list).unwrap();
+        assert_eq!(view, instances);
+
+        // The view shares the memory of the Arrow array.
+        let values = list.values().to_data();
+        assert_eq!(view.as_ptr(), values.buffers()[0].as_ptr() as *const f32);
+
+        let sliced = list.slice(1, 2);
+        assert_eq!(
+            fixed_size_list_view(&sliced).unwrap(),
+            instances.slice(s![1.., ..])
+        );
+
+        let pq = PQ::new(None, array![[[1., 0.], [0., 1.]], [[1., -1.], [0., 1.]]]);
+        assert_eq!(
+            pq.quantize_batch::<u8, _>(view),
+            pq.quantize_batch::<u8, _>(instances.view())
+        );
+    }
+
+    #[test]
+    fn view_float32_array() {
+        let array = Float32Array::from(vec![1., 2., 3., 4., 5., 6.]);
+        assert_eq!(
+            float32_view(&array, 3).unwrap(),
+            array![[1f32, 2., 3.], [4., 5., 6.]]
+        );
+        assert!(matches!(
+            float32_view(&array, 4),
+            Err(Error::InvalidArrowArray(_))
+        ));
+
+        let nulls = Float32Array::from(vec![Some(1.), None]);
+        assert!(matches!(
+            float32_view(&nulls, 1),
+            Err(Error::InvalidArrowArray(_))
+        ));
+
+        let list = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![Some(vec![Some(1.), Some(2.)]), None],
+            2,
+        );
+        assert!(matches!(
+            fixed_size_list_view(&list),
+            Err(Error::InvalidArrowArray(_))
+        ));
+    }
+}
@@ -0,0 +1,662 @@
+//! Composite quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, Array3, ArrayBase, ArrayView1, ArrayView2, ArrayView3, ArrayViewMut2, Axis,
+    Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+use rayon::prelude::*;
+
+use crate::float_ord::min_by_float_key;
+use crate::parallel::NestedParallelism;
+use crate::pq::{QuantizeVector, ReconstructVector};
+use crate::rq::{greedy_quantize_into, ResidualQuantizer};
+
+/// Number of instances that are encoded in one task.
+const ENCODE_BLOCK: usize = 256;
+
+/// Number of k-means iterations of the residual quantizer that is used
+/// for initialization.
+const RQ_INIT_ITERATIONS: usize = 10;
+
+/// Maximum number of times that the step size is halved in a gradient
+/// step.
+const MAX_STEP_HALVINGS: usize = 20;
+
+/// Trainer for composite quantizers.
+///
+/// The trainer holds the weight of the near-orthogonality penalty and
+/// the parameters of the optimization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CQTrainer {
+    penalty: f64,
+    n_icm_iterations: usize,
+    n_gradient_steps: usize,
+}
+
+impl Default for CQTrainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CQTrainer {
+    /// Construct a trainer with the default parameters.
+    ///
+    /// The default is a penalty weight of 0.1, 4 ICM iterations, and 4
+    /// gradient steps per iteration.
+    pub fn new() -> Self {
+        CQTrainer {
+            penalty: 0.1,
+            n_icm_iterations: 4,
+            n_gradient_steps: 4,
+        }
+    }
+
+    /// Set the weight of the near-orthogonality penalty.
+    ///
+    /// Higher weights make the inner products between the centroids of a
+    /// reconstruction closer to constant, at the cost of a higher
+    /// reconstruction error.
+    pub fn with_penalty(mut self, penalty: f64) -> Self {
+        assert!(
+            penalty >= 0.,
+            "The penalty weight should be non-negative, was: {}",
+            penalty
+        );
+        self.penalty = penalty;
+        self
+    }
+
+    /// Set the number of ICM sweeps over the codes.
+    pub fn with_icm_iterations(mut self, n_icm_iterations: usize) -> Self {
+        assert!(
+            n_icm_iterations > 0,
+            "Codes should be optimized for at least one ICM iteration."
+        );
+        self.n_icm_iterations = n_icm_iterations;
+        self
+    }
+
+    /// Set the number of gradient steps on the codebooks per iteration.
+    pub fn with_gradient_steps(mut self, n_gradient_steps: usize) -> Self {
+        assert!(
+            n_gradient_steps > 0,
+            "The codebooks should be updated with at least one gradient step."
+        );
+        self.n_gradient_steps = n_gradient_steps;
+        self
+    }
+
+    /// Train a composite quantizer.
+    ///
+    /// Train a composite quantizer with `n_codebooks` codebooks on
+    /// `instances`. Each codebook has 2^`n_codebook_bits` centroids. The
+    /// codebooks are initialized with a residual quantizer, using `rng`
+    /// to pick initial centroids. Then, for `n_iterations` iterations,
+    /// the instances are encoded with ICM, the constant of the
+    /// near-orthogonality constraint is set to its optimum, and the
+    /// codebooks are updated with gradient descent.
+    pub fn train_using<A, S, R>(
+        &self,
+        n_codebooks: usize,
+        n_codebook_bits: u32,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> CompositeQuantizer<A>
+    where
+        A: NdFloat + Sum,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_iterations > 0,
+            "The codebooks should be optimized for at least one iteration."
+        );
+
+        let rq = ResidualQuantizer::train_rq_parallel_using(
+            n_codebooks,
+            n_codebook_bits,
+            RQ_INIT_ITERATIONS,
+            1,
+            instances.view(),
+            NestedParallelism::for_tasks(1),
+            rng,
+        );
+
+        let mut quantizer = CompositeQuantizer {
+            codebooks: rq.codebooks().to_owned(),
+            epsilon: A::zero(),
+            penalty: A::from(self.penalty).unwrap(),
+            n_icm_iterations: self.n_icm_iterations,
+        };
+
+        let mut codes = Array2::zeros((instances.nrows(), n_codebooks));
+        greedy_quantize_into::<A, usize>(
+            quantizer.codebooks.view(),
+            instances.view(),
+            codes.view_mut(),
+        );
+        quantizer.epsilon = quantizer.mean_cross_term(codes.view());
+
+        // The step size is adapted across iterations.
+        let mut step_size = A::one();
+        for iteration in 0..n_iterations {
+            quantizer.icm_batch(instances.view(), codes.view_mut());
+            quantizer.epsilon = quantizer.mean_cross_term(codes.view());
+            for _ in 0..self.n_gradient_steps {
+                step_size = quantizer.gradient_step(instances.view(), codes.view(), step_size);
+            }
+
+            info!(
+                "CQ iteration {}, loss: {}",
+                iteration,
+                quantizer.loss(instances.view(), codes.view())
+            );
+        }
+
+        quantizer
+    }
+}
+
+/// Composite quantizer (Zhang et al., 2014).
+///
+/// A composite quantizer is an additive quantizer: every codebook spans
+/// the full vector space and a vector is reconstructed as the sum of one
+/// centroid of each codebook. The codebooks are trained under the
+/// near-orthogonality constraint: the sum of the inner products between
+/// the centroids of a reconstruction is (close to) a constant *ε*. The
+/// squared distance between a query *q* and a reconstruction then
+/// decomposes as
+///
+/// *||q||² - 2 Σᵢ <q, cᵢ> + Σᵢ ||cᵢ||² + ε*
+///
+/// so that distances can be computed with per-codebook lookup tables,
+/// as in product quantization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompositeQuantizer<A> {
+    codebooks: Array3<A>,
+    epsilon: A,
+    penalty: A,
+    n_icm_iterations: usize,
+}
+
+impl<A> CompositeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a composite quantizer from its codebooks.
+    ///
+    /// `epsilon` is the constant of the near-orthogonality constraint and
+    /// `penalty` the weight of the constraint when vectors are encoded.
+    pub fn new(codebooks: Array3<A>, epsilon: A, penalty: A, n_icm_iterations: usize) -> Self {
+        assert!(
+            codebooks.len_of(Axis(0)) > 0,
+            "A composite quantizer should have at least one codebook."
+        );
+        assert!(
+            codebooks.len_of(Axis(1)) > 0,
+            "The codebooks of a composite quantizer should have at least one centroid."
+        );
+        assert!(
+            n_icm_iterations > 0,
+            "Codes should be optimized for at least one ICM iteration."
+        );
+
+        CompositeQuantizer {
+            codebooks,
+            epsilon,
+            penalty,
+            n_icm_iterations,
+        }
+    }
+
+    /// Get the codebooks.
+    pub fn codebooks(&self) -> ArrayView3<A> {
+        self.codebooks.view()
+    }
+
+    /// Get the constant of the near-orthogonality constraint.
+    pub fn epsilon(&self) -> A {
+        self.epsilon
+    }
+
+    /// Get the number of codebooks.
+    pub fn n_codebooks(&self) -> usize {
+        self.codebooks.len_of(Axis(0))
+    }
+
+    /// Get the number of centroids per codebook.
+    pub fn n_codebook_centroids(&self) -> usize {
+        self.codebooks.len_of(Axis(1))
+    }
+
+    /// Compute the distance lookup table for a query.
+    ///
+    /// The table has shape *(n_codebooks, n_centroids)* and contains
+    /// *||c||² - 2 <q, c>* for every centroid *c*. The squared distance
+    /// between the query and a reconstruction is approximated by the sum
+    /// of the table entries of its codes, plus *||q||²* and *ε*.
+    pub fn distance_table<S>(&self, query: ArrayBase<S, Ix1>) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        assert_eq!(
+            query.len(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+
+        let (n_codebooks, n_centroids, _) = self.codebooks.dim();
+        let centroids = self.centroids();
+        let dots = centroids.dot(&query);
+        let table = Array1::from_shape_fn(centroids.nrows(), |idx| {
+            let centroid = centroids.index_axis(Axis(0), idx);
+            centroid.dot(&centroid) - (dots[idx] + dots[idx])
+        });
+
+        table
+            .into_shape((n_codebooks, n_centroids))
+            .expect("Table has incorrect shape")
+    }
+
+    /// Get the codebooks as a matrix with all centroids as rows.
+    fn centroids(&self) -> ArrayView2<A> {
+        let (n_codebooks, n_centroids, n_dims) = self.codebooks.dim();
+        self.codebooks
+            .view()
+            .into_shape((n_codebooks * n_centroids, n_dims))
+            .expect("Codebooks are not contiguous")
+    }
+
+    /// Get the sum of inner products between different centroids of a
+    /// reconstruction.
+    fn cross_term(&self, codes: ArrayView1<usize>) -> A {
+        let reconstruction = self.reconstruct_vector(codes);
+        let sqnorms = self
+            .codebooks
+            .outer_iter()
+            .zip(codes.iter())
+            .map(|(codebook, &code)| {
+                let centroid = codebook.index_axis(Axis(0), code);
+                centroid.dot(&centroid)
+            })
+            .sum::<A>();
+        reconstruction.dot(&reconstruction) - sqnorms
+    }
+
+    /// Get the mean cross term of a batch of codes.
+    ///
+    /// This is the optimal value of *ε* for the codes.
+    fn mean_cross_term(&self, codes: ArrayView2<usize>) -> A
+    where
+        usize: AsPrimitive<A>,
+    {
+        codes
+            .outer_iter()
+            .map(|codes| self.cross_term(codes))
+            .sum::<A>()
+            / codes.nrows().max(1).as_()
+    }
+
+    /// Get the training objective: the mean squared reconstruction error
+    /// plus the weighted squared violation of the constraint.
+    fn loss(&self, instances: ArrayView2<A>, codes: ArrayView2<usize>) -> A
+    where
+        usize: AsPrimitive<A>,
+    {
+        instances
+            .outer_iter()
+            .zip(codes.outer_iter())
+            .map(|(instance, codes)| {
+                let error = &instance - &self.reconstruct_vector(codes);
+                let violation = self.cross_term(codes) - self.epsilon;
+                error.dot(&error) + self.penalty * violation * violation
+            })
+            .sum::<A>()
+            / instances.nrows().max(1).as_()
+    }
+
+    /// Update the codebooks with a gradient step.
+    ///
+    /// The step size is halved until the objective decreases. Returns the
+    /// step size for the next step.
+    fn gradient_step(
+        &mut self,
+        instances: ArrayView2<A>,
+        codes: ArrayView2<usize>,
+        mut step_size: A,
+    ) -> A
+    where
+        usize: AsPrimitive<A>,
+    {
+        let two = A::from(2.).unwrap();
+        let four = A::from(4.).unwrap();
+
+        // Gradient of the objective with respect to the centroids.
+        let mut gradient = Array3::zeros(self.codebooks.dim());
+        for (instance, codes) in instances.outer_iter().zip(codes.outer_iter()) {
+            let reconstruction = self.reconstruct_vector(codes);
+            let error = &instance - &reconstruction;
+            let violation = self.cross_term(codes) - self.epsilon;
+            for (codebook, (mut gradient, &code)) in self
+                .codebooks
+                .outer_iter()
+                .zip(gradient.outer_iter_mut().zip(codes.iter()))
+            {
+                let centroid = codebook.index_axis(Axis(0), code);
+                let mut centroid_gradient = gradient.index_axis_mut(Axis(0), code);
+                centroid_gradient.scaled_add(-two, &error);
+                centroid_gradient.scaled_add(
+                    four * self.penalty * violation,
+                    &(&reconstruction - &centroid),
+                );
+            }
+        }
+        gradient /= instances.nrows().max(1).as_();
+
+        let loss = self.loss(instances, codes);
+        let codebooks = self.codebooks.clone();
+        for _ in 0..MAX_STEP_HALVINGS {
+            self.codebooks = &codebooks - &(&gradient * step_size);
+            if self.loss(instances, codes) < loss {
+                // Try a larger step next time.
+                return step_size * two;
+            }
+            step_size /= two;
+        }
+
+        // No step decreased the objective.
+        self.codebooks = codebooks;
+        step_size
+    }
+
+    /// Optimize the codes of a batch of instances with ICM.
+    fn icm_batch(&self, instances: ArrayView2<A>, mut codes: ArrayViewMut2<usize>) {
+        let n_codebooks = self.n_codebooks();
+        let centroids = self.centroids();
+
+        // Pairwise terms: 2 <c_i, c_j> for all centroid pairs.
+        let pairwise = centroids.dot(&centroids.t()) * A::from(2.).unwrap();
+        let sqnorms = centroids.map_axis(Axis(1), |centroid| centroid.dot(&centroid));
+
+        let codes = codes.as_slice_mut().expect("Codes are not contiguous");
+        codes
+            .par_chunks_mut(ENCODE_BLOCK * n_codebooks)
+            .enumerate()
+            .for_each(|(block, block_codes)| {
+                let offset = block * ENCODE_BLOCK;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let block_instances =
+                    instances.slice(s![offset..offset + block_codes.len() / n_codebooks, ..]);
+
+                // Unary terms: ||c||^2 - 2 <x, c> for all centroids.
+                let mut unary = block_instances.dot(&centroids.t());
+                for mut instance_unary in unary.outer_iter_mut() {
+                    instance_unary.zip_mut_with(&sqnorms, |u, &sqnorm| *u = sqnorm - (*u + *u));
+                }
+
+                for (instance_unary, instance_codes) in unary
+                    .outer_iter()
+                    .zip(block_codes.chunks_exact_mut(n_codebooks))
+                {
+                    self.icm(pairwise.view(), instance_unary, instance_codes);
+                }
+            });
+    }
+
+    /// Optimize codes with iterated conditional modes.
+    ///
+    /// Every code is set in turn to the centroid that minimizes the
+    /// reconstruction error plus the constraint penalty given the other
+    /// codes.
+    fn icm(&self, pairwise: ArrayView2<A>, unary: ArrayView1<A>, codes: &mut [usize]) {
+        let n_codebooks = self.n_codebooks();
+        let n_centroids = self.n_codebook_centroids();
+
+        let mut cross = Array1::zeros(n_centroids);
+        for _ in 0..self.n_icm_iterations {
+            for codebook in 0..n_codebooks {
+                let offset = codebook * n_centroids;
+
+                // Cross term of the other codes and the cross terms of
+                // each centroid with the other codes.
+                let mut other_cross = A::zero();
+                cross.fill(A::zero());
+                for (other, &code) in codes.iter().enumerate() {
+                    if other == codebook {
+                        continue;
+                    }
+
+                    let row = pairwise.index_axis(Axis(0), other * n_centroids + code);
+                    // ndarray#474
+                    #[allow(clippy::deref_addrof)]
+                    let pair_costs = row.slice(s![offset..offset + n_centroids]);
+                    cross += &pair_costs;
+
+                    for (other2, &code2) in codes.iter().enumerate().skip(other + 1) {
+                        if other2 != codebook {
+                            other_cross += row[other2 * n_centroids + code2];
+                        }
+                    }
+                }
+
+                codes[codebook] = min_by_float_key(
+                    cross.iter().enumerate().map(|(idx, &cross)| {
+                        let violation = other_cross + cross - self.epsilon;
+                        (
+                            idx,
+                            unary[offset + idx] + cross + self.penalty * violation * violation,
+                        )
+                    }),
+                    |v| v.1,
+                )
+                .unwrap()
+                .0;
+            }
+        }
+    }
+}
+
+impl<A> QuantizeVector<A> for CompositeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_codebook_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        let mut codes = Array2::zeros((x.nrows(), self.n_codebooks()));
+        greedy_quantize_into::<A, usize>(self.codebooks.view(), x.view(), codes.view_mut());
+        self.icm_batch(x.view(), codes.view_mut());
+        quantized.zip_mut_with(&codes, |quantized, &code: &usize| *quantized = code.as_());
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        self.quantize_batch(x.view().insert_axis(Axis(0)))
+            .index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_codebooks()
+    }
+}
+
+impl<A> ReconstructVector<A> for CompositeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction.assign(&self.reconstruct_vector(codes));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.len(),
+            self.quantized_len(),
+            "Quantization length does not match number of codebooks"
+        );
+
+        let mut reconstruction = Array1::zeros(self.reconstructed_len());
+        for (codebook, &code) in self.codebooks.outer_iter().zip(quantized.iter()) {
+            reconstruction += &codebook.index_axis(Axis(0), code.as_());
+        }
+
+        reconstruction
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.codebooks.len_of(Axis(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array1, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{CQTrainer, CompositeQuantizer};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+    use crate::rq::{ResidualQuantizer, TrainRQ};
+
+    fn mean_squared_error<Q>(quantizer: &Q, instances: &Array2<f32>) -> f32
+    where
+        Q: QuantizeVector<f32> + ReconstructVector<f32>,
+    {
+        let quantized: Array2<u8> = quantizer.quantize_batch(instances.view());
+        let errors = instances - &quantizer.reconstruct_batch(quantized);
+        errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+    }
+
+    fn cross_term_variance(quantizer: &CompositeQuantizer<f32>, instances: &Array2<f32>) -> f32 {
+        let quantized: Array2<usize> = quantizer.quantize_batch(instances.view());
+        let cross: Array1<f32> = quantized
+            .outer_iter()
+            .map(|codes| quantizer.cross_term(codes))
+            .collect();
+        cross.var_axis(Axis(0), 0.).into_scalar()
+    }
+
+    #[test]
+    fn distance_table_with_orthogonal_codebooks() {
+        let cq = CompositeQuantizer::new(
+            array![[[1., 0., 0.], [2., 0., 0.]], [[0., 1., 0.], [0., -1., 1.]]],
+            0.,
+            1.,
+            1,
+        );
+
+        let query = array![0.5f64, -1., 2.];
+        let table = cq.distance_table(query.view());
+        for codes in &[[0, 0], [0, 1], [1, 0], [1, 1]] {
+            let reconstruction = cq.reconstruct_vector(Array1::from(codes.to_vec()));
+            let error = &query - &reconstruction;
+            let distance =
+                query.dot(&query) + table[(0, codes[0])] + table[(1, codes[1])] + cq.epsilon();
+            assert!((error.dot(&error) - distance).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn composite_quantizer_is_near_orthogonal() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(0., 1.), &mut rng);
+
+        let cq =
+            CQTrainer::new().train_using(4, 4, 8, instances.view(), XorShiftRng::seed_from_u64(1));
+        let rq = ResidualQuantizer::train_rq_using(
+            4,
+            4,
+            10,
+            1,
+            instances.view(),
+            XorShiftRng::seed_from_u64(1),
+        );
+        let unconstrained = CompositeQuantizer::new(rq.codebooks().to_owned(), 0., 0., 1);
+
+        // The constraint reduces the variance of the cross terms of the
+        // residual quantizer that is used for initialization.
+        assert!(
+            cross_term_variance(&cq, &instances)
+                < 0.5 * cross_term_variance(&unconstrained, &instances)
+        );
+
+        let pq = PQ::train_pq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        assert!(mean_squared_error(&cq, &instances) < mean_squared_error(&pq, &instances));
+    }
+}
@@ -0,0 +1,138 @@
+//! Error types.
+
+use std::error;
+use std::fmt;
+
+use crate::pq::Fingerprint;
+
+/// Errors of quantization operations.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A quantization code does not refer to a centroid.
+    CodeOutOfRange {
+        /// The subquantizer of the code.
+        subquantizer: usize,
+
+        /// The offending code.
+        code: usize,
+
+        /// The number of centroids of the subquantizer.
+        n_centroids: usize,
+    },
+
+    /// The number of codes per vector does not match the storage.
+    CodeLengthMismatch {
+        /// The number of codes per vector of the storage.
+        expected: usize,
+
+        /// The number of codes that were provided.
+        actual: usize,
+    },
+
+    /// A row is not in the code storage.
+    RowOutOfRange {
+        /// The offending row.
+        row: u64,
+
+        /// The number of rows of the storage.
+        n_rows: u64,
+    },
+
+    /// A vector does not have the normalization of the quantizer.
+    NotNormalized {
+        /// The row of the vector.
+        row: usize,
+
+        /// Why the vector is not normalized.
+        reason: String,
+    },
+
+    /// A row is missing from a sparse code storage.
+    MissingRow {
+        /// The missing row.
+        row: u64,
+    },
+
+    /// An error of the underlying code store.
+    Store(String),
+
+    /// An error of the GPU backend.
+    Gpu(String),
+
+    /// An Arrow array cannot be used as an instance matrix.
+    InvalidArrowArray(String),
+
+    /// A Polars column cannot be used as an instance matrix.
+    InvalidPolarsColumn(String),
+
+    /// Training of a subquantizer diverged.
+    TrainingDiverged {
+        /// The subquantizer that was trained.
+        subquantizer: usize,
+
+        /// The iteration in which the divergence was detected, 0 when
+        /// the training instances are invalid.
+        iteration: usize,
+
+        /// Description of the divergence.
+        reason: String,
+    },
+
+    /// The fingerprint of a quantizer does not match the expected fingerprint.
+    FingerprintMismatch {
+        /// The expected fingerprint.
+        expected: Fingerprint,
+
+        /// The fingerprint of the quantizer.
+        actual: Fingerprint,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CodeOutOfRange {
+                subquantizer,
+                code,
+                n_centroids,
+            } => write!(
+                f,
+                "Code {} of subquantizer {} is out of range, the subquantizer has {} centroids",
+                code, subquantizer, n_centroids
+            ),
+            Error::CodeLengthMismatch { expected, actual } => {
+                write!(f, "Expected {} codes per vector, got {}", expected, actual)
+            }
+            Error::RowOutOfRange { row, n_rows } => write!(
+                f,
+                "Row {} is out of range, the storage has {} rows",
+                row, n_rows
+            ),
+            Error::NotNormalized { row, reason } => {
+                write!(f, "Vector {} is not normalized: {}", row, reason)
+            }
+            Error::MissingRow { row } => write!(f, "Row {} is not in the storage", row),
+            Error::Store(err) => write!(f, "Code store error: {}", err),
+            Error::Gpu(err) => write!(f, "GPU error: {}", err),
+            Error::InvalidArrowArray(err) => write!(f, "Invalid Arrow array: {}", err),
+            Error::InvalidPolarsColumn(err) => write!(f, "Invalid Polars column: {}", err),
+            Error::TrainingDiverged {
+                subquantizer,
+                iteration,
+                reason,
+            } => write!(
+                f,
+                "Training of subquantizer {} diverged in iteration {}: {}",
+                subquantizer, iteration, reason
+            ),
+            Error::FingerprintMismatch { expected, actual } => write!(
+                f,
+                "Quantizer fingerprint {} does not match expected fingerprint {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
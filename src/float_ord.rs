@@ -0,0 +1,77 @@
+//! Comparison of floating point values.
+//!
+//! Floating point numbers are only partially ordered, because NaN is
+//! not comparable to any value. The functions in this module treat NaN
+//! as an error and panic when they encounter NaN in a comparison.
+
+use std::cmp::Ordering;
+
+/// Compare two floating point values.
+///
+/// Panics when one of the values is NaN.
+#[inline]
+pub(crate) fn float_cmp<A>(a: A, b: A) -> Ordering
+where
+    A: PartialOrd,
+{
+    a.partial_cmp(&b)
+        .expect("Cannot compare NaN to a floating point value")
+}
+
+/// Get the element with the minimum floating point key.
+///
+/// If several elements are equally minimum, the first element is
+/// returned. Panics when a comparison involves NaN.
+pub(crate) fn min_by_float_key<I, A, F>(iter: I, mut key: F) -> Option<I::Item>
+where
+    I: IntoIterator,
+    A: PartialOrd,
+    F: FnMut(&I::Item) -> A,
+{
+    iter.into_iter().min_by(|a, b| float_cmp(key(a), key(b)))
+}
+
+/// Get the element with the maximum floating point key.
+///
+/// If several elements are equally maximum, the last element is
+/// returned. Panics when a comparison involves NaN.
+pub(crate) fn max_by_float_key<I, A, F>(iter: I, mut key: F) -> Option<I::Item>
+where
+    I: IntoIterator,
+    A: PartialOrd,
+    F: FnMut(&I::Item) -> A,
+{
+    iter.into_iter().max_by(|a, b| float_cmp(key(a), key(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::{float_cmp, max_by_float_key, min_by_float_key};
+
+    #[test]
+    fn float_cmp_orders() {
+        assert_eq!(float_cmp(1f32, 2f32), Ordering::Less);
+        assert_eq!(float_cmp(-0f32, 0f32), Ordering::Equal);
+    }
+
+    #[test]
+    #[should_panic]
+    fn float_cmp_panics_on_nan() {
+        float_cmp(1f32, f32::NAN);
+    }
+
+    #[test]
+    fn min_max_by_float_key_ties() {
+        let values = [3f32, 1., 5., 1., 5.];
+        assert_eq!(
+            min_by_float_key(values.iter().enumerate(), |v| *v.1),
+            Some((1, &1.))
+        );
+        assert_eq!(
+            max_by_float_key(values.iter().enumerate(), |v| *v.1),
+            Some((4, &5.))
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Half-precision storage.
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use crate::pq::{QuantizeVector, ReconstructVector};
+
+/// Convert a single-precision float to half precision.
+///
+/// Rounds to the nearest half-precision float, ties to even. Values
+/// that are too large for half precision become infinity.
+pub(crate) fn f32_to_f16(v: f32) -> u16 {
+    let bits = v.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+
+    // Infinity or NaN, NaNs are kept quiet.
+    if exp == 0xff {
+        let nan = if mant != 0 {
+            0x200 | (mant >> 13) as u16
+        } else {
+            0
+        };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    // Subnormal or zero.
+    if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+
+        let mant = mant | 0x80_0000;
+        let shift = (14 - exp) as u32;
+        let half_mant = mant >> shift;
+        let round_bit = 1 << (shift - 1);
+        let rest = mant & ((round_bit << 1) - 1);
+        let rounded = if rest > round_bit || (rest == round_bit && half_mant & 1 == 1) {
+            half_mant + 1
+        } else {
+            half_mant
+        };
+
+        return sign | rounded as u16;
+    }
+
+    // A carry out of the mantissa increments the exponent, which also
+    // correctly rounds the largest values to infinity.
+    let half = ((exp as u32) << 10) | (mant >> 13);
+    let rest = mant & 0x1fff;
+    let half = if rest > 0x1000 || (rest == 0x1000 && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+
+    sign | half as u16
+}
+
+/// Convert a half-precision float to single precision.
+pub(crate) fn f16_to_f32(h: u16) -> f32 {
+    let sign = u32::from(h & 0x8000) << 16;
+    let exp = u32::from((h >> 10) & 0x1f);
+    let mant = u32::from(h & 0x3ff);
+
+    let bits = match (exp, mant) {
+        (0, 0) => sign,
+        // Subnormal, normalize the mantissa.
+        (0, _) => {
+            let shift = mant.leading_zeros() - 21;
+            let mant = (mant << shift) & 0x3ff;
+            sign | ((127 - 15 + 1 - shift) << 23) | (mant << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mant << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (mant << 13),
+    };
+
+    f32::from_bits(bits)
+}
+
+/// Half-precision quantizer.
+///
+/// This quantizer stores every component of a vector as a
+/// half-precision float, which halves the memory use of single-precision
+/// vectors with a small loss of precision. The code of a component is
+/// the bit pattern of the half-precision float, so codes should be
+/// stored as `u16`. The quantizer does not need to be trained and is
+/// mainly useful as a baseline for other quantizers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fp16Quantizer {
+    n_dims: usize,
+}
+
+impl Fp16Quantizer {
+    /// Construct a quantizer for vectors with `n_dims` components.
+    pub fn new(n_dims: usize) -> Self {
+        Fp16Quantizer { n_dims }
+    }
+}
+
+impl<A> QuantizeVector<A> for Fp16Quantizer
+where
+    A: NdFloat,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.n_dims));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.n_dims,
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.n_dims),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            u16::MAX as usize <= I::max_value().as_(),
+            "Cannot store half-precision floats in quantizer index type"
+        );
+
+        quantized.zip_mut_with(&x, |code, &v| {
+            *code = (f32_to_f16(v.to_f32().unwrap()) as usize).as_()
+        });
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.n_dims));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_dims
+    }
+}
+
+impl<A> ReconstructVector<A> for Fp16Quantizer
+where
+    A: NdFloat,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.n_dims));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.n_dims,
+            "Quantization length does not match number of dimensions"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.n_dims),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        reconstructions.zip_mut_with(&quantized, |v, &code| {
+            *v = A::from(f16_to_f32(code.as_() as u16)).unwrap()
+        });
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.n_dims));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.n_dims
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{f16_to_f32, f32_to_f16, Fp16Quantizer};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn convert_half_precision() {
+        assert_eq!(f32_to_f16(1.), 0x3c00);
+        assert_eq!(f32_to_f16(-2.), 0xc000);
+        assert_eq!(f32_to_f16(65504.), 0x7bff);
+        assert_eq!(f32_to_f16(65520.), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_f16(1e-8), 0);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 1);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+
+        // Ties are rounded to even.
+        assert_eq!(f32_to_f16(1. + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(1. + 3. * 2f32.powi(-11)), 0x3c02);
+
+        // All half-precision floats round-trip.
+        for h in 0..=u16::MAX {
+            let v = f16_to_f32(h);
+            if !v.is_nan() {
+                assert_eq!(f32_to_f16(v), h);
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_and_reconstruct() {
+        let fp16 = Fp16Quantizer::new(3);
+        let quantized: Array2<u16> = fp16.quantize_batch(array![[1f32, 0.5, -3.], [0., 1e-3, 1e5]]);
+        assert_eq!(
+            fp16.quantize_vector::<u16, _>(array![1f32, 0.5, -3.]),
+            array![0x3c00, 0x3800, 0xc200]
+        );
+
+        let reconstructions: Array2<f32> = fp16.reconstruct_batch(quantized);
+        assert_eq!(reconstructions.row(0), array![1., 0.5, -3.]);
+        assert!((reconstructions[(1, 1)] - 1e-3).abs() < 1e-6);
+        assert!(reconstructions[(1, 2)].is_infinite());
+    }
+
+    #[test]
+    fn reconstruction_error_is_small() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(-1., 1.), &mut rng);
+
+        let fp16 = Fp16Quantizer::new(16);
+        let quantized: Array2<u16> = fp16.quantize_batch(instances.view());
+        let reconstructions: Array2<f32> = fp16.reconstruct_batch(quantized);
+        for (&v, &r) in instances.iter().zip(reconstructions.iter()) {
+            assert!((v - r).abs() <= v.abs() * 2f32.powi(-11));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn quantize_with_too_narrow_type() {
+        let fp16 = Fp16Quantizer::new(1);
+        let _: Array2<u8> = fp16.quantize_batch(array![[1f32]]);
+    }
+}
@@ -0,0 +1,157 @@
+//! Golden regression tests.
+//!
+//! These tests train tiny models with fixed seeds and compare the
+//! resulting codebooks against expected codebooks that are stored in
+//! `testdata/golden`. They guard against refactors that silently change
+//! the numerical behavior of training.
+//!
+//! When a change in behavior is intended, the expected codebooks can be
+//! regenerated by running the tests with the `REDUCTIVE_BLESS_GOLDEN`
+//! environment variable set. The updated files should then be reviewed
+//! and committed together with the change.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use ndarray::{Array2, ArrayView, Axis, Dimension};
+use rand::distributions::Uniform;
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+
+use crate::kmeans::{KMeans, NIterationsCondition, RandomInstanceCentroids};
+use crate::ndarray_rand::RandomExt;
+use crate::pq::{TrainPQ, PQ};
+
+/// Environment variable that regenerates the expected codebooks.
+const BLESS_VAR: &str = "REDUCTIVE_BLESS_GOLDEN";
+
+/// Default tolerance of differences with expected codebooks.
+const GOLDEN_TOLERANCE: f32 = 1e-4;
+
+fn golden_path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "testdata", "golden"]
+        .iter()
+        .collect::<PathBuf>()
+        .join(format!("{}.txt", name))
+}
+
+/// Serialize an array as its shape followed by one line per innermost
+/// row.
+fn format_array<D>(array: ArrayView<f32, D>) -> String
+where
+    D: Dimension,
+{
+    let shape = array
+        .shape()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let mut text = format!("# shape {}\n", shape.join(" "));
+
+    let row_len = array.shape().last().cloned().unwrap_or(1).max(1);
+    let values = array.iter().map(ToString::to_string).collect::<Vec<_>>();
+    for row in values.chunks(row_len) {
+        text.push_str(&row.join(" "));
+        text.push('\n');
+    }
+
+    text
+}
+
+/// Parse an array serialized with `format_array`.
+fn parse_array(text: &str) -> (Vec<usize>, Vec<f32>) {
+    let mut lines = text.lines();
+    let shape = lines
+        .next()
+        .and_then(|line| line.strip_prefix("# shape"))
+        .expect("Golden file does not start with a shape")
+        .split_whitespace()
+        .map(|dim| dim.parse().expect("Invalid dimension in golden file"))
+        .collect();
+    let values = lines
+        .flat_map(str::split_whitespace)
+        .map(|v| v.parse().expect("Invalid value in golden file"))
+        .collect();
+
+    (shape, values)
+}
+
+/// Compare an array against the expected array `name`.
+///
+/// The expected array is overwritten when `REDUCTIVE_BLESS_GOLDEN` is
+/// set.
+fn assert_golden<D>(name: &str, actual: ArrayView<f32, D>, tolerance: f32)
+where
+    D: Dimension,
+{
+    let path = golden_path(name);
+
+    if env::var_os(BLESS_VAR).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).expect("Cannot create golden directory");
+        fs::write(&path, format_array(actual.view())).expect("Cannot write golden file");
+        return;
+    }
+
+    let text = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "Cannot read golden file {}: {}, set {} to create it",
+            path.display(),
+            err,
+            BLESS_VAR
+        )
+    });
+    let (shape, expected) = parse_array(&text);
+
+    assert_eq!(
+        actual.shape(),
+        shape.as_slice(),
+        "Shape differs from golden file {}",
+        path.display()
+    );
+    for (idx, (&a, &e)) in actual.iter().zip(expected.iter()).enumerate() {
+        assert!(
+            (a - e).abs() <= tolerance,
+            "Value {} differs from golden file {}: {} != {}",
+            idx,
+            path.display(),
+            a,
+            e
+        );
+    }
+}
+
+fn golden_instances(rng: &mut XorShiftRng) -> Array2<f32> {
+    Array2::random_using((256, 8), Uniform::new(-1., 1.), rng)
+}
+
+#[test]
+fn golden_format_round_trip() {
+    let array = ndarray::array![[[0.1f32, -2.5e-7], [3., 1e10]]];
+    let (shape, values) = parse_array(&format_array(array.view()));
+    assert_eq!(shape, vec![1, 2, 2]);
+    assert_eq!(values, array.iter().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn golden_kmeans() {
+    let mut rng = XorShiftRng::seed_from_u64(42);
+    let instances = golden_instances(&mut rng);
+    let (centroids, _) = instances.k_means(
+        Axis(0),
+        8,
+        RandomInstanceCentroids::new(&mut rng),
+        NIterationsCondition(10),
+    );
+
+    assert_golden("kmeans", centroids.view(), GOLDEN_TOLERANCE);
+}
+
+#[test]
+fn golden_pq() {
+    let mut rng = XorShiftRng::seed_from_u64(42);
+    let instances = golden_instances(&mut rng);
+    let pq = PQ::train_pq_using(4, 3, 10, 1, instances.view(), &mut rng);
+
+    assert_golden("pq", pq.subquantizers(), GOLDEN_TOLERANCE);
+}
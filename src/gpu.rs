@@ -0,0 +1,525 @@
+//! GPU backend.
+//!
+//! This module provides a wgpu implementation of batch quantization and
+//! ADC scanning of `f32` product quantizers. It is intended for offline
+//! jobs that process many vectors at once, such as re-encoding a dataset
+//! or scoring a batch of queries against all codes. Distance tables are
+//! computed on the CPU, the scans run on the GPU.
+
+use std::borrow::Cow;
+use std::sync::mpsc;
+
+use ndarray::{s, Array2, ArrayBase, Axis, Data, Ix2};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use wgpu::util::DeviceExt;
+
+use crate::error::Error;
+use crate::pq::primitives;
+use crate::pq::{PQView, QuantizeVector, ReconstructVector};
+
+const WORKGROUP_SIZE: usize = 64;
+
+const MAX_WORKGROUPS: usize = 65_535;
+
+const QUANTIZE_SHADER: &str = r#"
+struct Params {
+    n_rows: u32,
+    n_subquantizers: u32,
+    n_centroids: u32,
+    sub_dims: u32,
+    n_queries: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> centroids: array<f32>;
+@group(0) @binding(2) var<storage, read> instances: array<f32>;
+@group(0) @binding(3) var<storage, read_write> codes: array<u32>;
+
+@compute @workgroup_size(64)
+fn quantize(@builtin(global_invocation_id) id: vec3<u32>) {
+    let row = id.x;
+    let subquantizer = id.y;
+    if (row >= params.n_rows || subquantizer >= params.n_subquantizers) {
+        return;
+    }
+
+    let instance_offset = (row * params.n_subquantizers + subquantizer) * params.sub_dims;
+    var best = 0u;
+    var best_dist = 3.40282347e38;
+    for (var centroid = 0u; centroid < params.n_centroids; centroid = centroid + 1u) {
+        let centroid_offset = (subquantizer * params.n_centroids + centroid) * params.sub_dims;
+        var dist = 0.0;
+        for (var dim = 0u; dim < params.sub_dims; dim = dim + 1u) {
+            let diff = instances[instance_offset + dim] - centroids[centroid_offset + dim];
+            dist = dist + diff * diff;
+        }
+
+        if (dist < best_dist) {
+            best_dist = dist;
+            best = centroid;
+        }
+    }
+
+    codes[row * params.n_subquantizers + subquantizer] = best;
+}
+"#;
+
+const SCAN_SHADER: &str = r#"
+struct Params {
+    n_rows: u32,
+    n_subquantizers: u32,
+    n_centroids: u32,
+    sub_dims: u32,
+    n_queries: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> tables: array<f32>;
+@group(0) @binding(2) var<storage, read> codes: array<u32>;
+@group(0) @binding(3) var<storage, read_write> distances: array<f32>;
+
+@compute @workgroup_size(64)
+fn scan(@builtin(global_invocation_id) id: vec3<u32>) {
+    let row = id.x;
+    let query = id.y;
+    if (row >= params.n_rows || query >= params.n_queries) {
+        return;
+    }
+
+    let table_offset = query * params.n_subquantizers * params.n_centroids;
+    let codes_offset = row * params.n_subquantizers;
+    var dist = 0.0;
+    for (var subquantizer = 0u; subquantizer < params.n_subquantizers; subquantizer = subquantizer + 1u) {
+        let code = codes[codes_offset + subquantizer];
+        dist = dist + tables[table_offset + subquantizer * params.n_centroids + code];
+    }
+
+    distances[query * params.n_rows + row] = dist;
+}
+"#;
+
+/// GPU quantizer.
+///
+/// Holds a GPU device and the compiled compute pipelines. Constructing
+/// a `GpuQuantizer` is relatively expensive, so it should be reused
+/// across batches.
+pub struct GpuQuantizer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    quantize_pipeline: wgpu::ComputePipeline,
+    scan_pipeline: wgpu::ComputePipeline,
+}
+
+impl GpuQuantizer {
+    /// Construct a GPU quantizer on the default high-performance adapter.
+    ///
+    /// Returns an error if there is no suitable adapter.
+    pub fn new() -> Result<Self, Error> {
+        Self::with_adapter_options(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        })
+    }
+
+    /// Construct a GPU quantizer on a software adapter.
+    ///
+    /// Software adapters run the shaders on the CPU, which is slow, but
+    /// allows using the quantizer on machines without a GPU. Returns an
+    /// error if the platform does not provide a software adapter.
+    pub fn with_fallback_adapter() -> Result<Self, Error> {
+        Self::with_adapter_options(&wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..Default::default()
+        })
+    }
+
+    fn with_adapter_options(options: &wgpu::RequestAdapterOptions) -> Result<Self, Error> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(options))
+            .ok_or_else(|| Error::Gpu("no suitable adapter".to_string()))?;
+
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("reductive"),
+                required_limits: adapter.limits(),
+                ..Default::default()
+            },
+            None,
+        ))
+        .map_err(|err| Error::Gpu(err.to_string()))?;
+
+        let quantize_pipeline = create_pipeline(&device, QUANTIZE_SHADER, "quantize");
+        let scan_pipeline = create_pipeline(&device, SCAN_SHADER, "scan");
+
+        Ok(GpuQuantizer {
+            device,
+            queue,
+            quantize_pipeline,
+            scan_pipeline,
+        })
+    }
+
+    /// Quantize a batch of vectors.
+    ///
+    /// This is the GPU counterpart of `QuantizeVector::quantize_batch`.
+    /// If the quantizer has a projection, the vectors are projected on
+    /// the CPU.
+    pub fn quantize_batch<I, S>(
+        &self,
+        quantizer: PQView<f32>,
+        x: ArrayBase<S, Ix2>,
+    ) -> Result<Array2<I>, Error>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = f32>,
+        usize: AsPrimitive<I>,
+    {
+        let quantizers = quantizer.subquantizers();
+        assert!(
+            quantizers.len_of(Axis(1)) - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+        assert_eq!(
+            x.ncols(),
+            quantizer.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+
+        let x = quantizer.rotate_batch(x);
+        let n_subquantizers = quantizer.quantized_len();
+        let mut quantized = Array2::zeros((x.nrows(), n_subquantizers));
+        if x.is_empty() || n_subquantizers == 0 {
+            return Ok(quantized);
+        }
+
+        let centroids = f32_bytes(quantizers.iter().cloned());
+        let chunk_rows = self.chunk_len(x.ncols().max(n_subquantizers) * 4);
+        for (x_chunk, mut quantized_chunk) in x
+            .axis_chunks_iter(Axis(0), chunk_rows)
+            .zip(quantized.axis_chunks_iter_mut(Axis(0), chunk_rows))
+        {
+            let params = [
+                x_chunk.nrows() as u32,
+                n_subquantizers as u32,
+                quantizers.len_of(Axis(1)) as u32,
+                quantizers.len_of(Axis(2)) as u32,
+                0,
+                0,
+                0,
+                0,
+            ];
+            let codes = self.run(
+                &self.quantize_pipeline,
+                params,
+                &[&centroids, &f32_bytes(x_chunk.iter().cloned())],
+                quantized_chunk.len(),
+                (x_chunk.nrows(), n_subquantizers),
+            )?;
+
+            for (code, bytes) in quantized_chunk.iter_mut().zip(codes.chunks_exact(4)) {
+                *code =
+                    (u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize).as_();
+            }
+        }
+
+        Ok(quantized)
+    }
+
+    /// Compute the approximate distances between queries and quantized vectors.
+    ///
+    /// Returns a matrix with shape *(n_queries, n_vectors)* of approximate
+    /// squared Euclidean distances. The distance tables are computed on
+    /// the CPU with `PQView::adc_tables_batch`, the codes are scanned on
+    /// the GPU. Returns an error when a code does not refer to a
+    /// centroid.
+    pub fn adc_distances_batch<I, S, T>(
+        &self,
+        quantizer: PQView<f32>,
+        queries: ArrayBase<S, Ix2>,
+        quantized: ArrayBase<T, Ix2>,
+    ) -> Result<Array2<f32>, Error>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = f32>,
+        T: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            quantizer.quantized_len(),
+            "Quantizer and quantized vector length mismatch"
+        );
+
+        for codes in quantized.outer_iter() {
+            primitives::check_codes(quantizer.subquantizers(), codes)?;
+        }
+
+        let tables = quantizer.adc_tables_batch(queries);
+        let (n_queries, n_subquantizers, n_centroids) = tables.dim();
+        let mut distances = Array2::zeros((n_queries, quantized.nrows()));
+        if distances.is_empty() || n_subquantizers == 0 {
+            return Ok(distances);
+        }
+
+        let codes = quantized
+            .iter()
+            .flat_map(|&code| (code.as_() as u32).to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        let query_chunk = self
+            .chunk_len(n_subquantizers * n_centroids * 4)
+            .min(MAX_WORKGROUPS);
+        for (query_offset, tables_chunk) in (0..n_queries)
+            .step_by(query_chunk)
+            .zip(tables.axis_chunks_iter(Axis(0), query_chunk))
+        {
+            let n_chunk_queries = tables_chunk.len_of(Axis(0));
+            let tables_chunk = f32_bytes(tables_chunk.iter().cloned());
+            let row_chunk = self.chunk_len((n_subquantizers + n_chunk_queries) * 4);
+
+            for row_offset in (0..quantized.nrows()).step_by(row_chunk) {
+                let n_rows = row_chunk.min(quantized.nrows() - row_offset);
+                let params = [
+                    n_rows as u32,
+                    n_subquantizers as u32,
+                    n_centroids as u32,
+                    0,
+                    n_chunk_queries as u32,
+                    0,
+                    0,
+                    0,
+                ];
+                let chunk_distances = self.run(
+                    &self.scan_pipeline,
+                    params,
+                    &[
+                        &tables_chunk,
+                        &codes[row_offset * n_subquantizers * 4
+                            ..(row_offset + n_rows) * n_subquantizers * 4],
+                    ],
+                    n_chunk_queries * n_rows,
+                    (n_rows, n_chunk_queries),
+                )?;
+
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let mut distances_chunk = distances.slice_mut(s![
+                    query_offset..query_offset + n_chunk_queries,
+                    row_offset..row_offset + n_rows
+                ]);
+                for (distance, bytes) in distances_chunk
+                    .iter_mut()
+                    .zip(chunk_distances.chunks_exact(4))
+                {
+                    *distance = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                }
+            }
+        }
+
+        Ok(distances)
+    }
+
+    /// Get the number of rows per dispatch, given the largest number of
+    /// bytes per row of any buffer.
+    fn chunk_len(&self, row_bytes: usize) -> usize {
+        let limits = self.device.limits();
+        let max_bytes =
+            (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size) as usize;
+        (max_bytes / row_bytes.max(1)).clamp(1, MAX_WORKGROUPS * WORKGROUP_SIZE)
+    }
+
+    /// Run a pipeline with two input buffers and return the output buffer.
+    ///
+    /// The output buffer has `output_len` 32-bit elements. One invocation
+    /// is dispatched for every element of the `invocations` grid.
+    fn run(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        params: [u32; 8],
+        inputs: &[&[u8]; 2],
+        output_len: usize,
+        invocations: (usize, usize),
+    ) -> Result<Vec<u8>, Error> {
+        let params = params
+            .iter()
+            .flat_map(|v| v.to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: &params,
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let inputs = inputs
+            .iter()
+            .map(|input| {
+                self.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("input"),
+                        contents: input,
+                        usage: wgpu::BufferUsages::STORAGE,
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let output_size = (output_len * 4) as u64;
+        let output = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("output"),
+            size: output_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging"),
+            size: output_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: inputs[0].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: inputs[1].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: output.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                (invocations.0 / WORKGROUP_SIZE + usize::from(invocations.0 % WORKGROUP_SIZE != 0))
+                    as u32,
+                invocations.1 as u32,
+                1,
+            );
+        }
+        encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, output_size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|err| Error::Gpu(err.to_string()))?
+            .map_err(|err| Error::Gpu(err.to_string()))?;
+
+        let result = slice.get_mapped_range().to_vec();
+        staging.unmap();
+
+        Ok(result)
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    source: &str,
+    entry_point: &str,
+) -> wgpu::ComputePipeline {
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(entry_point),
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(source)),
+    });
+
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some(entry_point),
+        layout: None,
+        module: &module,
+        entry_point: Some(entry_point),
+        compilation_options: Default::default(),
+        cache: None,
+    })
+}
+
+fn f32_bytes(values: impl Iterator<Item = f32>) -> Vec<u8> {
+    values.flat_map(|v| v.to_ne_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+
+    use super::GpuQuantizer;
+    use crate::error::Error;
+    use crate::pq::{test_pq, QuantizeVector};
+
+    /// Get a quantizer, preferring a software adapter.
+    fn gpu() -> GpuQuantizer {
+        GpuQuantizer::with_fallback_adapter()
+            .or_else(|_| GpuQuantizer::new())
+            .expect("No GPU or software adapter available")
+    }
+
+    #[test]
+    #[ignore = "requires a GPU or software adapter, run with --ignored"]
+    fn gpu_quantize_and_scan_match_cpu() {
+        let gpu = gpu();
+
+        let pq = test_pq();
+        let instances: Array2<f32> = array![
+            [0., 2., 0., -0.5, 0., 0.],
+            [1., -0.2, 0., 0.5, 0.5, 0.],
+            [-0.2, 0.2, 0., 0., -2., 0.],
+        ];
+
+        let quantized = pq.quantize_batch::<u8, _>(instances.view());
+        assert_eq!(
+            gpu.quantize_batch::<u8, _>(pq.view(), instances.view()),
+            Ok(quantized.clone())
+        );
+
+        let queries = array![[1f32, 0., 0., 0., 1., 0.], [0., 1., 0., 1., -1., 0.]];
+        let tables = pq.adc_tables_batch(queries.view());
+        let distances = gpu
+            .adc_distances_batch(pq.view(), queries.view(), quantized.view())
+            .unwrap();
+        for (query, query_distances) in distances.outer_iter().enumerate() {
+            for (row, &distance) in query_distances.iter().enumerate() {
+                let expected = tables[(query, 0, quantized[(row, 0)] as usize)]
+                    + tables[(query, 1, quantized[(row, 1)] as usize)];
+                assert!((distance - expected).abs() < 1e-5);
+            }
+        }
+
+        assert_eq!(
+            gpu.adc_distances_batch(pq.view(), queries.view(), array![[0u8, 2]]),
+            Err(Error::CodeOutOfRange {
+                subquantizer: 1,
+                code: 2,
+                n_centroids: 2
+            })
+        );
+    }
+}
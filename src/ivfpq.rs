@@ -0,0 +1,372 @@
+//! Jointly trained coarse and residual product quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use crate::kmeans::{
+    cluster_assignments, InitialCentroids, KMeansWithCentroids, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+/// Two-level quantizer with a coarse quantizer and a residual product
+/// quantizer.
+///
+/// The coarse k-means quantizer partitions the vector space into cells
+/// and a single product quantizer quantizes the residuals of vectors
+/// with respect to their cell centroid. This is the encoding that is
+/// used by inverted file indexes with product quantization (IVFADC).
+///
+/// Usually the coarse quantizer is trained first and the residual
+/// quantizer is then trained on the residuals of the fixed coarse
+/// quantizer. `IvfPQ::train_using` instead alternates between the two
+/// levels, such that the coarse centroids also adapt to the errors of
+/// the residual quantizer.
+///
+/// The first element of a code is the cell of the vector, the remaining
+/// elements are the codes of the residual quantizer. A vector is
+/// reconstructed by adding the reconstruction of the residual to the
+/// cell centroid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IvfPQ<A> {
+    coarse: Array2<A>,
+    residual: PQ<A>,
+}
+
+impl<A> IvfPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a quantizer from its coarse centroids and residual
+    /// quantizer.
+    pub fn new(coarse: Array2<A>, residual: PQ<A>) -> Self {
+        assert!(
+            coarse.nrows() > 0,
+            "A coarse quantizer should have at least one cell."
+        );
+        assert_eq!(
+            residual.reconstructed_len(),
+            coarse.ncols(),
+            "The residual quantizer has an incorrect vector length"
+        );
+
+        IvfPQ { coarse, residual }
+    }
+
+    /// Train the coarse and residual quantizers jointly.
+    ///
+    /// The coarse quantizer with `n_cells` cells is initialized with
+    /// `n_coarse_iterations` k-means iterations. Training then runs
+    /// `n_rounds` rounds that each:
+    ///
+    /// 1. assign the instances to their nearest coarse centroid;
+    /// 2. train the residual quantizer on the residuals of these
+    ///    assignments, the first round trains a new quantizer and later
+    ///    rounds refine its codebooks with `n_iterations` sweeps;
+    /// 3. update every coarse centroid to the mean difference between
+    ///    the instances of its cell and their reconstructed residuals.
+    ///
+    /// Step 3 minimizes the reconstruction error for the residual codes
+    /// of step 2. See `TrainPQ::train_pq_using` for a description of the
+    /// remaining arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_using<S, R>(
+        n_cells: usize,
+        n_coarse_iterations: usize,
+        n_rounds: usize,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(n_cells > 0, "The number of cells should at least be 1.");
+        assert!(
+            n_coarse_iterations > 0,
+            "The coarse quantizer should be optimized for at least one iteration."
+        );
+        assert!(n_rounds > 0, "Training should run for at least one round.");
+
+        info!("Training coarse quantizer with {} cells", n_cells);
+        let mut coarse = RandomInstanceCentroids::new(&mut rng).initial_centroids(
+            instances.view(),
+            Axis(0),
+            n_cells,
+        );
+        instances.kmeans_with_centroids(
+            Axis(0),
+            coarse.view_mut(),
+            NIterationsCondition(n_coarse_iterations),
+        );
+
+        let mut residual: Option<PQ<A>> = None;
+        for round in 0..n_rounds {
+            let assignments = cluster_assignments(coarse.view(), instances.view(), Axis(0));
+            let mut residuals = instances.to_owned();
+            for (mut instance, &cell) in residuals.outer_iter_mut().zip(assignments.iter()) {
+                instance -= &coarse.row(cell);
+            }
+
+            let pq = match residual.as_mut() {
+                Some(pq) => {
+                    pq.refine(residuals.view(), n_iterations);
+                    pq
+                }
+                None => residual.get_or_insert(PQ::train_pq_using(
+                    n_subquantizers,
+                    n_subquantizer_bits,
+                    n_iterations,
+                    n_attempts,
+                    residuals.view(),
+                    &mut rng,
+                )),
+            };
+
+            let codes = pq.quantize_batch::<usize, _>(residuals.view());
+            let targets = &instances - &pq.reconstruct_batch(codes);
+
+            let mut sums = Array2::zeros(coarse.dim());
+            let mut counts = vec![0usize; n_cells];
+            for (target, &cell) in targets.outer_iter().zip(assignments.iter()) {
+                let mut sum = sums.row_mut(cell);
+                sum += &target;
+                counts[cell] += 1;
+            }
+            for ((mut centroid, sum), &count) in coarse
+                .outer_iter_mut()
+                .zip(sums.outer_iter())
+                .zip(counts.iter())
+            {
+                // Empty cells keep their centroid.
+                if count > 0 {
+                    centroid.assign(&(&sum / count.as_()));
+                }
+            }
+
+            let mut errors = targets;
+            for (mut error, &cell) in errors.outer_iter_mut().zip(assignments.iter()) {
+                error -= &coarse.row(cell);
+            }
+            info!(
+                "Round {}: mean squared error {}",
+                round,
+                errors.iter().map(|&v| v * v).sum::<A>() / instances.nrows().as_()
+            );
+        }
+
+        IvfPQ {
+            coarse,
+            residual: residual.expect("No residual quantizer was trained"),
+        }
+    }
+
+    /// Get the coarse centroids.
+    pub fn coarse_centroids(&self) -> ArrayView2<A> {
+        self.coarse.view()
+    }
+
+    /// Get the number of cells.
+    pub fn n_cells(&self) -> usize {
+        self.coarse.nrows()
+    }
+
+    /// Get the quantizer of the residuals.
+    pub fn residual_quantizer(&self) -> &PQ<A> {
+        &self.residual
+    }
+}
+
+impl<A> QuantizeVector<A> for IvfPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_cells() - 1 <= I::max_value().as_(),
+            "Cannot store cells in quantizer index type"
+        );
+
+        let assignments = cluster_assignments(self.coarse.view(), x.view(), Axis(0));
+        let mut residuals = x.to_owned();
+        for (mut residual, &cell) in residuals.outer_iter_mut().zip(assignments.iter()) {
+            residual -= &self.coarse.row(cell);
+        }
+
+        for (mut codes, &cell) in quantized.outer_iter_mut().zip(assignments.iter()) {
+            codes[0] = cell.as_();
+        }
+        self.residual
+            .quantize_batch_into(residuals, quantized.slice_mut(s![.., 1..]));
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        1 + self.residual.quantized_len()
+    }
+}
+
+impl<A> ReconstructVector<A> for IvfPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization length does not match the number of subquantizers plus the cell"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        self.residual
+            .reconstruct_batch_into(quantized.slice(s![.., 1..]), reconstructions.view_mut());
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction += &self.coarse.row(codes[0].as_());
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.coarse.ncols()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Array3, Axis};
+    use rand::SeedableRng;
+    use rand_distr::Normal;
+    use rand_xorshift::XorShiftRng;
+
+    use super::IvfPQ;
+    use crate::kmeans::{
+        cluster_assignments, KMeans, NIterationsCondition, RandomInstanceCentroids,
+    };
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn quantize_residuals_of_cells() {
+        let residual = PQ::new(
+            None,
+            Array3::from_shape_fn((2, 2, 1), |(_, c, _)| c as f32 * 2. - 1.),
+        );
+        let ivf = IvfPQ::new(array![[0f32, 0.], [10., 10.]], residual);
+        assert_eq!(ivf.quantized_len(), 3);
+
+        let quantized: Array2<u8> = ivf.quantize_batch(array![[1f32, -1.], [8., 13.]]);
+        assert_eq!(quantized, array![[0, 1, 0], [1, 0, 1]]);
+        assert_eq!(
+            ivf.reconstruct_batch(quantized),
+            array![[1., -1.], [9., 11.]]
+        );
+    }
+
+    #[test]
+    fn joint_training_reduces_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = Array2::random_using((2048, 8), Normal::new(0f32, 1.).unwrap(), &mut rng);
+
+        let mse = |quantizer: &IvfPQ<f32>| {
+            let quantized: Array2<u8> = quantizer.quantize_batch(instances.view());
+            let errors = &instances - &quantizer.reconstruct_batch(quantized);
+            errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+        };
+
+        let joint = IvfPQ::train_using(16, 10, 5, 4, 2, 10, 1, instances.view(), &mut rng);
+        assert_eq!(joint.n_cells(), 16);
+
+        // Compare to a residual quantizer of a fixed coarse quantizer.
+        let (coarse, _) = instances.k_means(
+            Axis(0),
+            16,
+            RandomInstanceCentroids::new(&mut rng),
+            NIterationsCondition(10),
+        );
+        let cells = cluster_assignments(coarse.view(), instances.view(), Axis(0)).to_vec();
+        let residuals = &instances - &coarse.select(Axis(0), &cells);
+        let residual = PQ::train_pq_using(4, 2, 10, 1, residuals, &mut rng);
+        let sequential = IvfPQ::new(coarse, residual);
+
+        assert!(mse(&joint) < mse(&sequential));
+    }
+}
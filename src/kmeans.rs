@@ -1,17 +1,24 @@
 //! K-means clustering.
 
-use std::collections::HashSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::VecDeque;
 use std::iter::Sum;
+use std::mem;
 
+use ndarray::linalg::general_mat_mul;
 use ndarray::{
-    Array1, Array2, ArrayBase, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+    s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, Axis, Data,
+    Ix1, Ix2, NdFloat, Zip,
 };
 use num_traits::AsPrimitive;
-use ordered_float::OrderedFloat;
-use rand::distributions::{Distribution, Uniform};
+use rand::seq::index;
 use rand::Rng;
+use rayon::prelude::*;
 
+use crate::float_ord::{float_cmp, max_by_float_key, min_by_float_key};
 use crate::linalg::SquaredEuclideanDistance;
+use crate::parallel::NestedParallelism;
+use crate::simd;
 
 /// Initial centroid selection.
 pub trait InitialCentroids<A> {
@@ -70,131 +77,1553 @@ where
             "Cannot pick centroids from zero-length instances"
         );
 
-        // Use random instances as centroids.
-        let uniform = Uniform::new(0, data.len_of(instance_axis));
-        let mut initial_indices = HashSet::new();
-        while initial_indices.len() != k {
-            initial_indices.insert(uniform.sample(&mut self.0));
+        // Use random instances as centroids. The indices are sampled
+        // in a deterministic order, so that clustering is reproducible
+        // for a seeded RNG.
+        let initial_indices = index::sample(&mut self.0, data.len_of(instance_axis), k);
+
+        // Assign instances.
+        let mut centroids = Array2::zeros((k, data.len() / data.len_of(instance_axis)));
+        for (idx, mut centroid) in initial_indices.iter().zip(centroids.outer_iter_mut()) {
+            centroid.assign(&data.index_axis(instance_axis, idx));
+        }
+
+        centroids
+    }
+}
+
+/// Initialization method of k-means centroids.
+///
+/// This selects one of the `InitialCentroids` implementations in
+/// training APIs that construct initial centroids for multiple
+/// codebooks, such as `KMeansTrainer` and
+/// `OPQ::train_pq_initialized_using`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CentroidInitialization {
+    /// Random instances, see `RandomInstanceCentroids`.
+    #[default]
+    RandomInstances,
+
+    /// Per-dimension quantiles, see `QuantileCentroids`.
+    Quantiles,
+
+    /// Bisecting k-means with the given number of 2-means iterations
+    /// per split, see `BisectingCentroids`.
+    Bisecting(usize),
+}
+
+impl CentroidInitialization {
+    /// Pick *k* initial centroids.
+    ///
+    /// See `InitialCentroids::initial_centroids`, `rng` is used by the
+    /// random initializations.
+    pub fn initial_centroids<A, S, R>(
+        self,
+        data: ArrayBase<S, Ix2>,
+        instance_axis: Axis,
+        k: usize,
+        rng: &mut R,
+    ) -> Array2<A>
+    where
+        A: NdFloat + Sum,
+        S: Data<Elem = A>,
+        R: Rng + ?Sized,
+        usize: AsPrimitive<A>,
+    {
+        match self {
+            CentroidInitialization::RandomInstances => {
+                RandomInstanceCentroids::new(rng).initial_centroids(data, instance_axis, k)
+            }
+            CentroidInitialization::Quantiles => {
+                QuantileCentroids.initial_centroids(data, instance_axis, k)
+            }
+            CentroidInitialization::Bisecting(n_iterations) => {
+                BisectingCentroids::new(rng, n_iterations).initial_centroids(data, instance_axis, k)
+            }
+        }
+    }
+}
+
+/// Pick centroids from per-dimension quantiles.
+///
+/// The centroids are the points of a rank-1 lattice in quantile space:
+/// centroid *j* takes in each dimension *i* the quantile at level
+/// *frac((j g_i + 0.5) / k)*, where the generators *g_i* are coprime
+/// with *k*. As a result, each dimension of the centroids consists of
+/// *k* evenly spaced quantiles, while the centroids are spread over the
+/// space spanned by the instances.
+///
+/// This initialization is deterministic and can outperform random
+/// instances as centroids on skewed data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuantileCentroids;
+
+impl<A> InitialCentroids<A> for QuantileCentroids
+where
+    A: NdFloat,
+{
+    fn initial_centroids<S>(
+        &mut self,
+        data: ArrayBase<S, Ix2>,
+        instance_axis: Axis,
+        k: usize,
+    ) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(k > 0, "Cannot pick 0 quantile centroids");
+        assert!(
+            data.len_of(instance_axis) > 0,
+            "Cannot pick quantile centroids without instances"
+        );
+
+        let n_instances = data.len_of(instance_axis);
+        let n_dims = data.len() / n_instances;
+
+        // Korobov generator close to the golden section, which spreads
+        // the lattice points well.
+        let mut generator = ((k as f64 * 0.618_034).round() as usize).max(1);
+        while gcd(generator, k) != 1 {
+            generator += 1;
+        }
+
+        let mut centroids = Array2::zeros((k, n_dims));
+        let mut dim_generator = 1;
+        for (dim, mut centroid_dim) in data
+            .axis_iter(Axis(1 - instance_axis.index()))
+            .zip(centroids.axis_iter_mut(Axis(1)))
+        {
+            let mut sorted = dim.to_vec();
+            sorted.sort_unstable_by(|&a, &b| float_cmp(a, b));
+
+            for (j, centroid) in centroid_dim.iter_mut().enumerate() {
+                let level = ((j * dim_generator) % k) as f64 + 0.5;
+                let idx = (level / k as f64 * n_instances as f64) as usize;
+                *centroid = sorted[idx.min(n_instances - 1)];
+            }
+
+            dim_generator = (dim_generator * generator) % k;
+        }
+
+        centroids
+    }
+}
+
+/// Pick centroids using bisecting k-means.
+///
+/// Bisecting k-means starts with a single cluster and then repeatedly
+/// splits the cluster with the largest sum of squared errors in two
+/// using 2-means clustering, until there are *k* clusters. The centroids
+/// of these clusters are returned.
+///
+/// Bisecting k-means is often more robust than random initialization
+/// for large *k*. Since the resulting centroids are used as initial
+/// centroids, they are refined further by the k-means iterations of
+/// `KMeans::k_means`.
+///
+/// When the data has fewer than *k* distinct instances, clusters run
+/// out before *k* clusters are formed. The remaining centroids are then
+/// duplicates of the centroids of the largest clusters.
+pub struct BisectingCentroids<R> {
+    rng: R,
+    n_iterations: usize,
+}
+
+impl<R> BisectingCentroids<R>
+where
+    R: Rng,
+{
+    /// Construct `BisectingCentroids`.
+    ///
+    /// Each split is performed using `n_iterations` iterations of 2-means
+    /// clustering, starting from two random instances drawn using `rng`.
+    pub fn new(rng: R, n_iterations: usize) -> Self {
+        assert!(
+            n_iterations > 0,
+            "Clusters should be split using at least one iteration"
+        );
+
+        BisectingCentroids { rng, n_iterations }
+    }
+}
+
+impl<A, R> InitialCentroids<A> for BisectingCentroids<R>
+where
+    A: NdFloat + Sum,
+    R: Rng,
+    usize: AsPrimitive<A>,
+{
+    fn initial_centroids<S>(
+        &mut self,
+        data: ArrayBase<S, Ix2>,
+        instance_axis: Axis,
+        k: usize,
+    ) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(k > 0, "Cannot pick 0 bisecting centroids");
+        assert!(
+            k <= data.len_of(instance_axis),
+            "Cannot pick more centroids than instances: {} instances, {} centroids",
+            data.len_of(instance_axis),
+            k
+        );
+
+        let instances = match instance_axis {
+            Axis(0) => data.view(),
+            Axis(1) => data.t(),
+            _ => unreachable!(),
+        };
+
+        let all_indices = (0..instances.nrows()).collect::<Vec<_>>();
+        let mut clusters = vec![BisectingCluster::new(instances, all_indices)];
+
+        while clusters.len() < k {
+            let split_idx = match max_by_float_key(
+                clusters
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, cluster)| cluster.splittable),
+                |(_, cluster)| cluster.sse,
+            ) {
+                Some((split_idx, _)) => split_idx,
+                // All remaining clusters consist of identical instances.
+                None => break,
+            };
+
+            match self.split(instances, &clusters[split_idx].indices) {
+                Some((left, right)) => {
+                    clusters[split_idx] = left;
+                    clusters.push(right);
+                }
+                None => clusters[split_idx].splittable = false,
+            }
+        }
+
+        // Duplicate centroids of the largest clusters when there are
+        // fewer than k clusters.
+        if clusters.len() < k {
+            clusters.sort_by_key(|cluster| Reverse(cluster.indices.len()));
+        }
+
+        let mut centroids = Array2::zeros((k, instances.ncols()));
+        for (cluster, mut centroid) in clusters.iter().cycle().zip(centroids.outer_iter_mut()) {
+            centroid.assign(&cluster.centroid);
+        }
+
+        centroids
+    }
+}
+
+impl<R> BisectingCentroids<R>
+where
+    R: Rng,
+{
+    /// Split a cluster using 2-means clustering.
+    ///
+    /// Returns `None` if the cluster cannot be split, because all
+    /// instances are assigned to the same centroid.
+    fn split<A>(
+        &mut self,
+        instances: ArrayView2<A>,
+        indices: &[usize],
+    ) -> Option<(BisectingCluster<A>, BisectingCluster<A>)>
+    where
+        A: NdFloat + Sum,
+        usize: AsPrimitive<A>,
+    {
+        let cluster_instances = instances.select(Axis(0), indices);
+
+        let initial_indices = index::sample(&mut self.rng, indices.len(), 2).into_vec();
+        let mut centroids = cluster_instances.select(Axis(0), &initial_indices);
+        cluster_instances.kmeans_with_centroids(
+            Axis(0),
+            centroids.view_mut(),
+            NIterationsCondition(self.n_iterations),
+        );
+
+        let assignments = cluster_assignments(centroids.view(), cluster_instances.view(), Axis(0));
+        let (left, right): (Vec<_>, Vec<_>) = indices
+            .iter()
+            .zip(assignments.iter())
+            .partition(|(_, &assignment)| assignment == 0);
+
+        if left.is_empty() || right.is_empty() {
+            return None;
+        }
+
+        Some((
+            BisectingCluster::new(instances, left.into_iter().map(|(&idx, _)| idx).collect()),
+            BisectingCluster::new(instances, right.into_iter().map(|(&idx, _)| idx).collect()),
+        ))
+    }
+}
+
+struct BisectingCluster<A> {
+    indices: Vec<usize>,
+    centroid: Array1<A>,
+    sse: A,
+    splittable: bool,
+}
+
+impl<A> BisectingCluster<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn new(instances: ArrayView2<A>, indices: Vec<usize>) -> Self {
+        let cluster_instances = instances.select(Axis(0), &indices);
+        let centroid = cluster_instances.sum_axis(Axis(0)) / indices.len().as_();
+        let sse = cluster_instances
+            .outer_iter()
+            .map(|instance| {
+                let diff = &instance - &centroid;
+                diff.dot(&diff)
+            })
+            .sum();
+
+        BisectingCluster {
+            splittable: indices.len() > 1,
+            indices,
+            centroid,
+            sse,
+        }
+    }
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+
+    a
+}
+
+/// k-means stopping conditions.
+pub trait StopCondition<A> {
+    /// Returns `true` when k-means clustering should stop.
+    fn should_stop(&mut self, iteration: usize, loss: A) -> bool;
+}
+
+/// Condition that stops clustering after N iterations.
+#[derive(Copy, Clone, Debug)]
+pub struct NIterationsCondition(pub usize);
+
+impl<A> StopCondition<A> for NIterationsCondition {
+    fn should_stop(&mut self, iteration: usize, _loss: A) -> bool {
+        iteration >= self.0
+    }
+}
+
+impl<A, C> StopCondition<A> for &mut C
+where
+    C: StopCondition<A>,
+{
+    fn should_stop(&mut self, iteration: usize, loss: A) -> bool {
+        (**self).should_stop(iteration, loss)
+    }
+}
+
+/// Condition that stops clustering when the loss diverges.
+///
+/// The k-means loss should not increase between iterations. This
+/// condition wraps another condition and additionally stops clustering
+/// when the loss is not finite (e.g. due to overflow) or when it grows
+/// to more than `max_loss_growth` times the loss of the first
+/// iteration. After clustering, `divergence` returns the iteration and
+/// the reason of the divergence.
+#[derive(Clone, Debug)]
+pub struct DivergenceCondition<C> {
+    condition: C,
+    max_loss_growth: f64,
+    initial_loss: Option<f64>,
+    divergence: Option<(usize, String)>,
+}
+
+impl<C> DivergenceCondition<C> {
+    /// Wrap a stop condition.
+    pub fn new(condition: C, max_loss_growth: f64) -> Self {
+        assert!(
+            max_loss_growth >= 1.,
+            "The maximum loss growth should at least be 1, was: {}",
+            max_loss_growth
+        );
+
+        DivergenceCondition {
+            condition,
+            max_loss_growth,
+            initial_loss: None,
+            divergence: None,
+        }
+    }
+
+    /// Get the iteration and reason of the divergence (if any).
+    pub fn divergence(&self) -> Option<(usize, &str)> {
+        self.divergence
+            .as_ref()
+            .map(|(iteration, reason)| (*iteration, reason.as_str()))
+    }
+}
+
+impl<A, C> StopCondition<A> for DivergenceCondition<C>
+where
+    A: NdFloat,
+    C: StopCondition<A>,
+{
+    fn should_stop(&mut self, iteration: usize, loss: A) -> bool {
+        let loss_f64 = loss.to_f64().unwrap_or(f64::NAN);
+        if !loss_f64.is_finite() {
+            self.divergence = Some((iteration, format!("loss is {}", loss)));
+            return true;
+        }
+
+        let initial_loss = *self.initial_loss.get_or_insert(loss_f64);
+        if initial_loss > 0. && loss_f64 > initial_loss * self.max_loss_growth {
+            self.divergence = Some((
+                iteration,
+                format!(
+                    "loss grew from {} to {}, more than {} times",
+                    initial_loss, loss_f64, self.max_loss_growth
+                ),
+            ));
+            return true;
+        }
+
+        self.condition.should_stop(iteration, loss)
+    }
+}
+
+/// Kernel for assigning instances to their nearest centroids.
+///
+/// An instance that is equally close to several centroids is assigned
+/// to the centroid with the lowest index. This holds for every kernel,
+/// with or without parallelism and SIMD, so that assignments are stable
+/// across kernel choices. (The coarse kernel picks the lowest index among
+/// the centroids that it probes.) Note that the kernels compute distances
+/// in different ways, so distances that are equal in exact arithmetic
+/// may differ after rounding.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AssignmentKernel {
+    /// Compute distances between blocks of instances and centroids with
+    /// matrix multiplication.
+    ///
+    /// This kernel is the fastest for low-dimensional instances.
+    #[default]
+    Blocked,
+
+    /// Compute the distance of every instance to every centroid, but
+    /// abandon the accumulation of a distance as soon as the partial
+    /// sum exceeds the distance to the best centroid so far.
+    ///
+    /// The distance to the centroid that an instance was assigned to in
+    /// the previous iteration is computed first, so that most distance
+    /// computations are abandoned early. This kernel does not use matrix
+    /// multiplication and is typically slower than the blocked kernel. It
+    /// can be beneficial for well-separated clusters, when few distance
+    /// computations run to completion.
+    EarlyAbandon,
+
+    /// Approximate assignment with a coarse quantizer over the centroids.
+    ///
+    /// In every iteration, the centroids are clustered into `n_coarse`
+    /// groups. An instance is only compared to the centroids of the
+    /// `n_probe` groups that are nearest to the instance. The assigned
+    /// centroid is not necessarily the nearest centroid, so this kernel
+    /// trades exactness for speed. It is intended for very large
+    /// codebooks (e.g. 2^14 centroids or more), where exact assignment
+    /// dominates the training time. See `AssignmentKernel::coarse`.
+    Coarse {
+        /// The number of groups of centroids.
+        n_coarse: usize,
+
+        /// The number of groups that is searched for each instance.
+        n_probe: usize,
+    },
+}
+
+impl AssignmentKernel {
+    /// Approximate assignment kernel for `n_centroids` centroids.
+    ///
+    /// The centroids are clustered into *sqrt(n_centroids)* groups, of
+    /// which 1/16th is searched for each instance. For 2^14 centroids,
+    /// this computes 1,152 instead of 16,384 distances per instance.
+    pub fn coarse(n_centroids: usize) -> Self {
+        let n_coarse = ((n_centroids as f64).sqrt().round() as usize).max(1);
+        AssignmentKernel::Coarse {
+            n_coarse,
+            n_probe: (n_coarse / 16).max(1),
+        }
+    }
+}
+
+/// Find nearest cluster centroid for an instance.
+///
+/// Find nearest centroid for each instance along `instance_axis` of
+/// `instances`. Returns for each instance the index of the nearest
+/// cluster centroid.
+///
+/// Panics when a distance is NaN, since the nearest centroid is
+/// undefined in that case.
+pub(crate) fn cluster_assignment<A, S>(
+    centroids: ArrayView2<A>,
+    instance: ArrayBase<S, Ix1>,
+) -> usize
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+{
+    // Use the SIMD kernel for contiguous f32 data.
+    let n_dims = centroids.ncols();
+    if let (Some(instance), Some(centroids)) = (
+        instance.as_slice().and_then(simd::as_f32),
+        centroids.as_slice().and_then(simd::as_f32),
+    ) {
+        if n_dims != 0 && instance.len() == n_dims {
+            return min_by_float_key(
+                centroids
+                    .chunks_exact(instance.len())
+                    .map(|centroid| simd::squared_euclidean_distance(instance, centroid))
+                    .enumerate(),
+                |v| v.1,
+            )
+            .unwrap()
+            .0;
+        }
+    }
+
+    min_by_float_key(
+        instance
+            .squared_euclidean_distance(centroids)
+            .iter()
+            .enumerate(),
+        |v| *v.1,
+    )
+    .unwrap()
+    .0
+}
+
+/// Find nearest cluster centroid for each instance.
+///
+/// Find nearest centroid for each instance along `instance_axis` of
+/// `instances`. Returns for each instance the index of the nearest
+/// cluster centroid.
+///
+/// Panics when a distance is NaN, since the nearest centroid is
+/// undefined in that case.
+pub(crate) fn cluster_assignments<A>(
+    centroids: ArrayView2<A>,
+    instances: ArrayView2<A>,
+    instance_axis: Axis,
+) -> Array1<usize>
+where
+    A: NdFloat + Sum,
+{
+    let rows = match instance_axis {
+        Axis(0) => instances,
+        Axis(1) => instances.reversed_axes(),
+        _ => unreachable!(),
+    };
+
+    let mut assignments = Array1::zeros(rows.nrows());
+    if rows.nrows() == 0 {
+        return assignments;
+    }
+
+    // Use the blocked kernel, so that the memory use does not grow with
+    // the product of the number of instances and centroids.
+    let instance_sqnorms = rows.map_axis(Axis(1), |instance| instance.dot(&instance));
+    let centroid_sqnorms = centroids.map_axis(Axis(1), |centroid| centroid.dot(&centroid));
+    let (instance_block, centroid_block) = assignment_block_sizes::<A>(centroids.nrows());
+    let mut block_scratch = BlockScratch::new(instance_block, centroid_block);
+    for (block, block_assignments) in assignments
+        .as_slice_mut()
+        .expect("Assignments are not contiguous")
+        .chunks_mut(instance_block)
+        .enumerate()
+    {
+        let offset = block * instance_block;
+        assign_block(
+            rows.slice(s![offset..offset + block_assignments.len(), ..]),
+            instance_sqnorms.slice(s![offset..offset + block_assignments.len()]),
+            centroids,
+            centroid_sqnorms.view(),
+            &mut block_scratch,
+            block_assignments,
+        );
+    }
+
+    assignments
+}
+
+/// Streaming cluster assignment.
+///
+/// This iterator consumes an iterator of instances and yields for each
+/// instance a tuple of the instance index, the index of the nearest
+/// centroid, and the squared Euclidean distance to that centroid.
+///
+/// Instances are pulled from the underlying iterator in batches of
+/// `batch_size`, so that distances can be computed using matrix
+/// multiplication. Since at most one batch is buffered, the instances
+/// are only read as fast as the assignments are consumed. This makes
+/// it possible to label very large data sets without materializing
+/// them as a single matrix.
+pub struct StreamingAssignments<'a, A, I> {
+    centroids: ArrayView2<'a, A>,
+    instances: I,
+    batch_size: usize,
+    offset: usize,
+    buffer: VecDeque<(usize, usize, A)>,
+}
+
+impl<'a, A, I, S> StreamingAssignments<'a, A, I>
+where
+    A: NdFloat,
+    I: Iterator<Item = ArrayBase<S, Ix1>>,
+    S: Data<Elem = A>,
+{
+    /// Construct streaming assignments.
+    ///
+    /// Assigns the instances of `instances` to the nearest row of
+    /// `centroids`, processing `batch_size` instances at a time.
+    pub fn new<II>(centroids: ArrayView2<'a, A>, instances: II, batch_size: usize) -> Self
+    where
+        II: IntoIterator<IntoIter = I, Item = ArrayBase<S, Ix1>>,
+    {
+        assert!(
+            centroids.nrows() > 0,
+            "Cannot assign instances to zero centroids."
+        );
+        assert!(batch_size > 0, "The batch size should at least be one.");
+
+        StreamingAssignments {
+            centroids,
+            instances: instances.into_iter(),
+            batch_size,
+            offset: 0,
+            buffer: VecDeque::with_capacity(batch_size),
+        }
+    }
+
+    fn fill_buffer(&mut self) {
+        let dims = self.centroids.ncols();
+
+        let mut batch = Vec::with_capacity(self.batch_size * dims);
+        let mut n_instances = 0;
+        for instance in self.instances.by_ref().take(self.batch_size) {
+            assert_eq!(
+                instance.len(),
+                dims,
+                "Centroid and instance lengths differ."
+            );
+            batch.extend(instance.iter().cloned());
+            n_instances += 1;
+        }
+
+        if n_instances == 0 {
+            return;
+        }
+
+        let batch =
+            Array2::from_shape_vec((n_instances, dims), batch).expect("Batch has incorrect shape");
+        let dists = batch.squared_euclidean_distance(self.centroids);
+
+        for (idx, inst_dists) in dists.outer_iter().enumerate() {
+            let (cluster, &dist) =
+                min_by_float_key(inst_dists.iter().enumerate(), |v| *v.1).unwrap();
+            self.buffer.push_back((self.offset + idx, cluster, dist));
+        }
+
+        self.offset += n_instances;
+    }
+}
+
+impl<'a, A, I, S> Iterator for StreamingAssignments<'a, A, I>
+where
+    A: NdFloat,
+    I: Iterator<Item = ArrayBase<S, Ix1>>,
+    S: Data<Elem = A>,
+{
+    type Item = (usize, usize, A);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            self.fill_buffer();
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+/// Sequential k-means clustering (MacQueen, 1967).
+///
+/// Sequential k-means updates the centroids after each instance, which
+/// makes it suitable for adapting centroids to a stream of instances.
+/// An instance moves its nearest centroid towards it with learning rate
+/// *max(1 / (n + 1), min_learning_rate)*, where *n* is the number of
+/// instances that were assigned to the centroid before. With a
+/// `min_learning_rate` of zero, each centroid is the mean of its
+/// instances. A non-zero `min_learning_rate` keeps adapting the
+/// centroids to changes in the distribution of the stream.
+#[derive(Clone, Debug)]
+pub struct SequentialKMeans<A> {
+    centroids: Array2<A>,
+    counts: Array1<usize>,
+    min_learning_rate: A,
+}
+
+impl<A> SequentialKMeans<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Construct sequential k-means clustering from initial centroids.
+    ///
+    /// The centroids are the rows of `centroids`. Each centroid is
+    /// treated as if `initial_count` instances were assigned to it, a
+    /// higher count makes the centroids adapt more slowly.
+    pub fn new(centroids: Array2<A>, initial_count: usize, min_learning_rate: A) -> Self {
+        assert!(
+            centroids.nrows() > 0,
+            "Cannot cluster instances with zero centroids."
+        );
+        assert!(
+            min_learning_rate >= A::zero() && min_learning_rate <= A::one(),
+            "The minimum learning rate should be in [0, 1]"
+        );
+
+        SequentialKMeans {
+            counts: Array1::from_elem(centroids.nrows(), initial_count),
+            centroids,
+            min_learning_rate,
+        }
+    }
+
+    /// Get the centroids.
+    pub fn centroids(&self) -> ArrayView2<A> {
+        self.centroids.view()
+    }
+
+    /// Get the centroids, consuming the clustering.
+    pub fn into_centroids(self) -> Array2<A> {
+        self.centroids
+    }
+
+    /// Update the centroids with an instance.
+    ///
+    /// Returns the index of the centroid that the instance was assigned to.
+    pub fn update<S>(&mut self, instance: ArrayBase<S, Ix1>) -> usize
+    where
+        S: Data<Elem = A>,
+    {
+        sequential_update(
+            self.centroids.view_mut(),
+            self.counts.view_mut(),
+            instance.view(),
+            self.min_learning_rate,
+        )
+    }
+
+    /// Update the centroids with a batch of instances.
+    ///
+    /// The instances are the rows of `instances`, which are processed
+    /// in order. Returns the cluster assignments of the instances.
+    pub fn update_batch<S>(&mut self, instances: ArrayBase<S, Ix2>) -> Array1<usize>
+    where
+        S: Data<Elem = A>,
+    {
+        instances
+            .outer_iter()
+            .map(|instance| self.update(instance))
+            .collect()
+    }
+}
+
+/// Move the centroid nearest to `instance` towards it.
+///
+/// See `SequentialKMeans` for a description of the learning rate.
+/// Returns the index of the updated centroid.
+pub(crate) fn sequential_update<A>(
+    mut centroids: ArrayViewMut2<A>,
+    mut counts: ArrayViewMut1<usize>,
+    instance: ArrayView1<A>,
+    min_learning_rate: A,
+) -> usize
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    assert_eq!(
+        centroids.ncols(),
+        instance.len(),
+        "Centroid and instance lengths differ."
+    );
+
+    let cluster = cluster_assignment(centroids.view(), instance);
+    counts[cluster] += 1;
+    let learning_rate = (A::one() / counts[cluster].as_()).max(min_learning_rate);
+
+    let mut centroid = centroids.index_axis_mut(Axis(0), cluster);
+    Zip::from(&mut centroid)
+        .and(&instance)
+        .apply(|c, &v| *c += (v - *c) * learning_rate);
+
+    cluster
+}
+
+/// Perform weighted k-means clustering with the given centroids.
+///
+/// Every centroid is updated to the weighted mean of the instances
+/// that are assigned to it, centroids without weight are retained.
+/// Performs at most `max_iterations` iterations, stopping early when
+/// the assignments do not change. The weights must be finite and
+/// non-negative, with a positive sum.
+///
+/// Returns the weighted mean squared error of the final centroids.
+pub(crate) fn weighted_kmeans<A>(
+    mut centroids: ArrayViewMut2<A>,
+    instances: ArrayView2<A>,
+    weights: ArrayView1<A>,
+    max_iterations: usize,
+) -> A
+where
+    A: NdFloat + Sum,
+{
+    assert_eq!(
+        instances.nrows(),
+        weights.len(),
+        "The number of weights should be equal to the number of instances."
+    );
+    assert!(
+        weights.iter().all(|&w| w.is_finite() && w >= A::zero()),
+        "Weights should be finite and non-negative"
+    );
+    let weight_sum = weights.sum();
+    assert!(
+        weight_sum > A::zero(),
+        "At least one instance should have a positive weight"
+    );
+
+    let mut assignments = cluster_assignments(centroids.view(), instances, Axis(0));
+    for _ in 0..max_iterations {
+        let mut sums = Array2::<A>::zeros(centroids.dim());
+        let mut total_weights = Array1::<A>::zeros(centroids.nrows());
+        for ((instance, &cluster), &weight) in instances
+            .outer_iter()
+            .zip(assignments.iter())
+            .zip(weights.iter())
+        {
+            sums.row_mut(cluster).scaled_add(weight, &instance);
+            total_weights[cluster] += weight;
+        }
+
+        for ((mut centroid, sum), &weight) in centroids
+            .outer_iter_mut()
+            .zip(sums.outer_iter())
+            .zip(total_weights.iter())
+        {
+            if weight > A::zero() {
+                centroid.assign(&(&sum / weight));
+            }
+        }
+
+        let new_assignments = cluster_assignments(centroids.view(), instances, Axis(0));
+        if new_assignments == assignments {
+            break;
+        }
+        assignments = new_assignments;
+    }
+
+    let mut loss = A::zero();
+    for ((instance, &cluster), &weight) in instances
+        .outer_iter()
+        .zip(assignments.iter())
+        .zip(weights.iter())
+    {
+        let diff = &instance - &centroids.row(cluster);
+        loss += weight * diff.dot(&diff);
+    }
+
+    loss / weight_sum
+}
+
+/// Update the centroids to the means of their clusters.
+///
+/// Centroids of empty clusters are left unchanged. Large codebooks are
+/// likely to have some empty clusters, resetting their centroids would
+/// make them useless in later iterations.
+pub(crate) fn update_centroids<A, I, S>(
+    mut centroids: ArrayViewMut2<A>,
+    data: ArrayView2<A>,
+    instance_axis: Axis,
+    assignments: ArrayBase<S, Ix1>,
+    mut centroid_counts: ArrayViewMut1<A>,
+) where
+    A: NdFloat,
+    I: AsPrimitive<usize>,
+    S: Data<Elem = I>,
+{
+    assert_eq!(
+        assignments.len(),
+        data.len_of(instance_axis),
+        "The number of assignments should be equal to the number of instances."
+    );
+
+    centroid_counts.fill(A::zero());
+    for assignment in assignments.iter() {
+        centroid_counts[assignment.as_()] += A::one();
+    }
+
+    for (mut centroid, &centroid_count) in centroids.outer_iter_mut().zip(centroid_counts.iter()) {
+        if centroid_count > A::zero() {
+            centroid.fill(A::zero());
+        }
+    }
+
+    for (instance, assignment) in data.axis_iter(instance_axis).zip(assignments.iter()) {
+        let mut centroid = centroids.index_axis_mut(Axis(0), assignment.as_());
+        centroid += &instance;
+    }
+
+    for (mut centroid, centroid_count) in
+        centroids.outer_iter_mut().zip(centroid_counts.outer_iter())
+    {
+        if centroid_count[()] > A::zero() {
+            centroid /= &centroid_count;
+        }
+    }
+}
+
+/// Reusable buffers for k-means iterations.
+///
+/// A k-means iteration needs the squared norms of the instances and
+/// centroids, the cluster assignments, and the cluster sizes. These
+/// buffers are allocated once and reused across iterations and training
+/// attempts, as long as the number of instances and centroids does not
+/// change.
+///
+/// The squared norms of the instances do not change between iterations.
+/// They are computed once per k-means run, or once for all runs on the
+/// same instances with `KMeansScratch::cache_instance_sqnorms`.
+#[derive(Debug)]
+pub(crate) struct KMeansScratch<A> {
+    instance_sqnorms: Array1<A>,
+    instance_sqnorms_cached: bool,
+    centroid_sqnorms: Array1<A>,
+    assignments: Array1<u32>,
+    counts: Array1<A>,
+    parallelism: NestedParallelism,
+    kernel: AssignmentKernel,
+}
+
+impl<A> KMeansScratch<A>
+where
+    A: NdFloat,
+{
+    pub(crate) fn new() -> Self {
+        KMeansScratch {
+            instance_sqnorms: Array1::zeros(0),
+            instance_sqnorms_cached: false,
+            centroid_sqnorms: Array1::zeros(0),
+            assignments: Array1::zeros(0),
+            counts: Array1::zeros(0),
+            parallelism: NestedParallelism::sequential(),
+            kernel: AssignmentKernel::default(),
         }
+    }
 
-        // Assign instances.
-        let mut centroids = Array2::zeros((k, data.len() / data.len_of(instance_axis)));
-        for (idx, mut centroid) in initial_indices.iter().zip(centroids.outer_iter_mut()) {
-            centroid.assign(&data.index_axis(instance_axis, *idx));
+    /// Estimate the peak size of the buffers in bytes.
+    ///
+    /// The estimate covers the buffers for clustering `n_instances`
+    /// instances with `k` centroids, including the distance tiles of
+    /// the `parallelism.inner()` assignment tasks.
+    pub(crate) fn memory_estimate(
+        n_instances: usize,
+        k: usize,
+        parallelism: NestedParallelism,
+    ) -> usize {
+        let (instance_block, centroid_block) = assignment_block_sizes::<A>(k);
+        let tiles = parallelism.inner() * (instance_block * (centroid_block + 1));
+        (n_instances + 2 * k + tiles) * mem::size_of::<A>() + n_instances * mem::size_of::<u32>()
+    }
+
+    /// Get the cluster assignments of the last iteration.
+    pub(crate) fn assignments(&self) -> ArrayView1<u32> {
+        self.assignments.view()
+    }
+
+    /// Parallelize cluster assignment over `parallelism.inner()` tasks.
+    pub(crate) fn with_parallelism(mut self, parallelism: NestedParallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Use `kernel` for cluster assignment.
+    pub(crate) fn with_kernel(mut self, kernel: AssignmentKernel) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    /// Cache the squared norms of the instances.
+    ///
+    /// The cached norms are used by all subsequent k-means iterations
+    /// and runs, until `KMeansScratch::clear_instance_sqnorms` is called.
+    /// This avoids recomputing the norms in every training attempt. The
+    /// caller must ensure that the instances are not modified while the
+    /// norms are cached.
+    pub(crate) fn cache_instance_sqnorms(&mut self, instances: ArrayView2<A>, instance_axis: Axis) {
+        self.instance_sqnorms = instances.map_axis(Axis(instance_axis.index() ^ 1), |instance| {
+            instance.dot(&instance)
+        });
+        self.instance_sqnorms_cached = true;
+    }
+
+    /// Clear cached squared norms of the instances.
+    pub(crate) fn clear_instance_sqnorms(&mut self) {
+        self.instance_sqnorms_cached = false;
+    }
+
+    /// Compute the squared norms of the instances, unless they are cached.
+    fn update_instance_sqnorms(&mut self, rows: ArrayView2<A>) {
+        if self.instance_sqnorms_cached {
+            assert_eq!(
+                self.instance_sqnorms.len(),
+                rows.nrows(),
+                "Cached norms are for a different number of instances"
+            );
+            return;
         }
 
-        centroids
+        if self.instance_sqnorms.len() != rows.nrows() {
+            self.instance_sqnorms = Array1::zeros(rows.nrows());
+        }
+        for (sqnorm, instance) in self.instance_sqnorms.iter_mut().zip(rows.outer_iter()) {
+            *sqnorm = instance.dot(&instance);
+        }
+    }
+
+    /// Resize the buffers, only reallocating when the shape changes.
+    fn resize(&mut self, n_instances: usize, k: usize) {
+        if self.assignments.len() != n_instances {
+            self.assignments = Array1::zeros(n_instances);
+        }
+        if self.counts.len() != k {
+            self.centroid_sqnorms = Array1::zeros(k);
+            self.counts = Array1::zeros(k);
+        }
     }
 }
 
-/// k-means stopping conditions.
-pub trait StopCondition<A> {
-    /// Returns `true` when k-means clustering should stop.
-    fn should_stop(&mut self, iteration: usize, loss: A) -> bool;
+/// Number of instances per block of the assignment kernel.
+const ASSIGNMENT_INSTANCE_BLOCK: usize = 256;
+
+/// Approximate L2 cache size in bytes.
+const L2_CACHE_BYTES: usize = 256 * 1024;
+
+/// Get the instance and centroid block sizes of the assignment kernel.
+///
+/// The instance blocks are the unit of parallelism. The centroid block
+/// size is chosen such that the tile of distances between an instance
+/// block and a centroid block takes half of the L2 cache. The matrix
+/// multiplication kernel does its own blocking for the L1 cache. These
+/// sizes were tuned on 96- and 768-dimensional embeddings with 256
+/// centroids.
+fn assignment_block_sizes<A>(k: usize) -> (usize, usize) {
+    let tile_row_bytes = ASSIGNMENT_INSTANCE_BLOCK * mem::size_of::<A>();
+    let centroid_block = (L2_CACHE_BYTES / 2 / tile_row_bytes).clamp(1, k.max(1));
+
+    (ASSIGNMENT_INSTANCE_BLOCK, centroid_block)
 }
 
-/// Condition that stops clustering after N iterations.
-#[derive(Copy, Clone, Debug)]
-pub struct NIterationsCondition(pub usize);
+/// Per-task buffers of the blocked assignment kernel.
+struct BlockScratch<A> {
+    tile: Array2<A>,
+    best_dists: Array1<A>,
+}
 
-impl<A> StopCondition<A> for NIterationsCondition {
-    fn should_stop(&mut self, iteration: usize, _loss: A) -> bool {
-        iteration >= self.0
+impl<A> BlockScratch<A>
+where
+    A: NdFloat,
+{
+    fn new(instance_block: usize, centroid_block: usize) -> Self {
+        BlockScratch {
+            tile: Array2::zeros((instance_block, centroid_block)),
+            best_dists: Array1::zeros(instance_block),
+        }
     }
 }
 
-/// Find nearest cluster centroid for an instance.
+/// Assign a block of instances to their nearest centroids.
 ///
-/// Find nearest centroid for each instance along `instance_axis` of
-/// `instances`. Returns for each instance the index of the nearest
-/// cluster centroid.
-pub(crate) fn cluster_assignment<A, S>(
+/// The distances are computed one tile of centroids at a time, keeping
+/// the running minimum of every instance, so that the distance matrix
+/// of all instances and centroids is never materialized.
+fn assign_block<A, I>(
+    instances: ArrayView2<A>,
+    instance_sqnorms: ArrayView1<A>,
     centroids: ArrayView2<A>,
-    instance: ArrayBase<S, Ix1>,
-) -> usize
-where
-    A: NdFloat + Sum,
-    S: Data<Elem = A>,
+    centroid_sqnorms: ArrayView1<A>,
+    scratch: &mut BlockScratch<A>,
+    assignments: &mut [I],
+) where
+    A: NdFloat,
+    I: Copy + 'static,
+    usize: AsPrimitive<I>,
 {
-    instance
-        .squared_euclidean_distance(centroids)
-        .iter()
+    let n_instances = instances.nrows();
+    let centroid_block = scratch.tile.ncols();
+
+    // ndarray#474
+    #[allow(clippy::deref_addrof)]
+    let mut best_dists = scratch.best_dists.slice_mut(s![..n_instances]);
+    best_dists.fill(A::infinity());
+
+    for (block, centroids_block) in centroids
+        .axis_chunks_iter(Axis(0), centroid_block)
         .enumerate()
-        .min_by_key(|v| OrderedFloat(*v.1))
-        .unwrap()
-        .0
+    {
+        let offset = block * centroid_block;
+
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        let mut tile = scratch
+            .tile
+            .slice_mut(s![..n_instances, ..centroids_block.nrows()]);
+        general_mat_mul(
+            A::one(),
+            &instances,
+            &centroids_block.t(),
+            A::zero(),
+            &mut tile,
+        );
+
+        for (i, (tile_row, (assignment, best_dist))) in tile
+            .outer_iter()
+            .zip(assignments.iter_mut().zip(best_dists.iter_mut()))
+            .enumerate()
+        {
+            for (j, &dp) in tile_row.iter().enumerate() {
+                let dist = instance_sqnorms[i] + centroid_sqnorms[offset + j] - (dp + dp);
+                if float_cmp(dist, *best_dist) == Ordering::Less {
+                    *best_dist = dist;
+                    *assignment = (offset + j).as_();
+                }
+            }
+        }
+    }
 }
 
-/// Find nearest cluster centroid for each instance.
+/// Check whether centroid `idx` at `dist` is nearer than centroid
+/// `best` at `best_dist`.
 ///
-/// Find nearest centroid for each instance along `instance_axis` of
-/// `instances`. Returns for each instance the index of the nearest
-/// cluster centroid.
-pub(crate) fn cluster_assignments<A>(
-    centroids: ArrayView2<A>,
-    instances: ArrayView2<A>,
-    instance_axis: Axis,
-) -> Array1<usize>
+/// Ties are broken in favor of the centroid with the lowest index, so
+/// that assignments do not depend on the order in which a kernel visits
+/// the centroids.
+#[inline]
+fn is_nearer<A>(dist: A, idx: usize, best_dist: A, best: usize) -> bool
+where
+    A: PartialOrd,
+{
+    match float_cmp(dist, best_dist) {
+        Ordering::Less => true,
+        Ordering::Equal => idx < best,
+        Ordering::Greater => false,
+    }
+}
+
+/// Number of dimensions after which early abandoning is checked.
+const EARLY_ABANDON_CHUNK: usize = 32;
+
+/// Compute the squared Euclidean distance, abandoning at `bound`.
+///
+/// Returns `None` when the partial distance exceeds `bound`.
+fn bounded_squared_euclidean_distance<A>(a: ArrayView1<A>, b: ArrayView1<A>, bound: A) -> Option<A>
 where
+    A: NdFloat,
+{
+    let mut distance = A::zero();
+
+    // Use the SIMD kernel for contiguous f32 data.
+    if let (Some(a), Some(b)) = (
+        a.as_slice().and_then(simd::as_f32),
+        b.as_slice().and_then(simd::as_f32),
+    ) {
+        for (a, b) in a
+            .chunks(EARLY_ABANDON_CHUNK)
+            .zip(b.chunks(EARLY_ABANDON_CHUNK))
+        {
+            distance += A::from(simd::squared_euclidean_distance(a, b)).unwrap();
+            if float_cmp(distance, bound) == Ordering::Greater {
+                return None;
+            }
+        }
+
+        return Some(distance);
+    }
+
+    for (idx, (&a, &b)) in a.iter().zip(b.iter()).enumerate() {
+        distance += (a - b) * (a - b);
+        if (idx + 1) % EARLY_ABANDON_CHUNK == 0 && float_cmp(distance, bound) == Ordering::Greater {
+            return None;
+        }
+    }
+
+    Some(distance)
+}
+
+/// Assign a block of instances to their nearest centroids with early
+/// abandoning.
+///
+/// `assignments` should contain the assignments of the previous
+/// iteration, these are used as the initial best centroids.
+fn assign_block_early_abandon<A>(
+    instances: ArrayView2<A>,
+    centroids: ArrayView2<A>,
+    assignments: &mut [u32],
+) where
+    A: NdFloat,
+{
+    for (instance, assignment) in instances.outer_iter().zip(assignments.iter_mut()) {
+        let previous = if (*assignment as usize) < centroids.nrows() {
+            *assignment as usize
+        } else {
+            0
+        };
+
+        let mut best = previous;
+        let mut best_dist = bounded_squared_euclidean_distance(
+            instance,
+            centroids.index_axis(Axis(0), previous),
+            A::infinity(),
+        )
+        .unwrap();
+
+        for (idx, centroid) in centroids.outer_iter().enumerate() {
+            if idx == previous {
+                continue;
+            }
+
+            if let Some(dist) = bounded_squared_euclidean_distance(instance, centroid, best_dist) {
+                if is_nearer(dist, idx, best_dist, best) {
+                    best = idx;
+                    best_dist = dist;
+                }
+            }
+        }
+
+        *assignment = best as u32;
+    }
+}
+
+/// Number of k-means iterations for grouping the centroids.
+const COARSE_ITERATIONS: usize = 5;
+
+/// Assign instances to centroids with a coarse quantizer over the centroids.
+fn assign_coarse<A>(
+    rows: ArrayView2<A>,
+    centroids: ArrayView2<A>,
+    scratch: &mut KMeansScratch<A>,
+    n_coarse: usize,
+    n_probe: usize,
+) where
     A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
 {
-    let mut assignments = Array1::zeros(instances.len_of(instance_axis));
+    // Group the centroids, starting from evenly spaced centroids, so
+    // that the grouping is deterministic.
+    let step = centroids.nrows() / n_coarse;
+    let mut coarse = centroids.select(
+        Axis(0),
+        &(0..n_coarse).map(|idx| idx * step).collect::<Vec<_>>(),
+    );
+    let mut coarse_scratch = KMeansScratch::new();
+    for _ in 0..COARSE_ITERATIONS {
+        kmeans_iteration_with_scratch(centroids, Axis(0), coarse.view_mut(), &mut coarse_scratch);
+    }
 
-    let dists = if instance_axis == Axis(0) {
-        instances.squared_euclidean_distance(centroids)
-    } else {
-        instances.t().squared_euclidean_distance(centroids)
+    let mut groups = vec![Vec::new(); n_coarse];
+    for (idx, &group) in coarse_scratch.assignments().iter().enumerate() {
+        groups[group as usize].push(idx);
+    }
+
+    let assignments = scratch
+        .assignments
+        .as_slice_mut()
+        .expect("Assignments are not contiguous");
+    let assign = |offset: usize, block_assignments: &mut [u32]| {
+        let instances = rows.slice(s![offset..offset + block_assignments.len(), ..]);
+        assign_block_coarse(
+            instances,
+            centroids,
+            coarse.view(),
+            &groups,
+            n_probe,
+            block_assignments,
+        )
     };
 
-    for (assignment, inst_dists) in assignments.iter_mut().zip(dists.outer_iter()) {
-        *assignment = inst_dists
-            .iter()
+    if scratch.parallelism.inner() > 1 {
+        let n_blocks = rows.nrows() / ASSIGNMENT_INSTANCE_BLOCK
+            + usize::from(rows.nrows() % ASSIGNMENT_INSTANCE_BLOCK != 0);
+        let min_len = scratch.parallelism.inner_min_len(n_blocks);
+        assignments
+            .par_chunks_mut(ASSIGNMENT_INSTANCE_BLOCK)
             .enumerate()
-            .min_by_key(|v| OrderedFloat(*v.1))
+            .with_min_len(min_len)
+            .for_each(|(block, block_assignments)| {
+                assign(block * ASSIGNMENT_INSTANCE_BLOCK, block_assignments)
+            });
+    } else {
+        assign(0, assignments);
+    }
+}
+
+/// Assign instances to the nearest centroid in the nearest groups.
+fn assign_block_coarse<A>(
+    instances: ArrayView2<A>,
+    centroids: ArrayView2<A>,
+    coarse: ArrayView2<A>,
+    groups: &[Vec<usize>],
+    n_probe: usize,
+    assignments: &mut [u32],
+) where
+    A: NdFloat,
+{
+    let mut coarse_dists = Vec::with_capacity(coarse.nrows());
+    for (instance, assignment) in instances.outer_iter().zip(assignments.iter_mut()) {
+        coarse_dists.clear();
+        coarse_dists.extend(coarse.outer_iter().enumerate().map(|(group, centroid)| {
+            let diff = &instance - &centroid;
+            (diff.dot(&diff), group)
+        }));
+        coarse_dists.select_nth_unstable_by(n_probe - 1, |a, b| float_cmp(a.0, b.0));
+
+        let mut best = None;
+        let mut best_dist = A::infinity();
+        for &(_, group) in &coarse_dists[..n_probe] {
+            for &idx in &groups[group] {
+                if let Some(dist) =
+                    bounded_squared_euclidean_distance(instance, centroids.row(idx), best_dist)
+                {
+                    let nearer = match best {
+                        Some(best) => is_nearer(dist, idx, best_dist, best),
+                        None => true,
+                    };
+                    if nearer {
+                        best = Some(idx);
+                        best_dist = dist;
+                    }
+                }
+            }
+        }
+
+        // The probed groups can all be empty, fall back to an exhaustive
+        // search in that case.
+        let best = best.unwrap_or_else(|| {
+            min_by_float_key(0..centroids.nrows(), |&idx| {
+                let diff = &instance - &centroids.row(idx);
+                diff.dot(&diff)
+            })
             .unwrap()
-            .0;
+        });
+
+        *assignment = best as u32;
     }
+}
 
-    assignments
+/// Assign instances to their nearest centroids with the blocked kernel.
+fn assign_blocked<A>(rows: ArrayView2<A>, centroids: ArrayView2<A>, scratch: &mut KMeansScratch<A>)
+where
+    A: NdFloat,
+{
+    // Squared distances, see SquaredEuclideanDistance.
+    scratch.update_instance_sqnorms(rows);
+    for (sqnorm, centroid) in scratch
+        .centroid_sqnorms
+        .iter_mut()
+        .zip(centroids.outer_iter())
+    {
+        *sqnorm = centroid.dot(&centroid);
+    }
+
+    let (instance_block, centroid_block) = assignment_block_sizes::<A>(centroids.nrows());
+    let instance_sqnorms = scratch.instance_sqnorms.view();
+    let centroid_sqnorms = scratch.centroid_sqnorms.view();
+    let assignments = scratch
+        .assignments
+        .as_slice_mut()
+        .expect("Assignments are not contiguous");
+    let new_tile = || BlockScratch::new(instance_block, centroid_block);
+    if scratch.parallelism.inner() > 1 {
+        let n_blocks =
+            rows.nrows() / instance_block + usize::from(rows.nrows() % instance_block != 0);
+        let min_len = scratch.parallelism.inner_min_len(n_blocks);
+        assignments
+            .par_chunks_mut(instance_block)
+            .enumerate()
+            .with_min_len(min_len)
+            .for_each_init(new_tile, |block_scratch, (block, block_assignments)| {
+                let offset = block * instance_block;
+                assign_block::<A, u32>(
+                    rows.slice(s![offset..offset + block_assignments.len(), ..]),
+                    instance_sqnorms.slice(s![offset..offset + block_assignments.len()]),
+                    centroids,
+                    centroid_sqnorms,
+                    block_scratch,
+                    block_assignments,
+                )
+            });
+    } else {
+        let mut block_scratch = new_tile();
+        for (block, block_assignments) in assignments.chunks_mut(instance_block).enumerate() {
+            let offset = block * instance_block;
+            assign_block::<A, u32>(
+                rows.slice(s![offset..offset + block_assignments.len(), ..]),
+                instance_sqnorms.slice(s![offset..offset + block_assignments.len()]),
+                centroids,
+                centroid_sqnorms,
+                &mut block_scratch,
+                block_assignments,
+            );
+        }
+    }
 }
 
-/// Update centroids to the mean of the assigned data points.
-///
-/// `instance_axis` is the instance axis of `data`. The centroids
-/// are row-based. `assignments` contains an assignment for each
-/// data point.
-fn update_centroids<A, S>(
-    mut centroids: ArrayViewMut2<A>,
-    data: ArrayView2<A>,
-    instance_axis: Axis,
-    assignments: ArrayBase<S, Ix1>,
+/// Assign instances to their nearest centroids with early abandoning.
+fn assign_early_abandon<A>(
+    rows: ArrayView2<A>,
+    centroids: ArrayView2<A>,
+    scratch: &mut KMeansScratch<A>,
 ) where
     A: NdFloat,
-    S: Data<Elem = usize>,
 {
+    let assignments = scratch
+        .assignments
+        .as_slice_mut()
+        .expect("Assignments are not contiguous");
+    if scratch.parallelism.inner() > 1 {
+        let n_blocks = rows.nrows() / ASSIGNMENT_INSTANCE_BLOCK
+            + usize::from(rows.nrows() % ASSIGNMENT_INSTANCE_BLOCK != 0);
+        let min_len = scratch.parallelism.inner_min_len(n_blocks);
+        assignments
+            .par_chunks_mut(ASSIGNMENT_INSTANCE_BLOCK)
+            .enumerate()
+            .with_min_len(min_len)
+            .for_each(|(block, block_assignments)| {
+                let offset = block * ASSIGNMENT_INSTANCE_BLOCK;
+                assign_block_early_abandon(
+                    rows.slice(s![offset..offset + block_assignments.len(), ..]),
+                    centroids,
+                    block_assignments,
+                )
+            });
+    } else {
+        assign_block_early_abandon(rows, centroids, assignments);
+    }
+}
+
+/// Perform a k-means iteration using scratch buffers.
+///
+/// See `KMeansIteration::kmeans_iteration`.
+pub(crate) fn kmeans_iteration_with_scratch<A>(
+    instances: ArrayView2<A>,
+    instance_axis: Axis,
+    mut centroids: ArrayViewMut2<A>,
+    scratch: &mut KMeansScratch<A>,
+) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    assert!(
+        centroids.nrows() > 0,
+        "Cannot cluster instances with zero centroids."
+    );
     assert_eq!(
-        assignments.len(),
-        data.len_of(instance_axis),
-        "The number of assignments should be equal to the number of instances."
+        centroids.ncols(),
+        instances.len_of(Axis(instance_axis.index() ^ 1)),
+        "Centroid and instance lengths differ."
+    );
+    assert!(
+        centroids.nrows() - 1 <= u32::MAX as usize,
+        "Cannot store cluster assignments of {} centroids",
+        centroids.nrows()
     );
 
-    centroids.fill(A::zero());
+    let rows = match instance_axis {
+        Axis(0) => instances,
+        Axis(1) => instances.reversed_axes(),
+        _ => unreachable!(),
+    };
 
-    let mut centroid_counts = Array1::zeros(centroids.nrows());
+    scratch.resize(rows.nrows(), centroids.nrows());
 
-    for (instance, assignment) in data.axis_iter(instance_axis).zip(assignments.iter()) {
-        let mut centroid = centroids.index_axis_mut(Axis(0), *assignment);
-        centroid += &instance;
-        centroid_counts[*assignment] += A::one();
+    match scratch.kernel {
+        AssignmentKernel::Blocked => assign_blocked(rows, centroids.view(), scratch),
+        AssignmentKernel::EarlyAbandon => assign_early_abandon(rows, centroids.view(), scratch),
+        AssignmentKernel::Coarse { n_coarse, n_probe } => {
+            assert!(
+                n_coarse > 0 && n_probe > 0,
+                "The coarse kernel should at least use one group and probe, groups: {}, probes: {}",
+                n_coarse,
+                n_probe
+            );
+
+            if n_probe >= n_coarse || n_coarse >= centroids.nrows() {
+                // Probing all groups is exact.
+                assign_blocked(rows, centroids.view(), scratch)
+            } else {
+                assign_coarse(rows, centroids.view(), scratch, n_coarse, n_probe)
+            }
+        }
     }
 
-    for (mut centroid, centroid_count) in
-        centroids.outer_iter_mut().zip(centroid_counts.outer_iter())
-    {
-        if centroid_count[()] > A::zero() {
-            centroid /= &centroid_count;
+    update_centroids(
+        centroids.view_mut(),
+        rows,
+        Axis(0),
+        scratch.assignments.view(),
+        scratch.counts.view_mut(),
+    );
+
+    mean_squared_error(centroids.view(), rows, Axis(0), scratch.assignments.view())
+}
+
+/// Perform k-means clustering using scratch buffers.
+///
+/// See `KMeansWithCentroids::kmeans_with_centroids`.
+pub(crate) fn kmeans_with_centroids_scratch<A>(
+    instances: ArrayView2<A>,
+    instance_axis: Axis,
+    mut centroids: ArrayViewMut2<A>,
+    mut stop_condition: impl StopCondition<A>,
+    scratch: &mut KMeansScratch<A>,
+) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    // The instances do not change during the run, so their norms only
+    // need to be computed once.
+    let cached = scratch.instance_sqnorms_cached;
+    if !cached {
+        scratch.cache_instance_sqnorms(instances, instance_axis);
+    }
+
+    for iter in 0.. {
+        let loss =
+            kmeans_iteration_with_scratch(instances, instance_axis, centroids.view_mut(), scratch);
+        if stop_condition.should_stop(iter + 1, loss) {
+            if !cached {
+                scratch.clear_instance_sqnorms();
+            }
+            return loss;
         }
     }
+
+    unreachable!()
 }
 
 /// Trait for types that implement k-means clustering.
@@ -252,6 +1681,29 @@ pub trait KMeansWithCentroids<A> {
         centroids: ArrayViewMut2<A>,
         stop_condition: impl StopCondition<A>,
     ) -> A;
+
+    /// Perform k-means clustering using the given assignment kernel.
+    fn kmeans_with_centroids_using(
+        &self,
+        instance_axis: Axis,
+        centroids: ArrayViewMut2<A>,
+        stop_condition: impl StopCondition<A>,
+        kernel: AssignmentKernel,
+    ) -> A;
+
+    /// Perform k-means clustering and return the cluster assignments.
+    ///
+    /// Returns the cluster assignments of the instances in the last
+    /// iteration and the mean squared error. These are the assignments
+    /// that the final centroids were computed from and the mean squared
+    /// error is computed using these assignments, so they do not have to
+    /// be recomputed after clustering.
+    fn kmeans_with_centroids_assignments(
+        &self,
+        instance_axis: Axis,
+        centroids: ArrayViewMut2<A>,
+        stop_condition: impl StopCondition<A>,
+    ) -> (Array1<usize>, A);
 }
 
 impl<S, A> KMeansWithCentroids<A> for ArrayBase<S, Ix2>
@@ -263,27 +1715,49 @@ where
     fn kmeans_with_centroids(
         &self,
         instance_axis: Axis,
-        mut centroids: ArrayViewMut2<A>,
-        mut stop_condition: impl StopCondition<A>,
+        centroids: ArrayViewMut2<A>,
+        stop_condition: impl StopCondition<A>,
     ) -> A {
-        assert!(
-            centroids.nrows() > 0,
-            "Cannot cluster instances with zero centroids."
-        );
-        assert_eq!(
-            centroids.ncols(),
-            self.len_of(Axis(instance_axis.index() ^ 1)),
-            "Centroid and instance lengths differ."
+        kmeans_with_centroids_scratch(
+            self.view(),
+            instance_axis,
+            centroids,
+            stop_condition,
+            &mut KMeansScratch::new(),
+        )
+    }
+
+    fn kmeans_with_centroids_using(
+        &self,
+        instance_axis: Axis,
+        centroids: ArrayViewMut2<A>,
+        stop_condition: impl StopCondition<A>,
+        kernel: AssignmentKernel,
+    ) -> A {
+        kmeans_with_centroids_scratch(
+            self.view(),
+            instance_axis,
+            centroids,
+            stop_condition,
+            &mut KMeansScratch::new().with_kernel(kernel),
+        )
+    }
+
+    fn kmeans_with_centroids_assignments(
+        &self,
+        instance_axis: Axis,
+        centroids: ArrayViewMut2<A>,
+        stop_condition: impl StopCondition<A>,
+    ) -> (Array1<usize>, A) {
+        let mut scratch = KMeansScratch::new();
+        let loss = kmeans_with_centroids_scratch(
+            self.view(),
+            instance_axis,
+            centroids,
+            stop_condition,
+            &mut scratch,
         );
-
-        for iter in 0.. {
-            let loss = self.kmeans_iteration(instance_axis, centroids.view_mut());
-            if stop_condition.should_stop(iter + 1, loss) {
-                return loss;
-            }
-        }
-
-        unreachable!()
+        (scratch.assignments().mapv(|idx| idx as usize), loss)
     }
 }
 
@@ -305,29 +1779,17 @@ where
     A: NdFloat + Sum,
     usize: AsPrimitive<A>,
 {
-    fn kmeans_iteration(&self, instance_axis: Axis, mut centroids: ArrayViewMut2<A>) -> A {
-        assert!(
-            centroids.nrows() > 0,
-            "Cannot cluster instances with zero centroids."
-        );
-        assert_eq!(
-            centroids.ncols(),
-            self.len_of(Axis(instance_axis.index() ^ 1)),
-            "Centroid and instance lengths differ."
-        );
-
-        let assignments = cluster_assignments(centroids.view(), self.view(), instance_axis);
-        update_centroids(
-            centroids.view_mut(),
+    fn kmeans_iteration(&self, instance_axis: Axis, centroids: ArrayViewMut2<A>) -> A {
+        kmeans_iteration_with_scratch(
             self.view(),
             instance_axis,
-            assignments.view(),
-        );
-        mean_squared_error(centroids.view(), self.view(), instance_axis, assignments)
+            centroids,
+            &mut KMeansScratch::new(),
+        )
     }
 }
 
-fn mean_squared_error<A, S>(
+fn mean_squared_error<A, I, S>(
     centroids: ArrayView2<A>,
     instances: ArrayView2<A>,
     instance_axis: Axis,
@@ -335,48 +1797,72 @@ fn mean_squared_error<A, S>(
 ) -> A
 where
     A: NdFloat + Sum,
+    I: AsPrimitive<usize>,
     usize: AsPrimitive<A>,
-    S: Data<Elem = usize>,
+    S: Data<Elem = I>,
 {
-    // Get the centroids representing the instances. Future: do not
-    // construct an explicit matrix. Though I guess that it is
-    // potentially optimized away due to the fold below.
-    let mut errors = centroids.select(
-        Axis(0),
-        assignments.as_slice().expect("Non-contiguous vector"),
-    );
-
-    // Absolute errors.
-    match instance_axis {
-        Axis(0) => errors -= &instances,
-        Axis(1) => errors -= &instances.t(),
+    let instances = match instance_axis {
+        Axis(0) => instances,
+        Axis(1) => instances.reversed_axes(),
         _ => unreachable!(),
-    }
+    };
 
-    // Summed squared error
-    let sse = errors.into_iter().map(|&v| v * v).sum::<A>();
+    // Summed squared error, computed without materializing the errors.
+    let mut sse = A::zero();
+    for (instance, &assignment) in instances.outer_iter().zip(assignments.iter()) {
+        let centroid = centroids.index_axis(Axis(0), assignment.as_());
+        for (&c, &v) in centroid.iter().zip(instance.iter()) {
+            sse += (c - v) * (c - v);
+        }
+    }
 
     sse / instances.len().as_()
 }
 
 #[cfg(test)]
 mod tests {
-    use ndarray::{array, concatenate, Array2, ArrayBase, Axis, Data, Ix2};
+    use std::iter::Sum;
+
+    use ndarray::{array, concatenate, s, Array1, Array2, ArrayBase, Axis, Data, Ix2, NdFloat};
+    use num_traits::AsPrimitive;
     use rand::{Rng, SeedableRng};
     use rand_distr::Normal;
     use rand_xorshift::XorShiftRng;
 
     use super::{
-        cluster_assignments, mean_squared_error, update_centroids, KMeans, NIterationsCondition,
-        RandomInstanceCentroids,
+        assign_block_coarse, cluster_assignment, cluster_assignments,
+        kmeans_iteration_with_scratch, kmeans_with_centroids_scratch, mean_squared_error,
+        update_centroids, weighted_kmeans, AssignmentKernel, BisectingCentroids,
+        CentroidInitialization, DivergenceCondition, InitialCentroids, KMeans, KMeansScratch,
+        KMeansWithCentroids, NIterationsCondition, QuantileCentroids, RandomInstanceCentroids,
+        SequentialKMeans, StopCondition, StreamingAssignments,
     };
     use crate::ndarray_rand::RandomExt;
+    use crate::parallel::NestedParallelism;
 
     const SEED: [u8; 16] = [
         0xd3, 0x68, 0x34, 0x05, 0xf2, 0x6e, 0xa4, 0x45, 0x2b, 0x2b, 0xea, 0x1f, 0x08, 0xce, 0x88,
         0xf6,
     ];
 
+    #[test]
+    fn divergence_condition_detects_divergence() {
+        let mut condition = DivergenceCondition::new(NIterationsCondition(10), 100.);
+        assert!(!condition.should_stop(1, 1f32));
+        assert!(!condition.should_stop(2, 50f32));
+        assert_eq!(condition.divergence(), None);
+        assert!(condition.should_stop(3, 101f32));
+        assert_eq!(
+            condition.divergence().map(|(iteration, _)| iteration),
+            Some(3)
+        );
+
+        let mut condition = DivergenceCondition::new(NIterationsCondition(10), 100.);
+        assert!(!condition.should_stop(1, 1f32));
+        assert!(condition.should_stop(2, f32::NAN));
+        assert_eq!(condition.divergence(), Some((2, "loss is NaN")));
+    }
+
     #[test]
     fn correct_cluster_assignments() {
         let centroids = array![[0.5, 0., 0.], [0., -1., 0.], [0., 0., 1.], [0., 1., 1.]];
@@ -399,6 +1885,395 @@ mod tests {
         assert_eq!(assignments, array![0, 2, 0, 2, 1, 3, 0]);
     }
 
+    #[test]
+    #[should_panic(expected = "Weights should be finite and non-negative")]
+    fn weighted_kmeans_rejects_negative_weights() {
+        let mut centroids = array![[0.], [1.]];
+        let instances = array![[0.], [1.], [2.]];
+        weighted_kmeans(
+            centroids.view_mut(),
+            instances.view(),
+            array![1., -1., 1.].view(),
+            10,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "At least one instance should have a positive weight")]
+    fn weighted_kmeans_rejects_zero_weights() {
+        let mut centroids = array![[0.], [1.]];
+        let instances = array![[0.], [1.], [2.]];
+        weighted_kmeans(
+            centroids.view_mut(),
+            instances.view(),
+            array![0., 0., 0.].view(),
+            10,
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn cluster_assignments_nan() {
+        let centroids = array![[0.5, 0.], [0., -1.]];
+        let instances = array![[0., 0.5], [f64::NAN, 0.]];
+        cluster_assignments(centroids.view(), instances.view(), Axis(0));
+    }
+
+    #[test]
+    fn correct_streaming_assignments() {
+        let centroids = array![[0.5, 0., 0.], [0., -1., 0.], [0., 0., 1.], [0., 1., 1.]];
+        let instances = array![
+            [0., 0.5, 0.],
+            [0., 0., 2.],
+            [1., 0., 0.],
+            [0., 0., 1.],
+            [0., -2., 0.],
+            [0., 0.7, 0.7],
+            [0., 0., 0.]
+        ];
+
+        // Use a batch size that does not divide the number of instances.
+        let assignments: Vec<(usize, usize, f64)> =
+            StreamingAssignments::new(centroids.view(), instances.outer_iter(), 3).collect();
+
+        assert_eq!(
+            assignments.iter().map(|a| a.0).collect::<Vec<_>>(),
+            (0..7).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            assignments.iter().map(|a| a.1).collect::<Vec<_>>(),
+            vec![0, 2, 0, 2, 1, 3, 0]
+        );
+        assert!((assignments[1].2 - 1.).abs() < 1e-6);
+        assert!((assignments[4].2 - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sequential_kmeans_means() {
+        let mut kmeans = SequentialKMeans::new(array![[0f64], [10.]], 0, 0.);
+        let assignments = kmeans.update_batch(array![[1.], [3.], [9.], [12.], [2.]].view());
+        assert_eq!(assignments, array![0, 0, 1, 1, 0]);
+        assert_eq!(kmeans.centroids(), array![[2.], [10.5]]);
+    }
+
+    #[test]
+    fn sequential_kmeans_min_learning_rate() {
+        let mut kmeans = SequentialKMeans::new(array![[0f64]], 100, 0.5);
+        kmeans.update(array![4.].view());
+        kmeans.update(array![4.].view());
+        assert_eq!(kmeans.into_centroids(), array![[3.]]);
+    }
+
+    #[test]
+    fn kmeans_scratch_reuse() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+        let instances = gaussian_spheres(array![[0., 0.], [1., 0.], [1., 1.]], &mut rng);
+        let initial = instances.select(Axis(0), &[0, 1, 2]);
+
+        let mut centroids = initial.clone();
+        let assignments = cluster_assignments(centroids.view(), instances.view(), Axis(0));
+        let mut counts = Array1::zeros(3);
+        update_centroids(
+            centroids.view_mut(),
+            instances.view(),
+            Axis(0),
+            assignments.view(),
+            counts.view_mut(),
+        );
+        let loss = mean_squared_error(centroids.view(), instances.view(), Axis(0), assignments);
+
+        // Scratch buffers that were used for a different shape.
+        let mut scratch = KMeansScratch::new();
+        let mut other_centroids = initial.select(Axis(0), &[0, 1]);
+        kmeans_iteration_with_scratch(
+            instances.view(),
+            Axis(0),
+            other_centroids.view_mut(),
+            &mut scratch,
+        );
+
+        for instance_axis in &[Axis(0), Axis(1)] {
+            let mut scratch_centroids = initial.clone();
+            let scratch_loss = if *instance_axis == Axis(0) {
+                kmeans_iteration_with_scratch(
+                    instances.view(),
+                    Axis(0),
+                    scratch_centroids.view_mut(),
+                    &mut scratch,
+                )
+            } else {
+                kmeans_iteration_with_scratch(
+                    instances.t(),
+                    Axis(1),
+                    scratch_centroids.view_mut(),
+                    &mut scratch,
+                )
+            };
+            assert_eq!(scratch_centroids, centroids);
+            assert_eq!(scratch_loss, loss);
+        }
+    }
+
+    #[test]
+    fn blocked_assignments_match_unblocked() {
+        // Several instance and centroid blocks, including partial blocks.
+        let mut rng = XorShiftRng::from_seed(SEED);
+        let instances: Array2<f64> =
+            Array2::random_using((600, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+        let initial = instances.select(Axis(0), &(0..300).collect::<Vec<_>>());
+
+        let mut centroids = initial.clone();
+        let assignments = cluster_assignments(centroids.view(), instances.view(), Axis(0));
+        let mut counts = Array1::zeros(300);
+        update_centroids(
+            centroids.view_mut(),
+            instances.view(),
+            Axis(0),
+            assignments.view(),
+            counts.view_mut(),
+        );
+
+        for &parallelism in &[
+            NestedParallelism::sequential(),
+            NestedParallelism::new(1, 4),
+        ] {
+            let mut scratch = KMeansScratch::new().with_parallelism(parallelism);
+            let mut blocked_centroids = initial.clone();
+            kmeans_iteration_with_scratch(
+                instances.view(),
+                Axis(0),
+                blocked_centroids.view_mut(),
+                &mut scratch,
+            );
+            assert_eq!(scratch.assignments.mapv(|v| v as usize), assignments);
+            assert_eq!(blocked_centroids, centroids);
+        }
+    }
+
+    #[test]
+    fn cached_instance_sqnorms_match_uncached() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+        let instances: Array2<f64> =
+            Array2::random_using((300, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+        let initial = instances.select(Axis(0), &(0..10).collect::<Vec<_>>());
+
+        let mut centroids = initial.clone();
+        let loss = kmeans_with_centroids_scratch(
+            instances.view(),
+            Axis(0),
+            centroids.view_mut(),
+            NIterationsCondition(5),
+            &mut KMeansScratch::new(),
+        );
+
+        // Norms are cached across runs and instance axes.
+        let mut scratch = KMeansScratch::new();
+        scratch.cache_instance_sqnorms(instances.t(), Axis(1));
+        for _ in 0..2 {
+            let mut cached_centroids = initial.clone();
+            let cached_loss = kmeans_with_centroids_scratch(
+                instances.view(),
+                Axis(0),
+                cached_centroids.view_mut(),
+                NIterationsCondition(5),
+                &mut scratch,
+            );
+            assert_eq!(cached_centroids, centroids);
+            assert_eq!(cached_loss, loss);
+        }
+        assert!(scratch.instance_sqnorms_cached);
+
+        scratch.clear_instance_sqnorms();
+        let mut centroids = initial.slice(s![.., ..2]).to_owned();
+        kmeans_with_centroids_scratch(
+            instances.slice(s![..100, ..2]),
+            Axis(0),
+            centroids.view_mut(),
+            NIterationsCondition(1),
+            &mut scratch,
+        );
+        assert!(!scratch.instance_sqnorms_cached);
+    }
+
+    #[test]
+    fn kmeans_with_centroids_assignments_match_loss() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+        let instances: Array2<f64> =
+            Array2::random_using((300, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+        let mut centroids = instances.select(Axis(0), &(0..10).collect::<Vec<_>>());
+
+        let (assignments, loss) = instances.t().kmeans_with_centroids_assignments(
+            Axis(1),
+            centroids.view_mut(),
+            NIterationsCondition(5),
+        );
+
+        assert_eq!(assignments.len(), 300);
+        assert_eq!(
+            mean_squared_error(
+                centroids.view(),
+                instances.view(),
+                Axis(0),
+                assignments.view()
+            ),
+            loss
+        );
+    }
+
+    fn check_early_abandon_matches_blocked<A>(instances: Array2<A>)
+    where
+        A: NdFloat + Sum,
+        usize: AsPrimitive<A>,
+    {
+        let initial = instances.select(Axis(0), &(0..10).collect::<Vec<_>>());
+
+        let mut centroids = initial.clone();
+        let mut scratch = KMeansScratch::new();
+        kmeans_with_centroids_scratch(
+            instances.view(),
+            Axis(0),
+            centroids.view_mut(),
+            NIterationsCondition(5),
+            &mut scratch,
+        );
+
+        for &parallelism in &[
+            NestedParallelism::sequential(),
+            NestedParallelism::new(1, 4),
+        ] {
+            let mut early_centroids = initial.clone();
+            let mut early_scratch = KMeansScratch::new()
+                .with_kernel(AssignmentKernel::EarlyAbandon)
+                .with_parallelism(parallelism);
+            kmeans_with_centroids_scratch(
+                instances.view(),
+                Axis(0),
+                early_centroids.view_mut(),
+                NIterationsCondition(5),
+                &mut early_scratch,
+            );
+
+            assert_eq!(early_scratch.assignments, scratch.assignments);
+            for (&early, &blocked) in early_centroids.iter().zip(centroids.iter()) {
+                assert!((early - blocked).abs() < A::from(1e-5).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn coarse_kernel_approximates_blocked() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+        let instances: Array2<f32> =
+            Array2::random_using((2000, 8), Normal::new(0., 1.).unwrap(), &mut rng);
+        let initial = RandomInstanceCentroids::new(&mut rng).initial_centroids(
+            instances.view(),
+            Axis(0),
+            256,
+        );
+
+        let run = |kernel| {
+            let mut centroids = initial.clone();
+            let mut scratch = KMeansScratch::new()
+                .with_kernel(kernel)
+                .with_parallelism(NestedParallelism::new(1, 4));
+            let loss = kmeans_with_centroids_scratch(
+                instances.view(),
+                Axis(0),
+                centroids.view_mut(),
+                NIterationsCondition(5),
+                &mut scratch,
+            );
+            (scratch.assignments, loss)
+        };
+
+        let (blocked_assignments, blocked_loss) = run(AssignmentKernel::Blocked);
+
+        // Probing all groups is exact.
+        let (assignments, _) = run(AssignmentKernel::Coarse {
+            n_coarse: 16,
+            n_probe: 16,
+        });
+        assert_eq!(assignments, blocked_assignments);
+
+        let (_, coarse_loss) = run(AssignmentKernel::coarse(256));
+        assert!(coarse_loss >= blocked_loss * 0.99);
+        assert!(coarse_loss < blocked_loss * 1.2);
+    }
+
+    #[test]
+    fn early_abandon_matches_blocked() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+        check_early_abandon_matches_blocked::<f32>(Array2::random_using(
+            (1000, 96),
+            Normal::new(0., 1.).unwrap(),
+            &mut rng,
+        ));
+        check_early_abandon_matches_blocked::<f64>(Array2::random_using(
+            (1000, 40),
+            Normal::new(0., 1.).unwrap(),
+            &mut rng,
+        ));
+    }
+
+    #[test]
+    fn ties_break_to_lowest_index() {
+        // Every instance is equally close to two or more centroids.
+        let centroids = array![[0f32, 2.], [2., 0.], [0., -2.], [-2., 0.], [0., 2.]];
+        let instances = array![
+            [1f32, 1.],
+            [-1., -1.],
+            [1., -1.],
+            [0., 0.],
+            [-1., 1.],
+            [0., 2.]
+        ];
+        let expected = array![0u32, 2, 1, 0, 0, 0];
+
+        assert_eq!(
+            cluster_assignments(centroids.view(), instances.view(), Axis(0)),
+            expected.mapv(|idx| idx as usize)
+        );
+        for (instance, &idx) in instances.outer_iter().zip(expected.iter()) {
+            assert_eq!(cluster_assignment(centroids.view(), instance), idx as usize);
+            assert_eq!(
+                cluster_assignment(centroids.mapv(f64::from).view(), instance.mapv(f64::from)),
+                idx as usize
+            );
+        }
+
+        for &kernel in &[AssignmentKernel::Blocked, AssignmentKernel::EarlyAbandon] {
+            for &parallelism in &[
+                NestedParallelism::sequential(),
+                NestedParallelism::new(1, 4),
+            ] {
+                let mut scratch = KMeansScratch::new()
+                    .with_kernel(kernel)
+                    .with_parallelism(parallelism);
+                // Start early abandoning from the highest index.
+                scratch.assignments = Array1::from_elem(instances.nrows(), 4);
+                kmeans_iteration_with_scratch(
+                    instances.view(),
+                    Axis(0),
+                    centroids.clone().view_mut(),
+                    &mut scratch,
+                );
+                assert_eq!(scratch.assignments, expected);
+            }
+        }
+
+        // Probe the groups with the highest indices first.
+        let mut assignments = [0; 6];
+        assign_block_coarse(
+            instances.view(),
+            centroids.view(),
+            array![[0f32, 0.], [10., 10.]].view(),
+            &[vec![4, 3], vec![2, 1, 0]],
+            2,
+            &mut assignments,
+        );
+        assert_eq!(&assignments, expected.as_slice().unwrap());
+    }
+
     #[test]
     fn correct_update_centroids() {
         let mut centroids = array![[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
@@ -411,6 +2286,7 @@ mod tests {
             [0., 0., 2.],
         ];
         let assignments = array![1, 0, 1, 0, 2, 2];
+        let mut counts = array![7., 7., 7.];
 
         // Test instances along axis 0.
         update_centroids(
@@ -418,15 +2294,23 @@ mod tests {
             instances.view(),
             Axis(0),
             assignments.view(),
+            counts.view_mut(),
         );
 
         assert_eq!(
             centroids,
             array![[0.5, 0.5, 0.], [-1.5, -1., 0.], [0., 0., 1.5]]
         );
+        assert_eq!(counts, array![2., 2., 2.]);
 
         // Test instances along axis 1.
-        update_centroids(centroids.view_mut(), instances.t(), Axis(1), assignments);
+        update_centroids(
+            centroids.view_mut(),
+            instances.t(),
+            Axis(1),
+            assignments,
+            counts.view_mut(),
+        );
 
         assert_eq!(
             centroids,
@@ -434,6 +2318,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn update_centroids_keeps_empty_clusters() {
+        let mut centroids = array![[1., 0.], [5., 5.], [0., 1.]];
+        let instances = array![[2., 0.], [0., 2.], [0., 4.]];
+        let mut counts = Array1::zeros(3);
+
+        update_centroids(
+            centroids.view_mut(),
+            instances.view(),
+            Axis(0),
+            array![0u32, 2, 2],
+            counts.view_mut(),
+        );
+
+        assert_eq!(centroids, array![[2., 0.], [5., 5.], [0., 3.]]);
+        assert_eq!(counts, array![1., 0., 2.]);
+    }
+
     fn gaussian_spheres<S>(centers: ArrayBase<S, Ix2>, mut rng: &mut impl Rng) -> Array2<f64>
     where
         S: Data<Elem = f64>,
@@ -478,6 +2380,108 @@ mod tests {
         assert_eq!(centroids, [[0, 0], [1, 0], [1, 1]]);
     }
 
+    #[test]
+    fn correct_quantile_centroids() {
+        let data = array![[4f64, 0.], [3., 10.], [2., 20.], [1., 30.]];
+
+        // Quantile levels 1/4 and 3/4.
+        assert_eq!(
+            QuantileCentroids.initial_centroids(data.view(), Axis(0), 2),
+            array![[2., 10.], [4., 30.]]
+        );
+        assert_eq!(
+            QuantileCentroids.initial_centroids(data.t(), Axis(1), 2),
+            array![[2., 10.], [4., 30.]]
+        );
+
+        // Levels 1/6, 1/2, 5/6 with generators 1 and 2.
+        assert_eq!(
+            QuantileCentroids.initial_centroids(data.view(), Axis(0), 3),
+            array![[1., 0.], [3., 30.], [4., 20.]]
+        );
+    }
+
+    #[test]
+    fn k_means_3_quantile_centroids() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+
+        let gaussians = gaussian_spheres(array![[0., 0.], [1., 0.], [1., 1.]], &mut rng);
+
+        let mut centroids: Vec<_> = gaussians
+            .k_means(Axis(0), 3, QuantileCentroids, NIterationsCondition(10))
+            .0
+            .map(|v| v.round() as isize)
+            .outer_iter()
+            .map(|r| r.to_vec())
+            .collect();
+        centroids.sort();
+
+        assert_eq!(centroids, [[0, 0], [1, 0], [1, 1]]);
+    }
+
+    #[test]
+    fn k_means_3_bisecting_centroids() {
+        let mut rng = XorShiftRng::from_seed(SEED);
+
+        let gaussians = gaussian_spheres(array![[0., 0.], [1., 0.], [1., 1.]], &mut rng);
+
+        let bisecting_centroids = BisectingCentroids::new(rng, 10);
+        let mut centroids: Vec<_> = gaussians
+            .k_means(Axis(0), 3, bisecting_centroids, NIterationsCondition(1))
+            .0
+            .map(|v| v.round() as isize)
+            .outer_iter()
+            .map(|r| r.to_vec())
+            .collect();
+        centroids.sort();
+
+        assert_eq!(centroids, [[0, 0], [1, 0], [1, 1]]);
+    }
+
+    #[test]
+    fn bisecting_centroids_identical_instances() {
+        let data = array![[1f64, 1.], [1., 1.], [1., 1.], [2., 2.]];
+        let mut centroids = BisectingCentroids::new(XorShiftRng::from_seed(SEED), 5)
+            .initial_centroids(data.t(), Axis(1), 2)
+            .outer_iter()
+            .map(|r| r.to_vec())
+            .collect::<Vec<_>>();
+        centroids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(centroids, vec![vec![1., 1.], vec![2., 2.]]);
+    }
+
+    #[test]
+    fn bisecting_centroids_fewer_distinct_instances() {
+        let data = array![[1f64, 1.], [1., 1.], [1., 1.], [2., 2.]];
+        let centroids = BisectingCentroids::new(XorShiftRng::from_seed(SEED), 5).initial_centroids(
+            data.view(),
+            Axis(0),
+            4,
+        );
+
+        // The centroid of the largest cluster is duplicated.
+        assert_eq!(centroids, array![[1., 1.], [2., 2.], [1., 1.], [2., 2.]]);
+    }
+
+    #[test]
+    fn centroid_initializations() {
+        let data = array![[0f64, 0.], [0., 1.], [1., 0.], [1., 1.], [0.5, 0.5]];
+        let mut rng = XorShiftRng::from_seed(SEED);
+        for &initialization in &[
+            CentroidInitialization::RandomInstances,
+            CentroidInitialization::Quantiles,
+            CentroidInitialization::Bisecting(5),
+        ] {
+            let centroids = initialization.initial_centroids(data.view(), Axis(0), 3, &mut rng);
+            assert_eq!(centroids.dim(), (3, 2));
+        }
+
+        assert_eq!(
+            CentroidInitialization::Quantiles.initial_centroids(data.view(), Axis(0), 3, &mut rng),
+            QuantileCentroids.initial_centroids(data.view(), Axis(0), 3)
+        );
+    }
+
     #[test]
     fn k_means_3_axis1() {
         let mut rng = XorShiftRng::from_seed(SEED);
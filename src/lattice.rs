@@ -0,0 +1,419 @@
+//! Lattice quantization.
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayViewMut2, Data, Ix1, Ix2, NdFloat};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use crate::pq::{QuantizeVector, ReconstructVector};
+
+/// Lattice of a lattice quantizer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lattice {
+    /// The integer lattice *Z^n*.
+    ///
+    /// Blocks of `n` dimensions are quantized by rounding every
+    /// coordinate to the nearest integer.
+    Z(usize),
+
+    /// The Gosset lattice *E8*.
+    ///
+    /// *E8* consists of the points of *Z^8* and *(Z + 1/2)^8* with an
+    /// even coordinate sum. It is the densest packing in eight
+    /// dimensions, so its quantization error is lower than that of
+    /// *Z^8* for the same number of points per unit volume.
+    E8,
+}
+
+impl Lattice {
+    /// Get the number of dimensions of a block.
+    pub fn block_dims(self) -> usize {
+        match self {
+            Lattice::Z(n) => n,
+            Lattice::E8 => 8,
+        }
+    }
+
+    /// Get the number of bits of the code of a block.
+    pub fn code_bits(self, coordinate_bits: u32) -> u32 {
+        match self {
+            Lattice::Z(n) => n as u32 * coordinate_bits,
+            // One bit for the coset of the point.
+            Lattice::E8 => 8 * coordinate_bits + 1,
+        }
+    }
+}
+
+/// Lattice quantizer.
+///
+/// A lattice quantizer splits a vector into blocks of
+/// `Lattice::block_dims` dimensions. Each block is divided by `scale`
+/// and quantized to the nearest point of the lattice. Since the
+/// codebook is the lattice itself, the quantizer does not need to be
+/// trained, which makes it an alternative to the subquantizers of `PQ`
+/// for high-throughput pipelines.
+///
+/// The lattice is truncated, so that every block can be stored in a
+/// single code. Each coordinate of a lattice point is stored in
+/// `coordinate_bits` bits, for lattice points with coordinates in
+/// *[-2^(coordinate_bits - 1), 2^(coordinate_bits - 1))*. Blocks are
+/// clamped to this range before quantization, so `scale` should be
+/// chosen such that few values are clamped, see
+/// `LatticeQuantizer::for_instances`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatticeQuantizer<A> {
+    lattice: Lattice,
+    n_dims: usize,
+    coordinate_bits: u32,
+    scale: A,
+}
+
+impl<A> LatticeQuantizer<A>
+where
+    A: NdFloat,
+{
+    /// Construct a lattice quantizer.
+    ///
+    /// The vector length `n_dims` must be a multiple of the block
+    /// length of the lattice. A block code can have at most 32 bits,
+    /// see `Lattice::code_bits`.
+    pub fn new(lattice: Lattice, n_dims: usize, coordinate_bits: u32, scale: A) -> Self {
+        assert!(
+            lattice.block_dims() > 0 && n_dims > 0 && n_dims % lattice.block_dims() == 0,
+            "The vector length {} should be a non-zero multiple of the block length {}",
+            n_dims,
+            lattice.block_dims()
+        );
+        assert!(
+            coordinate_bits >= 2,
+            "The number of coordinate bits should at least be 2, was: {}",
+            coordinate_bits
+        );
+        assert!(
+            lattice.code_bits(coordinate_bits) <= 32,
+            "Block codes should have at most 32 bits, have: {}",
+            lattice.code_bits(coordinate_bits)
+        );
+        assert!(
+            scale > A::zero(),
+            "The scale should be positive, was: {}",
+            scale
+        );
+
+        LatticeQuantizer {
+            lattice,
+            n_dims,
+            coordinate_bits,
+            scale,
+        }
+    }
+
+    /// Construct a lattice quantizer that covers the range of instances.
+    ///
+    /// The scale is chosen such that the largest absolute value of
+    /// `instances` is not clamped. This only requires a pass over the
+    /// instances, no training.
+    pub fn for_instances<S>(
+        lattice: Lattice,
+        coordinate_bits: u32,
+        instances: ArrayBase<S, Ix2>,
+    ) -> Self
+    where
+        S: Data<Elem = A>,
+    {
+        let max_abs = instances
+            .iter()
+            .fold(A::zero(), |max_abs, &v| max_abs.max(v.abs()));
+        let max_coordinate = A::from(clamp_bound(coordinate_bits)).unwrap();
+        let scale = if max_abs > A::zero() {
+            max_abs / max_coordinate
+        } else {
+            A::one()
+        };
+
+        LatticeQuantizer::new(lattice, instances.ncols(), coordinate_bits, scale)
+    }
+
+    /// Get the lattice.
+    pub fn lattice(&self) -> Lattice {
+        self.lattice
+    }
+
+    /// Get the number of bits per lattice point coordinate.
+    pub fn coordinate_bits(&self) -> u32 {
+        self.coordinate_bits
+    }
+
+    /// Get the scale of the lattice.
+    pub fn scale(&self) -> A {
+        self.scale
+    }
+
+    /// Get the number of bits of the code of a block.
+    pub fn code_bits(&self) -> u32 {
+        self.lattice.code_bits(self.coordinate_bits)
+    }
+
+    fn quantize_block(&self, block: impl Iterator<Item = A>) -> u64 {
+        let bound = clamp_bound(self.coordinate_bits);
+        let point = block
+            .map(|v| (v / self.scale).to_f64().unwrap().max(-bound).min(bound))
+            .collect::<Vec<_>>();
+
+        let (coset, point) = match self.lattice {
+            Lattice::Z(_) => (0, point.iter().map(|v| v.round()).collect()),
+            Lattice::E8 => nearest_e8(&point),
+        };
+
+        let offset = 1i64 << (self.coordinate_bits - 1);
+        let mut code = coset << (point.len() as u32 * self.coordinate_bits);
+        for (idx, v) in point.into_iter().enumerate() {
+            // Points in the half-integer coset are stored rounded down.
+            let k = (v.floor() as i64 + offset) as u64;
+            code |= k << (idx as u32 * self.coordinate_bits);
+        }
+
+        code
+    }
+
+    fn reconstruct_block(&self, code: u64, block: &mut [A]) {
+        let offset = 1i64 << (self.coordinate_bits - 1);
+        let mask = (1u64 << self.coordinate_bits) - 1;
+        let half = if code >> (block.len() as u32 * self.coordinate_bits) & 1 == 1 {
+            0.5
+        } else {
+            0.
+        };
+
+        for (idx, v) in block.iter_mut().enumerate() {
+            let k = (code >> (idx as u32 * self.coordinate_bits)) & mask;
+            let coordinate = (k as i64 - offset) as f64 + half;
+            *v = A::from(coordinate).unwrap() * self.scale;
+        }
+    }
+}
+
+impl<A> QuantizeVector<A> for LatticeQuantizer<A>
+where
+    A: NdFloat,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert!(
+            quantized.nrows() == x.nrows() && quantized.ncols() == self.quantized_len(),
+            "Quantized matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
+            x.nrows(),
+            self.quantized_len(),
+            quantized.nrows(),
+            quantized.ncols()
+        );
+
+        for (x, mut quantized) in x.outer_iter().zip(quantized.outer_iter_mut()) {
+            quantized.assign(&self.quantize_vector(x));
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(x.len(), self.n_dims, "Quantizer and vector length mismatch");
+        assert!(
+            (u64::MAX >> (64 - self.code_bits())) as usize <= I::max_value().as_(),
+            "Cannot store {}-bit block codes in quantizer index type",
+            self.code_bits()
+        );
+
+        let block_dims = self.lattice.block_dims();
+        let x = x.to_vec();
+        x.chunks(block_dims)
+            .map(|block| (self.quantize_block(block.iter().cloned()) as usize).as_())
+            .collect()
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_dims / self.lattice.block_dims()
+    }
+}
+
+impl<A> ReconstructVector<A> for LatticeQuantizer<A>
+where
+    A: NdFloat,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.n_dims));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert!(
+            reconstructions.nrows() == quantized.nrows() && reconstructions.ncols() == self.n_dims,
+            "Reconstructions matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
+            quantized.nrows(),
+            self.n_dims,
+            reconstructions.nrows(),
+            reconstructions.ncols()
+        );
+
+        for (quantized, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction.assign(&self.reconstruct_vector(quantized));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.len(),
+            self.quantized_len(),
+            "Quantization length does not match number of blocks"
+        );
+
+        let mut reconstruction = vec![A::zero(); self.n_dims];
+        for (&code, block) in quantized
+            .iter()
+            .zip(reconstruction.chunks_mut(self.lattice.block_dims()))
+        {
+            self.reconstruct_block(code.as_() as u64, block);
+        }
+
+        Array1::from(reconstruction)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.n_dims
+    }
+}
+
+/// Get the bound of the coordinates that blocks are clamped to.
+///
+/// The bound leaves room for the rounding of `nearest_d8`, so that the
+/// coordinates of the nearest lattice point fit in the coordinate bits.
+fn clamp_bound(coordinate_bits: u32) -> f64 {
+    ((1i64 << (coordinate_bits - 1)) - 2).max(0) as f64
+}
+
+/// Find the nearest point of *D8* (integer points with an even sum).
+fn nearest_d8(x: &[f64]) -> Vec<f64> {
+    let mut point = x.iter().map(|v| v.round()).collect::<Vec<_>>();
+    let sum: f64 = point.iter().sum();
+    if sum.rem_euclid(2.) != 0. {
+        // Round the coordinate with the largest rounding error the
+        // other way (Conway & Sloane, 1982).
+        let (idx, _) = x
+            .iter()
+            .zip(&point)
+            .map(|(v, p)| (v - p).abs())
+            .enumerate()
+            .fold(
+                (0, -1.),
+                |best, (idx, err)| {
+                    if err > best.1 {
+                        (idx, err)
+                    } else {
+                        best
+                    }
+                },
+            );
+        point[idx] += if x[idx] > point[idx] { 1. } else { -1. };
+    }
+
+    point
+}
+
+/// Find the nearest point of *E8*, returns the coset and the point.
+fn nearest_e8(x: &[f64]) -> (u64, Vec<f64>) {
+    let integer = nearest_d8(x);
+    let half = nearest_d8(&x.iter().map(|v| v - 0.5).collect::<Vec<_>>())
+        .into_iter()
+        .map(|v| v + 0.5)
+        .collect::<Vec<_>>();
+
+    let sq_dist =
+        |point: &[f64]| -> f64 { x.iter().zip(point).map(|(v, p)| (v - p) * (v - p)).sum() };
+
+    if sq_dist(&half) < sq_dist(&integer) {
+        (1, half)
+    } else {
+        (0, integer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{Lattice, LatticeQuantizer};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn e8_quantizes_lattice_points_exactly() {
+        let quantizer = LatticeQuantizer::new(Lattice::E8, 16, 3, 0.5f32);
+        let points = array![[
+            0.25, 0.25, 0.25, 0.25, 0.25, 0.25, 0.25, 0.25, 0.5, -0.5, 0., 0., 0., 0., 0., 0.
+        ]];
+        let quantized: Array2<u32> = quantizer.quantize_batch(points.view());
+        assert_eq!(quantized.ncols(), 2);
+        assert_eq!(quantizer.reconstruct_batch(quantized), points);
+    }
+
+    #[test]
+    fn lattice_error_is_bounded() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(-1., 1.), &mut rng);
+
+        for &(lattice, covering_radius) in &[(Lattice::Z(4), 1f32), (Lattice::E8, 1f32)] {
+            let quantizer = LatticeQuantizer::for_instances(lattice, 3, instances.view());
+            let quantized: Array2<u32> = quantizer.quantize_batch(instances.view());
+            let reconstructions = quantizer.reconstruct_batch(quantized.view());
+            assert_eq!(
+                quantizer.quantize_batch::<u32, _>(reconstructions.view()),
+                quantized
+            );
+
+            // No values are clamped, so the error of each block is at
+            // most the covering radius of the lattice.
+            let errors = &instances - &reconstructions;
+            for block in errors.exact_chunks((1, lattice.block_dims())) {
+                let sq_error = block.iter().map(|v| v * v).sum::<f32>();
+                assert!(sq_error.sqrt() <= covering_radius * quantizer.scale() + 1e-5);
+            }
+        }
+    }
+}
@@ -1,9 +1,63 @@
+#[cfg(feature = "arrow")]
+pub mod arrow;
+
+pub mod cq;
+
+pub mod error;
+
+pub(crate) mod float_ord;
+
+pub mod fp16;
+
+#[cfg(test)]
+mod golden;
+
+#[cfg(feature = "gpu")]
+pub mod gpu;
+
+pub mod ivfpq;
+
 pub mod kmeans;
 
+pub mod lattice;
+
 pub mod linalg;
 
+pub mod lopq;
+
+pub mod lsh;
+
+pub mod lsq;
+
+pub mod metrics;
+
+pub mod msq;
+
 pub(crate) mod ndarray_rand;
 
+pub mod neq;
+
+pub mod parallel;
+
 pub mod pq;
 
-pub(crate) mod rng;
+pub mod rabitq;
+
+#[cfg(feature = "polars")]
+pub mod polars;
+
+pub mod rq;
+
+pub mod selection;
+
+pub(crate) mod simd;
+
+pub mod sketch;
+
+pub mod split;
+
+pub mod sq;
+
+pub mod training_log;
+
+pub mod tree;
@@ -1,7 +1,11 @@
 //! Various linear algebra utility traits.
 
+#[cfg(feature = "opq-train")]
+use ndarray::ArrayView2;
 use ndarray::{Array1, Array2, ArrayBase, Axis, Data, Ix1, Ix2, NdFloat};
 use num_traits::{AsPrimitive, FromPrimitive};
+#[cfg(feature = "opq-train")]
+use rayon::prelude::*;
 
 /// Trait for computing covariance matrices.
 pub trait Covariance<A> {
@@ -44,6 +48,41 @@ where
     }
 }
 
+/// Compute *x^T y* in parallel over blocks of rows.
+///
+/// The rows of `x` and `y` are split into blocks of `block_len` rows.
+/// The products of the blocks are computed in parallel and summed. For
+/// matrices with many rows, such as the instances and reconstructions
+/// in the OPQ rotation update, this is considerably faster than a
+/// single matrix multiplication.
+#[cfg(feature = "opq-train")]
+pub(crate) fn par_cross_product<A>(
+    x: ArrayView2<A>,
+    y: ArrayView2<A>,
+    block_len: usize,
+) -> Array2<A>
+where
+    A: NdFloat,
+{
+    assert_eq!(
+        x.nrows(),
+        y.nrows(),
+        "Matrices have a different number of rows: {} {}",
+        x.nrows(),
+        y.nrows()
+    );
+    assert!(block_len > 0, "Block length should be at least one");
+
+    x.axis_chunks_iter(Axis(0), block_len)
+        .into_par_iter()
+        .zip(y.axis_chunks_iter(Axis(0), block_len))
+        .map(|(x_block, y_block)| x_block.t().dot(&y_block))
+        .reduce(
+            || Array2::zeros((x.ncols(), y.ncols())),
+            |acc, product| acc + product,
+        )
+}
+
 /// Squared euclidean distance *|u-v|^2*.
 ///
 /// Computes the squared euclidean distances between two arrays.
@@ -312,4 +351,31 @@ mod tests {
             array![[14., 10., 6.], [6.0, 10.0, 14.0]]
         );
     }
+
+    #[cfg(feature = "opq-train")]
+    #[test]
+    fn par_cross_product_is_cross_product() {
+        use ndarray::{s, Array2};
+
+        use super::par_cross_product;
+
+        let x = array![[1., 2.], [3., 4.], [5., 6.], [7., 8.], [9., 10.]];
+        let y = array![
+            [1., 0., 2.],
+            [0., 1., 3.],
+            [1., 1., 4.],
+            [2., 0., 5.],
+            [0., 2., 6.]
+        ];
+        let product = x.t().dot(&y);
+        for block_len in 1..=6 {
+            assert_eq!(par_cross_product(x.view(), y.view(), block_len), product);
+        }
+
+        let empty = x.slice(s![..0, ..]);
+        assert_eq!(
+            par_cross_product(empty, y.slice(s![..0, ..]), 2),
+            Array2::<f64>::zeros((2, 3))
+        );
+    }
 }
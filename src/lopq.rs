@@ -0,0 +1,375 @@
+//! Locally optimized product quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use crate::kmeans::{
+    cluster_assignments, InitialCentroids, KMeansWithCentroids, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+/// Locally optimized product quantizer (Kalantidis & Avrithis, 2014).
+///
+/// This quantizer partitions the vector space into cells with a coarse
+/// k-means quantizer. Every cell has its own product quantizer, which
+/// quantizes the residuals of the vectors in the cell with respect to
+/// the cell centroid. Since the product quantizer of a cell (and its
+/// rotation, when trained with OPQ) only has to model the local
+/// distribution of residuals, the error is lower than that of a single
+/// product quantizer of the residuals of all cells.
+///
+/// The first element of a code is the cell of the vector, the remaining
+/// elements are the codes of the product quantizer of the cell. A
+/// vector is reconstructed by adding the reconstruction of the residual
+/// to the cell centroid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocallyOptimizedPQ<A> {
+    coarse: Array2<A>,
+    cells: Vec<PQ<A>>,
+}
+
+impl<A> LocallyOptimizedPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a quantizer from its coarse centroids and cell quantizers.
+    ///
+    /// `cells[i]` quantizes the residuals of the vectors in the cell of
+    /// coarse centroid *i*. All cell quantizers should have the same
+    /// number of subquantizers.
+    pub fn new(coarse: Array2<A>, cells: Vec<PQ<A>>) -> Self {
+        assert!(
+            coarse.nrows() > 0,
+            "A locally optimized quantizer should have at least one cell."
+        );
+        assert_eq!(
+            coarse.nrows(),
+            cells.len(),
+            "The number of cell quantizers should match the number of coarse centroids"
+        );
+        for (idx, cell) in cells.iter().enumerate() {
+            assert_eq!(
+                cell.reconstructed_len(),
+                coarse.ncols(),
+                "Quantizer of cell {} has an incorrect vector length",
+                idx
+            );
+            assert_eq!(
+                cell.quantized_len(),
+                cells[0].quantized_len(),
+                "Quantizer of cell {} has an incorrect number of subquantizers",
+                idx
+            );
+        }
+
+        LocallyOptimizedPQ { coarse, cells }
+    }
+
+    /// Train a locally optimized product quantizer.
+    ///
+    /// The coarse quantizer with `n_cells` cells is trained with
+    /// `n_coarse_iterations` k-means iterations. Then a product
+    /// quantizer is trained with `T` on the residuals of every cell, see
+    /// `TrainPQ::train_pq_using` for a description of the remaining
+    /// arguments. Use `OPQ` as the trainer to optimize the rotation of
+    /// each cell.
+    ///
+    /// Cells with too few instances to train their own product
+    /// quantizer share a product quantizer that is trained on the
+    /// residuals of all instances.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_using<T, S, R>(
+        n_cells: usize,
+        n_coarse_iterations: usize,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        T: TrainPQ<A>,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(n_cells > 0, "The number of cells should at least be 1.");
+        assert!(
+            n_coarse_iterations > 0,
+            "The coarse quantizer should be optimized for at least one iteration."
+        );
+
+        info!("Training coarse quantizer with {} cells", n_cells);
+        let mut coarse = RandomInstanceCentroids::new(&mut rng).initial_centroids(
+            instances.view(),
+            Axis(0),
+            n_cells,
+        );
+        instances.kmeans_with_centroids(
+            Axis(0),
+            coarse.view_mut(),
+            NIterationsCondition(n_coarse_iterations),
+        );
+
+        let assignments = cluster_assignments(coarse.view(), instances.view(), Axis(0));
+        let mut residuals = instances.to_owned();
+        for (mut residual, &cell) in residuals.outer_iter_mut().zip(assignments.iter()) {
+            residual -= &coarse.row(cell);
+        }
+
+        let mut cell_instances = vec![Vec::new(); n_cells];
+        for (idx, &cell) in assignments.iter().enumerate() {
+            cell_instances[cell].push(idx);
+        }
+
+        // Cells need more instances than centroids to initialize k-means.
+        let min_instances = 2usize.pow(n_subquantizer_bits);
+        let mut shared = None;
+        let mut cells = Vec::with_capacity(n_cells);
+        for (cell, cell_instances) in cell_instances.iter().enumerate() {
+            if cell_instances.len() > min_instances {
+                info!("Training quantizer of cell {}", cell);
+                cells.push(T::train_pq_using(
+                    n_subquantizers,
+                    n_subquantizer_bits,
+                    n_iterations,
+                    n_attempts,
+                    residuals.select(Axis(0), cell_instances),
+                    &mut rng,
+                ));
+            } else {
+                let shared = shared.get_or_insert_with(|| {
+                    info!("Training shared quantizer for cells with few instances");
+                    T::train_pq_using(
+                        n_subquantizers,
+                        n_subquantizer_bits,
+                        n_iterations,
+                        n_attempts,
+                        residuals.view(),
+                        &mut rng,
+                    )
+                });
+                cells.push(shared.clone());
+            }
+        }
+
+        LocallyOptimizedPQ { coarse, cells }
+    }
+
+    /// Get the quantizers of the cells.
+    pub fn cells(&self) -> &[PQ<A>] {
+        &self.cells
+    }
+
+    /// Get the coarse centroids.
+    pub fn coarse_centroids(&self) -> ArrayView2<A> {
+        self.coarse.view()
+    }
+
+    /// Get the number of cells.
+    pub fn n_cells(&self) -> usize {
+        self.coarse.nrows()
+    }
+}
+
+impl<A> QuantizeVector<A> for LocallyOptimizedPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_cells() - 1 <= I::max_value().as_(),
+            "Cannot store cells in quantizer index type"
+        );
+
+        let assignments = cluster_assignments(self.coarse.view(), x.view(), Axis(0));
+        for (row, (instance, &cell)) in x.outer_iter().zip(assignments.iter()).enumerate() {
+            let residual = &instance - &self.coarse.row(cell);
+            let mut codes = quantized.row_mut(row);
+            codes[0] = cell.as_();
+            self.cells[cell].quantize_batch_into(
+                residual.insert_axis(Axis(0)),
+                codes.slice_mut(s![1..]).insert_axis(Axis(0)),
+            );
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        1 + self.cells[0].quantized_len()
+    }
+}
+
+impl<A> ReconstructVector<A> for LocallyOptimizedPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization length does not match the number of subquantizers plus the cell"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            let cell = codes[0].as_();
+            reconstruction.assign(&self.cells[cell].reconstruct_vector(codes.slice(s![1..])));
+            reconstruction += &self.coarse.row(cell);
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.coarse.ncols()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Array3, Axis};
+    use rand::SeedableRng;
+    use rand_distr::Normal;
+    use rand_xorshift::XorShiftRng;
+
+    use super::LocallyOptimizedPQ;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn quantize_residuals_of_cells() {
+        let cell = |offset: f32| {
+            PQ::new(
+                None,
+                Array3::from_shape_fn((2, 2, 1), |(_, c, _)| offset * (c as f32 * 2. - 1.)),
+            )
+        };
+        let lopq =
+            LocallyOptimizedPQ::new(array![[0f32, 0.], [10., 10.]], vec![cell(1.), cell(2.)]);
+        assert_eq!(lopq.quantized_len(), 3);
+
+        let quantized: Array2<u8> = lopq.quantize_batch(array![[1f32, -1.], [8., 13.]]);
+        assert_eq!(quantized, array![[0, 1, 0], [1, 0, 1]]);
+        assert_eq!(
+            lopq.reconstruct_batch(quantized),
+            array![[1., -1.], [8., 12.]]
+        );
+    }
+
+    #[test]
+    fn local_quantizers_reduce_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+
+        // Clusters that are stretched along different dimensions.
+        let mut instances =
+            Array2::random_using((2048, 4), Normal::new(0f32, 0.1).unwrap(), &mut rng);
+        for (idx, mut instance) in instances.outer_iter_mut().enumerate() {
+            let cluster = idx % 4;
+            instance[cluster] *= 20.;
+            instance += 10. * cluster as f32;
+        }
+
+        let lopq = LocallyOptimizedPQ::train_using::<PQ<f32>, _, _>(
+            4,
+            10,
+            2,
+            2,
+            10,
+            1,
+            instances.view(),
+            &mut rng,
+        );
+        assert_eq!(lopq.n_cells(), 4);
+
+        // Compare to a single quantizer of the residuals of all cells.
+        let quantized: Array2<usize> = lopq.quantize_batch(instances.view());
+        let cells = quantized.column(0).to_vec();
+        let residuals = &instances - &lopq.coarse_centroids().select(Axis(0), &cells);
+        let shared = PQ::train_pq_using(2, 2, 10, 1, residuals, &mut rng);
+        let ivf = LocallyOptimizedPQ::new(lopq.coarse_centroids().to_owned(), vec![shared; 4]);
+
+        let mse = |quantizer: &LocallyOptimizedPQ<f32>| {
+            let quantized: Array2<u8> = quantizer.quantize_batch(instances.view());
+            let errors = &instances - &quantizer.reconstruct_batch(quantized);
+            errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+        };
+
+        assert!(mse(&lopq) < mse(&ivf));
+    }
+}
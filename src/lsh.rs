@@ -0,0 +1,235 @@
+//! Locality-sensitive hashing.
+
+use ndarray::{
+    Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::Rng;
+
+use crate::pq::{standard_normal, QuantizeVector};
+use crate::simd;
+
+/// Random hyperplane quantizer (Charikar, 2002).
+///
+/// This quantizer encodes a vector as a binary code, where bit *i* is
+/// set when the vector is on the positive side of random hyperplane *i*.
+/// The normals of the hyperplanes are sampled from a standard normal
+/// distribution, so that the probability that two vectors differ in a
+/// bit is proportional to the angle between the vectors. The Hamming
+/// distance between codes therefore estimates the angular distance
+/// between vectors.
+///
+/// The quantizer does not learn from data and is mainly useful as a
+/// cheap baseline for learned binary codes. Since hyperplanes pass
+/// through the origin, data should be centered first.
+///
+/// The `QuantizeVector` implementation uses one code (0 or 1) per bit.
+/// `quantize_batch_packed` packs the bits into bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyperplaneLSH<A> {
+    normals: Array2<A>,
+}
+
+impl<A> HyperplaneLSH<A>
+where
+    A: NdFloat,
+{
+    /// Construct a quantizer from hyperplane normals.
+    ///
+    /// `normals` has the shape *(n_bits, n_dims)*.
+    pub fn new(normals: Array2<A>) -> Self {
+        assert!(
+            normals.nrows() > 0 && normals.ncols() > 0,
+            "Hyperplane quantizer should have at least one bit and one dimension, was: {:?}",
+            normals.shape()
+        );
+
+        HyperplaneLSH { normals }
+    }
+
+    /// Sample a quantizer with `n_bits` random hyperplanes.
+    pub fn random_using<R>(n_dims: usize, n_bits: usize, rng: &mut R) -> Self
+    where
+        R: Rng + ?Sized,
+    {
+        Self::new(Array2::from_shape_fn((n_bits, n_dims), |_| {
+            A::from(standard_normal(rng)).unwrap()
+        }))
+    }
+
+    /// Get the hyperplane normals.
+    pub fn normals(&self) -> ArrayView2<A> {
+        self.normals.view()
+    }
+
+    /// Get the number of bits of a code.
+    pub fn n_bits(&self) -> usize {
+        self.normals.nrows()
+    }
+
+    /// Get the number of dimensions of the vectors.
+    pub fn n_dims(&self) -> usize {
+        self.normals.ncols()
+    }
+
+    /// Get the number of bytes of a packed code.
+    pub fn packed_len(&self) -> usize {
+        self.n_bits() / 8 + usize::from(self.n_bits() % 8 != 0)
+    }
+
+    /// Quantize a batch of vectors to packed codes.
+    ///
+    /// Bit *i* of a code is stored in bit *i % 8* of byte *i / 8*.
+    /// Unused bits of the last byte are zero.
+    pub fn quantize_batch_packed<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<u8>
+    where
+        S: Data<Elem = A>,
+    {
+        let quantized: Array2<u8> = self.quantize_batch(x);
+        let mut packed = Array2::zeros((quantized.nrows(), self.packed_len()));
+        for (bits, mut packed) in quantized.outer_iter().zip(packed.outer_iter_mut()) {
+            for (idx, &bit) in bits.iter().enumerate() {
+                packed[idx / 8] |= bit << (idx % 8);
+            }
+        }
+
+        packed
+    }
+}
+
+/// Compute the Hamming distances between a packed code and a batch of
+/// packed codes.
+pub fn hamming_distances(code: ArrayView1<u8>, codes: ArrayView2<u8>) -> Array1<u32> {
+    assert_eq!(
+        code.len(),
+        codes.ncols(),
+        "Packed code lengths do not match"
+    );
+
+    codes.map_axis(Axis(1), |other| match (code.as_slice(), other.as_slice()) {
+        (Some(code), Some(other)) => simd::hamming_distance(code, other),
+        _ => code
+            .iter()
+            .zip(other.iter())
+            .map(|(&a, &b)| (a ^ b).count_ones())
+            .sum(),
+    })
+}
+
+impl<A> QuantizeVector<A> for HyperplaneLSH<A>
+where
+    A: NdFloat,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.n_bits()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.n_dims(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.n_bits()),
+            "Quantized matrix has incorrect shape"
+        );
+
+        let projections = x.dot(&self.normals.t());
+        quantized.zip_mut_with(&projections, |code, &p| {
+            *code = usize::from(p > A::zero()).as_();
+        });
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.n_bits()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{hamming_distances, HyperplaneLSH};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::QuantizeVector;
+
+    #[test]
+    fn quantize_and_pack() {
+        let lsh = HyperplaneLSH::new(array![
+            [1f32, 0.],
+            [0., 1.],
+            [1., 1.],
+            [-1., 0.],
+            [0., -1.],
+            [1., -1.],
+            [-1., 1.],
+            [-1., -1.],
+            [2., 1.]
+        ]);
+        assert_eq!(lsh.packed_len(), 2);
+
+        let x = array![[1f32, 2.], [-1., -0.5]];
+        assert_eq!(
+            lsh.quantize_vector::<u8, _>(x.row(0)),
+            array![1, 1, 1, 0, 0, 0, 1, 0, 1]
+        );
+        assert_eq!(
+            lsh.quantize_batch_packed(x.view()),
+            array![[0b0100_0111, 1], [0b1101_1000, 0]]
+        );
+    }
+
+    #[test]
+    fn hamming_distance_estimates_angle() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let lsh = HyperplaneLSH::<f32>::random_using(16, 1024, &mut rng);
+        assert_eq!(lsh.normals().dim(), (1024, 16));
+
+        let x: Array2<f32> = Array2::random_using((8, 16), Uniform::new(-1., 1.), &mut rng);
+        let packed = lsh.quantize_batch_packed(x.view());
+        let distances = hamming_distances(packed.row(0), packed.view());
+        assert_eq!(distances[0], 0);
+
+        // Codes that are not contiguous give the same distances.
+        let column_major = packed.t().to_owned();
+        assert_eq!(
+            hamming_distances(packed.row(0), column_major.t()),
+            distances
+        );
+
+        let norms = x.map_axis(Axis(1), |v| v.dot(&v).sqrt());
+        for (idx, &distance) in distances.iter().enumerate().skip(1) {
+            let cos = x.row(0).dot(&x.row(idx)) / (norms[0] * norms[idx]);
+            let angle = cos.clamp(-1., 1.).acos() / std::f32::consts::PI;
+            assert!((distance as f32 / 1024. - angle).abs() < 0.05);
+        }
+    }
+}
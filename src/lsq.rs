@@ -0,0 +1,615 @@
+//! Local search quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, Array3, ArrayBase, ArrayView1, ArrayView2, ArrayView3, ArrayViewMut2, Axis,
+    Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::seq::index;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use rayon::prelude::*;
+
+use crate::float_ord::min_by_float_key;
+use crate::kmeans::update_centroids;
+use crate::parallel::NestedParallelism;
+use crate::pq::{QuantizeVector, ReconstructVector};
+use crate::rq::{greedy_quantize_into, ResidualQuantizer};
+
+/// Number of instances that are encoded in one task.
+const ENCODE_BLOCK: usize = 256;
+
+/// Number of k-means iterations of the residual quantizer that is used
+/// for initialization.
+const RQ_INIT_ITERATIONS: usize = 10;
+
+/// Trainer for local search quantizers.
+///
+/// The trainer holds the parameters of the local search that is used
+/// to encode vectors. Vectors are encoded with iterated local search
+/// (ILS): the codes are optimized with iterated conditional modes
+/// (ICM), after which `n_ils_iterations` times `n_perturbations` codes
+/// are set to random centroids and the perturbed codes are optimized
+/// with ICM again. The perturbed codes replace the current codes when
+/// they have a lower reconstruction error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LSQTrainer {
+    n_ils_iterations: usize,
+    n_perturbations: usize,
+    n_icm_iterations: usize,
+}
+
+impl Default for LSQTrainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LSQTrainer {
+    /// Construct a trainer with the default parameters.
+    ///
+    /// The default is 8 ILS iterations, 4 perturbations, and 4 ICM
+    /// iterations.
+    pub fn new() -> Self {
+        LSQTrainer {
+            n_ils_iterations: 8,
+            n_perturbations: 4,
+            n_icm_iterations: 4,
+        }
+    }
+
+    /// Set the number of iterated local search iterations.
+    pub fn with_ils_iterations(mut self, n_ils_iterations: usize) -> Self {
+        self.n_ils_iterations = n_ils_iterations;
+        self
+    }
+
+    /// Set the number of codes that are perturbed per ILS iteration.
+    pub fn with_perturbations(mut self, n_perturbations: usize) -> Self {
+        assert!(
+            n_perturbations > 0,
+            "At least one code should be perturbed per ILS iteration."
+        );
+        self.n_perturbations = n_perturbations;
+        self
+    }
+
+    /// Set the number of ICM sweeps over the codes.
+    pub fn with_icm_iterations(mut self, n_icm_iterations: usize) -> Self {
+        assert!(
+            n_icm_iterations > 0,
+            "Codes should be optimized for at least one ICM iteration."
+        );
+        self.n_icm_iterations = n_icm_iterations;
+        self
+    }
+
+    /// Train a local search quantizer with the xorshift PRNG and a seed.
+    ///
+    /// See `LSQTrainer::train_using`.
+    pub fn train_with_seed<A, S>(
+        &self,
+        n_codebooks: usize,
+        n_codebook_bits: u32,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        seed: u64,
+    ) -> LocalSearchQuantizer<A>
+    where
+        A: NdFloat + Sum,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        self.train_using(
+            n_codebooks,
+            n_codebook_bits,
+            n_iterations,
+            instances,
+            XorShiftRng::seed_from_u64(seed),
+        )
+    }
+
+    /// Train a local search quantizer.
+    ///
+    /// Train a local search quantizer with `n_codebooks` codebooks on
+    /// `instances`. Each codebook has 2^`n_codebook_bits` centroids. The
+    /// codebooks are initialized with a residual quantizer. Then, for
+    /// `n_iterations` iterations, the instances are encoded with
+    /// iterated local search and the codebooks are refit to the codes.
+    /// `rng` is used for initialization and perturbations. Training is
+    /// deterministic given the state of `rng`.
+    pub fn train_using<A, S, R>(
+        &self,
+        n_codebooks: usize,
+        n_codebook_bits: u32,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> LocalSearchQuantizer<A>
+    where
+        A: NdFloat + Sum,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_iterations > 0,
+            "The codebooks should be optimized for at least one iteration."
+        );
+
+        let rq = ResidualQuantizer::train_rq_parallel_using(
+            n_codebooks,
+            n_codebook_bits,
+            RQ_INIT_ITERATIONS,
+            1,
+            instances.view(),
+            NestedParallelism::for_tasks(1),
+            &mut rng,
+        );
+
+        let mut quantizer = LocalSearchQuantizer {
+            codebooks: rq.codebooks().to_owned(),
+            trainer: *self,
+            seed: rng.next_u64(),
+        };
+
+        let mut codes = quantizer.greedy_codes(instances.view());
+        for iteration in 0..n_iterations {
+            let iteration_seed = rng.next_u64();
+            quantizer.local_search_batch(instances.view(), codes.view_mut(), &|idx| {
+                iteration_seed ^ idx as u64
+            });
+            let loss = quantizer.update_codebooks(instances.view(), codes.view());
+            info!("LSQ iteration {}, loss: {}", iteration, loss);
+        }
+
+        quantizer
+    }
+}
+
+/// Local search quantizer (Martinez et al., 2018).
+///
+/// A local search quantizer is an additive quantizer: every codebook
+/// spans the full vector space and a vector is reconstructed as the sum
+/// of one centroid of each codebook. In contrast to residual
+/// quantization, the codes are optimized jointly with iterated local
+/// search. With the same code length, local search quantization has a
+/// lower reconstruction error than product and residual quantization,
+/// at the cost of much more expensive quantization.
+///
+/// Quantization is deterministic: the perturbations of the local search
+/// are drawn from a PRNG with a seed that is stored in the quantizer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalSearchQuantizer<A> {
+    codebooks: Array3<A>,
+    trainer: LSQTrainer,
+    seed: u64,
+}
+
+impl<A> LocalSearchQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a local search quantizer from its codebooks.
+    ///
+    /// Vectors are encoded using the local search parameters of
+    /// `trainer` and perturbations are drawn using `seed`.
+    pub fn new(codebooks: Array3<A>, trainer: LSQTrainer, seed: u64) -> Self {
+        assert!(
+            codebooks.len_of(Axis(0)) > 0,
+            "A local search quantizer should have at least one codebook."
+        );
+        assert!(
+            codebooks.len_of(Axis(1)) > 0,
+            "The codebooks of a local search quantizer should have at least one centroid."
+        );
+
+        LocalSearchQuantizer {
+            codebooks,
+            trainer,
+            seed,
+        }
+    }
+
+    /// Get the codebooks.
+    pub fn codebooks(&self) -> ArrayView3<A> {
+        self.codebooks.view()
+    }
+
+    /// Get the number of codebooks.
+    pub fn n_codebooks(&self) -> usize {
+        self.codebooks.len_of(Axis(0))
+    }
+
+    /// Get the number of centroids per codebook.
+    pub fn n_codebook_centroids(&self) -> usize {
+        self.codebooks.len_of(Axis(1))
+    }
+
+    /// Get the local search parameters.
+    pub fn trainer(&self) -> LSQTrainer {
+        self.trainer
+    }
+
+    /// Get the codebooks as a matrix with all centroids as rows.
+    fn centroids(&self) -> ArrayView2<A> {
+        let (n_codebooks, n_centroids, n_dims) = self.codebooks.dim();
+        self.codebooks
+            .view()
+            .into_shape((n_codebooks * n_centroids, n_dims))
+            .expect("Codebooks are not contiguous")
+    }
+
+    /// Encode instances greedily, as in residual quantization.
+    fn greedy_codes(&self, instances: ArrayView2<A>) -> Array2<usize> {
+        let mut codes = Array2::zeros((instances.nrows(), self.n_codebooks()));
+        greedy_quantize_into(self.codebooks.view(), instances, codes.view_mut());
+        codes
+    }
+
+    /// Optimize the codes of a batch of instances with local search.
+    ///
+    /// `seed` returns the PRNG seed for the instance with the given
+    /// index.
+    fn local_search_batch(
+        &self,
+        instances: ArrayView2<A>,
+        mut codes: ArrayViewMut2<usize>,
+        seed: &(dyn Fn(usize) -> u64 + Sync),
+    ) {
+        let n_codebooks = self.n_codebooks();
+        let centroids = self.centroids();
+
+        // Pairwise terms: 2 <c_i, c_j> for all centroid pairs.
+        let pairwise = centroids.dot(&centroids.t()) * A::from(2.).unwrap();
+        let sqnorms = centroids.map_axis(Axis(1), |centroid| centroid.dot(&centroid));
+
+        let codes = codes.as_slice_mut().expect("Codes are not contiguous");
+        codes
+            .par_chunks_mut(ENCODE_BLOCK * n_codebooks)
+            .enumerate()
+            .for_each(|(block, block_codes)| {
+                let offset = block * ENCODE_BLOCK;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let block_instances =
+                    instances.slice(s![offset..offset + block_codes.len() / n_codebooks, ..]);
+
+                // Unary terms: ||c||^2 - 2 <x, c> for all centroids.
+                let mut unary = block_instances.dot(&centroids.t());
+                for mut instance_unary in unary.outer_iter_mut() {
+                    instance_unary.zip_mut_with(&sqnorms, |u, &sqnorm| *u = sqnorm - (*u + *u));
+                }
+
+                for (idx, (instance_unary, instance_codes)) in unary
+                    .outer_iter()
+                    .zip(block_codes.chunks_exact_mut(n_codebooks))
+                    .enumerate()
+                {
+                    let mut rng = XorShiftRng::seed_from_u64(seed(offset + idx));
+                    self.local_search(pairwise.view(), instance_unary, instance_codes, &mut rng);
+                }
+            });
+    }
+
+    /// Optimize the codes of an instance with iterated local search.
+    fn local_search(
+        &self,
+        pairwise: ArrayView2<A>,
+        unary: ArrayView1<A>,
+        codes: &mut [usize],
+        rng: &mut impl Rng,
+    ) {
+        let n_codebooks = self.n_codebooks();
+        let n_centroids = self.n_codebook_centroids();
+
+        self.icm(pairwise, unary, codes);
+        let mut best_cost = Self::cost(pairwise, unary, n_centroids, codes);
+
+        let mut candidate = codes.to_vec();
+        for _ in 0..self.trainer.n_ils_iterations {
+            candidate.copy_from_slice(codes);
+            let n_perturbations = self.trainer.n_perturbations.min(n_codebooks);
+            for codebook in index::sample(rng, n_codebooks, n_perturbations).iter() {
+                candidate[codebook] = rng.gen_range(0..n_centroids);
+            }
+
+            self.icm(pairwise, unary, &mut candidate);
+            let cost = Self::cost(pairwise, unary, n_centroids, &candidate);
+            if cost < best_cost {
+                codes.copy_from_slice(&candidate);
+                best_cost = cost;
+            }
+        }
+    }
+
+    /// Optimize codes with iterated conditional modes.
+    ///
+    /// Every code is set in turn to the centroid that minimizes the
+    /// reconstruction error given the other codes.
+    fn icm(&self, pairwise: ArrayView2<A>, unary: ArrayView1<A>, codes: &mut [usize]) {
+        let n_codebooks = self.n_codebooks();
+        let n_centroids = self.n_codebook_centroids();
+
+        let mut costs = Array1::zeros(n_centroids);
+        for _ in 0..self.trainer.n_icm_iterations {
+            for codebook in 0..n_codebooks {
+                let offset = codebook * n_centroids;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                costs.assign(&unary.slice(s![offset..offset + n_centroids]));
+                for (other, &code) in codes.iter().enumerate() {
+                    if other == codebook {
+                        continue;
+                    }
+
+                    // ndarray#474
+                    #[allow(clippy::deref_addrof)]
+                    let pair_costs = pairwise
+                        .index_axis(Axis(0), other * n_centroids + code)
+                        .slice_move(s![offset..offset + n_centroids]);
+                    costs += &pair_costs;
+                }
+
+                codes[codebook] = min_by_float_key(costs.iter().enumerate(), |v| *v.1)
+                    .unwrap()
+                    .0;
+            }
+        }
+    }
+
+    /// Get the reconstruction error of codes, up to the squared norm of
+    /// the instance.
+    fn cost(
+        pairwise: ArrayView2<A>,
+        unary: ArrayView1<A>,
+        n_centroids: usize,
+        codes: &[usize],
+    ) -> A {
+        let mut cost = A::zero();
+        for (codebook, &code) in codes.iter().enumerate() {
+            let idx = codebook * n_centroids + code;
+            cost += unary[idx];
+            for (other, &other_code) in codes.iter().enumerate().skip(codebook + 1) {
+                cost += pairwise[(idx, other * n_centroids + other_code)];
+            }
+        }
+
+        cost
+    }
+
+    /// Refit the codebooks to the codes of the instances.
+    ///
+    /// The codebooks are updated with one block coordinate descent sweep,
+    /// where the centroids of each codebook are set to the mean residual
+    /// of their instances given the other codebooks. Returns the mean
+    /// squared reconstruction error after the update.
+    fn update_codebooks(&mut self, instances: ArrayView2<A>, codes: ArrayView2<usize>) -> A
+    where
+        usize: AsPrimitive<A>,
+    {
+        let mut reconstructions = self.reconstruct_batch(codes);
+        let mut counts = Array1::zeros(self.n_codebook_centroids());
+        for (mut codebook, codebook_codes) in self
+            .codebooks
+            .outer_iter_mut()
+            .zip(codes.axis_iter(Axis(1)))
+        {
+            // Residuals of the instances given all other codebooks.
+            let mut residuals = &instances - &reconstructions;
+            for (mut residual, &code) in residuals.outer_iter_mut().zip(codebook_codes.iter()) {
+                residual += &codebook.index_axis(Axis(0), code);
+            }
+
+            update_centroids(
+                codebook.view_mut(),
+                residuals.view(),
+                Axis(0),
+                codebook_codes,
+                counts.view_mut(),
+            );
+
+            for ((mut reconstruction, instance), (residual, &code)) in reconstructions
+                .outer_iter_mut()
+                .zip(instances.outer_iter())
+                .zip(residuals.outer_iter().zip(codebook_codes.iter()))
+            {
+                reconstruction.assign(&(&instance - &residual));
+                reconstruction += &codebook.index_axis(Axis(0), code);
+            }
+        }
+
+        let errors = &instances - &reconstructions;
+        errors.iter().map(|&v| v * v).sum::<A>() / instances.nrows().max(1).as_()
+    }
+}
+
+impl<A> QuantizeVector<A> for LocalSearchQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_codebook_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        // Every vector uses the same seed, so that its codes do not depend
+        // on its position in the batch.
+        let mut codes = self.greedy_codes(x.view());
+        self.local_search_batch(x.view(), codes.view_mut(), &|_| self.seed);
+        quantized.zip_mut_with(&codes, |quantized, &code| *quantized = code.as_());
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        self.quantize_batch(x.view().insert_axis(Axis(0)))
+            .index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_codebooks()
+    }
+}
+
+impl<A> ReconstructVector<A> for LocalSearchQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction.assign(&self.reconstruct_vector(codes));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.len(),
+            self.quantized_len(),
+            "Quantization length does not match number of codebooks"
+        );
+
+        let mut reconstruction = Array1::zeros(self.reconstructed_len());
+        for (codebook, &code) in self.codebooks.outer_iter().zip(quantized.iter()) {
+            reconstruction += &codebook.index_axis(Axis(0), code.as_());
+        }
+
+        reconstruction
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.codebooks.len_of(Axis(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{LSQTrainer, LocalSearchQuantizer};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    fn mean_squared_error<Q>(quantizer: &Q, instances: &Array2<f32>) -> f32
+    where
+        Q: QuantizeVector<f32> + ReconstructVector<f32>,
+    {
+        let quantized: Array2<u8> = quantizer.quantize_batch(instances.view());
+        let errors = instances - &quantizer.reconstruct_batch(quantized);
+        errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+    }
+
+    #[test]
+    fn local_search_finds_optimal_codes() {
+        // Greedy encoding picks [1.1, 1.1] for [1., 1.], after which no
+        // centroid of the second codebook reconstructs the vector. ICM
+        // alone cannot escape this local minimum.
+        let lsq = LocalSearchQuantizer::new(
+            array![[[1.1, 1.1], [2., 0.]], [[-1., 1.], [0.5, -0.5]]],
+            LSQTrainer::new(),
+            42,
+        );
+
+        assert_eq!(lsq.quantize_vector::<u8, _>(array![1f32, 1.]), array![1, 0]);
+        assert_eq!(lsq.reconstruct_vector(array![1u8, 0]), array![1., 1.]);
+    }
+
+    #[test]
+    fn quantization_is_deterministic() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> = Array2::random_using((64, 8), Uniform::new(0., 1.), &mut rng);
+        let lsq = LSQTrainer::new().train_with_seed(2, 3, 2, instances.view(), 42);
+
+        let quantized: Array2<u8> = lsq.quantize_batch(instances.view());
+        for (instance, codes) in instances.outer_iter().zip(quantized.outer_iter()) {
+            assert_eq!(lsq.quantize_vector::<u8, _>(instance), codes);
+        }
+
+        assert_eq!(
+            LSQTrainer::new().train_with_seed(2, 3, 2, instances.view(), 42),
+            lsq
+        );
+    }
+
+    #[test]
+    fn lsq_outperforms_pq() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(0., 1.), &mut rng);
+
+        let lsq = LSQTrainer::new()
+            .with_ils_iterations(4)
+            .with_perturbations(2)
+            .train_using(4, 4, 4, instances.view(), &mut rng);
+        assert_eq!(lsq.n_codebooks(), 4);
+        assert_eq!(lsq.n_codebook_centroids(), 16);
+
+        let pq = PQ::train_pq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        assert!(mean_squared_error(&lsq, &instances) < mean_squared_error(&pq, &instances));
+    }
+}
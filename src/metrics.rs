@@ -0,0 +1,459 @@
+//! Clustering quality metrics.
+
+use std::collections::HashMap;
+use std::iter::Sum;
+
+use ndarray::{Array1, ArrayBase, ArrayView1, ArrayView2, Axis, Data, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+use rand::seq::index;
+use rand::Rng;
+
+use crate::float_ord::{float_cmp, max_by_float_key, min_by_float_key};
+use crate::kmeans::cluster_assignments;
+use crate::linalg::SquaredEuclideanDistance;
+use crate::pq::{QuantizeVector, ReconstructVector};
+use crate::selection::subsample;
+
+/// Compute the mean silhouette coefficient of a clustering.
+///
+/// Instances are the rows of `instances`, and are assigned to their
+/// nearest row in `centroids`. The silhouette coefficient of an
+/// instance is *(b - a) / max(a, b)*, where *a* is the mean distance to
+/// the other instances of its cluster and *b* the mean distance to the
+/// instances of the nearest other cluster. The coefficient lies in
+/// *[-1, 1]*, higher values indicating a better clustering.
+///
+/// This computes all pairwise distances between instances, which is
+/// quadratic in the number of instances. Use `silhouette_score_subsample`
+/// for larger data sets.
+pub fn silhouette_score<A, S>(instances: ArrayBase<S, Ix2>, centroids: ArrayView2<A>) -> A
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let assignments = cluster_assignments(centroids, instances.view(), Axis(0));
+    silhouette(instances.view(), assignments.view(), centroids.nrows())
+}
+
+/// Compute the mean silhouette coefficient on a random subsample.
+///
+/// This function estimates the silhouette coefficient (see
+/// `silhouette_score`) using `n_samples` instances that are sampled
+/// without replacement from `instances` using `rng`.
+pub fn silhouette_score_subsample<A, S>(
+    instances: ArrayBase<S, Ix2>,
+    centroids: ArrayView2<A>,
+    n_samples: usize,
+    rng: &mut impl Rng,
+) -> A
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    assert!(
+        n_samples <= instances.nrows(),
+        "Cannot sample more instances than available: {} instances, {} samples",
+        instances.nrows(),
+        n_samples
+    );
+
+    let indices = index::sample(rng, instances.nrows(), n_samples).into_vec();
+    silhouette_score(instances.select(Axis(0), &indices), centroids)
+}
+
+fn silhouette<A>(instances: ArrayView2<A>, assignments: ArrayView1<usize>, k: usize) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    let mut cluster_sizes = vec![0usize; k];
+    for &assignment in assignments {
+        cluster_sizes[assignment] += 1;
+    }
+
+    assert!(
+        cluster_sizes.iter().filter(|&&size| size > 0).count() >= 2,
+        "The silhouette coefficient requires at least two non-empty clusters."
+    );
+
+    let dists = instances
+        .squared_euclidean_distance(instances)
+        .mapv_into(|v| v.max(A::zero()).sqrt());
+
+    let mut cluster_dists = Array1::zeros(k);
+    let mut silhouette_sum = A::zero();
+    for (inst_dists, &cluster) in dists.outer_iter().zip(assignments) {
+        // The coefficient of an instance in a singleton cluster is zero.
+        if cluster_sizes[cluster] == 1 {
+            continue;
+        }
+
+        cluster_dists.fill(A::zero());
+        for (&dist, &other_cluster) in inst_dists.iter().zip(assignments) {
+            cluster_dists[other_cluster] += dist;
+        }
+
+        let a = cluster_dists[cluster] / (cluster_sizes[cluster] - 1).as_();
+        let b = min_by_float_key(
+            (0..k)
+                .filter(|&other| other != cluster && cluster_sizes[other] != 0)
+                .map(|other| cluster_dists[other] / cluster_sizes[other].as_()),
+            |&dist| dist,
+        )
+        .unwrap();
+
+        let max = a.max(b);
+        if max > A::zero() {
+            silhouette_sum += (b - a) / max;
+        }
+    }
+
+    silhouette_sum / instances.nrows().as_()
+}
+
+/// Compute the Davies-Bouldin index of a clustering.
+///
+/// Instances are the rows of `instances`, and are assigned to their
+/// nearest row in `centroids`. The index is the average over clusters
+/// of the maximum ratio of within-cluster scatter to between-cluster
+/// separation. Lower values indicate a better clustering. Empty
+/// clusters, such as those of duplicate centroids, are ignored. If two
+/// non-empty clusters have coincident centroids, their ratio is
+/// infinite and so is the index.
+pub fn davies_bouldin_score<A, S>(instances: ArrayBase<S, Ix2>, centroids: ArrayView2<A>) -> A
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let assignments = cluster_assignments(centroids, instances.view(), Axis(0));
+    let dists = instances.squared_euclidean_distance(centroids);
+
+    let k = centroids.nrows();
+    let mut cluster_sizes = vec![0usize; k];
+    let mut scatter = Array1::<A>::zeros(k);
+    for (inst_dists, &cluster) in dists.outer_iter().zip(assignments.iter()) {
+        cluster_sizes[cluster] += 1;
+        scatter[cluster] += inst_dists[cluster].max(A::zero()).sqrt();
+    }
+
+    let non_empty: Vec<usize> = (0..k).filter(|&c| cluster_sizes[c] != 0).collect();
+    assert!(
+        non_empty.len() >= 2,
+        "The Davies-Bouldin index requires at least two non-empty clusters."
+    );
+
+    for &cluster in &non_empty {
+        scatter[cluster] /= cluster_sizes[cluster].as_();
+    }
+
+    let centroid_dists = centroids
+        .squared_euclidean_distance(centroids)
+        .mapv_into(|v| v.max(A::zero()).sqrt());
+
+    let index_sum = non_empty
+        .iter()
+        .map(|&i| {
+            max_by_float_key(
+                non_empty.iter().filter(|&&j| j != i).map(|&j| {
+                    if centroid_dists[(i, j)] > A::zero() {
+                        (scatter[i] + scatter[j]) / centroid_dists[(i, j)]
+                    } else {
+                        A::infinity()
+                    }
+                }),
+                |&ratio| ratio,
+            )
+            .unwrap()
+        })
+        .sum::<A>();
+
+    index_sum / non_empty.len().as_()
+}
+
+/// Compute the energy distance between two samples.
+///
+/// The samples are the rows of `x` and `y`. The energy distance is
+/// *2E|X - Y| - E|X - X'| - E|Y - Y'|*, where *|.|* is the Euclidean
+/// distance. It is zero if and only if the samples are drawn from the
+/// same distribution (in the limit), so unlike the mean squared error
+/// it also captures distortions of the shape of a distribution.
+///
+/// This computes all pairwise distances, which is quadratic in the
+/// number of instances.
+pub fn energy_distance<A, S1, S2>(x: ArrayBase<S1, Ix2>, y: ArrayBase<S2, Ix2>) -> A
+where
+    A: NdFloat + Sum,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    assert!(
+        x.nrows() != 0 && y.nrows() != 0,
+        "Cannot compute the energy distance of empty samples."
+    );
+
+    let mean_dist = |a: ArrayView2<A>, b: ArrayView2<A>| {
+        a.squared_euclidean_distance(b)
+            .iter()
+            .map(|&v| v.max(A::zero()).sqrt())
+            .sum::<A>()
+            / (a.nrows() * b.nrows()).as_()
+    };
+
+    let two = A::one() + A::one();
+    two * mean_dist(x.view(), y.view())
+        - mean_dist(x.view(), x.view())
+        - mean_dist(y.view(), y.view())
+}
+
+/// Compute the energy distance between instances and reconstructions.
+///
+/// The instances are the rows of `instances`. `n_samples` instances are
+/// sampled without replacement using `rng`, quantized and reconstructed
+/// with `quantizer`. Returns the energy distance (see `energy_distance`)
+/// between the sampled instances and their reconstructions. If the
+/// quantizer normalizes its inputs, the reconstructions are compared to
+/// the normalized instances.
+pub fn reconstruction_energy_distance<A, Q, S>(
+    quantizer: &Q,
+    instances: ArrayBase<S, Ix2>,
+    n_samples: usize,
+    rng: &mut impl Rng,
+) -> A
+where
+    A: NdFloat + Sum,
+    Q: QuantizeVector<A> + ReconstructVector<A>,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let sample = subsample(instances.view(), n_samples, rng);
+    let quantized = quantizer.quantize_batch::<usize, _>(sample.view());
+    let reconstructions = quantizer.reconstruct_batch(quantized);
+    energy_distance(quantizer.normalize_batch(sample), reconstructions)
+}
+
+/// Compute the Rand index of two clusterings.
+///
+/// `assignments1` and `assignments2` contain the cluster assignments of
+/// the same instances. The Rand index is the fraction of instance pairs
+/// on which both clusterings agree, that is, pairs that are either in
+/// the same cluster or in different clusters in both clusterings. The
+/// index is invariant to permutations of the cluster indices.
+pub fn rand_index<A>(assignments1: ArrayView1<usize>, assignments2: ArrayView1<usize>) -> A
+where
+    A: NdFloat,
+    usize: AsPrimitive<A>,
+{
+    assert_eq!(
+        assignments1.len(),
+        assignments2.len(),
+        "The clusterings have a different number of assignments."
+    );
+
+    let n = assignments1.len();
+    if n < 2 {
+        return A::one();
+    }
+
+    let mut sizes1 = HashMap::new();
+    let mut sizes2 = HashMap::new();
+    let mut contingency = HashMap::new();
+    for (&a1, &a2) in assignments1.iter().zip(assignments2) {
+        *sizes1.entry(a1).or_insert(0usize) += 1;
+        *sizes2.entry(a2).or_insert(0usize) += 1;
+        *contingency.entry((a1, a2)).or_insert(0usize) += 1;
+    }
+
+    let n_pairs = |n: &usize| n * n.saturating_sub(1) / 2;
+    let same_both: usize = contingency.values().map(n_pairs).sum();
+    let same1: usize = sizes1.values().map(n_pairs).sum();
+    let same2: usize = sizes2.values().map(n_pairs).sum();
+    let total = n_pairs(&n);
+
+    // Pairs that are in different clusters in both clusterings.
+    let different_both = total + same_both - same1 - same2;
+
+    (same_both + different_both).as_() / total.as_()
+}
+
+/// Compute the overlap of the nearest neighbors of two sets of vectors.
+///
+/// `instances` and `reconstructions` contain the same vectors as rows,
+/// typically original vectors and their reconstructions after
+/// quantization. For every instance, the `k` nearest neighbors
+/// (excluding the instance itself) are computed in both sets. Returns
+/// the mean fraction of the neighbors of an instance that are the same
+/// in both sets.
+///
+/// This computes all pairwise distances, which is quadratic in the
+/// number of instances.
+pub fn neighbor_overlap<A, S1, S2>(
+    instances: ArrayBase<S1, Ix2>,
+    reconstructions: ArrayBase<S2, Ix2>,
+    k: usize,
+) -> A
+where
+    A: NdFloat,
+    S1: Data<Elem = A>,
+    S2: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    assert_eq!(
+        instances.dim(),
+        reconstructions.dim(),
+        "Instances and reconstructions have different shapes."
+    );
+    assert!(
+        k > 0 && k < instances.nrows(),
+        "The number of neighbors should be in [1, {}), was: {}",
+        instances.nrows(),
+        k
+    );
+
+    let neighbors = |x: ArrayView2<A>| -> Vec<Vec<usize>> {
+        let distances = x.squared_euclidean_distance(x);
+        distances
+            .outer_iter()
+            .enumerate()
+            .map(|(idx, distances)| {
+                let mut neighbors = (0..distances.len())
+                    .filter(|&other| other != idx)
+                    .collect::<Vec<_>>();
+                neighbors.sort_by(|&a, &b| float_cmp(distances[a], distances[b]));
+                neighbors.truncate(k);
+                neighbors.sort_unstable();
+                neighbors
+            })
+            .collect()
+    };
+
+    let instance_neighbors = neighbors(instances.view());
+    let reconstruction_neighbors = neighbors(reconstructions.view());
+
+    let n_shared: usize = instance_neighbors
+        .iter()
+        .zip(&reconstruction_neighbors)
+        .map(|(n1, n2)| n1.iter().filter(|n| n2.binary_search(n).is_ok()).count())
+        .sum();
+
+    n_shared.as_() / (instances.nrows() * k).as_()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{
+        davies_bouldin_score, energy_distance, neighbor_overlap, rand_index,
+        reconstruction_energy_distance, silhouette_score, silhouette_score_subsample,
+    };
+    use crate::pq::{Normalization, PQ};
+
+    #[test]
+    fn correct_davies_bouldin_score() {
+        let instances = array![[0f64], [1.], [10.], [11.]];
+        let centroids = array![[0.5], [10.5]];
+        assert!((davies_bouldin_score(instances.view(), centroids.view()) - 0.1f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn davies_bouldin_score_duplicate_centroids() {
+        let instances = array![[0f64], [1.], [10.], [11.]];
+        let centroids = array![[0.5], [10.5], [0.5], [10.5]];
+        let score = davies_bouldin_score(instances.view(), centroids.view());
+        assert!(score.is_finite());
+        assert!((score - 0.1f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn correct_neighbor_overlap() {
+        let instances = array![[0f64], [1.], [3.], [7.]];
+        assert_eq!(neighbor_overlap(instances.view(), instances.view(), 2), 1.);
+
+        // The neighbors of the third instance change from the first and
+        // second to the second and fourth instance.
+        let reconstructions = array![[0f64], [2.], [3.], [5.]];
+        assert_eq!(
+            neighbor_overlap(instances.view(), reconstructions.view(), 2),
+            0.875
+        );
+    }
+
+    #[test]
+    fn correct_energy_distance() {
+        let x = array![[0f64], [1.]];
+        assert_eq!(energy_distance(x.view(), x.view()), 0.);
+
+        // 2 * 1 - 0.5 - 0.5
+        let y = array![[1f64], [2.]];
+        assert!((energy_distance(x.view(), y.view()) - 1f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reconstruction_energy_distance_exact() {
+        let instances = array![[0f64, 1.], [1., 0.], [0., 1.]];
+        let pq = PQ::new(None, array![[[0., 1.], [1., 0.]]]);
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        assert_eq!(
+            reconstruction_energy_distance(&pq, instances.view(), 2, &mut rng),
+            0.
+        );
+    }
+
+    #[test]
+    fn reconstruction_energy_distance_normalized() {
+        // The quantizer reconstructs the centered instances exactly.
+        let instances = array![[9f64, 11.], [11., 9.], [9., 11.]];
+        let pq = PQ::new(None, array![[[-1., 1.], [1., -1.]]])
+            .with_normalization(Normalization::MeanCentering(array![10., 10.]));
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        assert_eq!(
+            reconstruction_energy_distance(&pq, instances.view(), 3, &mut rng),
+            0.
+        );
+    }
+
+    #[test]
+    fn correct_rand_index() {
+        let a = array![0, 0, 1, 1, 2];
+        assert_eq!(
+            rand_index::<f64>(a.view(), array![2, 2, 0, 0, 1].view()),
+            1.
+        );
+
+        // Only pairs (0,2), (0,3), (0,4) and (2,3) are agreements.
+        let b = array![0, 1, 1, 1, 1];
+        assert_eq!(rand_index::<f64>(a.view(), b.view()), 0.4);
+    }
+
+    #[test]
+    fn correct_silhouette_score() {
+        let instances = array![[0f64], [1.], [10.], [11.]];
+        let centroids = array![[0.5], [10.5]];
+        let expected = (9.5 / 10.5 + 8.5 / 9.5) / 2.;
+        assert!((silhouette_score(instances.view(), centroids.view()) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn silhouette_score_full_subsample() {
+        let instances = array![[0f64], [1.], [2.], [10.], [11.]];
+        let centroids = array![[1.], [10.5]];
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let full = silhouette_score(instances.view(), centroids.view());
+        let sampled = silhouette_score_subsample(instances.view(), centroids.view(), 5, &mut rng);
+        assert!((full - sampled).abs() < 1e-6f64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn silhouette_score_one_cluster() {
+        let instances = array![[0f64], [1.]];
+        let centroids = array![[0.5], [10.5]];
+        silhouette_score(instances.view(), centroids.view());
+    }
+}
@@ -0,0 +1,418 @@
+//! Multi-scale quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2,
+    NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use crate::float_ord::min_by_float_key;
+use crate::kmeans::{
+    cluster_assignments, InitialCentroids, KMeansWithCentroids, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::neq::normalize;
+use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+/// Multi-scale quantizer (Wu et al., 2017).
+///
+/// This quantizer first quantizes a vector with a coarse k-means
+/// quantizer. The residual of the vector is then split into a scale and
+/// a direction. The direction (the residual divided by its norm) is
+/// quantized with a product quantizer. The scale is the least-squares
+/// scale of the quantized direction, which is quantized with a scalar
+/// codebook. A vector is reconstructed as the coarse centroid plus the
+/// quantized scale times the quantized direction.
+///
+/// Since the product quantizer only has to quantize unit vectors,
+/// its codebooks are not dominated by vectors with large residuals.
+/// This improves accuracy on data with widely varying norms, such as
+/// many embeddings.
+///
+/// A code consists of the coarse code, the scale code, and the codes
+/// of the direction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiScalePQ<A> {
+    coarse: Array2<A>,
+    scales: Array1<A>,
+    directions: PQ<A>,
+}
+
+impl<A> MultiScalePQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a quantizer from its parts.
+    pub fn new(coarse: Array2<A>, scales: Array1<A>, directions: PQ<A>) -> Self {
+        assert!(
+            coarse.nrows() > 0,
+            "A multi-scale quantizer should have at least one coarse centroid."
+        );
+        assert!(
+            !scales.is_empty(),
+            "The scale codebook should have at least one scale."
+        );
+        assert_eq!(
+            coarse.ncols(),
+            directions.reconstructed_len(),
+            "The coarse centroids and direction quantizer have different lengths"
+        );
+
+        MultiScalePQ {
+            coarse,
+            scales,
+            directions,
+        }
+    }
+
+    /// Train a multi-scale quantizer.
+    ///
+    /// The coarse quantizer has `n_coarse` centroids and the scale
+    /// codebook 2^`n_scale_bits` scales, both are trained with
+    /// `n_iterations` k-means iterations. The direction quantizer is
+    /// trained with `T` on the normalized residuals, see
+    /// `TrainPQ::train_pq_using` for a description of the remaining
+    /// arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_using<T, S, R>(
+        n_coarse: usize,
+        n_scale_bits: u32,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        T: TrainPQ<A>,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_coarse > 0,
+            "The number of coarse centroids should at least be 1."
+        );
+        assert!(
+            n_scale_bits > 0 && n_scale_bits <= 16,
+            "The number of scale bits should be in [1, 16], was: {}",
+            n_scale_bits
+        );
+        assert!(
+            n_iterations > 0,
+            "The quantizer should be optimized for at least one iteration."
+        );
+
+        info!("Training coarse quantizer");
+        let coarse = train_kmeans(instances.view(), n_coarse, n_iterations, &mut rng);
+        let (_, residuals) = coarse_residuals(coarse.view(), instances.view());
+
+        info!("Training direction quantizer");
+        let (_, directions) = normalize(residuals.view());
+        let directions = T::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            directions,
+            &mut rng,
+        );
+
+        info!("Training scale codebook");
+        let (optimal_scales, _) = optimal_scales(&directions, residuals.view());
+        let scales = train_kmeans(
+            optimal_scales.insert_axis(Axis(1)).view(),
+            2usize.pow(n_scale_bits),
+            n_iterations,
+            &mut rng,
+        );
+
+        MultiScalePQ {
+            coarse,
+            scales: scales.index_axis_move(Axis(1), 0),
+            directions,
+        }
+    }
+
+    /// Get the coarse centroids.
+    pub fn coarse_centroids(&self) -> ArrayView2<A> {
+        self.coarse.view()
+    }
+
+    /// Get the quantizer of the directions.
+    pub fn directions(&self) -> &PQ<A> {
+        &self.directions
+    }
+
+    /// Get the scale codebook.
+    pub fn scales(&self) -> ArrayView1<A> {
+        self.scales.view()
+    }
+}
+
+/// Train a k-means codebook with `k` centroids.
+fn train_kmeans<A>(
+    instances: ArrayView2<A>,
+    k: usize,
+    n_iterations: usize,
+    rng: &mut impl RngCore,
+) -> Array2<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    let mut centroids = RandomInstanceCentroids::new(rng).initial_centroids(instances, Axis(0), k);
+    instances.kmeans_with_centroids(
+        Axis(0),
+        centroids.view_mut(),
+        NIterationsCondition(n_iterations),
+    );
+    centroids
+}
+
+/// Quantize instances with the coarse quantizer.
+///
+/// Returns the coarse codes and the residuals of the instances.
+fn coarse_residuals<A>(
+    coarse: ArrayView2<A>,
+    instances: ArrayView2<A>,
+) -> (Array1<usize>, Array2<A>)
+where
+    A: NdFloat + Sum,
+{
+    let assignments = cluster_assignments(coarse, instances, Axis(0));
+    let mut residuals = instances.to_owned();
+    for (mut residual, &assignment) in residuals.outer_iter_mut().zip(assignments.iter()) {
+        residual -= &coarse.row(assignment);
+    }
+    (assignments, residuals)
+}
+
+/// Quantize the directions of residuals and compute optimal scales.
+///
+/// The optimal scale of a residual *r* with quantized direction *d* is
+/// the least-squares solution *r·d / d·d*. Returns the scales and the
+/// direction codes.
+fn optimal_scales<A>(directions: &PQ<A>, residuals: ArrayView2<A>) -> (Array1<A>, Array2<usize>)
+where
+    A: NdFloat + Sum,
+{
+    let (_, normalized) = normalize(residuals);
+    let quantized: Array2<usize> = directions.quantize_batch(normalized);
+    let reconstructions = directions.reconstruct_batch(quantized.view());
+
+    let scales = residuals
+        .outer_iter()
+        .zip(reconstructions.outer_iter())
+        .map(|(residual, direction)| {
+            let norm_sq = direction.dot(&direction);
+            if norm_sq > A::zero() {
+                residual.dot(&direction) / norm_sq
+            } else {
+                A::zero()
+            }
+        })
+        .collect();
+
+    (scales, quantized)
+}
+
+impl<A> QuantizeVector<A> for MultiScalePQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.coarse.nrows() - 1 <= I::max_value().as_()
+                && self.scales.len() - 1 <= I::max_value().as_()
+                && self.directions.n_quantizer_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store codes in quantizer index type"
+        );
+
+        let (assignments, residuals) = coarse_residuals(self.coarse.view(), x.view());
+        let (scales, directions) = optimal_scales(&self.directions, residuals.view());
+
+        for (((&coarse, &scale), directions), mut quantized) in assignments
+            .iter()
+            .zip(scales.iter())
+            .zip(directions.outer_iter())
+            .zip(quantized.outer_iter_mut())
+        {
+            quantized[0] = coarse.as_();
+            quantized[1] = min_by_float_key(0..self.scales.len(), |&idx| {
+                (self.scales[idx] - scale).abs()
+            })
+            .unwrap()
+            .as_();
+            quantized
+                .slice_mut(s![2..])
+                .assign(&directions.mapv(AsPrimitive::as_));
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        2 + self.directions.quantized_len()
+    }
+}
+
+impl<A> ReconstructVector<A> for MultiScalePQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization length does not match the number of subquantizers plus two"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        self.directions
+            .reconstruct_batch_into(quantized.slice(s![.., 2..]), reconstructions.view_mut());
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction *= self.scales[codes[1].as_()];
+            reconstruction += &self.coarse.row(codes[0].as_());
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.coarse.ncols()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Array3};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::MultiScalePQ;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn quantize_scales_and_directions() {
+        let directions = PQ::new(
+            None,
+            Array3::from_shape_fn((2, 2, 1), |(_, c, _)| c as f32 * 2. - 1.),
+        );
+        let msq = MultiScalePQ::new(array![[0f32, 0.], [10., 10.]], array![0.5, 2.], directions);
+        assert_eq!(msq.quantized_len(), 4);
+
+        // The optimal scale of [1.8, -2.2] with direction [1, -1] is 2.
+        let quantized: Array2<u8> = msq.quantize_batch(array![[-0.4f32, 0.6], [11.8, 7.8]]);
+        assert_eq!(quantized, array![[0, 0, 0, 1], [1, 1, 1, 0]]);
+        assert_eq!(
+            msq.reconstruct_batch(quantized),
+            array![[-0.5, 0.5], [12., 8.]]
+        );
+    }
+
+    #[test]
+    fn scales_help_with_varying_norms() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((1024, 8), Uniform::new(-1., 1.), &mut rng);
+        for (idx, mut instance) in instances.outer_iter_mut().enumerate() {
+            instance *= (2f32).powi((idx % 6) as i32);
+        }
+
+        let mse = |reconstructions: Array2<f32>| {
+            let errors = &instances - &reconstructions;
+            errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+        };
+
+        let pq = PQ::train_pq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        let pq_mse = mse(pq.reconstruct_batch(pq.quantize_batch::<u8, _>(instances.view())));
+
+        let msq = MultiScalePQ::train_using::<PQ<f32>, _, _>(
+            1,
+            4,
+            4,
+            4,
+            10,
+            1,
+            instances.view(),
+            &mut rng,
+        );
+        let msq_mse = mse(msq.reconstruct_batch(msq.quantize_batch::<u8, _>(instances.view())));
+
+        assert!(msq_mse < pq_mse);
+    }
+}
@@ -0,0 +1,381 @@
+//! Norm-explicit quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    arr1, s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Data, Ix1,
+    Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use crate::kmeans::{
+    cluster_assignment, InitialCentroids, KMeansWithCentroids, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+/// Norm-explicit quantizer (Dai et al., 2020).
+///
+/// Product quantization distorts the norms of vectors, which changes
+/// the ranking of inner products, since vectors with large norms tend
+/// to have the largest inner products. This quantizer encodes the norm
+/// of a vector explicitly with a scalar codebook and the direction of
+/// the vector (the vector divided by its norm) with a product
+/// quantizer. A vector is reconstructed as the product of its quantized
+/// norm and its quantized direction.
+///
+/// The first element of a code is the norm code, the remaining elements
+/// are the codes of the direction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NormExplicitPQ<A> {
+    norms: Array1<A>,
+    directions: PQ<A>,
+}
+
+impl<A> NormExplicitPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a quantizer from a norm codebook and a direction quantizer.
+    pub fn new(norms: Array1<A>, directions: PQ<A>) -> Self {
+        assert!(
+            !norms.is_empty(),
+            "The norm codebook should have at least one norm."
+        );
+
+        NormExplicitPQ { norms, directions }
+    }
+
+    /// Train a norm-explicit quantizer.
+    ///
+    /// The norm codebook has 2^`n_norm_bits` norms and is trained with
+    /// k-means. The direction quantizer is trained with `T` on the
+    /// normalized instances, see `TrainPQ::train_pq_using` for a
+    /// description of the remaining arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_using<T, S, R>(
+        n_norm_bits: u32,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        T: TrainPQ<A>,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_norm_bits > 0 && n_norm_bits <= 16,
+            "The number of norm bits should be in [1, 16], was: {}",
+            n_norm_bits
+        );
+        assert!(
+            n_iterations > 0,
+            "The norms should be optimized for at least one iteration."
+        );
+
+        let (norms, directions) = normalize(instances.view());
+
+        info!("Training norm codebook");
+        let norm_instances = norms.insert_axis(Axis(1));
+        let mut norm_codebook = RandomInstanceCentroids::new(&mut rng).initial_centroids(
+            norm_instances.view(),
+            Axis(0),
+            2usize.pow(n_norm_bits),
+        );
+        norm_instances.kmeans_with_centroids(
+            Axis(0),
+            norm_codebook.view_mut(),
+            NIterationsCondition(n_iterations),
+        );
+
+        info!("Training direction quantizer");
+        let directions = T::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            directions,
+            rng,
+        );
+
+        NormExplicitPQ {
+            norms: norm_codebook.index_axis_move(Axis(1), 0),
+            directions,
+        }
+    }
+
+    /// Get the quantizer of the directions.
+    pub fn directions(&self) -> &PQ<A> {
+        &self.directions
+    }
+
+    /// Get the norm codebook.
+    pub fn norms(&self) -> ArrayView1<A> {
+        self.norms.view()
+    }
+
+    /// Compute the inner products of a query with quantized vectors.
+    ///
+    /// The inner products are computed from the codes using a lookup
+    /// table of the inner products of the query with the centroids, as
+    /// in asymmetric distance computation.
+    pub fn inner_products<I, S1, S2>(
+        &self,
+        query: ArrayBase<S1, Ix1>,
+        quantized: ArrayBase<S2, Ix2>,
+    ) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S1: Data<Elem = A>,
+        S2: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization length does not match the number of subquantizers plus the norm"
+        );
+
+        // The projection is orthogonal, so it preserves inner products.
+        let query = self.directions.rotate_vector(query);
+        let quantizers = self.directions.subquantizers();
+        let sq_dims = quantizers.len_of(Axis(2));
+        let tables = Array2::from_shape_fn(
+            (quantizers.len_of(Axis(0)), quantizers.len_of(Axis(1))),
+            |(sq, centroid)| {
+                quantizers
+                    .slice(s![sq, centroid, ..])
+                    .dot(&query.slice(s![sq * sq_dims..(sq + 1) * sq_dims]))
+            },
+        );
+
+        quantized.map_axis(Axis(1), |codes| {
+            let direction = codes
+                .iter()
+                .skip(1)
+                .enumerate()
+                .map(|(sq, code)| tables[(sq, code.as_())])
+                .sum::<A>();
+            self.norms[codes[0].as_()] * direction
+        })
+    }
+}
+
+/// Split instances into their norms and directions.
+///
+/// The direction of a zero vector is the zero vector.
+pub(crate) fn normalize<A>(instances: ArrayView2<A>) -> (Array1<A>, Array2<A>)
+where
+    A: NdFloat,
+{
+    let norms = instances.map_axis(Axis(1), |instance| instance.dot(&instance).sqrt());
+    let mut directions = instances.to_owned();
+    for (mut direction, &norm) in directions.outer_iter_mut().zip(norms.iter()) {
+        if norm > A::zero() {
+            direction /= norm;
+        }
+    }
+
+    (norms, directions)
+}
+
+impl<A> QuantizeVector<A> for NormExplicitPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.norms.len() - 1 <= I::max_value().as_(),
+            "Cannot store norms in quantizer index type"
+        );
+
+        let (norms, directions) = normalize(x.view());
+        self.directions
+            .quantize_batch_into(directions, quantized.slice_mut(s![.., 1..]));
+
+        let norm_codebook = self.norms.view().insert_axis(Axis(1));
+        for (&norm, code) in norms.iter().zip(quantized.column_mut(0)) {
+            *code = cluster_assignment(norm_codebook, arr1(&[norm])).as_();
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        1 + self.directions.quantized_len()
+    }
+}
+
+impl<A> ReconstructVector<A> for NormExplicitPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization length does not match the number of subquantizers plus the norm"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        self.directions
+            .reconstruct_batch_into(quantized.slice(s![.., 1..]), reconstructions.view_mut());
+        for (&code, mut reconstruction) in quantized
+            .column(0)
+            .iter()
+            .zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction *= self.norms[code.as_()];
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.directions.reconstructed_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use ndarray::{array, s, Array2, Array3};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::NormExplicitPQ;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn quantize_norms_and_directions() {
+        let directions = PQ::new(
+            None,
+            Array3::from_shape_fn((2, 2, 1), |(_, c, _)| c as f32 * 2. - 1.),
+        );
+        let neq = NormExplicitPQ::new(array![1f32, 4.], directions);
+        assert_eq!(neq.quantized_len(), 3);
+
+        let instances = array![[-0.5f32, 0.5], [3., 3.], [0., 0.]];
+        let quantized: Array2<u8> = neq.quantize_batch(instances.view());
+        assert_eq!(quantized.row(0), array![0, 0, 1]);
+        assert_eq!(quantized.row(1), array![1, 1, 1]);
+        assert_eq!(quantized[(2, 0)], 0);
+        assert_eq!(
+            neq.reconstruct_batch(quantized.slice(s![..2, ..])),
+            array![[-1., 1.], [4., 4.]]
+        );
+
+        let query = array![1f32, 2.];
+        let inner_products = neq.inner_products(query.view(), quantized.slice(s![..2, ..]));
+        assert_abs_diff_eq!(inner_products, array![1., 12.]);
+    }
+
+    #[test]
+    fn norm_explicit_quantization_preserves_norms() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((1024, 8), Uniform::new(-1., 1.), &mut rng);
+        for (idx, mut instance) in instances.outer_iter_mut().enumerate() {
+            instance *= 1. + (idx % 10) as f32;
+        }
+
+        let norm_error = |reconstructions: Array2<f32>| {
+            instances
+                .outer_iter()
+                .zip(reconstructions.outer_iter())
+                .map(|(x, r)| (x.dot(&x).sqrt() - r.dot(&r).sqrt()).abs())
+                .sum::<f32>()
+                / instances.nrows() as f32
+        };
+
+        let pq = PQ::train_pq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        let pq_error =
+            norm_error(pq.reconstruct_batch(pq.quantize_batch::<u8, _>(instances.view())));
+
+        let neq = NormExplicitPQ::train_using::<PQ<f32>, _, _>(
+            4,
+            4,
+            3,
+            10,
+            1,
+            instances.view(),
+            &mut rng,
+        );
+        let neq_error =
+            norm_error(neq.reconstruct_batch(neq.quantize_batch::<u8, _>(instances.view())));
+
+        assert!(neq_error < pq_error);
+    }
+}
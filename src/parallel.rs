@@ -0,0 +1,176 @@
+//! Tuning of nested parallelism and batch parallelism.
+
+/// Default minimum amount of work for parallel batch processing.
+///
+/// Work is measured in scalar operations, e.g. quantizing a vector of
+/// length *d* with *k* centroids per subquantizer is *d k* work.
+pub const DEFAULT_MIN_PARALLEL_WORK: usize = 1 << 18;
+
+/// Allocation of threads to nested parallel loops.
+///
+/// Training parallelizes an outer loop (e.g. over subquantizers) and an
+/// inner loop (the cluster assignment of instances). When both loops
+/// are split into as many tasks as there are threads, the scheduling
+/// overhead can outweigh the gains. This type bounds the number of
+/// tasks that each loop is split into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NestedParallelism {
+    outer: usize,
+    inner: usize,
+}
+
+impl NestedParallelism {
+    /// Construct a thread allocation.
+    ///
+    /// The outer loop is split into at most `outer` tasks and the inner
+    /// loop into at most `inner` tasks.
+    pub fn new(outer: usize, inner: usize) -> Self {
+        assert!(
+            outer > 0 && inner > 0,
+            "Loops should at least use one thread, outer: {}, inner: {}",
+            outer,
+            inner
+        );
+
+        NestedParallelism { outer, inner }
+    }
+
+    /// Derive a thread allocation from the number of outer tasks.
+    ///
+    /// The outer loop gets as many threads as there are outer tasks,
+    /// bounded by the number of threads of the current rayon thread
+    /// pool. The remaining threads are divided over the inner loops.
+    /// E.g. with 16 threads and 4 subquantizers, each subquantizer
+    /// parallelizes cluster assignment over 4 threads.
+    pub fn for_tasks(n_outer_tasks: usize) -> Self {
+        let n_threads = rayon::current_num_threads();
+        let outer = n_outer_tasks.min(n_threads).max(1);
+        NestedParallelism::new(outer, (n_threads / outer).max(1))
+    }
+
+    /// Single-threaded execution.
+    pub fn sequential() -> Self {
+        NestedParallelism::new(1, 1)
+    }
+
+    /// Get the maximum number of tasks of the outer loop.
+    pub fn outer(&self) -> usize {
+        self.outer
+    }
+
+    /// Get the maximum number of tasks of the inner loop.
+    pub fn inner(&self) -> usize {
+        self.inner
+    }
+
+    /// Get the minimum length of an outer task for `len` iterations.
+    pub(crate) fn outer_min_len(&self, len: usize) -> usize {
+        min_len(len, self.outer)
+    }
+
+    /// Get the minimum length of an inner task for `len` iterations.
+    pub(crate) fn inner_min_len(&self, len: usize) -> usize {
+        min_len(len, self.inner)
+    }
+}
+
+/// Switching between serial and parallel batch kernels.
+///
+/// Quantization, reconstruction, and distance computations over a batch
+/// of vectors can be parallelized over the vectors. For small batches
+/// (e.g. single-vector calls in a low-latency service), the overhead of
+/// scheduling rayon tasks outweighs the gains. This type decides per
+/// call whether the parallel kernel is used, based on the estimated
+/// amount of work of the batch.
+///
+/// The default uses the parallel kernel when the batch has at least
+/// `DEFAULT_MIN_PARALLEL_WORK` work. `serial` and `parallel` override
+/// the heuristic.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchParallelism {
+    min_work: usize,
+}
+
+impl BatchParallelism {
+    /// Use the parallel kernel for batches with at least `min_work` work.
+    pub fn new(min_work: usize) -> Self {
+        BatchParallelism { min_work }
+    }
+
+    /// Always use the serial kernel.
+    pub fn serial() -> Self {
+        BatchParallelism::new(usize::MAX)
+    }
+
+    /// Use the parallel kernel for every batch of more than one vector.
+    pub fn parallel() -> Self {
+        BatchParallelism::new(0)
+    }
+
+    /// Get the minimum amount of work for the parallel kernel.
+    pub fn min_work(&self) -> usize {
+        self.min_work
+    }
+
+    /// Check whether a batch should be processed in parallel.
+    ///
+    /// `work_per_row` is the estimated amount of work for a single
+    /// vector of the batch of `n_rows` vectors.
+    pub fn is_parallel(&self, n_rows: usize, work_per_row: usize) -> bool {
+        n_rows > 1
+            && self.min_work != usize::MAX
+            && n_rows.saturating_mul(work_per_row) >= self.min_work
+    }
+
+    /// Get the number of rows of a parallel task for `n_rows` rows.
+    pub(crate) fn chunk_len(&self, n_rows: usize) -> usize {
+        min_len(n_rows, rayon::current_num_threads())
+    }
+}
+
+impl Default for BatchParallelism {
+    fn default() -> Self {
+        BatchParallelism::new(DEFAULT_MIN_PARALLEL_WORK)
+    }
+}
+
+fn min_len(len: usize, n_tasks: usize) -> usize {
+    (len / n_tasks + usize::from(len % n_tasks != 0)).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchParallelism, NestedParallelism, DEFAULT_MIN_PARALLEL_WORK};
+
+    #[test]
+    fn batch_parallelism_switches_on_work() {
+        let parallelism = BatchParallelism::default();
+        assert!(!parallelism.is_parallel(1, DEFAULT_MIN_PARALLEL_WORK));
+        assert!(!parallelism.is_parallel(2, DEFAULT_MIN_PARALLEL_WORK / 4));
+        assert!(parallelism.is_parallel(2, DEFAULT_MIN_PARALLEL_WORK / 2));
+
+        assert!(BatchParallelism::parallel().is_parallel(2, 0));
+        assert!(!BatchParallelism::serial().is_parallel(usize::MAX, usize::MAX));
+    }
+
+    #[test]
+    fn nested_parallelism_for_tasks() {
+        let n_threads = rayon::current_num_threads();
+
+        let parallelism = NestedParallelism::for_tasks(1);
+        assert_eq!(parallelism.outer(), 1);
+        assert_eq!(parallelism.inner(), n_threads);
+
+        let parallelism = NestedParallelism::for_tasks(n_threads * 2);
+        assert_eq!(parallelism.outer(), n_threads);
+        assert_eq!(parallelism.inner(), 1);
+    }
+
+    #[test]
+    fn nested_parallelism_min_len() {
+        let parallelism = NestedParallelism::new(4, 3);
+        assert_eq!(parallelism.outer_min_len(10), 3);
+        assert_eq!(parallelism.inner_min_len(10), 4);
+        assert_eq!(parallelism.inner_min_len(0), 1);
+    }
+}
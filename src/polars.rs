@@ -0,0 +1,131 @@
+//! Polars conversions.
+//!
+//! Helpers to convert a DataFrame column of embeddings into an instance
+//! matrix and to convert quantization codes back into a column.
+
+use ndarray::{Array2, ArrayBase, Data, Ix2};
+use polars_core::prelude::*;
+
+use crate::error::Error;
+
+/// Convert a column of embeddings into an instance matrix.
+///
+/// The column must be a list or array column of floating point values,
+/// where every embedding has the same length. The embeddings are
+/// converted to `f32`. Null embeddings and null values are rejected.
+pub fn instances_from_column(column: &Column) -> Result<Array2<f32>, Error> {
+    let embeddings = column
+        .cast(&DataType::List(Box::new(DataType::Float32)))
+        .map_err(polars_error)?;
+    let embeddings = embeddings.list().map_err(polars_error)?;
+
+    let mut n_dims = None;
+    let mut data = Vec::new();
+    for (idx, embedding) in embeddings.into_iter().enumerate() {
+        let embedding = embedding
+            .ok_or_else(|| Error::InvalidPolarsColumn(format!("embedding {} is null", idx)))?;
+        let embedding = embedding.f32().map_err(polars_error)?;
+
+        let expected_dims = *n_dims.get_or_insert(embedding.len());
+        if embedding.len() != expected_dims {
+            return Err(Error::InvalidPolarsColumn(format!(
+                "embedding {} has length {}, expected {}",
+                idx,
+                embedding.len(),
+                expected_dims
+            )));
+        }
+
+        if embedding.null_count() != 0 {
+            return Err(Error::InvalidPolarsColumn(format!(
+                "embedding {} contains null values",
+                idx
+            )));
+        }
+
+        data.extend(embedding.into_no_null_iter());
+    }
+
+    Array2::from_shape_vec((embeddings.len(), n_dims.unwrap_or(0)), data)
+        .map_err(|err| Error::InvalidPolarsColumn(err.to_string()))
+}
+
+/// Convert a matrix of quantization codes into a list column.
+///
+/// Each row of `codes` becomes a list of `u8` codes.
+pub fn codes_to_column<S>(name: &str, codes: ArrayBase<S, Ix2>) -> Column
+where
+    S: Data<Elem = u8>,
+{
+    let mut builder = ListPrimitiveChunkedBuilder::<UInt8Type>::new(
+        name.into(),
+        codes.nrows(),
+        codes.len(),
+        DataType::UInt8,
+    );
+
+    for row in codes.outer_iter() {
+        builder.append_slice(&row.to_vec());
+    }
+
+    builder.finish().into_series().into_column()
+}
+
+fn polars_error(err: PolarsError) -> Error {
+    Error::InvalidPolarsColumn(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use polars_core::prelude::*;
+
+    use super::{codes_to_column, instances_from_column};
+    use crate::error::Error;
+    use crate::pq::{QuantizeVector, PQ};
+
+    fn embedding(values: &[f64]) -> Series {
+        Series::new("".into(), values)
+    }
+
+    #[test]
+    fn embeddings_and_codes_roundtrip() {
+        let embeddings = Series::new(
+            "embedding".into(),
+            &[
+                embedding(&[0., 2., 0., -0.5]),
+                embedding(&[1., -0.2, 0., 0.5]),
+            ],
+        );
+        let mut df = DataFrame::new(vec![embeddings.into_column()]).unwrap();
+
+        let instances = instances_from_column(df.column("embedding").unwrap()).unwrap();
+        assert_eq!(instances, array![[0f32, 2., 0., -0.5], [1., -0.2, 0., 0.5]]);
+
+        let pq = PQ::new(None, array![[[1., 0.], [0., 1.]], [[1., -1.], [0., 1.]]]);
+        let codes: Array2<u8> = pq.quantize_batch(instances.view());
+        df.with_column(codes_to_column("codes", codes.view()))
+            .unwrap();
+
+        let column = df.column("codes").unwrap().list().unwrap();
+        for (row, codes_row) in column.into_iter().zip(codes.outer_iter()) {
+            let row = row.unwrap();
+            assert_eq!(
+                row.u8().unwrap().into_no_null_iter().collect::<Vec<_>>(),
+                codes_row.to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn reject_ragged_embeddings() {
+        let embeddings = Series::new(
+            "embedding".into(),
+            &[embedding(&[0., 2.]), embedding(&[1., -0.2, 0.])],
+        );
+        assert!(matches!(
+            instances_from_column(&embeddings.into_column()),
+            Err(Error::InvalidPolarsColumn(_))
+        ));
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+use std::iter::Sum;
+use std::time::Instant;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayView3, ArrayViewMut3, Axis, Data,
+    Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use super::primitives;
+use super::{Normalization, TrainPQ, TrainingManifest, PQ};
+
+/// Number of coordinate descent passes over the subquantizers when
+/// encoding a vector with the anisotropic loss.
+const N_ENCODING_PASSES: usize = 2;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Train a product quantizer with the anisotropic loss.
+    ///
+    /// The anisotropic loss (Guo et al., 2020) weighs the component of
+    /// the quantization error that is parallel to the quantized vector
+    /// `eta` times as much as the orthogonal component. For maximum
+    /// inner product search, the parallel error matters most, since it
+    /// changes the inner products with the queries that have the largest
+    /// inner products with the vector. With `eta` equal to 1, the loss is
+    /// the squared Euclidean error.
+    ///
+    /// The quantizer is first trained with `PQ`, after which the codes
+    /// and codebooks are optimized for `n_iterations` iterations of the
+    /// anisotropic loss. Since the parallel error depends on all
+    /// subquantizers, vectors are encoded by coordinate descent and the
+    /// centroids are updated by minimizing the loss given the codes of
+    /// the other subquantizers. The mean loss of every iteration is
+    /// stored in the training manifest.
+    ///
+    /// Quantize vectors with `quantize_batch_anisotropic` to benefit
+    /// from the anisotropic loss during encoding as well.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_anisotropic_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        eta: A,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Data<Elem = A>,
+        R: RngCore,
+    {
+        assert!(
+            eta >= A::one(),
+            "The parallel error weight should at least be 1, was: {}",
+            eta
+        );
+
+        let start = Instant::now();
+
+        let pq = PQ::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+            rng,
+        );
+        let mut quantizers = pq.quantizers;
+        let settings = pq
+            .manifest
+            .map(|manifest| manifest.settings)
+            .unwrap_or_default();
+
+        let mut objective = Vec::with_capacity(n_iterations);
+        let mut codes =
+            primitives::quantize_batch::<_, usize, _>(quantizers.view(), instances.view());
+        for i in 0..n_iterations {
+            for (instance, mut codes) in instances.outer_iter().zip(codes.outer_iter_mut()) {
+                encode_anisotropic(
+                    quantizers.view(),
+                    instance,
+                    codes.as_slice_mut().unwrap(),
+                    eta,
+                );
+            }
+
+            let loss = update_codebooks(quantizers.view_mut(), instances.view(), codes.view(), eta);
+            info!("Anisotropic loss after iteration {}: {}", i, loss);
+            objective.push(loss.to_f64().unwrap());
+        }
+
+        let mut manifest = TrainingManifest::new(
+            "AnisotropicPQ",
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.dim(),
+            start,
+        )
+        .with_rng::<R>()
+        .with_settings(settings)
+        .with_setting("eta", eta);
+        manifest.objective = objective;
+
+        PQ {
+            projection: None,
+            quantizers,
+            manifest: Some(manifest),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors with the anisotropic loss.
+    ///
+    /// The vectors are first quantized to their nearest centroids, after
+    /// which the codes are optimized by coordinate descent of the
+    /// anisotropic loss with parallel error weight `eta`. See
+    /// `PQ::train_anisotropic_using`.
+    pub fn quantize_batch_anisotropic<I, S>(&self, x: ArrayBase<S, Ix2>, eta: A) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert!(
+            eta >= A::one(),
+            "The parallel error weight should at least be 1, was: {}",
+            eta
+        );
+        assert!(
+            self.n_quantizer_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        let rx = self.rotate_batch(x);
+        let mut codes =
+            primitives::quantize_batch::<_, usize, _>(self.quantizers.view(), rx.view());
+        for (instance, mut codes) in rx.outer_iter().zip(codes.outer_iter_mut()) {
+            encode_anisotropic(
+                self.quantizers.view(),
+                instance,
+                codes.as_slice_mut().unwrap(),
+                eta,
+            );
+        }
+
+        codes.mapv(AsPrimitive::as_)
+    }
+}
+
+/// Compute the weight of the squared parallel error of a vector.
+///
+/// The anisotropic loss of a residual *r* of *x* is *|r|² + w (r·x)²*.
+fn parallel_weight<A>(x: ArrayView1<A>, eta: A) -> A
+where
+    A: NdFloat,
+{
+    let norm_sq = x.dot(&x);
+    if norm_sq > A::zero() {
+        (eta - A::one()) / norm_sq
+    } else {
+        A::zero()
+    }
+}
+
+/// Optimize the codes of a vector by coordinate descent.
+pub(crate) fn encode_anisotropic<A>(
+    quantizers: ArrayView3<A>,
+    x: ArrayView1<A>,
+    codes: &mut [usize],
+    eta: A,
+) where
+    A: NdFloat + Sum,
+{
+    let weight = parallel_weight(x, eta);
+    if weight == A::zero() {
+        return;
+    }
+
+    let sq_dims = quantizers.len_of(Axis(2));
+
+    // Squared norm and dot product with x of the residual of each subspace.
+    let block_stats = |sq: usize, code: usize| {
+        let x_sq = x.slice(s![sq * sq_dims..(sq + 1) * sq_dims]);
+        let residual = &x_sq - &quantizers.slice(s![sq, code, ..]);
+        (residual.dot(&residual), residual.dot(&x_sq))
+    };
+
+    let mut stats = codes
+        .iter()
+        .enumerate()
+        .map(|(sq, &code)| block_stats(sq, code))
+        .collect::<Vec<_>>();
+    let mut total_sq = stats.iter().map(|&(sq, _)| sq).sum::<A>();
+    let mut total_dot = stats.iter().map(|&(_, dot)| dot).sum::<A>();
+
+    for _ in 0..N_ENCODING_PASSES {
+        for sq in 0..codes.len() {
+            let other_sq = total_sq - stats[sq].0;
+            let other_dot = total_dot - stats[sq].1;
+
+            let mut best = (codes[sq], stats[sq]);
+            let mut best_loss = total_sq + weight * total_dot * total_dot;
+            for code in 0..quantizers.len_of(Axis(1)) {
+                let (block_sq, block_dot) = block_stats(sq, code);
+                let dot = other_dot + block_dot;
+                let loss = other_sq + block_sq + weight * dot * dot;
+                if loss < best_loss {
+                    best = (code, (block_sq, block_dot));
+                    best_loss = loss;
+                }
+            }
+
+            codes[sq] = best.0;
+            stats[sq] = best.1;
+            total_sq = other_sq + best.1 .0;
+            total_dot = other_dot + best.1 .1;
+        }
+    }
+}
+
+/// Update the centroids to minimize the anisotropic loss.
+///
+/// Subquantizers are updated one at a time given the codes of the other
+/// subquantizers. The loss of a cluster is quadratic in its centroid,
+/// so each centroid is the solution of a linear system. Centroids of
+/// empty clusters are not changed.
+///
+/// Returns the mean anisotropic loss.
+pub(crate) fn update_codebooks<A>(
+    mut quantizers: ArrayViewMut3<A>,
+    instances: ArrayView2<A>,
+    codes: ArrayView2<usize>,
+    eta: A,
+) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    let (n_subquantizers, n_centroids, sq_dims) = quantizers.dim();
+    let weights = instances.map_axis(Axis(1), |x| parallel_weight(x, eta));
+
+    // Dot product of the residual of each subspace with the instance.
+    let mut block_dots = Array2::zeros(codes.dim());
+    for ((instance, codes), mut dots) in instances
+        .outer_iter()
+        .zip(codes.outer_iter())
+        .zip(block_dots.outer_iter_mut())
+    {
+        for (sq, (&code, dot)) in codes.iter().zip(dots.iter_mut()).enumerate() {
+            let x_sq = instance.slice(s![sq * sq_dims..(sq + 1) * sq_dims]);
+            *dot = (&x_sq - &quantizers.slice(s![sq, code, ..])).dot(&x_sq);
+        }
+    }
+
+    for sq in 0..n_subquantizers {
+        let mut lhs = vec![Array2::<A>::zeros((sq_dims, sq_dims)); n_centroids];
+        let mut rhs = vec![Array1::<A>::zeros(sq_dims); n_centroids];
+        for (((instance, codes), dots), &weight) in instances
+            .outer_iter()
+            .zip(codes.outer_iter())
+            .zip(block_dots.outer_iter())
+            .zip(weights.iter())
+        {
+            let x_sq = instance.slice(s![sq * sq_dims..(sq + 1) * sq_dims]);
+            let other_dot = dots.sum() - dots[sq];
+            let code = codes[sq];
+
+            // Minimizing |x_sq - c|² + w ((x_sq - c)·x_sq + other_dot)²
+            // gives (I + w x_sq x_sqᵀ) c = x_sq + w x_sq (|x_sq|² + other_dot).
+            let outer = x_sq
+                .view()
+                .insert_axis(Axis(1))
+                .dot(&x_sq.view().insert_axis(Axis(0)));
+            lhs[code] += &(outer * weight);
+            lhs[code].diag_mut().mapv_inplace(|v| v + A::one());
+            rhs[code] += &(&x_sq * (A::one() + weight * (x_sq.dot(&x_sq) + other_dot)));
+        }
+
+        for (code, (lhs, rhs)) in lhs.into_iter().zip(rhs).enumerate() {
+            // The diagonal is the cluster size plus non-negative terms.
+            if lhs[(0, 0)] > A::zero() {
+                if let Some(centroid) = solve_positive_definite(lhs, rhs) {
+                    quantizers.slice_mut(s![sq, code, ..]).assign(&centroid);
+                }
+            }
+        }
+
+        for ((instance, codes), mut dots) in instances
+            .outer_iter()
+            .zip(codes.outer_iter())
+            .zip(block_dots.outer_iter_mut())
+        {
+            let x_sq = instance.slice(s![sq * sq_dims..(sq + 1) * sq_dims]);
+            dots[sq] = (&x_sq - &quantizers.slice(s![sq, codes[sq], ..])).dot(&x_sq);
+        }
+    }
+
+    let mut loss = A::zero();
+    for (((instance, codes), dots), &weight) in instances
+        .outer_iter()
+        .zip(codes.outer_iter())
+        .zip(block_dots.outer_iter())
+        .zip(weights.iter())
+    {
+        let reconstruction = primitives::reconstruct(quantizers.view(), codes);
+        let residual = &instance - &reconstruction;
+        let dot = dots.sum();
+        loss += residual.dot(&residual) + weight * dot * dot;
+    }
+
+    loss / instances.nrows().as_()
+}
+
+/// Solve a symmetric positive definite system with the Cholesky
+/// decomposition.
+///
+/// Returns `None` if the matrix is not positive definite.
+fn solve_positive_definite<A>(mut a: Array2<A>, mut b: Array1<A>) -> Option<Array1<A>>
+where
+    A: NdFloat,
+{
+    let n = a.nrows();
+
+    // Decompose a = l lᵀ, storing l in the lower triangle of a.
+    for j in 0..n {
+        let mut diag = a[(j, j)];
+        for k in 0..j {
+            diag -= a[(j, k)] * a[(j, k)];
+        }
+        if diag <= A::zero() {
+            return None;
+        }
+        let diag = diag.sqrt();
+        a[(j, j)] = diag;
+
+        for i in j + 1..n {
+            let mut v = a[(i, j)];
+            for k in 0..j {
+                v -= a[(i, k)] * a[(j, k)];
+            }
+            a[(i, j)] = v / diag;
+        }
+    }
+
+    // Forward substitution, l y = b.
+    for i in 0..n {
+        for k in 0..i {
+            b[i] = b[i] - a[(i, k)] * b[k];
+        }
+        b[i] /= a[(i, i)];
+    }
+
+    // Back substitution, lᵀ x = y.
+    for i in (0..n).rev() {
+        for k in i + 1..n {
+            b[i] = b[i] - a[(k, i)] * b[k];
+        }
+        b[i] /= a[(i, i)];
+    }
+
+    Some(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::solve_positive_definite;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn solve_with_cholesky() {
+        let a = array![[4f64, 2., 0.4], [2., 5., 1.], [0.4, 1., 3.]];
+        let x = solve_positive_definite(a.clone(), array![1., 2., 3.]).unwrap();
+        assert_abs_diff_eq!(a.dot(&x), array![1., 2., 3.], epsilon = 1e-10);
+
+        assert!(solve_positive_definite(array![[1f64, 2.], [2., 1.]], array![1., 1.]).is_none());
+    }
+
+    #[test]
+    fn anisotropic_loss_reduces_parallel_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((512, 8), Uniform::new(-1., 1.), &mut rng);
+
+        // The mean squared parallel and total error.
+        let errors = |quantized: Array2<u8>, pq: &PQ<f32>| {
+            let residuals = &instances - &pq.reconstruct_batch(quantized);
+            let parallel = residuals
+                .outer_iter()
+                .zip(instances.outer_iter())
+                .map(|(r, x)| r.dot(&x).powi(2) / x.dot(&x))
+                .sum::<f32>();
+            let total = residuals.mapv(|v| v * v).sum();
+            (parallel / 512., total / 512.)
+        };
+
+        let pq = PQ::train_pq_using(4, 3, 5, 1, instances.view(), &mut rng);
+        let (parallel, total) = errors(pq.quantize_batch(instances.view()), &pq);
+
+        let anisotropic = PQ::train_anisotropic_using(4, 3, 5, 1, 8., instances.view(), &mut rng);
+        let objective = &anisotropic.manifest().unwrap().objective;
+        assert_eq!(objective.len(), 5);
+        assert!(objective[4] <= objective[0]);
+
+        let (anisotropic_parallel, anisotropic_total) = errors(
+            anisotropic.quantize_batch_anisotropic(instances.view(), 8.),
+            &anisotropic,
+        );
+        assert!(anisotropic_parallel < parallel / 2.);
+        assert!(anisotropic_total > total);
+
+        // Without extra weight on the parallel error, codes are nearest
+        // centroids.
+        assert_eq!(
+            anisotropic.quantize_batch_anisotropic::<u8, _>(instances.view(), 1.),
+            anisotropic.quantize_batch::<u8, _>(instances.view())
+        );
+    }
+}
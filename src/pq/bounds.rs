@@ -0,0 +1,164 @@
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, Axis, Data, Ix2, NdFloat};
+
+use super::{PQView, QuantizeVector, PQ};
+
+/// Quantization error bounds.
+///
+/// The bounds are measured on a set of vectors and are based on the
+/// Euclidean distance between each vector and its reconstruction. By
+/// the triangle inequality, the reconstruction error of a vector bounds
+/// the error of the estimated distance between any query and that
+/// vector, |*d(q, x)* - *d(q, x̂)*| ≤ *d(x, x̂)*. For asymmetric distance
+/// computation, *d(q, x̂)* is the square root of the summed distance
+/// table entries.
+///
+/// Since the subspaces of a product quantizer are orthogonal, the
+/// reconstruction error of a vector is the root of the summed squared
+/// errors of its subspaces. The sum of the subspace errors is a looser
+/// bound that can be used when subspace errors are bounded separately.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorBounds<A> {
+    subquantizer_bounds: Array1<A>,
+    vector_bounds: Array1<A>,
+}
+
+impl<A> ErrorBounds<A>
+where
+    A: NdFloat,
+{
+    /// Get the bound of every vector.
+    ///
+    /// The bound of a vector is the distance to its reconstruction.
+    pub fn vector_bounds(&self) -> ArrayView1<A> {
+        self.vector_bounds.view()
+    }
+
+    /// Get the bound of every subquantizer.
+    ///
+    /// The bound of a subquantizer is the maximum distance between a
+    /// slice of a vector and its nearest centroid.
+    pub fn subquantizer_bounds(&self) -> ArrayView1<A> {
+        self.subquantizer_bounds.view()
+    }
+
+    /// Get the worst-case bound.
+    ///
+    /// This is the root of the summed squared subquantizer bounds. It
+    /// bounds the reconstruction error of all measured vectors and, in
+    /// practice, of vectors from the same distribution. The bound can be
+    /// larger than the largest vector bound, since the largest errors of
+    /// the subquantizers do not have to occur in the same vector.
+    pub fn bound(&self) -> A {
+        self.subquantizer_bounds
+            .iter()
+            .fold(A::zero(), |acc, &b| acc + b * b)
+            .sqrt()
+    }
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    /// Measure the quantization error bounds of a batch of vectors.
+    ///
+    /// The vectors are quantized and the errors are measured in rotated
+    /// space, which gives the same distances for orthogonal projections.
+    /// See `ErrorBounds`.
+    pub fn error_bounds<S>(&self, x: ArrayBase<S, Ix2>) -> ErrorBounds<A>
+    where
+        S: Data<Elem = A>,
+    {
+        let rotated = self.rotated();
+        let rx = self.rotate_batch(x);
+        let quantized: Array2<usize> = rotated.quantize_batch(rx.view());
+        let reconstructions = rotated.centroid_embeddings_batch(quantized);
+
+        let sq_dims = self.quantizers.len_of(Axis(2));
+        let mut errors = Array2::zeros((rx.nrows(), self.quantizers.len_of(Axis(0))));
+        for ((x, reconstruction), mut errors) in rx
+            .outer_iter()
+            .zip(reconstructions.outer_iter())
+            .zip(errors.outer_iter_mut())
+        {
+            let diff = &x - &reconstruction;
+            for (slice, error) in diff
+                .exact_chunks(sq_dims)
+                .into_iter()
+                .zip(errors.iter_mut())
+            {
+                *error = slice.dot(&slice);
+            }
+        }
+
+        let vector_bounds = errors.map_axis(Axis(1), |errors| errors.sum().sqrt());
+        let subquantizer_bounds = errors.fold_axis(Axis(0), A::zero(), |&max, &e| max.max(e));
+
+        ErrorBounds {
+            subquantizer_bounds: subquantizer_bounds.mapv(A::sqrt),
+            vector_bounds,
+        }
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Measure the quantization error bounds of a batch of vectors.
+    ///
+    /// See `PQView::error_bounds`.
+    pub fn error_bounds<S>(&self, x: ArrayBase<S, Ix2>) -> ErrorBounds<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.view().error_bounds(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::linalg::EuclideanDistance;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn error_bounds_are_reconstruction_errors() {
+        let pq = PQ::new(None, array![[[0., 0.], [2., 0.]], [[0., 1.], [0., 3.]]]);
+        let bounds = pq.error_bounds(array![[0f32, 1., 1., 0.], [2., 0., 0., 3.]]);
+        assert_eq!(bounds.vector_bounds(), array![3f32.sqrt(), 0.]);
+        assert_eq!(bounds.subquantizer_bounds(), array![1., 2f32.sqrt()]);
+        assert_eq!(bounds.bound(), 3f32.sqrt());
+    }
+
+    #[test]
+    fn error_bounds_bound_distance_errors() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 8), Uniform::new(-1., 1.), &mut rng);
+        let queries: Array2<f32> = Array2::random_using((16, 8), Uniform::new(-1., 1.), &mut rng);
+        let pq = PQ::train_pq_using(4, 3, 5, 1, instances.view(), &mut rng);
+
+        let bounds = pq.error_bounds(instances.view());
+        let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+        let reconstructions = pq.reconstruct_batch(quantized);
+        for ((x, reconstruction), &bound) in instances
+            .outer_iter()
+            .zip(reconstructions.outer_iter())
+            .zip(bounds.vector_bounds())
+        {
+            assert!(bound <= bounds.bound());
+            for query in queries.outer_iter() {
+                let error = query.euclidean_distance(x) - query.euclidean_distance(reconstruction);
+                assert!(error.abs() <= bound + 1e-5);
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use super::{CodeSink, CodeSource};
+use crate::error::Error;
+
+/// Default size of a chunk of `ChunkedCodes` in bytes.
+pub const DEFAULT_CHUNK_BYTES: usize = 1 << 30;
+
+/// In-memory code storage in fixed-size chunks.
+///
+/// A `Vec<u8>` stores all codes in a single allocation, which has to
+/// be copied when it grows and cannot be larger than `isize::MAX`
+/// bytes. This storage splits the rows over chunks of `chunk_rows`
+/// rows instead, so that no allocation crosses a chunk boundary (1 GiB
+/// by default) and stores with more than 2^32 vectors grow without
+/// copying existing codes.
+///
+/// Chunks are allocated when a row in the chunk is written, so sparse
+/// row identifiers only use memory for the chunks that they touch.
+/// Rows that were not written read as zero codes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkedCodes {
+    code_len: usize,
+    chunk_rows: u64,
+    chunks: BTreeMap<u64, Vec<u8>>,
+    n_rows: u64,
+}
+
+impl ChunkedCodes {
+    /// Construct storage for codes of length `code_len`.
+    ///
+    /// The chunk size is `DEFAULT_CHUNK_BYTES`.
+    pub fn new(code_len: usize) -> Self {
+        Self::with_chunk_rows(
+            code_len,
+            (DEFAULT_CHUNK_BYTES / code_len.max(1)).max(1) as u64,
+        )
+    }
+
+    /// Construct storage with chunks of `chunk_rows` rows.
+    pub fn with_chunk_rows(code_len: usize, chunk_rows: u64) -> Self {
+        assert!(code_len > 0, "Codes should have a non-zero length");
+        assert!(chunk_rows > 0, "Chunks should have at least one row");
+        assert!(
+            usize::try_from(chunk_rows)
+                .ok()
+                .and_then(|rows| rows.checked_mul(code_len))
+                .is_some(),
+            "A chunk of {} rows does not fit in memory",
+            chunk_rows
+        );
+
+        ChunkedCodes {
+            code_len,
+            chunk_rows,
+            chunks: BTreeMap::new(),
+            n_rows: 0,
+        }
+    }
+
+    /// Get the number of codes per vector.
+    pub fn code_len(&self) -> usize {
+        self.code_len
+    }
+
+    /// Get the number of rows per chunk.
+    pub fn chunk_rows(&self) -> u64 {
+        self.chunk_rows
+    }
+
+    /// Get the number of rows, one more than the largest written row.
+    pub fn len(&self) -> u64 {
+        self.n_rows
+    }
+
+    /// Check whether no rows were written.
+    pub fn is_empty(&self) -> bool {
+        self.n_rows == 0
+    }
+
+    /// Get the number of bytes of the allocated chunks.
+    pub fn allocated_bytes(&self) -> usize {
+        self.chunks.values().map(Vec::len).sum()
+    }
+
+    /// Get the chunk and the byte offset within the chunk of a row.
+    fn locate(&self, row: u64) -> (u64, usize) {
+        // Chunks fit in memory, so the offset fits in usize.
+        let offset = (row % self.chunk_rows) as usize * self.code_len;
+        (row / self.chunk_rows, offset)
+    }
+}
+
+impl CodeSink for ChunkedCodes {
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error> {
+        if codes.len() != self.code_len {
+            return Err(Error::CodeLengthMismatch {
+                expected: self.code_len,
+                actual: codes.len(),
+            });
+        }
+
+        let (chunk, offset) = self.locate(row);
+        let chunk = self.chunks.entry(chunk).or_default();
+        if chunk.len() < offset + codes.len() {
+            chunk.resize(offset + codes.len(), 0);
+        }
+        chunk[offset..offset + codes.len()].copy_from_slice(codes);
+
+        self.n_rows = self.n_rows.max(row + 1);
+
+        Ok(())
+    }
+}
+
+impl CodeSource for ChunkedCodes {
+    fn n_rows(&self, _code_len: usize) -> u64 {
+        self.n_rows
+    }
+
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error> {
+        if codes.len() != self.code_len {
+            return Err(Error::CodeLengthMismatch {
+                expected: self.code_len,
+                actual: codes.len(),
+            });
+        }
+        if row >= self.n_rows {
+            return Err(Error::RowOutOfRange {
+                row,
+                n_rows: self.n_rows,
+            });
+        }
+
+        let (chunk, offset) = self.locate(row);
+        match self
+            .chunks
+            .get(&chunk)
+            .and_then(|chunk| chunk.get(offset..offset + codes.len()))
+        {
+            Some(stored) => codes.copy_from_slice(stored),
+            None => codes.iter_mut().for_each(|code| *code = 0),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::ChunkedCodes;
+    use crate::error::Error;
+    use crate::pq::{test_pq, CodeSink, CodeSource, QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn chunked_codes_beyond_u32_rows() {
+        let mut codes = ChunkedCodes::with_chunk_rows(2, 1 << 16);
+        let boundary = u64::from(u32::MAX);
+        for (idx, &row) in [boundary - 1, boundary, boundary + 1, 5_000_000_000]
+            .iter()
+            .enumerate()
+        {
+            codes.write_codes(row, &[idx as u8, 42]).unwrap();
+        }
+        assert_eq!(codes.len(), 5_000_000_001);
+        assert_eq!(codes.n_rows(2), 5_000_000_001);
+        // Only the three touched chunks are allocated.
+        assert!(codes.allocated_bytes() <= 3 << 17);
+
+        let mut row = [0u8; 2];
+        codes.read_codes(boundary + 1, &mut row).unwrap();
+        assert_eq!(row, [2, 42]);
+        codes.read_codes(5_000_000_000, &mut row).unwrap();
+        assert_eq!(row, [3, 42]);
+        codes.read_codes(3_000_000_000, &mut row).unwrap();
+        assert_eq!(row, [0, 0]);
+        assert_eq!(
+            codes.read_codes(5_000_000_001, &mut row),
+            Err(Error::RowOutOfRange {
+                row: 5_000_000_001,
+                n_rows: 5_000_000_001
+            })
+        );
+        assert_eq!(
+            codes.write_codes(0, &[1]),
+            Err(Error::CodeLengthMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn stream_codes_across_chunks() {
+        let pq = test_pq();
+        let instances = array![
+            [0f32, 2., 0., -0.5, 0., 0.],
+            [1., -0.2, 0., 0.5, 0.5, 0.],
+            [-0.2, 0.2, 0., 0., -2., 0.],
+        ];
+
+        // Rows are written across the 2^32 boundary and a chunk boundary.
+        let first_row = (1 << 32) - 2;
+        let mut codes = ChunkedCodes::with_chunk_rows(2, 1 << 20);
+        pq.quantize_batch_into_sink(instances.view(), &mut codes, first_row)
+            .unwrap();
+        assert_eq!(
+            pq.reconstruct_from_source(&codes, first_row..first_row + 3),
+            Ok(pq.reconstruct_batch(pq.quantize_batch::<u8, _>(instances.view())))
+        );
+    }
+}
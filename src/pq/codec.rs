@@ -0,0 +1,316 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, ArrayView2, Data, Ix2};
+use num_traits::AsPrimitive;
+
+use super::Fingerprint;
+
+const MAGIC: &[u8; 4] = b"RDQC";
+const VERSION: u8 = 1;
+const NORMS_FLAG: u8 = 1;
+
+/// Batch of quantized vectors for transport between processes.
+///
+/// A batch holds the codes of a set of vectors, optionally their norms,
+/// and the fingerprint of the quantizer that produced the codes. The
+/// receiver should check the fingerprint against its quantizer with
+/// `PQ::verify_fingerprint` before decoding the codes.
+///
+/// In the wire format, each batch is a frame that is prefixed by its
+/// length as an unsigned LEB128 varint, so that a stream can contain
+/// multiple batches. A frame consists of:
+///
+/// * The magic `RDQC` and the format version (1 byte).
+/// * The fingerprint (16 bytes).
+/// * The number of vectors and the number of codes per vector (varints).
+/// * Flags (1 byte), bit 0 is set when norms are present.
+/// * The width of a code in bytes (1, 2, or 4), which is the smallest
+///   width that can store all codes of the batch.
+/// * The codes in row-major order (little-endian).
+/// * The norms as little-endian `f32` (if present).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeBatch {
+    fingerprint: Fingerprint,
+    codes: Array2<u32>,
+    norms: Option<Array1<f32>>,
+}
+
+impl CodeBatch {
+    /// Construct a batch from codes and the fingerprint of their quantizer.
+    pub fn new<I, S>(fingerprint: Fingerprint, codes: ArrayBase<S, Ix2>) -> Self
+    where
+        I: AsPrimitive<u32>,
+        S: Data<Elem = I>,
+    {
+        CodeBatch {
+            fingerprint,
+            codes: codes.mapv(AsPrimitive::as_),
+            norms: None,
+        }
+    }
+
+    /// Add the norms of the vectors.
+    pub fn with_norms(mut self, norms: Array1<f32>) -> Self {
+        assert_eq!(
+            norms.len(),
+            self.codes.nrows(),
+            "Number of norms does not match the number of vectors"
+        );
+        self.norms = Some(norms);
+        self
+    }
+
+    /// Get the fingerprint of the quantizer that produced the codes.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Get the codes.
+    pub fn codes(&self) -> ArrayView2<u32> {
+        self.codes.view()
+    }
+
+    /// Get the norms of the vectors (if present).
+    pub fn norms(&self) -> Option<ArrayView1<f32>> {
+        self.norms.as_ref().map(Array1::view)
+    }
+
+    /// Encode the batch as a frame.
+    pub fn write_to(&self, mut write: impl Write) -> io::Result<()> {
+        let code_width = match self.codes.iter().max() {
+            Some(&max) if max > u32::from(u16::MAX) => 4,
+            Some(&max) if max > u32::from(u8::MAX) => 2,
+            _ => 1,
+        };
+
+        let mut frame = Vec::new();
+        frame.extend_from_slice(MAGIC);
+        frame.push(VERSION);
+        frame.extend_from_slice(&self.fingerprint.to_bytes());
+        write_varint(&mut frame, self.codes.nrows() as u64);
+        write_varint(&mut frame, self.codes.ncols() as u64);
+        frame.push(if self.norms.is_some() { NORMS_FLAG } else { 0 });
+        frame.push(code_width);
+        for &code in &self.codes {
+            frame.extend_from_slice(&code.to_le_bytes()[..code_width as usize]);
+        }
+        if let Some(norms) = &self.norms {
+            for &norm in norms {
+                frame.extend_from_slice(&norm.to_le_bytes());
+            }
+        }
+
+        let mut len = Vec::new();
+        write_varint(&mut len, frame.len() as u64);
+        write.write_all(&len)?;
+        write.write_all(&frame)
+    }
+
+    /// Encode the batch as a frame into a byte vector.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)
+            .expect("Writing to a vector cannot fail");
+        bytes
+    }
+
+    /// Decode a batch from a frame.
+    ///
+    /// Returns `None` when `read` is at the end of the stream.
+    pub fn read_from(mut read: impl Read) -> io::Result<Option<Self>> {
+        let len = match read_varint(&mut read)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut frame = Vec::new();
+        read.by_ref().take(len).read_to_end(&mut frame)?;
+        if (frame.len() as u64) < len {
+            return Err(invalid("Truncated code batch frame"));
+        }
+
+        Self::from_frame(&frame).map(Some)
+    }
+
+    fn from_frame(mut frame: &[u8]) -> io::Result<Self> {
+        let header = take(&mut frame, MAGIC.len() + 1)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid("Code batch frame has an incorrect magic"));
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(invalid(format!(
+                "Unsupported code batch version: {}",
+                header[MAGIC.len()]
+            )));
+        }
+
+        let mut fingerprint = [0u8; 16];
+        fingerprint.copy_from_slice(take(&mut frame, 16)?);
+        let fingerprint = Fingerprint::from_bytes(fingerprint);
+
+        let n_vectors = read_varint(&mut frame)?.ok_or_else(|| invalid("Missing shape"))?;
+        let n_codes = read_varint(&mut frame)?.ok_or_else(|| invalid("Missing shape"))?;
+        let flags = take(&mut frame, 2)?;
+        let (flags, code_width) = (flags[0], flags[1] as usize);
+        if ![1, 2, 4].contains(&code_width) {
+            return Err(invalid(format!("Invalid code width: {}", code_width)));
+        }
+
+        let (n_vectors, n_codes) = (n_vectors as usize, n_codes as usize);
+        let codes_len = n_vectors
+            .checked_mul(n_codes)
+            .and_then(|len| len.checked_mul(code_width))
+            .ok_or_else(|| invalid("Code batch is too large"))?;
+        let codes = take(&mut frame, codes_len)?
+            .chunks(code_width)
+            .map(|code| {
+                let mut bytes = [0u8; 4];
+                bytes[..code_width].copy_from_slice(code);
+                u32::from_le_bytes(bytes)
+            })
+            .collect::<Vec<_>>();
+        let codes =
+            Array2::from_shape_vec((n_vectors, n_codes), codes).expect("Incorrect number of codes");
+
+        let norms = if flags & NORMS_FLAG != 0 {
+            let norms_len = n_vectors
+                .checked_mul(4)
+                .ok_or_else(|| invalid("Code batch is too large"))?;
+            let norms = take(&mut frame, norms_len)?
+                .chunks(4)
+                .map(|norm| f32::from_le_bytes([norm[0], norm[1], norm[2], norm[3]]))
+                .collect::<Array1<_>>();
+            Some(norms)
+        } else {
+            None
+        };
+
+        if !frame.is_empty() {
+            return Err(invalid("Trailing data in code batch frame"));
+        }
+
+        Ok(CodeBatch {
+            fingerprint,
+            codes,
+            norms,
+        })
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// Take `len` bytes from the front of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(invalid("Truncated code batch frame"));
+    }
+
+    let (front, rest) = data.split_at(len);
+    *data = rest;
+    Ok(front)
+}
+
+/// Write an unsigned LEB128 varint.
+pub(super) fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Read an unsigned LEB128 varint.
+///
+/// Returns `None` when `read` ends before the first byte.
+pub(super) fn read_varint(mut read: impl Read) -> io::Result<Option<u64>> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        match read.read_exact(&mut byte) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        v |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(v));
+        }
+    }
+
+    Err(invalid("Varint is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use ndarray::{array, Array2};
+
+    use super::{read_varint, write_varint, CodeBatch};
+    use crate::pq::{Fingerprint, PQ};
+
+    #[test]
+    fn varint_round_trip() {
+        for &v in &[0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            assert_eq!(read_varint(buf.as_slice()).unwrap(), Some(v));
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0b1010_1100, 0b0000_0010]);
+    }
+
+    #[test]
+    fn encode_and_decode_batches() {
+        let pq = PQ::new(None, array![[[1., 0.], [0., 1.]], [[0., 0.], [1., 1.]]]);
+        let quantized: Array2<u8> = array![[0, 1], [1, 1], [1, 0]];
+
+        let batch = CodeBatch::new(pq.fingerprint(), quantized.view());
+        let with_norms =
+            CodeBatch::new(pq.fingerprint(), quantized.view()).with_norms(array![1., 2., 3.]);
+        let wide = CodeBatch::new(pq.fingerprint(), array![[0u32, 70_000]]);
+
+        let mut stream = Vec::new();
+        batch.write_to(&mut stream).unwrap();
+        with_norms.write_to(&mut stream).unwrap();
+        wide.write_to(&mut stream).unwrap();
+
+        // Codes that fit in a byte are stored in a byte.
+        assert_eq!(batch.to_bytes().len(), 1 + 5 + 16 + 2 + 2 + 6);
+
+        let mut read = stream.as_slice();
+        assert_eq!(
+            CodeBatch::read_from(&mut read).unwrap(),
+            Some(batch.clone())
+        );
+        assert_eq!(CodeBatch::read_from(&mut read).unwrap(), Some(with_norms));
+        assert_eq!(CodeBatch::read_from(&mut read).unwrap(), Some(wide));
+        assert_eq!(CodeBatch::read_from(&mut read).unwrap(), None);
+
+        let decoded = CodeBatch::read_from(batch.to_bytes().as_slice())
+            .unwrap()
+            .unwrap();
+        assert!(pq.verify_fingerprint(decoded.fingerprint()).is_ok());
+        assert_eq!(decoded.codes(), quantized.mapv(u32::from));
+        assert!(decoded.norms().is_none());
+    }
+
+    #[test]
+    fn reject_corrupt_frames() {
+        let batch = CodeBatch::new(Fingerprint::from_bytes([0; 16]), array![[1u8, 2]]);
+        let bytes = batch.to_bytes();
+
+        let err = CodeBatch::read_from(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut corrupt = bytes.clone();
+        corrupt[1] = b'X';
+        let err = CodeBatch::read_from(corrupt.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
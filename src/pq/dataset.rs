@@ -0,0 +1,259 @@
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView2, Axis, Data, Ix1, Ix2, NdFloat};
+use num_traits::{AsPrimitive, Bounded, ToPrimitive, Zero};
+
+use super::{primitives, Fingerprint, PQView, QuantizeVector, ScoreTransform};
+use crate::error::Error;
+use crate::parallel::BatchParallelism;
+use crate::sketch::QuantileSketch;
+
+/// Relative accuracy of the distance sketch of a dataset.
+const DISTANCE_SKETCH_ACCURACY: f64 = 0.01;
+
+/// Dataset of quantized vectors.
+///
+/// The dataset stores the quantization codes of a set of vectors
+/// together with the fingerprint of the quantizer that produced them.
+/// Decoding and searching the dataset with a different quantizer is an
+/// error, so that codes are never silently decoded with the wrong model,
+/// e.g. after the quantizer was retrained.
+///
+/// The dataset keeps a quantile sketch of the distances that were
+/// computed with `EncodedDataset::search_distances`, which can be used
+/// to calibrate distance thresholds from production traffic. It also
+/// stores a score transform that `EncodedDataset::scores` applies to
+/// the distances.
+///
+/// The codes are stored in a single in-memory array, so the number of
+/// vectors is bounded by the address space. `ChunkedCodes` can be used
+/// for larger stores. The code type `I` only needs to be convertible to
+/// `usize`, since codes are centroid indices rather than row identifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedDataset<I> {
+    codes: Array2<I>,
+    fingerprint: Fingerprint,
+    distance_sketch: QuantileSketch,
+    score_transform: ScoreTransform,
+}
+
+impl<I> EncodedDataset<I>
+where
+    I: AsPrimitive<usize>,
+{
+    /// Construct a dataset from codes and the fingerprint of their quantizer.
+    pub fn new(codes: Array2<I>, fingerprint: Fingerprint) -> Self {
+        EncodedDataset {
+            codes,
+            fingerprint,
+            distance_sketch: QuantileSketch::new(DISTANCE_SKETCH_ACCURACY),
+            score_transform: ScoreTransform::Identity,
+        }
+    }
+
+    /// Quantize a batch of vectors.
+    pub fn encode<A, S>(quantizer: PQView<A>, instances: ArrayBase<S, Ix2>) -> Self
+    where
+        A: NdFloat + Sum,
+        I: Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        EncodedDataset {
+            codes: quantizer.quantize_batch(instances),
+            fingerprint: quantizer.fingerprint(),
+            distance_sketch: QuantileSketch::new(DISTANCE_SKETCH_ACCURACY),
+            score_transform: ScoreTransform::Identity,
+        }
+    }
+
+    /// Set the transform from distances to scores.
+    pub fn with_score_transform(mut self, score_transform: ScoreTransform) -> Self {
+        self.score_transform = score_transform;
+        self
+    }
+
+    /// Get the transform from distances to scores.
+    pub fn score_transform(&self) -> &ScoreTransform {
+        &self.score_transform
+    }
+
+    /// Get the quantization codes.
+    pub fn codes(&self) -> ArrayView2<I> {
+        self.codes.view()
+    }
+
+    /// Get the fingerprint of the quantizer that produced the codes.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Get the number of vectors in the dataset.
+    ///
+    /// Rows are counted with `u64`, like in the other code stores.
+    pub fn len(&self) -> u64 {
+        self.codes.nrows() as u64
+    }
+
+    /// Check whether the dataset is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reconstruct the vectors of the dataset.
+    ///
+    /// Returns an error if `quantizer` did not produce the codes or if
+    /// a code is out of range.
+    pub fn decode<A>(&self, quantizer: PQView<A>) -> Result<Array2<A>, Error>
+    where
+        A: NdFloat + Sum,
+    {
+        quantizer.verify_fingerprint(self.fingerprint)?;
+        quantizer.try_reconstruct_batch(self.codes.view())
+    }
+
+    /// Compute approximate squared distances between a query and the vectors.
+    ///
+    /// Returns an error if `quantizer` did not produce the codes.
+    pub fn adc_distances<A, S>(
+        &self,
+        quantizer: PQView<A>,
+        query: ArrayBase<S, Ix1>,
+    ) -> Result<Array1<A>, Error>
+    where
+        A: NdFloat + Sum,
+        I: Sync,
+        S: Data<Elem = A>,
+    {
+        self.adc_distances_parallel(quantizer, query, BatchParallelism::default())
+    }
+
+    /// Compute approximate squared distances between a query and the vectors.
+    ///
+    /// `parallelism` decides whether the distances are computed in
+    /// parallel. Returns an error if `quantizer` did not produce the
+    /// codes.
+    pub fn adc_distances_parallel<A, S>(
+        &self,
+        quantizer: PQView<A>,
+        query: ArrayBase<S, Ix1>,
+        parallelism: BatchParallelism,
+    ) -> Result<Array1<A>, Error>
+    where
+        A: NdFloat + Sum,
+        I: Sync,
+        S: Data<Elem = A>,
+    {
+        quantizer.verify_fingerprint(self.fingerprint)?;
+
+        let tables = quantizer.adc_tables_batch(query.view().insert_axis(Axis(0)));
+        Ok(primitives::adc_distances(
+            tables.index_axis(Axis(0), 0),
+            self.codes.view(),
+            parallelism,
+        ))
+    }
+
+    /// Compute distances between a query and the vectors and record them.
+    ///
+    /// This computes the same distances as `adc_distances` and adds
+    /// them to the distance sketch of the dataset.
+    pub fn search_distances<A, S>(
+        &mut self,
+        quantizer: PQView<A>,
+        query: ArrayBase<S, Ix1>,
+    ) -> Result<Array1<A>, Error>
+    where
+        A: NdFloat + Sum,
+        I: Sync,
+        S: Data<Elem = A>,
+    {
+        let distances = self.adc_distances(quantizer, query)?;
+        for &distance in &distances {
+            // Approximate distances can be slightly negative due to
+            // rounding in the distance tables.
+            self.distance_sketch
+                .insert(ToPrimitive::to_f64(&distance).unwrap().max(0.));
+        }
+
+        Ok(distances)
+    }
+
+    /// Compute scores of the vectors for a query.
+    ///
+    /// The scores are the distances computed by `search_distances`,
+    /// transformed with the score transform of the dataset.
+    pub fn scores<A, S>(
+        &mut self,
+        quantizer: PQView<A>,
+        query: ArrayBase<S, Ix1>,
+    ) -> Result<Array1<A>, Error>
+    where
+        A: NdFloat + Sum,
+        I: Sync,
+        S: Data<Elem = A>,
+    {
+        let distances = self.search_distances(quantizer, query)?;
+        Ok(self.score_transform.scores(distances))
+    }
+
+    /// Get the sketch of the distances computed by `search_distances`.
+    pub fn distance_sketch(&self) -> &QuantileSketch {
+        &self.distance_sketch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+
+    use super::EncodedDataset;
+    use crate::error::Error;
+    use crate::pq::{test_pq, ReconstructVector, ScoreTransform, PQ};
+
+    #[test]
+    fn dataset_rejects_other_quantizer() {
+        let pq = test_pq();
+        let instances: Array2<f32> =
+            array![[0., 2., 0., -0.5, 0., 0.], [1., -0.2, 0., 0.5, 0.5, 0.]];
+        let mut dataset = EncodedDataset::<u8>::encode(pq.view(), instances.view());
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset.fingerprint(), pq.fingerprint());
+        assert_eq!(
+            dataset.decode(pq.view()),
+            Ok(pq.reconstruct_batch(dataset.codes()))
+        );
+
+        let distances = dataset
+            .adc_distances(pq.view(), array![1f32, 0., 0., 0., 1., 0.])
+            .unwrap();
+        assert_eq!(distances, array![2., 0.]);
+        assert!(dataset.distance_sketch().is_empty());
+        dataset
+            .search_distances(pq.view(), array![1f32, 0., 0., 0., 1., 0.])
+            .unwrap();
+        assert_eq!(dataset.distance_sketch().count(), 2);
+        assert_eq!(dataset.distance_sketch().quantile(0.), Some(0.));
+        assert_eq!(dataset.distance_sketch().quantile(1.), Some(2.));
+
+        let mut dataset = dataset.with_score_transform(ScoreTransform::Temperature(1.));
+        assert_eq!(
+            dataset.scores(pq.view(), array![1f32, 0., 0., 0., 1., 0.]),
+            Ok(array![(-2f32).exp(), 1.])
+        );
+        assert_eq!(dataset.distance_sketch().count(), 4);
+
+        let mut quantizers = pq.subquantizers().to_owned();
+        quantizers[[0, 0, 0]] = 2.;
+        let retrained = PQ::new(None, quantizers);
+        let mismatch = Error::FingerprintMismatch {
+            expected: pq.fingerprint(),
+            actual: retrained.fingerprint(),
+        };
+        assert_eq!(dataset.decode(retrained.view()), Err(mismatch.clone()));
+        assert_eq!(
+            dataset.adc_distances(retrained.view(), array![1f32, 0., 0., 0., 1., 0.]),
+            Err(mismatch)
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::iter::Sum;
+use std::time::Instant;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, Axis, Data,
+    Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use super::{EntropyModel, Normalization, TrainPQ, TrainingManifest, PQ};
+use crate::float_ord::min_by_float_key;
+use crate::linalg::SquaredEuclideanDistance;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Train a product quantizer under an entropy constraint.
+    ///
+    /// Entropy-constrained vector quantization (Chou et al., 1989)
+    /// minimizes the squared error plus `lambda` times the length of
+    /// the codes in bits, where the code lengths follow from the
+    /// empirical distribution of the codes. Vectors are assigned to
+    /// centroids with a low cost rather than to the nearest centroid,
+    /// so that frequent codes become more frequent and rare centroids
+    /// may be abandoned. This trades reconstruction error for codes
+    /// that compress better with entropy coding. With `lambda` equal to
+    /// zero, training is k-means.
+    ///
+    /// The quantizer is first trained with `PQ`, after which every
+    /// subquantizer is optimized for `n_iterations` iterations under
+    /// the entropy constraint. The mean cost (squared error plus
+    /// `lambda` times the bits per vector) of each iteration is stored
+    /// in the training manifest.
+    ///
+    /// Returns the quantizer and the entropy model of the codes of the
+    /// training instances. Encode vectors with
+    /// `quantize_batch_entropy_constrained` and compress them with the
+    /// model.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_entropy_constrained_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        lambda: A,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> (PQ<A>, EntropyModel)
+    where
+        S: Data<Elem = A>,
+        R: RngCore,
+    {
+        assert!(
+            lambda >= A::zero(),
+            "The rate weight should be non-negative, was: {}",
+            lambda
+        );
+
+        let start = Instant::now();
+
+        let pq = PQ::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+            rng,
+        );
+        let mut quantizers = pq.quantizers;
+        let settings = pq
+            .manifest
+            .map(|manifest| manifest.settings)
+            .unwrap_or_default();
+        let sq_dims = quantizers.len_of(Axis(2));
+
+        let mut codes = Array2::zeros((instances.nrows(), n_subquantizers));
+        let mut objective = vec![0f64; n_iterations];
+        for (sq, (mut quantizer, mut codes)) in quantizers
+            .outer_iter_mut()
+            .zip(codes.axis_iter_mut(Axis(1)))
+            .enumerate()
+        {
+            info!("Training entropy-constrained subquantizer {}", sq);
+
+            let instances = instances.slice(s![.., sq * sq_dims..(sq + 1) * sq_dims]);
+            // Start with the code lengths of nearest centroid assignment.
+            codes.assign(&assign_entropy_constrained(
+                quantizer.view(),
+                instances,
+                Array1::zeros(quantizer.nrows()).view(),
+                A::zero(),
+            ));
+            let mut lengths = smoothed_code_lengths(codes.view(), quantizer.nrows());
+            for cost in &mut objective {
+                *cost += entropy_constrained_iteration(
+                    quantizer.view_mut(),
+                    instances,
+                    codes.view_mut(),
+                    lengths.view(),
+                    lambda,
+                );
+                lengths = smoothed_code_lengths(codes.view(), quantizer.nrows());
+            }
+
+            codes.assign(&assign_entropy_constrained(
+                quantizer.view(),
+                instances,
+                lengths.view(),
+                lambda,
+            ));
+        }
+
+        let mut manifest = TrainingManifest::new(
+            "EntropyConstrainedPQ",
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.dim(),
+            start,
+        )
+        .with_rng::<R>()
+        .with_settings(settings)
+        .with_setting("lambda", lambda);
+        manifest.objective = objective;
+
+        let model = EntropyModel::fit(codes.view(), quantizers.len_of(Axis(1)));
+
+        let pq = PQ {
+            projection: None,
+            quantizers,
+            manifest: Some(manifest),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
+        };
+
+        (pq, model)
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors under an entropy constraint.
+    ///
+    /// Every subvector is assigned to the centroid that minimizes the
+    /// squared error plus `lambda` times the code length in bits under
+    /// `model`. See `PQ::train_entropy_constrained_using`.
+    pub fn quantize_batch_entropy_constrained<I, S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        lambda: A,
+        model: &EntropyModel,
+    ) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            model.n_columns(),
+            self.quantizers.len_of(Axis(0)),
+            "The entropy model does not match the number of subquantizers"
+        );
+        assert_eq!(
+            model.n_symbols(),
+            self.n_quantizer_centroids(),
+            "The entropy model does not match the number of centroids"
+        );
+        assert!(
+            self.n_quantizer_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        let rx = self.rotate_batch(x);
+        let sq_dims = self.quantizers.len_of(Axis(2));
+        let lengths = model
+            .code_lengths()
+            .mapv(|bits| A::from(bits).expect("Cannot convert code length"));
+
+        let mut quantized = Array2::zeros((rx.nrows(), self.quantizers.len_of(Axis(0))));
+        for (sq, ((quantizer, lengths), mut quantized)) in self
+            .quantizers
+            .outer_iter()
+            .zip(lengths.outer_iter())
+            .zip(quantized.axis_iter_mut(Axis(1)))
+            .enumerate()
+        {
+            let codes = assign_entropy_constrained(
+                quantizer,
+                rx.slice(s![.., sq * sq_dims..(sq + 1) * sq_dims]),
+                lengths,
+                lambda,
+            );
+            quantized.assign(&codes.mapv(AsPrimitive::as_));
+        }
+
+        quantized
+    }
+}
+
+/// Assign instances to the centroids with the lowest cost.
+fn assign_entropy_constrained<A>(
+    centroids: ArrayView2<A>,
+    instances: ArrayView2<A>,
+    lengths: ArrayView1<A>,
+    lambda: A,
+) -> Array1<usize>
+where
+    A: NdFloat + Sum,
+{
+    let distances = instances.squared_euclidean_distance(centroids);
+    distances.map_axis(Axis(1), |distances| {
+        min_by_float_key(0..centroids.nrows(), |&idx| {
+            distances[idx] + lambda * lengths[idx]
+        })
+        .unwrap()
+    })
+}
+
+/// Perform an iteration of entropy-constrained k-means.
+///
+/// Returns the mean cost of the assignments.
+fn entropy_constrained_iteration<A>(
+    mut centroids: ArrayViewMut2<A>,
+    instances: ArrayView2<A>,
+    mut codes: ArrayViewMut1<usize>,
+    lengths: ArrayView1<A>,
+    lambda: A,
+) -> f64
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    codes.assign(&assign_entropy_constrained(
+        centroids.view(),
+        instances,
+        lengths,
+        lambda,
+    ));
+
+    let mut cost = A::zero();
+    for (instance, &code) in instances.outer_iter().zip(codes.iter()) {
+        let diff = &instance - &centroids.row(code);
+        cost += diff.dot(&diff) + lambda * lengths[code];
+    }
+
+    // Centroids of clusters without instances are retained, their code
+    // lengths make further assignments unlikely.
+    let mut sums = Array2::<A>::zeros(centroids.dim());
+    let mut counts = vec![0usize; centroids.nrows()];
+    for (instance, &code) in instances.outer_iter().zip(codes.iter()) {
+        let mut sum = sums.row_mut(code);
+        sum += &instance;
+        counts[code] += 1;
+    }
+    for ((mut centroid, sum), &count) in centroids
+        .outer_iter_mut()
+        .zip(sums.outer_iter())
+        .zip(counts.iter())
+    {
+        if count > 0 {
+            centroid.assign(&(&sum / count.as_()));
+        }
+    }
+
+    (cost / instances.nrows().as_()).to_f64().unwrap()
+}
+
+/// Compute code lengths from add-one smoothed code frequencies.
+fn smoothed_code_lengths<A>(codes: ArrayView1<usize>, n_centroids: usize) -> Array1<A>
+where
+    A: NdFloat,
+    usize: AsPrimitive<A>,
+{
+    let mut counts = vec![1usize; n_centroids];
+    for &code in codes {
+        counts[code] += 1;
+    }
+
+    let total: A = (codes.len() + n_centroids).as_();
+    counts
+        .into_iter()
+        .map(|count| -(count.as_() / total).log2())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    #[test]
+    fn entropy_constraint_reduces_rate() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((1024, 4), Uniform::new(-1., 1.), &mut rng);
+
+        let mse = |pq: &PQ<f32>, quantized: Array2<u8>| {
+            let errors = &instances - &pq.reconstruct_batch(quantized);
+            errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+        };
+
+        let (pq, model) =
+            PQ::train_entropy_constrained_using(2, 4, 10, 1, 0., instances.view(), &mut rng);
+        let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+        assert_eq!(
+            pq.quantize_batch_entropy_constrained::<u8, _>(instances.view(), 0., &model),
+            quantized
+        );
+        let mse_unconstrained = mse(&pq, quantized);
+
+        let (constrained, constrained_model) =
+            PQ::train_entropy_constrained_using(2, 4, 10, 1, 0.2, instances.view(), &mut rng);
+        let objective = &constrained.manifest().unwrap().objective;
+        assert_eq!(objective.len(), 10);
+        assert!(objective[9] < objective[0]);
+
+        let quantized: Array2<u8> = constrained.quantize_batch_entropy_constrained(
+            instances.view(),
+            0.2,
+            &constrained_model,
+        );
+        assert!(constrained_model.bits_per_row() < model.bits_per_row() - 1.);
+        assert!(mse(&constrained, quantized) > mse_unconstrained);
+    }
+}
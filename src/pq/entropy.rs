@@ -0,0 +1,553 @@
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+
+use ndarray::{Array1, Array2, ArrayBase, Axis, Data, Ix2};
+use num_traits::AsPrimitive;
+
+use super::codec::{read_varint, write_varint};
+
+/// Number of bits of the probability scale.
+const SCALE_BITS: u32 = 16;
+
+/// Lower bound of the normalized rANS state.
+const STATE_LOWER: u32 = 1 << 23;
+
+/// Maximum number of codes in a block.
+///
+/// Codes with a probability of one are decoded without consuming
+/// input, so the number of codes of a block cannot be bounded by its
+/// compressed length.
+const MAX_BLOCK_CODES: usize = 1 << 26;
+
+/// Entropy model of quantization codes.
+///
+/// The model stores the frequencies of the codes of each column of a
+/// code matrix (e.g. of each subquantizer). Since centroids are not
+/// used equally often, codes can be stored in fewer bits than the raw
+/// bits per code using entropy coding. `EntropyEncoder` and
+/// `EntropyDecoder` compress and decompress code matrices with range
+/// asymmetric numeral systems (rANS) using this model.
+///
+/// Every code of a column has a non-zero probability, so codes that did
+/// not occur when fitting the model can still be encoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EntropyModel {
+    n_symbols: usize,
+    frequencies: Array2<u32>,
+    cumulative: Array2<u32>,
+}
+
+impl EntropyModel {
+    /// Fit a model to the codes in `codes`.
+    ///
+    /// `n_symbols` is the number of distinct codes per column, e.g. the
+    /// number of centroids per subquantizer. At most 2^16 symbols are
+    /// supported.
+    pub fn fit<I, S>(codes: ArrayBase<S, Ix2>, n_symbols: usize) -> Self
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert!(
+            n_symbols > 0 && n_symbols <= 1 << SCALE_BITS,
+            "The number of symbols should be in [1, {}], was: {}",
+            1 << SCALE_BITS,
+            n_symbols
+        );
+
+        let mut counts = Array2::<u64>::ones((codes.ncols(), n_symbols));
+        for (column, mut counts) in codes.axis_iter(Axis(1)).zip(counts.outer_iter_mut()) {
+            for &code in column {
+                let code = code.as_();
+                assert!(
+                    code < n_symbols,
+                    "Code {} is out of range, the model has {} symbols",
+                    code,
+                    n_symbols
+                );
+                counts[code] += 1;
+            }
+        }
+
+        let mut frequencies = Array2::zeros(counts.dim());
+        for (counts, mut frequencies) in counts.outer_iter().zip(frequencies.outer_iter_mut()) {
+            frequencies.assign(&Array1::from(normalize_counts(counts.as_slice().unwrap())));
+        }
+
+        Self::from_frequencies(frequencies)
+    }
+
+    fn from_frequencies(frequencies: Array2<u32>) -> Self {
+        let mut cumulative = Array2::zeros((frequencies.nrows(), frequencies.ncols() + 1));
+        for (frequencies, mut cumulative) in
+            frequencies.outer_iter().zip(cumulative.outer_iter_mut())
+        {
+            for (idx, &freq) in frequencies.iter().enumerate() {
+                cumulative[idx + 1] = cumulative[idx] + freq;
+            }
+        }
+
+        EntropyModel {
+            n_symbols: frequencies.ncols(),
+            frequencies,
+            cumulative,
+        }
+    }
+
+    /// Get the number of columns of the code matrices.
+    pub fn n_columns(&self) -> usize {
+        self.frequencies.nrows()
+    }
+
+    /// Get the number of distinct codes per column.
+    pub fn n_symbols(&self) -> usize {
+        self.n_symbols
+    }
+
+    /// Get the expected number of bits per row of codes.
+    ///
+    /// This is the entropy of the model, the compressed size of a block
+    /// of codes approaches this size plus a few bytes of overhead.
+    pub fn bits_per_row(&self) -> f64 {
+        let total = f64::from(1u32 << SCALE_BITS);
+        self.frequencies
+            .iter()
+            .map(|&freq| {
+                let p = f64::from(freq) / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Get the code lengths in bits.
+    ///
+    /// Returns a matrix with the shape *(n_columns, n_symbols)*, element
+    /// *(i, j)* is the number of bits needed to encode code *j* in
+    /// column *i*. Codes that did not occur in the fitted data are
+    /// smoothed to the smallest frequency, so every length is finite
+    /// and at most 16 bits.
+    pub fn code_lengths(&self) -> Array2<f64> {
+        let total = f64::from(1u32 << SCALE_BITS);
+        self.frequencies
+            .mapv(|freq| -(f64::from(freq.max(1)) / total).log2())
+    }
+
+    /// Write the model.
+    pub fn write_to(&self, mut write: impl Write) -> io::Result<()> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, self.n_columns() as u64);
+        write_varint(&mut buf, self.n_symbols as u64);
+        for &freq in &self.frequencies {
+            write_varint(&mut buf, u64::from(freq));
+        }
+        write.write_all(&buf)
+    }
+
+    /// Read a model.
+    ///
+    /// Returns an error with kind `InvalidData` if the model is
+    /// truncated or invalid. The frequencies are read before they are
+    /// stored, so a corrupt header cannot cause a large allocation.
+    pub fn read_from(mut read: impl Read) -> io::Result<Self> {
+        let mut read_usize = || -> io::Result<usize> {
+            let v = read_varint(&mut read)?.ok_or_else(|| invalid("Truncated entropy model"))?;
+            usize::try_from(v).map_err(|_| invalid(format!("Invalid entropy model value: {}", v)))
+        };
+
+        let n_columns = read_usize()?;
+        let n_symbols = read_usize()?;
+        if n_symbols == 0 || n_symbols > 1 << SCALE_BITS {
+            return Err(invalid(format!("Invalid number of symbols: {}", n_symbols)));
+        }
+        let n_frequencies = n_columns
+            .checked_mul(n_symbols)
+            .ok_or_else(|| invalid(format!("Invalid number of columns: {}", n_columns)))?;
+
+        // Every frequency takes at least one byte, so the frequencies
+        // vector only grows as far as the input goes.
+        let mut frequencies = Vec::with_capacity(n_frequencies.min(1 << SCALE_BITS));
+        for _ in 0..n_frequencies {
+            let freq = read_usize()?;
+            if freq > 1 << SCALE_BITS {
+                return Err(invalid("Invalid entropy model frequencies"));
+            }
+            frequencies.push(freq as u32);
+        }
+        let frequencies = Array2::from_shape_vec((n_columns, n_symbols), frequencies)
+            .expect("Incorrect frequencies shape");
+
+        let valid = frequencies.outer_iter().all(|frequencies| {
+            frequencies.iter().all(|&freq| freq > 0)
+                && frequencies.iter().map(|&freq| u64::from(freq)).sum::<u64>() == 1 << SCALE_BITS
+        });
+        if !valid {
+            return Err(invalid("Invalid entropy model frequencies"));
+        }
+
+        Ok(Self::from_frequencies(frequencies))
+    }
+
+    /// Construct an encoder that writes blocks to `write`.
+    pub fn encoder<W>(&self, write: W) -> EntropyEncoder<W>
+    where
+        W: Write,
+    {
+        EntropyEncoder { model: self, write }
+    }
+
+    /// Construct a decoder that reads blocks from `read`.
+    pub fn decoder<R>(&self, read: R) -> EntropyDecoder<R>
+    where
+        R: Read,
+    {
+        EntropyDecoder { model: self, read }
+    }
+}
+
+/// Streaming entropy encoder of code matrices.
+///
+/// Every call of `write_block` writes a self-delimiting block with the
+/// number of rows, the length of the compressed data, and the data. A
+/// stream can be compressed in constant memory by writing it in
+/// blocks; larger blocks amortize the overhead of the block headers.
+/// A block can contain at most 2^26 codes.
+pub struct EntropyEncoder<'a, W> {
+    model: &'a EntropyModel,
+    write: W,
+}
+
+impl<'a, W> EntropyEncoder<'a, W>
+where
+    W: Write,
+{
+    /// Compress and write a block of codes.
+    pub fn write_block<I, S>(&mut self, codes: ArrayBase<S, Ix2>) -> io::Result<()>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            codes.ncols(),
+            self.model.n_columns(),
+            "Number of code columns does not match the entropy model"
+        );
+        assert!(
+            codes.len() <= MAX_BLOCK_CODES,
+            "A block can contain at most {} codes, got: {}",
+            MAX_BLOCK_CODES,
+            codes.len()
+        );
+
+        // rANS is last-in first-out, so symbols are encoded in reverse
+        // and the output is reversed.
+        let mut state = STATE_LOWER;
+        let mut bytes = Vec::new();
+        for row in codes.outer_iter().rev() {
+            for (col, &code) in row.iter().enumerate().rev() {
+                let code = code.as_();
+                assert!(
+                    code < self.model.n_symbols,
+                    "Code {} is out of range, the model has {} symbols",
+                    code,
+                    self.model.n_symbols
+                );
+
+                let freq = self.model.frequencies[(col, code)];
+                let start = self.model.cumulative[(col, code)];
+                let state_max = ((STATE_LOWER >> SCALE_BITS) << 8) * freq;
+                while state >= state_max {
+                    bytes.push(state as u8);
+                    state >>= 8;
+                }
+                state = ((state / freq) << SCALE_BITS) + (state % freq) + start;
+            }
+        }
+        bytes.extend_from_slice(&state.to_be_bytes());
+        bytes.reverse();
+
+        let mut header = Vec::new();
+        write_varint(&mut header, codes.nrows() as u64);
+        write_varint(&mut header, bytes.len() as u64);
+        self.write.write_all(&header)?;
+        self.write.write_all(&bytes)
+    }
+
+    /// Get the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+}
+
+/// Streaming entropy decoder of code matrices.
+///
+/// See `EntropyEncoder`.
+pub struct EntropyDecoder<'a, R> {
+    model: &'a EntropyModel,
+    read: R,
+}
+
+impl<'a, R> EntropyDecoder<'a, R>
+where
+    R: Read,
+{
+    /// Read and decompress a block of codes.
+    ///
+    /// Returns `None` at the end of the stream. Returns an error with
+    /// kind `InvalidData` if the block is truncated, corrupt, or has
+    /// more codes than an encoder writes.
+    pub fn read_block(&mut self) -> io::Result<Option<Array2<u32>>> {
+        let n_rows = match read_varint(&mut self.read)? {
+            Some(n_rows) => n_rows,
+            None => return Ok(None),
+        };
+
+        let n_columns = self.model.n_columns();
+        let n_rows = usize::try_from(n_rows)
+            .ok()
+            .filter(|&n_rows| {
+                n_rows
+                    .checked_mul(n_columns)
+                    .map(|n_codes| n_codes <= MAX_BLOCK_CODES)
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| invalid(format!("Block has too many rows: {}", n_rows)))?;
+        let n_codes = n_rows * n_columns;
+
+        let len = read_varint(&mut self.read)?.ok_or_else(|| invalid("Truncated block"))?;
+
+        let mut bytes = Vec::new();
+        self.read.by_ref().take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len || bytes.len() < 4 {
+            return Err(invalid("Truncated block"));
+        }
+
+        let mut codes = Vec::with_capacity(n_codes.min(bytes.len() * 8));
+
+        let mut state = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let mut bytes = bytes[4..].iter();
+        let mask = (1 << SCALE_BITS) - 1;
+        for idx in 0..n_codes {
+            let col = idx % n_columns;
+            let cumulative = self.model.cumulative.row(col);
+            let slot = state & mask;
+            let code = cumulative
+                .as_slice()
+                .unwrap()
+                .partition_point(|&start| start <= slot)
+                - 1;
+
+            let freq = self.model.frequencies[(col, code)];
+            state = freq * (state >> SCALE_BITS) + slot - cumulative[code];
+            while state < STATE_LOWER {
+                let byte = bytes.next().ok_or_else(|| invalid("Truncated block"))?;
+                state = (state << 8) | u32::from(*byte);
+            }
+
+            codes.push(code as u32);
+        }
+
+        if state != STATE_LOWER || bytes.next().is_some() {
+            return Err(invalid("Corrupt entropy-coded block"));
+        }
+
+        Ok(Some(
+            Array2::from_shape_vec((n_rows, n_columns), codes).expect("Incorrect block shape"),
+        ))
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// Normalize counts to frequencies that sum to 2^SCALE_BITS.
+///
+/// Every frequency is at least one.
+fn normalize_counts(counts: &[u64]) -> Vec<u32> {
+    let total = 1u64 << SCALE_BITS;
+    let sum = counts.iter().sum::<u64>();
+    let mut frequencies = counts
+        .iter()
+        .map(|&count| ((count * total / sum) as u32).max(1))
+        .collect::<Vec<_>>();
+
+    // Distribute the rounding error, starting at the most frequent
+    // symbols.
+    let mut order = (0..counts.len()).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|&idx| std::cmp::Reverse(frequencies[idx]));
+    let mut diff = total as i64 - frequencies.iter().map(|&freq| i64::from(freq)).sum::<i64>();
+    while diff != 0 {
+        for &idx in &order {
+            if diff > 0 {
+                frequencies[idx] += 1;
+                diff -= 1;
+            } else if diff < 0 && frequencies[idx] > 1 {
+                frequencies[idx] -= 1;
+                diff += 1;
+            }
+
+            if diff == 0 {
+                break;
+            }
+        }
+    }
+
+    frequencies
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use ndarray::{array, Array2};
+    use rand::distributions::{Distribution, WeightedIndex};
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{normalize_counts, write_varint, EntropyModel};
+
+    fn skewed_codes(n_rows: usize) -> Array2<u8> {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let weights = (0..256)
+            .map(|code| (1. + code as f64).powi(-2))
+            .collect::<Vec<_>>();
+        let dist = WeightedIndex::new(&weights).unwrap();
+        Array2::from_shape_fn((n_rows, 8), |_| dist.sample(&mut rng) as u8)
+    }
+
+    #[test]
+    fn normalized_counts_sum_to_scale() {
+        let frequencies = normalize_counts(&[1, 1, 1000, 3]);
+        assert_eq!(frequencies.iter().sum::<u32>(), 1 << 16);
+        assert!(frequencies.iter().all(|&freq| freq > 0));
+
+        let frequencies = normalize_counts(&vec![1; 1 << 16]);
+        assert!(frequencies.iter().all(|&freq| freq == 1));
+    }
+
+    #[test]
+    fn compress_and_decompress_blocks() {
+        let codes = skewed_codes(4096);
+        let model = EntropyModel::fit(codes.view(), 256);
+        assert!(model.bits_per_row() < 8. * 8.);
+
+        let mut encoder = model.encoder(Vec::new());
+        encoder.write_block(codes.view()).unwrap();
+        encoder
+            .write_block(array![[255u8, 254, 0, 0, 0, 0, 0, 255]])
+            .unwrap();
+        encoder.write_block(Array2::<u8>::zeros((0, 8))).unwrap();
+        let compressed = encoder.into_inner();
+
+        // Skewed codes compress beyond one byte per code.
+        assert!(compressed.len() < codes.len() * 3 / 4);
+        assert!(compressed.len() as f64 <= model.bits_per_row() * 4096. / 8. + 64.);
+
+        let mut decoder = model.decoder(compressed.as_slice());
+        assert_eq!(
+            decoder.read_block().unwrap().unwrap(),
+            codes.mapv(u32::from)
+        );
+        assert_eq!(
+            decoder.read_block().unwrap().unwrap(),
+            array![[255, 254, 0, 0, 0, 0, 0, 255]]
+        );
+        assert_eq!(decoder.read_block().unwrap().unwrap().dim(), (0, 8));
+        assert!(decoder.read_block().unwrap().is_none());
+
+        let mut truncated = model.decoder(&compressed[..100]);
+        assert_eq!(
+            truncated.read_block().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn code_lengths_are_finite() {
+        // Only codes 0 and 1 occur.
+        let codes = Array2::from_shape_fn((1000, 2), |(row, _)| (row % 2) as u8);
+        let lengths = EntropyModel::fit(codes.view(), 256).code_lengths();
+        assert_eq!(lengths.dim(), (2, 256));
+        assert!(lengths.iter().all(|&bits| bits.is_finite() && bits <= 16.));
+        assert!(lengths[(0, 0)] < lengths[(0, 2)]);
+    }
+
+    #[test]
+    fn model_round_trip() {
+        let model = EntropyModel::fit(skewed_codes(128).view(), 256);
+        let mut buf = Vec::new();
+        model.write_to(&mut buf).unwrap();
+        assert_eq!(EntropyModel::read_from(buf.as_slice()).unwrap(), model);
+
+        assert!(EntropyModel::read_from(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn model_rejects_hostile_headers() {
+        let read_err = |header: &[u64]| {
+            let mut buf = Vec::new();
+            for &v in header {
+                write_varint(&mut buf, v);
+            }
+            EntropyModel::read_from(buf.as_slice()).unwrap_err().kind()
+        };
+
+        // Truncated header.
+        assert_eq!(read_err(&[4]), ErrorKind::InvalidData);
+
+        // Huge number of columns without the frequencies.
+        assert_eq!(read_err(&[1 << 40, 256, 1]), ErrorKind::InvalidData);
+
+        // The number of frequencies overflows.
+        assert_eq!(read_err(&[u64::MAX, 256]), ErrorKind::InvalidData);
+
+        // Frequency that does not fit the probability scale.
+        assert_eq!(read_err(&[1, 2, 1 << 32, 1 << 32]), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decoder_rejects_hostile_block_headers() {
+        // A code of a single-symbol model has probability one and is
+        // decoded without consuming input.
+        let model = EntropyModel::fit(Array2::<u8>::zeros((10, 2)), 1);
+
+        let block = |n_rows: u64| {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n_rows);
+            write_varint(&mut buf, 4);
+            buf.extend_from_slice(&(1u32 << 23).to_le_bytes());
+            buf
+        };
+
+        let mut encoder = model.encoder(Vec::new());
+        encoder.write_block(Array2::<u8>::zeros((1000, 2))).unwrap();
+        assert_eq!(encoder.into_inner(), block(1000));
+        assert_eq!(
+            model.decoder(block(1000).as_slice()).read_block().unwrap(),
+            Some(Array2::zeros((1000, 2)))
+        );
+
+        for &n_rows in &[1 << 40, u64::MAX] {
+            assert_eq!(
+                model
+                    .decoder(block(n_rows).as_slice())
+                    .read_block()
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidData
+            );
+        }
+
+        // Truncated block header.
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 1000);
+        assert_eq!(
+            model
+                .decoder(buf.as_slice())
+                .read_block()
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
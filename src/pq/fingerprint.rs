@@ -0,0 +1,225 @@
+use std::fmt;
+use std::mem;
+
+use ndarray::{ArrayView2, ArrayView3, NdFloat};
+
+use super::{Normalization, PQView, PQ};
+use crate::error::Error;
+
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Fingerprint of a quantizer.
+///
+/// The fingerprint is a 128-bit FNV-1a hash of the canonicalized
+/// codebooks, projection matrix, and input normalization. It identifies
+/// a model, so that a code store can verify that its codes were
+/// produced by the quantizer that will decode them. The fingerprint does
+/// not depend on the memory layout of the arrays, the training manifest,
+/// the metadata, the platform, or the version of this crate. It is not
+/// a cryptographic hash.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Fingerprint(u128);
+
+impl Fingerprint {
+    /// Construct a fingerprint from its big-endian bytes.
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Fingerprint(u128::from_be_bytes(bytes))
+    }
+
+    /// Get the big-endian bytes of the fingerprint.
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+struct FingerprintHasher(u128);
+
+impl FingerprintHasher {
+    fn new() -> Self {
+        FingerprintHasher(FNV_OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u128::from(byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_u64(&mut self, v: u64) {
+        self.write(&v.to_le_bytes());
+    }
+
+    fn write_shape(&mut self, shape: &[usize]) {
+        self.write_u64(shape.len() as u64);
+        for &len in shape {
+            self.write_u64(len as u64);
+        }
+    }
+
+    /// Write values in logical (row-major) order.
+    fn write_values<'a, A>(&mut self, values: impl IntoIterator<Item = &'a A>)
+    where
+        A: 'a + NdFloat,
+    {
+        for &v in values {
+            // Normalize negative zero, which compares equal to zero.
+            let v = if v == A::zero() {
+                0f64
+            } else {
+                v.to_f64().unwrap()
+            };
+            self.write_u64(v.to_bits());
+        }
+    }
+
+    fn finish(self) -> Fingerprint {
+        Fingerprint(self.0)
+    }
+}
+
+fn fingerprint<A>(
+    projection: Option<ArrayView2<A>>,
+    quantizers: ArrayView3<A>,
+    normalization: &Normalization<A>,
+) -> Fingerprint
+where
+    A: NdFloat,
+{
+    let mut hasher = FingerprintHasher::new();
+    hasher.write(b"reductive-pq");
+    hasher.write_u64(mem::size_of::<A>() as u64);
+
+    hasher.write_shape(quantizers.shape());
+    hasher.write_values(quantizers.iter());
+
+    match projection {
+        Some(projection) => {
+            hasher.write(&[1]);
+            hasher.write_shape(projection.shape());
+            hasher.write_values(projection.iter());
+        }
+        None => hasher.write(&[0]),
+    }
+
+    // Quantizers without normalization keep the fingerprints from
+    // before normalization was stored in quantizers.
+    match normalization {
+        Normalization::None => (),
+        Normalization::L2 => hasher.write(&[1]),
+        Normalization::MeanCentering(mean) => {
+            hasher.write(&[2]);
+            hasher.write_shape(mean.shape());
+            hasher.write_values(mean.iter());
+        }
+    }
+
+    hasher.finish()
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat,
+{
+    /// Get the fingerprint of the quantizer.
+    ///
+    /// See `Fingerprint`.
+    pub fn fingerprint(&self) -> Fingerprint {
+        fingerprint(self.projection, self.quantizers, self.normalization())
+    }
+
+    /// Verify that the quantizer has the given fingerprint.
+    pub fn verify_fingerprint(&self, expected: Fingerprint) -> Result<(), Error> {
+        let actual = self.fingerprint();
+        if actual != expected {
+            return Err(Error::FingerprintMismatch { expected, actual });
+        }
+
+        Ok(())
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat,
+{
+    /// Get the fingerprint of the quantizer.
+    ///
+    /// See `Fingerprint`.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.view().fingerprint()
+    }
+
+    /// Verify that the quantizer has the given fingerprint.
+    pub fn verify_fingerprint(&self, expected: Fingerprint) -> Result<(), Error> {
+        self.view().verify_fingerprint(expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array1, Array2, Array3, ShapeBuilder};
+
+    use super::Fingerprint;
+    use crate::error::Error;
+    use crate::pq::{test_pq, Normalization, PQ};
+
+    #[test]
+    fn fingerprint_identifies_model() {
+        let pq = test_pq();
+        let fingerprint = pq.fingerprint();
+        assert_eq!(fingerprint, pq.clone().fingerprint());
+        assert_eq!(fingerprint, pq.view().fingerprint());
+        assert_eq!(pq.verify_fingerprint(fingerprint), Ok(()));
+        assert_eq!(Fingerprint::from_bytes(fingerprint.to_bytes()), fingerprint);
+        assert_eq!(fingerprint.to_string().len(), 32);
+
+        let mut quantizers = pq.subquantizers().to_owned();
+        quantizers[[1, 0, 1]] = -0.999;
+        let other = PQ::new(None, quantizers);
+        assert_ne!(other.fingerprint(), fingerprint);
+        assert_eq!(
+            other.verify_fingerprint(fingerprint),
+            Err(Error::FingerprintMismatch {
+                expected: fingerprint,
+                actual: other.fingerprint()
+            })
+        );
+
+        let projected = PQ::new(Some(Array2::eye(6)), pq.subquantizers().to_owned());
+        assert_ne!(projected.fingerprint(), fingerprint);
+
+        let l2 = pq.clone().with_normalization(Normalization::L2);
+        let centered = pq
+            .clone()
+            .with_normalization(Normalization::MeanCentering(Array1::zeros(6)));
+        assert_ne!(l2.fingerprint(), fingerprint);
+        assert_ne!(centered.fingerprint(), fingerprint);
+        assert_ne!(centered.fingerprint(), l2.fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_is_canonical() {
+        let pq = test_pq();
+
+        // Column-major storage of the same codebooks.
+        let mut quantizers = Array3::zeros((2, 2, 3).f());
+        quantizers.assign(&pq.subquantizers());
+        assert_eq!(PQ::new(None, quantizers).fingerprint(), pq.fingerprint());
+
+        // Negative zero.
+        let quantizers = pq.subquantizers().mapv(|v| if v == 0. { -0. } else { v });
+        assert_eq!(PQ::new(None, quantizers).fingerprint(), pq.fingerprint());
+
+        // The element type is part of the model.
+        let quantizers = pq.subquantizers().mapv(f64::from);
+        assert_ne!(PQ::new(None, quantizers).fingerprint(), pq.fingerprint());
+    }
+}
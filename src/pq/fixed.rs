@@ -0,0 +1,399 @@
+use std::iter::Sum;
+
+use ndarray::{
+    s, Array1, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, Axis, Data, Ix1,
+    NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use super::{Normalization, QuantizeVector, ReconstructVector, PQ};
+use crate::float_ord::min_by_float_key;
+use crate::simd;
+
+/// Product quantizer with 8 subquantizers.
+pub type PQ8<A> = FixedPQ<A, 8>;
+
+/// Product quantizer with 16 subquantizers.
+pub type PQ16<A> = FixedPQ<A, 16>;
+
+/// Product quantizer with a fixed number of subquantizers.
+///
+/// This quantizer wraps a `PQ` with `N` subquantizers. Since the number
+/// of subquantizers is known at compile time, quantization codes are
+/// stored in arrays of type `[I; N]` and the loops over subquantizers
+/// can be unrolled by the compiler. This is useful in latency-critical
+/// code paths that quantize, reconstruct, or compute distances to
+/// single vectors.
+///
+/// Rotation, quantization of rotated vectors, reconstruction into an
+/// existing vector, and asymmetric distance computation do not
+/// allocate. A query or vector can be normalized and rotated into a
+/// reusable buffer with `rotate_vector_into`, to quantize it with
+/// `quantize_rotated_vector` or to compute its distance table with
+/// `adc_table_into`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedPQ<A, const N: usize> {
+    pq: PQ<A>,
+}
+
+impl<A, const N: usize> FixedPQ<A, N>
+where
+    A: NdFloat + Sum,
+{
+    /// Wrap a product quantizer.
+    ///
+    /// Panics if the product quantizer does not have `N` subquantizers.
+    pub fn new(pq: PQ<A>) -> Self {
+        assert_eq!(
+            pq.quantized_len(),
+            N,
+            "Expected a product quantizer with {} subquantizers, got {}",
+            N,
+            pq.quantized_len()
+        );
+
+        FixedPQ { pq }
+    }
+
+    /// Get the wrapped product quantizer.
+    pub fn as_pq(&self) -> &PQ<A> {
+        &self.pq
+    }
+
+    /// Unwrap the product quantizer.
+    pub fn into_inner(self) -> PQ<A> {
+        self.pq
+    }
+
+    /// Normalize and rotate a vector into an existing vector.
+    ///
+    /// This is the allocation-free counterpart of `PQ::rotate_vector`.
+    pub fn rotate_vector_into<S>(&self, x: ArrayBase<S, Ix1>, mut rotated: ArrayViewMut1<A>)
+    where
+        S: Data<Elem = A>,
+    {
+        let n_dims = self.pq.reconstructed_len();
+        assert_eq!(x.len(), n_dims, "Quantizer and vector length mismatch");
+        assert_eq!(rotated.len(), n_dims, "Rotated vector has incorrect length");
+
+        let mean = self.pq.normalization.mean();
+        let scale = match self.pq.normalization {
+            Normalization::L2 => {
+                let norm = x.dot(&x).sqrt();
+                if norm > A::zero() {
+                    norm.recip()
+                } else {
+                    A::one()
+                }
+            }
+            _ => A::one(),
+        };
+        let normalized = |idx: usize, v: A| match mean {
+            Some(mean) => v - mean[idx],
+            None => v * scale,
+        };
+
+        match self.pq.projection {
+            Some(ref projection) => {
+                for (rotated, direction) in rotated.iter_mut().zip(projection.axis_iter(Axis(1))) {
+                    *rotated = x
+                        .iter()
+                        .zip(direction.iter())
+                        .enumerate()
+                        .fold(A::zero(), |acc, (idx, (&v, &p))| {
+                            acc + normalized(idx, v) * p
+                        });
+                }
+            }
+            None => {
+                for (idx, (rotated, &v)) in rotated.iter_mut().zip(x.iter()).enumerate() {
+                    *rotated = normalized(idx, v);
+                }
+            }
+        }
+    }
+
+    /// Quantize a vector.
+    ///
+    /// This method only allocates when the quantizer has a projection
+    /// or normalizes its inputs. Use `rotate_vector_into` and
+    /// `quantize_rotated_vector` to reuse a buffer in that case.
+    pub fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> [I; N]
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        if self.pq.projection.is_none() && matches!(self.pq.normalization, Normalization::None) {
+            return self.quantize_rotated_vector(x);
+        }
+
+        let mut rotated = Array1::zeros(self.pq.reconstructed_len());
+        self.rotate_vector_into(x, rotated.view_mut());
+        self.quantize_rotated_vector(rotated)
+    }
+
+    /// Quantize a vector that was rotated with `rotate_vector_into`.
+    pub fn quantize_rotated_vector<I, S>(&self, rotated: ArrayBase<S, Ix1>) -> [I; N]
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert!(
+            self.pq.n_quantizer_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+        assert_eq!(
+            self.pq.reconstructed_len(),
+            rotated.len(),
+            "Quantizer and vector length mismatch"
+        );
+
+        let sq_dims = self.pq.quantizers.len_of(Axis(2));
+        let mut codes = [I::zero(); N];
+        for (sq, code) in codes.iter_mut().enumerate() {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_vec = rotated.slice(s![offset..offset + sq_dims]);
+            *code = nearest_centroid(self.pq.quantizers.index_axis(Axis(0), sq), sub_vec).as_();
+        }
+
+        codes
+    }
+
+    /// Compute the asymmetric distance table of a rotated query.
+    ///
+    /// `rotated_query` is a query that was rotated with
+    /// `rotate_vector_into`. The table is written to `table`, which
+    /// must have the shape *(N, n_centroids)*. See
+    /// `PQView::adc_tables_batch` for a description of the table.
+    pub fn adc_table_into<S>(&self, rotated_query: ArrayBase<S, Ix1>, mut table: ArrayViewMut2<A>)
+    where
+        S: Data<Elem = A>,
+    {
+        assert_eq!(
+            self.pq.reconstructed_len(),
+            rotated_query.len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            table.dim(),
+            (N, self.pq.n_quantizer_centroids()),
+            "Table has incorrect shape"
+        );
+
+        let sq_dims = self.pq.quantizers.len_of(Axis(2));
+        for (sq, mut sq_table) in table.outer_iter_mut().enumerate() {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_query = rotated_query.slice(s![offset..offset + sq_dims]);
+            for (distance, centroid) in sq_table
+                .iter_mut()
+                .zip(self.pq.quantizers.index_axis(Axis(0), sq).outer_iter())
+            {
+                *distance = squared_distance(centroid, sub_query.view());
+            }
+        }
+    }
+
+    /// Compute the asymmetric distance of a quantized vector.
+    ///
+    /// Returns the approximate squared Euclidean distance between the
+    /// query of the distance `table` (see `adc_table_into`) and the
+    /// vector quantized as `codes`.
+    pub fn adc_distance<I>(&self, table: ArrayView2<A>, codes: &[I; N]) -> A
+    where
+        I: AsPrimitive<usize>,
+    {
+        assert_eq!(table.nrows(), N, "Table has incorrect number of rows");
+
+        let mut distance = A::zero();
+        for (sq, code) in codes.iter().enumerate() {
+            distance += table[(sq, code.as_())];
+        }
+
+        distance
+    }
+
+    /// Reconstruct a vector into an existing vector.
+    pub fn reconstruct_vector_into<I>(&self, codes: &[I; N], mut reconstruction: ArrayViewMut1<A>)
+    where
+        I: AsPrimitive<usize>,
+    {
+        assert_eq!(
+            reconstruction.len(),
+            self.pq.reconstructed_len(),
+            "Reconstruction has incorrect length"
+        );
+
+        let sq_dims = self.pq.quantizers.len_of(Axis(2));
+        match self.pq.projection {
+            // Project back without an intermediate vector, element i of
+            // the reconstruction is the dot product of row i of the
+            // projection and the concatenated centroids.
+            Some(ref projection) => {
+                for (reconstruction, direction) in
+                    reconstruction.iter_mut().zip(projection.outer_iter())
+                {
+                    let mut sum = A::zero();
+                    for (sq, code) in codes.iter().enumerate() {
+                        let offset = sq * sq_dims;
+                        let centroid = self.pq.quantizers.slice(s![sq, code.as_(), ..]);
+                        // ndarray#474
+                        #[allow(clippy::deref_addrof)]
+                        let sub_direction = direction.slice(s![offset..offset + sq_dims]);
+                        sum += centroid.dot(&sub_direction);
+                    }
+                    *reconstruction = sum;
+                }
+            }
+            None => {
+                for (sq, code) in codes.iter().enumerate() {
+                    let offset = sq * sq_dims;
+                    reconstruction
+                        .slice_mut(s![offset..offset + sq_dims])
+                        .assign(&self.pq.quantizers.slice(s![sq, code.as_(), ..]));
+                }
+            }
+        }
+    }
+
+    /// Reconstruct a vector.
+    pub fn reconstruct_vector<I>(&self, codes: &[I; N]) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+    {
+        let mut reconstruction = Array1::zeros(self.pq.reconstructed_len());
+        self.reconstruct_vector_into(codes, reconstruction.view_mut());
+        reconstruction
+    }
+}
+
+/// Find the nearest centroid without allocating.
+fn nearest_centroid<A, S>(centroids: ArrayView2<A>, x: ArrayBase<S, Ix1>) -> usize
+where
+    A: NdFloat,
+    S: Data<Elem = A>,
+{
+    min_by_float_key(
+        centroids
+            .outer_iter()
+            .map(|centroid| squared_distance(centroid, x.view()))
+            .enumerate(),
+        |v| v.1,
+    )
+    .expect("Cannot find the nearest centroid without centroids")
+    .0
+}
+
+fn squared_distance<A>(a: ArrayView1<A>, b: ArrayView1<A>) -> A
+where
+    A: NdFloat,
+{
+    // Use the SIMD kernel for contiguous f32 data.
+    if let (Some(a), Some(b)) = (
+        a.as_slice().and_then(simd::as_f32),
+        b.as_slice().and_then(simd::as_f32),
+    ) {
+        return A::from(simd::squared_euclidean_distance(a, b)).unwrap();
+    }
+
+    a.iter().zip(b.iter()).fold(A::zero(), |acc, (&a, &b)| {
+        let diff = a - b;
+        acc + diff * diff
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use ndarray::{Array1, Array2, Array3, Axis};
+    use rand::distributions::Uniform;
+
+    use super::{FixedPQ, PQ8};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{Normalization, QuantizeVector, ReconstructVector, PQ};
+
+    fn random_pq(n_subquantizers: usize) -> PQ<f32> {
+        let uniform = Uniform::new(0f32, 1f32);
+        PQ::new(None, Array3::random((n_subquantizers, 16, 2), uniform))
+    }
+
+    /// Product quantizers with and without projection and normalization.
+    fn random_pqs() -> Vec<PQ<f32>> {
+        let uniform = Uniform::new(0f32, 1f32);
+        let pq = random_pq(8);
+        let projected = PQ::new(
+            Some(Array2::random((16, 16), uniform)),
+            pq.subquantizers().to_owned(),
+        );
+        let centered = projected
+            .clone()
+            .with_normalization(Normalization::MeanCentering(Array1::random(16, uniform)));
+        let l2 = pq.clone().with_normalization(Normalization::L2);
+
+        vec![pq, projected, centered, l2]
+    }
+
+    #[test]
+    fn fixed_pq_matches_pq() {
+        let uniform = Uniform::new(0f32, 1f32);
+        for pq in random_pqs() {
+            let fixed = PQ8::new(pq.clone());
+
+            for instance in Array2::random((10, 16), uniform).outer_iter() {
+                let codes: [u8; 8] = fixed.quantize_vector(instance);
+                assert_eq!(
+                    Array1::from(codes.to_vec()),
+                    pq.quantize_vector::<u8, _>(instance)
+                );
+                assert_abs_diff_eq!(
+                    fixed.reconstruct_vector(&codes),
+                    pq.reconstruct_vector(Array1::from(codes.to_vec())),
+                    epsilon = 1e-5
+                );
+
+                let mut rotated = Array1::zeros(16);
+                fixed.rotate_vector_into(instance, rotated.view_mut());
+                assert_abs_diff_eq!(rotated, pq.rotate_vector(instance), epsilon = 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_pq_adc_matches_pq() {
+        let uniform = Uniform::new(0f32, 1f32);
+        for pq in random_pqs() {
+            let fixed = PQ8::new(pq.clone());
+            let query = Array1::random(16, uniform);
+            let codes = pq.quantize_batch::<u8, _>(Array2::random((10, 16), uniform));
+
+            let mut rotated = Array1::zeros(16);
+            fixed.rotate_vector_into(query.view(), rotated.view_mut());
+            let mut table = Array2::zeros((8, 16));
+            fixed.adc_table_into(rotated.view(), table.view_mut());
+            let expected = pq.adc_tables_batch(query.insert_axis(Axis(0)));
+            assert_abs_diff_eq!(table, expected.index_axis(Axis(0), 0), epsilon = 1e-4);
+
+            for row in codes.outer_iter() {
+                let mut row_codes = [0u8; 8];
+                row_codes.copy_from_slice(row.as_slice().unwrap());
+                let expected = row
+                    .iter()
+                    .enumerate()
+                    .map(|(sq, &code)| table[(sq, code as usize)])
+                    .sum::<f32>();
+                assert_eq!(fixed.adc_distance(table.view(), &row_codes), expected);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn fixed_pq_rejects_incorrect_subquantizers() {
+        FixedPQ::<f32, 16>::new(random_pq(8));
+    }
+}
@@ -1,12 +1,15 @@
+use std::collections::BTreeMap;
 use std::iter::Sum;
+use std::time::Instant;
 
 use lax::Lapack;
-use ndarray::{ArrayBase, Data, Ix2, NdFloat};
+use ndarray::{Array2, ArrayBase, ArrayView1, ArrayView2, Data, Ix1, Ix2, NdFloat};
 use ndarray_linalg::types::Scalar;
-use num_traits::AsPrimitive;
-use rand::{RngCore, SeedableRng};
+use num_traits::{AsPrimitive, ToPrimitive};
+use rand::RngCore;
 
-use super::{TrainPQ, OPQ, PQ};
+use super::primitives;
+use super::{Normalization, TrainPQ, TrainWeightedPQ, TrainingManifest, OPQ, PQ};
 
 /// Optimized product quantizer for Gaussian variables (Ge et al., 2013).
 ///
@@ -39,8 +42,74 @@ where
     ) -> PQ<A>
     where
         S: Sync + Data<Elem = A>,
-        R: RngCore + SeedableRng + Send,
+        R: RngCore,
     {
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+            None,
+            rng,
+        )
+    }
+}
+
+impl<A> TrainWeightedPQ<A> for GaussianOPQ
+where
+    A: Lapack + NdFloat + Scalar + Sum,
+    A::Real: NdFloat,
+    usize: AsPrimitive<A>,
+{
+    /// Train a product quantizer with instance weights.
+    ///
+    /// The projection is computed from the unweighted covariance
+    /// matrix, the subquantizers are trained with weighted k-means.
+    fn train_pq_weighted_using<S, W, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        weights: ArrayBase<W, Ix1>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        W: Data<Elem = A>,
+        R: RngCore,
+    {
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+            Some(weights.view()),
+            rng,
+        )
+    }
+}
+
+impl GaussianOPQ {
+    fn train<A, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayView2<A>,
+        weights: Option<ArrayView1<A>>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        let start = Instant::now();
+
         PQ::check_quantizer_invariants(
             n_subquantizers,
             n_subquantizer_bits,
@@ -51,31 +120,81 @@ where
 
         let projection = OPQ::create_projection_matrix(instances.view(), n_subquantizers);
         let rx = instances.dot(&projection);
-        let pq = PQ::train_pq_using(
+        let pq = match weights {
+            Some(weights) => PQ::train_pq_weighted_using(
+                n_subquantizers,
+                n_subquantizer_bits,
+                n_iterations,
+                n_attempts,
+                rx.view(),
+                weights,
+                rng,
+            ),
+            None => PQ::train_pq_using(
+                n_subquantizers,
+                n_subquantizer_bits,
+                n_iterations,
+                n_attempts,
+                rx.view(),
+                rng,
+            ),
+        };
+
+        // Report the objective of the final quantizer, to allow for
+        // comparisons with OPQ.
+        let quantized = primitives::quantize_batch::<_, usize, _>(pq.quantizers.view(), rx.view());
+        let mut reconstructed = Array2::zeros(rx.dim());
+        primitives::reconstruct_batch_into(
+            pq.quantizers.view(),
+            quantized,
+            reconstructed.view_mut(),
+        );
+        let objective = (&rx - &reconstructed).iter().map(|&v| v * v).sum::<A>() / rx.len().as_();
+
+        let settings = pq
+            .manifest
+            .map(|manifest| manifest.settings)
+            .unwrap_or_default();
+        let name = if weights.is_some() {
+            "WeightedGaussianOPQ"
+        } else {
+            "GaussianOPQ"
+        };
+        let mut manifest = TrainingManifest::new(
+            name,
             n_subquantizers,
             n_subquantizer_bits,
             n_iterations,
             n_attempts,
-            rx,
-            rng,
-        );
+            instances.dim(),
+            start,
+        )
+        .with_rng::<R>()
+        .with_settings(settings);
+        manifest.objective = vec![ToPrimitive::to_f64(&objective).unwrap()];
 
         PQ {
             projection: Some(projection),
             quantizers: pq.quantizers,
+            manifest: Some(manifest),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use ndarray::{Array2, ArrayView2};
+    use ndarray::{Array1, Array2, ArrayView2};
     use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
 
     use super::GaussianOPQ;
     use crate::linalg::EuclideanDistance;
     use crate::ndarray_rand::RandomExt;
-    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, TrainWeightedPQ, PQ};
 
     /// Calculate the average euclidean distances between the the given
     /// instances and the instances returned by quantizing and then
@@ -102,4 +221,21 @@ mod tests {
         // Loss is around 0.1.
         assert!(loss < 0.12);
     }
+    #[test]
+    fn quantize_with_weighted_gaussian_opq() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((256, 20), uniform);
+        let weights = Array1::from_elem(256, 1f32);
+        let pq = GaussianOPQ::train_pq_weighted_using(
+            10,
+            7,
+            10,
+            1,
+            instances.view(),
+            weights.view(),
+            XorShiftRng::seed_from_u64(42),
+        );
+        assert_eq!(pq.manifest().unwrap().quantizer, "WeightedGaussianOPQ");
+        assert!(pq.projection().is_some());
+    }
 }
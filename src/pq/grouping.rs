@@ -0,0 +1,278 @@
+use std::iter::Sum;
+use std::mem;
+
+use ndarray::{Array2, ArrayBase, ArrayView2, Axis, Data, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+use rand::RngCore;
+
+use super::{TrainPQ, PQ};
+use crate::float_ord::max_by_float_key;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Partition the dimensions into groups of correlated dimensions.
+    ///
+    /// Product quantization quantizes the slices of a vector
+    /// independently, so it cannot exploit correlations between
+    /// dimensions that end up in different subquantizers. This function
+    /// partitions the dimensions of `instances` into `n_groups` groups
+    /// of equal size, such that strongly correlated dimensions are in
+    /// the same group.
+    ///
+    /// The partition is computed by recursive bisection of the absolute
+    /// correlation matrix. Every bisection starts from the least
+    /// correlated pair of dimensions, greedily assigns the other
+    /// dimensions to the half they are most correlated with, and then
+    /// swaps dimensions between the halves while this increases the
+    /// correlation within the halves.
+    pub fn correlated_dimension_groups<S>(
+        instances: ArrayBase<S, Ix2>,
+        n_groups: usize,
+    ) -> Vec<Vec<usize>>
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            n_groups > 0 && instances.ncols() % n_groups == 0,
+            "The number of dimensions ({}) should be a multiple of the number of groups ({})",
+            instances.ncols(),
+            n_groups
+        );
+        assert!(
+            instances.nrows() > 1,
+            "At least two instances are required to compute correlations"
+        );
+
+        let correlations = abs_correlations(instances);
+        let mut groups = Vec::with_capacity(n_groups);
+        bisect(
+            correlations.view(),
+            (0..correlations.nrows()).collect(),
+            n_groups,
+            &mut groups,
+        );
+
+        groups
+    }
+
+    /// Train a product quantizer on groups of correlated dimensions.
+    ///
+    /// The dimensions are partitioned with
+    /// `PQ::correlated_dimension_groups` and a product quantizer is
+    /// trained with one subquantizer per group. The projection of the
+    /// quantizer is the permutation matrix that moves the dimensions of
+    /// each group next to each other. Unlike the rotation of an
+    /// optimized product quantizer, the permutation can also be applied
+    /// to stored vectors once, after which the subquantizers can be used
+    /// without projection.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the arguments.
+    pub fn train_pq_grouped_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        let permutation = Self::correlated_dimension_groups(instances.view(), n_subquantizers)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        let mut pq = PQ::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.select(Axis(1), &permutation),
+            rng,
+        );
+
+        let mut projection = Array2::zeros((permutation.len(), permutation.len()));
+        for (idx, &dim) in permutation.iter().enumerate() {
+            projection[(dim, idx)] = A::one();
+        }
+        pq.projection = Some(projection);
+        if let Some(manifest) = pq.manifest.as_mut() {
+            manifest.quantizer = "GroupedPQ";
+        }
+
+        pq
+    }
+}
+
+/// Compute the absolute correlations between the dimensions.
+///
+/// The diagonal and the correlations of constant dimensions are zero.
+fn abs_correlations<A, S>(instances: ArrayBase<S, Ix2>) -> Array2<A>
+where
+    A: NdFloat,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let means = instances.sum_axis(Axis(0)) / instances.nrows().as_();
+    let centered = &instances - &means;
+    let covariances = centered.t().dot(&centered);
+    let stddevs = covariances.diag().mapv(A::sqrt);
+
+    let mut correlations = Array2::zeros(covariances.dim());
+    for ((i, j), correlation) in correlations.indexed_iter_mut() {
+        let norm = stddevs[i] * stddevs[j];
+        if i != j && norm > A::zero() {
+            *correlation = (covariances[(i, j)] / norm).abs();
+        }
+    }
+
+    correlations
+}
+
+/// Recursively bisect `dims` into `n_groups` groups.
+fn bisect<A>(
+    correlations: ArrayView2<A>,
+    mut dims: Vec<usize>,
+    n_groups: usize,
+    groups: &mut Vec<Vec<usize>>,
+) where
+    A: NdFloat + Sum,
+{
+    if n_groups == 1 {
+        dims.sort_unstable();
+        groups.push(dims);
+        return;
+    }
+
+    let left_groups = n_groups / 2;
+    let left_len = dims.len() / n_groups * left_groups;
+    let right_len = dims.len() - left_len;
+
+    // Start from the least correlated pair of dimensions.
+    let mut seeds = (dims[0], dims[1]);
+    for (idx, &i) in dims.iter().enumerate() {
+        for &j in &dims[idx + 1..] {
+            if correlations[(i, j)] < correlations[seeds] {
+                seeds = (i, j);
+            }
+        }
+    }
+
+    let mut left = vec![seeds.0];
+    let mut right = vec![seeds.1];
+    let mut remaining = dims
+        .iter()
+        .cloned()
+        .filter(|&dim| dim != seeds.0 && dim != seeds.1)
+        .collect::<Vec<_>>();
+
+    // Greedily assign the dimension with the strongest preference.
+    let mean_correlation = |dim: usize, side: &[usize]| {
+        side.iter()
+            .map(|&other| correlations[(dim, other)])
+            .sum::<A>()
+            / A::from(side.len()).unwrap()
+    };
+    while !remaining.is_empty() {
+        let (idx, preference) = max_by_float_key(
+            remaining.iter().enumerate().map(|(idx, &dim)| {
+                (
+                    idx,
+                    mean_correlation(dim, &left) - mean_correlation(dim, &right),
+                )
+            }),
+            |&(_, preference)| preference.abs(),
+        )
+        .unwrap();
+        let to_left = preference >= A::zero();
+
+        let dim = remaining.remove(idx);
+        if (to_left && left.len() < left_len) || right.len() == right_len {
+            left.push(dim);
+        } else {
+            right.push(dim);
+        }
+    }
+
+    refine_bisection(correlations, &mut left, &mut right);
+
+    bisect(correlations, left, left_groups, groups);
+    bisect(correlations, right, n_groups - left_groups, groups);
+}
+
+/// Swap dimensions between the halves while this increases the sum of
+/// the correlations within the halves.
+fn refine_bisection<A>(correlations: ArrayView2<A>, left: &mut [usize], right: &mut [usize])
+where
+    A: NdFloat + Sum,
+{
+    let side_sum = |dim: usize, side: &[usize]| {
+        side.iter()
+            .map(|&other| correlations[(dim, other)])
+            .sum::<A>()
+    };
+
+    // Every swap strictly increases the objective, bound the number of
+    // swaps to guard against cycles due to rounding.
+    for _ in 0..left.len() * right.len() {
+        let mut best = None;
+        let mut best_gain = A::zero();
+        for (i, &l) in left.iter().enumerate() {
+            for (j, &r) in right.iter().enumerate() {
+                let gain = side_sum(r, left) + side_sum(l, right)
+                    - side_sum(l, left)
+                    - side_sum(r, right)
+                    - (correlations[(l, r)] + correlations[(l, r)]);
+                if gain > best_gain {
+                    best = Some((i, j));
+                    best_gain = gain;
+                }
+            }
+        }
+
+        match best {
+            Some((i, j)) => mem::swap(&mut left[i], &mut right[j]),
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{concatenate, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn group_correlated_dimensions() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        // Dimensions i and i + 4 are noisy copies of the same variable.
+        let latent: Array2<f32> = Array2::random_using((512, 4), Uniform::new(-1., 1.), &mut rng);
+        let noise: Array2<f32> =
+            Array2::random_using((512, 8), Uniform::new(-0.05, 0.05), &mut rng);
+        let instances = concatenate(Axis(1), &[latent.view(), latent.view()]).unwrap() + noise;
+
+        let mut groups = PQ::correlated_dimension_groups(instances.view(), 4);
+        groups.sort();
+        assert_eq!(groups, vec![vec![0, 4], vec![1, 5], vec![2, 6], vec![3, 7]]);
+
+        let mse = |pq: &PQ<f32>| {
+            let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+            let errors = &instances - &pq.reconstruct_batch(quantized);
+            errors.mapv(|v| v * v).sum() / 512.
+        };
+        let pq = PQ::train_pq_using(4, 2, 10, 1, instances.view(), &mut rng);
+        let grouped = PQ::train_pq_grouped_using(4, 2, 10, 1, instances.view(), &mut rng);
+        assert_eq!(grouped.manifest().unwrap().quantizer, "GroupedPQ");
+        assert!(mse(&grouped) < mse(&pq) / 2.);
+    }
+}
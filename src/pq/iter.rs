@@ -0,0 +1,144 @@
+use std::iter::Sum;
+use std::marker::PhantomData;
+
+use ndarray::{Array1, ArrayBase, ArrayView2, Axis, Data, Ix1, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::ReconstructVector;
+
+/// Lazy reconstruction of quantized vectors.
+///
+/// This iterator adapter reconstructs the vectors of an iterator over
+/// quantization codes on demand, e.g. the rows of a code matrix or
+/// code rows that are read from disk. Only one reconstructed vector
+/// is materialized at a time.
+pub struct Reconstructions<'a, A, Q, It> {
+    quantizer: &'a Q,
+    codes: It,
+    _phantom: PhantomData<A>,
+}
+
+impl<'a, A, Q, It> Reconstructions<'a, A, Q, It>
+where
+    Q: ReconstructVector<A>,
+{
+    /// Reconstruct the codes of `codes` using `quantizer`.
+    pub fn new(quantizer: &'a Q, codes: impl IntoIterator<IntoIter = It>) -> Self {
+        Reconstructions {
+            quantizer,
+            codes: codes.into_iter(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, A, I, Q, S, It> Iterator for Reconstructions<'a, A, Q, It>
+where
+    I: AsPrimitive<usize>,
+    Q: ReconstructVector<A>,
+    S: Data<Elem = I>,
+    It: Iterator<Item = ArrayBase<S, Ix1>>,
+{
+    type Item = Array1<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.codes
+            .next()
+            .map(|codes| self.quantizer.reconstruct_vector(codes))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.codes.size_hint()
+    }
+}
+
+/// Lazy asymmetric distance computation.
+///
+/// This iterator adapter computes the approximate squared distances
+/// between a query and the vectors of an iterator over quantization
+/// codes on demand. The distances are computed from a distance table
+/// of the query, as returned by `PQ::adc_tables_batch`.
+pub struct AdcDistances<'a, A, It> {
+    table: ArrayView2<'a, A>,
+    codes: It,
+}
+
+impl<'a, A, It> AdcDistances<'a, A, It> {
+    /// Compute distances for the codes of `codes` using `table`.
+    ///
+    /// `table` has the shape *(n_subquantizers, n_centroids)*.
+    pub fn new(table: ArrayView2<'a, A>, codes: impl IntoIterator<IntoIter = It>) -> Self {
+        AdcDistances {
+            table,
+            codes: codes.into_iter(),
+        }
+    }
+}
+
+impl<'a, A, I, S, It> Iterator for AdcDistances<'a, A, It>
+where
+    A: NdFloat + Sum,
+    I: AsPrimitive<usize>,
+    S: Data<Elem = I>,
+    It: Iterator<Item = ArrayBase<S, Ix1>>,
+{
+    type Item = A;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let codes = self.codes.next()?;
+
+        assert_eq!(
+            codes.len(),
+            self.table.len_of(Axis(0)),
+            "Quantization length does not match number of subquantizers"
+        );
+
+        Some(
+            codes
+                .iter()
+                .zip(self.table.outer_iter())
+                .map(|(&code, sq_table)| sq_table[code.as_()])
+                .sum(),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.codes.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Axis};
+
+    use super::{AdcDistances, Reconstructions};
+    use crate::pq::{test_pq, QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn lazy_reconstruction_and_distances() {
+        let pq = test_pq();
+        let instances: Array2<f32> = array![
+            [0., 2., 0., -0.5, 0., 0.],
+            [1., -0.2, 0., 0.5, 0.5, 0.],
+            [-0.2, 0.2, 0., 0., -2., 0.],
+        ];
+        let quantized = pq.quantize_batch::<u8, _>(instances.view());
+        let reconstructions = pq.reconstruct_batch(quantized.view());
+
+        let lazy = Reconstructions::new(&pq, quantized.outer_iter());
+        assert_eq!(lazy.size_hint(), (3, Some(3)));
+        for (lazy, reconstruction) in lazy.zip(reconstructions.outer_iter()) {
+            assert_eq!(lazy, reconstruction);
+        }
+
+        let query = array![[1f32, 0., 0., 0., 1., 0.]];
+        let tables = pq.adc_tables_batch(query.view());
+        let distances = AdcDistances::new(tables.index_axis(Axis(0), 0), quantized.outer_iter())
+            .collect::<Vec<_>>();
+        assert_eq!(distances.len(), 3);
+        for (distance, reconstruction) in distances.into_iter().zip(reconstructions.outer_iter()) {
+            let diff = &query.row(0) - &reconstruction;
+            assert!((diff.dot(&diff) - distance).abs() < 1e-6);
+        }
+    }
+}
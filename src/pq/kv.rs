@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{Infallible, TryFrom};
+use std::error;
+use std::ops::Bound;
+
+use super::{CodeSink, CodeSource};
+use crate::error::Error;
+
+/// Key-value store with byte keys and values.
+///
+/// This is the small subset of the interface of embedded key-value
+/// stores, such as RocksDB or sled, that `KvCodeStore` needs.
+/// Implementing this trait for the handle of such a store makes it
+/// usable as a code store.
+pub trait KeyValueStore {
+    /// Error type of the store.
+    type Error: error::Error + Send + Sync + 'static;
+
+    /// Get the value of a key.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Get the values of a batch of keys.
+    ///
+    /// The default implementation gets the keys one by one. Stores that
+    /// support batched reads should override this method.
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Set the value of a key.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error>;
+
+    /// Get the largest key that starts with `prefix`.
+    ///
+    /// Returns `None` if there are no keys with the prefix.
+    fn last_key(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+}
+
+impl KeyValueStore for BTreeMap<Vec<u8>, Vec<u8>> {
+    type Error = Infallible;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+        self.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn last_key(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let end = prefix_end(prefix);
+        let upper = match end {
+            Some(ref end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+
+        Ok(self
+            .range::<[u8], _>((Bound::Included(prefix), upper))
+            .next_back()
+            .map(|(key, _)| key.clone()))
+    }
+}
+
+impl KeyValueStore for HashMap<Vec<u8>, Vec<u8>> {
+    type Error = Infallible;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(HashMap::get(self, key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+        self.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn last_key(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .max()
+            .cloned())
+    }
+}
+
+#[cfg(feature = "sled-store")]
+impl KeyValueStore for sled::Tree {
+    type Error = sled::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+        self.insert(key, value).map(|_| ())
+    }
+
+    fn last_key(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.scan_prefix(prefix)
+            .next_back()
+            .transpose()
+            .map(|entry| entry.map(|(key, _)| key.to_vec()))
+    }
+}
+
+/// Get the smallest key that is larger than all keys with `prefix`.
+///
+/// Returns `None` if there is no such key, i.e. when the prefix only
+/// consists of `0xff` bytes.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        if last != u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// Code store on top of a key-value store.
+///
+/// The codes of the vector with identifier *id* are stored as the value
+/// of the key *prefix + id*, where *id* is encoded as a big-endian
+/// `u64`, so that the codes are ordered by identifier in ordered
+/// stores. The prefix makes it possible to share a key-value store
+/// between several code stores, as long as no prefix is a prefix of
+/// another.
+///
+/// The number of rows of the store is one past the largest identifier
+/// that has codes. Since key-value stores are sparse, rows below that
+/// identifier may be missing, reading them results in
+/// `Error::MissingRow`.
+pub struct KvCodeStore<S> {
+    store: S,
+    prefix: Vec<u8>,
+    n_rows: u64,
+}
+
+impl<S> KvCodeStore<S>
+where
+    S: KeyValueStore,
+{
+    /// Construct a code store with the given key prefix.
+    ///
+    /// The number of rows is recovered from the largest key with the
+    /// prefix. Returns an error if the store cannot be read or if that
+    /// key is not a code key.
+    pub fn new(store: S, prefix: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        let prefix = prefix.into();
+
+        let n_rows = match store.last_key(&prefix).map_err(store_error)? {
+            Some(key) => <[u8; 8]>::try_from(&key[prefix.len()..])
+                .ok()
+                .and_then(|id| u64::from_be_bytes(id).checked_add(1))
+                .ok_or_else(|| Error::Store(format!("Key is not a code key: {:?}", key)))?,
+            None => 0,
+        };
+
+        Ok(KvCodeStore {
+            store,
+            prefix,
+            n_rows,
+        })
+    }
+
+    /// Get the underlying key-value store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Unwrap the key-value store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Get the key of a vector identifier.
+    pub fn key(&self, id: u64) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.prefix.len() + 8);
+        key.extend_from_slice(&self.prefix);
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    /// Get the codes of a batch of vectors.
+    ///
+    /// The codes are read with a single batched read, which is useful
+    /// when scanning candidates during search. Vectors that are not in
+    /// the store are `None`.
+    pub fn get_batch(&self, ids: &[u64]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let keys = ids.iter().map(|&id| self.key(id)).collect::<Vec<_>>();
+        self.store.get_batch(&keys).map_err(store_error)
+    }
+}
+
+impl<S> CodeSink for KvCodeStore<S>
+where
+    S: KeyValueStore,
+{
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error> {
+        // The row count must be representable.
+        if row == u64::MAX {
+            return Err(Error::RowOutOfRange {
+                row,
+                n_rows: u64::MAX,
+            });
+        }
+
+        let key = self.key(row);
+        self.store.put(&key, codes).map_err(store_error)?;
+        self.n_rows = self.n_rows.max(row + 1);
+
+        Ok(())
+    }
+}
+
+impl<S> CodeSource for KvCodeStore<S>
+where
+    S: KeyValueStore,
+{
+    fn n_rows(&self, _code_len: usize) -> u64 {
+        self.n_rows
+    }
+
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error> {
+        let value = self
+            .store
+            .get(&self.key(row))
+            .map_err(store_error)?
+            .ok_or(Error::MissingRow { row })?;
+
+        if value.len() != codes.len() {
+            return Err(Error::CodeLengthMismatch {
+                expected: value.len(),
+                actual: codes.len(),
+            });
+        }
+
+        codes.copy_from_slice(&value);
+
+        Ok(())
+    }
+}
+
+fn store_error(err: impl error::Error + Send + Sync + 'static) -> Error {
+    Error::Store(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use ndarray::{array, Array2, Axis};
+
+    use super::{KeyValueStore, KvCodeStore};
+    use crate::error::Error;
+    use crate::pq::{test_pq, CodeSink, CodeSource, QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn codes_in_key_value_store() {
+        let pq = test_pq();
+        let instances: Array2<f32> =
+            array![[0., 2., 0., -0.5, 0., 0.], [1., -0.2, 0., 0.5, 0.5, 0.]];
+        let quantized = pq.quantize_batch::<u8, _>(instances.view());
+
+        let mut store = KvCodeStore::new(BTreeMap::new(), "codes/").unwrap();
+        assert_eq!(store.n_rows(2), 0);
+        pq.quantize_batch_into_sink(instances.view(), &mut store, 10)
+            .unwrap();
+        assert_eq!(store.key(10), b"codes/\0\0\0\0\0\0\0\x0a");
+        assert_eq!(store.n_rows(2), 12);
+
+        assert_eq!(
+            store.get_batch(&[11, 12, 10]),
+            Ok(vec![
+                Some(quantized.row(1).to_vec()),
+                None,
+                Some(quantized.row(0).to_vec())
+            ])
+        );
+        assert_eq!(
+            pq.reconstruct_from_source(&store, vec![11, 10]),
+            Ok(pq.reconstruct_batch(quantized.select(Axis(0), &[1, 0])))
+        );
+
+        let mut codes = [0u8; 3];
+        assert_eq!(
+            store.read_codes(10, &mut codes),
+            Err(Error::CodeLengthMismatch {
+                expected: 2,
+                actual: 3
+            })
+        );
+        assert_eq!(
+            store.read_codes(0, &mut codes[..2]),
+            Err(Error::MissingRow { row: 0 })
+        );
+    }
+    #[test]
+    fn row_count_is_recovered() {
+        let mut kv = BTreeMap::new();
+        kv.put(b"codes.", &[1, 2]).unwrap();
+        kv.put(b"codes0", &[1, 2]).unwrap();
+
+        let mut store = KvCodeStore::new(kv, "codes/").unwrap();
+        store.write_codes(3, &[1, 2]).unwrap();
+        store.write_codes(1, &[3, 4]).unwrap();
+        assert_eq!(store.n_rows(2), 4);
+
+        let store = KvCodeStore::new(store.into_inner(), "codes/").unwrap();
+        assert_eq!(store.n_rows(2), 4);
+        let store = KvCodeStore::new(store.into_inner(), "other/").unwrap();
+        assert_eq!(store.n_rows(2), 0);
+
+        let mut kv = store.into_inner().into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(KvCodeStore::new(kv.clone(), "codes/").unwrap().n_rows(2), 4);
+        kv.put(b"codes/x", &[1, 2]).unwrap();
+        assert!(KvCodeStore::new(kv, "codes/").is_err());
+    }
+
+    #[test]
+    fn last_key_with_max_prefix() {
+        let mut kv = BTreeMap::new();
+        kv.put(b"\xff", &[]).unwrap();
+        kv.put(b"\xff\xff\x01", &[]).unwrap();
+        kv.put(b"\xfe\xff", &[]).unwrap();
+
+        assert_eq!(kv.last_key(b"\xff"), Ok(Some(b"\xff\xff\x01".to_vec())));
+        assert_eq!(kv.last_key(b"\xfe"), Ok(Some(b"\xfe\xff".to_vec())));
+        assert_eq!(kv.last_key(b"\x01"), Ok(None));
+    }
+}
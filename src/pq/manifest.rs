@@ -0,0 +1,271 @@
+use std::any;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use super::Normalization;
+
+/// Manifest of a quantizer training run.
+///
+/// The manifest records the hyperparameters, data shape, and
+/// environment of a training run, so that trained quantizers can be
+/// audited and training can be reproduced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainingManifest {
+    /// The name of the quantizer type that was trained.
+    pub quantizer: &'static str,
+
+    /// The number of subquantizers.
+    pub n_subquantizers: usize,
+
+    /// The number of bits per subquantizer.
+    pub n_subquantizer_bits: u32,
+
+    /// The number of training iterations.
+    pub n_iterations: usize,
+
+    /// The number of training attempts per subquantizer.
+    pub n_attempts: usize,
+
+    /// The seed of the random number generator, if known.
+    pub seed: Option<u64>,
+
+    /// The type of the random number generator, if known.
+    pub rng: Option<&'static str>,
+
+    /// The normalization of the quantizer inputs.
+    ///
+    /// One of `None`, `L2`, or `MeanCentering`, see `Normalization`.
+    pub normalization: &'static str,
+
+    /// Quantizer-specific training settings.
+    ///
+    /// For example, the centroid initialization and assignment kernel
+    /// of k-means, or the objective of `OPQ`.
+    pub settings: BTreeMap<&'static str, String>,
+
+    /// The number of training instances.
+    pub n_instances: usize,
+
+    /// The dimensionality of the training instances.
+    pub n_dims: usize,
+
+    /// The version of this crate that was used for training.
+    pub crate_version: &'static str,
+
+    /// The wall-clock duration of training.
+    pub duration: Duration,
+
+    /// The training objective.
+    ///
+    /// For `OPQ`, this is the mean squared error between the rotated
+    /// instances and their reconstructions after each outer iteration.
+    /// `GaussianOPQ` does not optimize the rotation iteratively and
+    /// reports the error of the trained quantizer. Empty for `PQ`.
+    pub objective: Vec<f64>,
+}
+
+impl TrainingManifest {
+    /// Create a manifest for a training run that started at `start`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        quantizer: &'static str,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        shape: (usize, usize),
+        start: Instant,
+    ) -> Self {
+        TrainingManifest {
+            quantizer,
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            seed: None,
+            rng: None,
+            normalization: "None",
+            settings: BTreeMap::new(),
+            n_instances: shape.0,
+            n_dims: shape.1,
+            crate_version: env!("CARGO_PKG_VERSION"),
+            duration: start.elapsed(),
+            objective: Vec::new(),
+        }
+    }
+
+    /// Record the type of the random number generator.
+    pub(crate) fn with_rng<R>(mut self) -> Self
+    where
+        R: ?Sized,
+    {
+        self.rng = Some(any::type_name::<R>());
+        self
+    }
+
+    /// Record the normalization of the quantizer inputs.
+    pub(crate) fn with_normalization<A>(mut self, normalization: &Normalization<A>) -> Self {
+        self.normalization = normalization.name();
+        self
+    }
+
+    /// Record a training setting.
+    pub(crate) fn with_setting(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.settings.insert(key, value.to_string());
+        self
+    }
+
+    /// Record training settings.
+    pub(crate) fn with_settings(
+        mut self,
+        settings: impl IntoIterator<Item = (&'static str, String)>,
+    ) -> Self {
+        self.settings.extend(settings);
+        self
+    }
+
+    /// Serialize the manifest to JSON.
+    ///
+    /// JSON has no representation of infinity and NaN, non-finite
+    /// objective values are written as `null`.
+    pub fn to_json(&self) -> String {
+        let seed = match self.seed {
+            Some(seed) => seed.to_string(),
+            None => "null".to_string(),
+        };
+        let rng = match self.rng {
+            Some(rng) => json_string(rng),
+            None => "null".to_string(),
+        };
+        let settings = self
+            .settings
+            .iter()
+            .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let objective = self
+            .objective
+            .iter()
+            .map(|&v| {
+                if v.is_finite() {
+                    v.to_string()
+                } else {
+                    "null".to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            concat!(
+                "{{\"quantizer\":\"{}\",\"n_subquantizers\":{},\"n_subquantizer_bits\":{},",
+                "\"n_iterations\":{},\"n_attempts\":{},\"seed\":{},\"rng\":{},",
+                "\"normalization\":\"{}\",\"settings\":{{{}}},\"n_instances\":{},",
+                "\"n_dims\":{},\"crate_version\":\"{}\",\"duration_secs\":{},",
+                "\"objective\":[{}]}}"
+            ),
+            self.quantizer,
+            self.n_subquantizers,
+            self.n_subquantizer_bits,
+            self.n_iterations,
+            self.n_attempts,
+            seed,
+            rng,
+            self.normalization,
+            settings,
+            self.n_instances,
+            self.n_dims,
+            self.crate_version,
+            self.duration.as_secs_f64(),
+            objective
+        )
+    }
+}
+
+/// Quote and escape a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::TrainingManifest;
+
+    #[test]
+    fn manifest_to_json() {
+        let manifest = TrainingManifest {
+            quantizer: "PQ",
+            n_subquantizers: 10,
+            n_subquantizer_bits: 7,
+            n_iterations: 10,
+            n_attempts: 1,
+            seed: Some(42),
+            rng: Some("rand_xorshift::XorShiftRng"),
+            normalization: "L2",
+            settings: vec![
+                ("kernel", "Blocked".to_string()),
+                ("note", "\"a\"\n".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            n_instances: 256,
+            n_dims: 20,
+            crate_version: "0.6.0",
+            duration: Duration::from_millis(1500),
+            objective: vec![0.5, 0.25],
+        };
+
+        assert_eq!(
+            manifest.to_json(),
+            "{\"quantizer\":\"PQ\",\"n_subquantizers\":10,\"n_subquantizer_bits\":7,\
+             \"n_iterations\":10,\"n_attempts\":1,\"seed\":42,\
+             \"rng\":\"rand_xorshift::XorShiftRng\",\"normalization\":\"L2\",\
+             \"settings\":{\"kernel\":\"Blocked\",\"note\":\"\\\"a\\\"\\u000a\"},\"n_instances\":256,\
+             \"n_dims\":20,\"crate_version\":\"0.6.0\",\"duration_secs\":1.5,\
+             \"objective\":[0.5,0.25]}"
+        );
+    }
+
+    #[test]
+    fn manifest_to_json_non_finite() {
+        let manifest = TrainingManifest {
+            quantizer: "OPQ",
+            n_subquantizers: 2,
+            n_subquantizer_bits: 4,
+            n_iterations: 3,
+            n_attempts: 1,
+            seed: None,
+            rng: None,
+            normalization: "None",
+            settings: BTreeMap::new(),
+            n_instances: 64,
+            n_dims: 8,
+            crate_version: "0.6.0",
+            duration: Duration::from_secs(2),
+            objective: vec![f64::NAN, f64::INFINITY, 0.5],
+        };
+
+        assert_eq!(
+            manifest.to_json(),
+            "{\"quantizer\":\"OPQ\",\"n_subquantizers\":2,\"n_subquantizer_bits\":4,\
+             \"n_iterations\":3,\"n_attempts\":1,\"seed\":null,\"rng\":null,\
+             \"normalization\":\"None\",\"settings\":{},\"n_instances\":64,\
+             \"n_dims\":8,\"crate_version\":\"0.6.0\",\"duration_secs\":2,\
+             \"objective\":[null,null,0.5]}"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::iter::Sum;
+
+use ndarray::{concatenate, Array1, Axis, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::PQ;
+use crate::kmeans::weighted_kmeans;
+
+/// Maximum number of weighted k-means iterations of a merge.
+const MAX_MERGE_ITERATIONS: usize = 100;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Merge quantizers that were trained on different data shards.
+    ///
+    /// The codebooks of every subquantizer are merged by clustering the
+    /// union of the centroids of the shards with weighted k-means. A
+    /// centroid of shard *i* has weight *weights[i] / n_centroids*, so
+    /// `weights` would typically be the number of training instances of
+    /// each shard. k-means starts from the centroids of the shard with
+    /// the largest weight and runs until the assignments do not change.
+    ///
+    /// All quantizers must have the same shape, projection, and
+    /// normalization. The merged quantizer has no training manifest or
+    /// metadata.
+    pub fn merge(quantizers: &[PQ<A>], weights: &[A]) -> PQ<A> {
+        assert!(!quantizers.is_empty(), "Cannot merge zero quantizers");
+        assert_eq!(
+            quantizers.len(),
+            weights.len(),
+            "The number of weights should match the number of quantizers"
+        );
+        assert!(
+            weights.iter().all(|&w| w >= A::zero()) && weights.iter().any(|&w| w > A::zero()),
+            "Weights should be non-negative with at least one positive weight"
+        );
+
+        let first = &quantizers[0];
+        for pq in &quantizers[1..] {
+            assert_eq!(
+                pq.quantizers.shape(),
+                first.quantizers.shape(),
+                "Quantizers should have the same shape"
+            );
+            assert!(
+                pq.projection == first.projection,
+                "Quantizers should have the same projection"
+            );
+            assert!(
+                pq.normalization == first.normalization,
+                "Quantizers should have the same normalization"
+            );
+        }
+
+        let heaviest = (0..weights.len()).fold(0, |best, idx| {
+            if weights[idx] > weights[best] {
+                idx
+            } else {
+                best
+            }
+        });
+        let n_centroids = first.n_quantizer_centroids();
+        let centroid_weight = |idx: usize| weights[idx / n_centroids] / n_centroids.as_();
+
+        let mut merged = quantizers[heaviest].quantizers.clone();
+        for (sq, mut centroids) in merged.outer_iter_mut().enumerate() {
+            let shard_centroids = quantizers
+                .iter()
+                .map(|pq| pq.quantizers.index_axis(Axis(0), sq))
+                .collect::<Vec<_>>();
+            let points = concatenate(Axis(0), &shard_centroids)
+                .expect("Subquantizers have different shapes");
+            let point_weights = Array1::from_shape_fn(points.nrows(), centroid_weight);
+
+            weighted_kmeans(
+                centroids.view_mut(),
+                points.view(),
+                point_weights.view(),
+                MAX_MERGE_ITERATIONS,
+            );
+        }
+
+        PQ {
+            projection: first.projection.clone(),
+            quantizers: merged,
+            manifest: None,
+            polysemous: None,
+            normalization: first.normalization.clone(),
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    fn mse(pq: &PQ<f32>, instances: &Array2<f32>) -> f32 {
+        let quantized: Array2<usize> = pq.quantize_batch(instances.view());
+        let errors = instances - &pq.reconstruct_batch(quantized);
+        errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+    }
+
+    #[test]
+    fn merge_shard_quantizers() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((1024, 4), Uniform::new(-1., 1.), &mut rng);
+        // The shards have different distributions.
+        instances.slice_mut(s![512.., ..]).mapv_inplace(|v| v + 2.);
+
+        let shard1 = PQ::train_pq_using(2, 3, 10, 1, instances.slice(s![..512, ..]), &mut rng);
+        let shard2 = PQ::train_pq_using(2, 3, 10, 1, instances.slice(s![512.., ..]), &mut rng);
+
+        // Merging a quantizer with itself is the identity.
+        let merged = PQ::merge(&[shard1.clone(), shard1.clone()], &[1., 2.]);
+        assert!(merged
+            .subquantizers()
+            .abs_diff_eq(&shard1.subquantizers(), 1e-6));
+
+        let merged = PQ::merge(&[shard1.clone(), shard2.clone()], &[512., 512.]);
+        let merged_mse = mse(&merged, &instances);
+        assert!(merged_mse < mse(&shard1, &instances) / 2.);
+        assert!(merged_mse < mse(&shard2, &instances) / 2.);
+    }
+}
@@ -0,0 +1,589 @@
+use std::iter;
+use std::iter::Sum;
+use std::ops::Range;
+
+use ndarray::{s, Array1, Array2, ArrayBase, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat, Zip};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use rayon::prelude::*;
+
+use super::{
+    KMeansTrainer, QuantizeVector, ReconstructVector, SubquantizerConfig, SubquantizerTrainer,
+};
+use crate::kmeans::{cluster_assignment, cluster_assignments};
+use crate::parallel::NestedParallelism;
+
+/// Product quantizer with a bit width and subspace per subquantizer.
+///
+/// `PQ` uses the same number of centroids for every subquantizer. When
+/// some subspaces carry much more variance than others, it is more
+/// accurate to spend more bits on those subspaces. This quantizer has a
+/// codebook of 2^`bits[i]` centroids for subquantizer *i*, e.g. with
+/// the bit widths `[8, 8, 6, 6, 4, 4]`.
+///
+/// `PQ` also requires that the number of subquantizers evenly divides
+/// the vector length. The subspaces of this quantizer can be given
+/// explicitly, e.g. `[0..100, 100..120, 120..128]`, so that vectors do
+/// not have to be padded or truncated.
+///
+/// Codes have one column per subquantizer, where the codes of column
+/// *i* are in *[0, 2^bits[i])*. A code can thus be stored in
+/// `code_bits` bits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixedBitsPQ<A> {
+    bits: Vec<u32>,
+    quantizers: Vec<Array2<A>>,
+}
+
+impl<A> MixedBitsPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a quantizer from its subquantizer codebooks.
+    ///
+    /// The number of centroids of every codebook must be a power of
+    /// two. The subspace of a subquantizer follows the subspace of the
+    /// previous subquantizer, with the length of its codebook.
+    pub fn new(quantizers: Vec<Array2<A>>) -> Self {
+        assert!(
+            !quantizers.is_empty(),
+            "Attempted to construct a product quantizer without quantizers."
+        );
+
+        let bits = quantizers
+            .iter()
+            .enumerate()
+            .map(|(idx, quantizer)| {
+                assert!(
+                    quantizer.nrows() > 1 && quantizer.nrows().is_power_of_two(),
+                    "Subquantizer {} should have a power of two centroids, has: {}",
+                    idx,
+                    quantizer.nrows()
+                );
+                assert!(
+                    quantizer.ncols() > 0,
+                    "Subquantizer {} has an empty subspace",
+                    idx
+                );
+                quantizer.nrows().trailing_zeros()
+            })
+            .collect();
+
+        MixedBitsPQ { bits, quantizers }
+    }
+
+    /// Train a quantizer with the given bit width per subquantizer.
+    ///
+    /// The number of subquantizers is the length of `bits`. The
+    /// subquantizers are trained with k-means, see
+    /// `TrainPQ::train_pq_using` for a description of the remaining
+    /// arguments.
+    pub fn train_using<S, R>(
+        bits: &[u32],
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> Self
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        Self::train_with_trainer_using(
+            &KMeansTrainer::new(),
+            bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            rng,
+        )
+    }
+
+    /// Train a quantizer with the given bit width per subquantizer.
+    ///
+    /// This is `MixedBitsPQ::train_using` with the codebooks trained by
+    /// `trainer`.
+    pub fn train_with_trainer_using<T, S, R>(
+        trainer: &T,
+        bits: &[u32],
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> Self
+    where
+        T: SubquantizerTrainer<A> + Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        assert!(
+            !bits.is_empty() && bits.len() <= instances.ncols(),
+            "The number of subquantizers should at least be 1 and at most be {}.",
+            instances.ncols()
+        );
+        assert!(
+            instances.ncols() % bits.len() == 0,
+            "The number of subquantizers should evenly divide each instance."
+        );
+
+        let sq_dims = instances.ncols() / bits.len();
+        let subspaces = (0..bits.len())
+            .map(|idx| idx * sq_dims..(idx + 1) * sq_dims)
+            .collect::<Vec<_>>();
+
+        Self::train_subspaces_with_trainer_using(
+            trainer,
+            &subspaces,
+            bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            rng,
+        )
+    }
+
+    /// Train a quantizer with the given subspace and bit width per
+    /// subquantizer.
+    ///
+    /// Subquantizer *i* quantizes the dimensions `subspaces[i]` with
+    /// 2^`bits[i]` centroids. The subspaces must be non-empty and
+    /// consecutive, starting at dimension 0 and ending at the length of
+    /// the instances. The subquantizers are trained with k-means, see
+    /// `TrainPQ::train_pq_using` for a description of the remaining
+    /// arguments.
+    pub fn train_subspaces_using<S, R>(
+        subspaces: &[Range<usize>],
+        bits: &[u32],
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> Self
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        Self::train_subspaces_with_trainer_using(
+            &KMeansTrainer::new(),
+            subspaces,
+            bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            rng,
+        )
+    }
+
+    /// Train a quantizer with the given subspace and bit width per
+    /// subquantizer.
+    ///
+    /// This is `MixedBitsPQ::train_subspaces_using` with the codebooks
+    /// trained by `trainer`.
+    pub fn train_subspaces_with_trainer_using<T, S, R>(
+        trainer: &T,
+        subspaces: &[Range<usize>],
+        bits: &[u32],
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        T: SubquantizerTrainer<A> + Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        check_subspaces(subspaces, instances.ncols());
+        assert_eq!(
+            subspaces.len(),
+            bits.len(),
+            "The number of subspaces and bit widths differ"
+        );
+        for &n_subquantizer_bits in bits {
+            assert!(
+                n_subquantizer_bits > 0
+                    && n_subquantizer_bits <= 32
+                    && 2usize.checked_pow(n_subquantizer_bits).is_some(),
+                "Number of quantizer bits should be in [1, 32], was: {}",
+                n_subquantizer_bits
+            );
+        }
+        assert!(
+            n_iterations > 0,
+            "The subquantizers should be optimized for at least one iteration."
+        );
+        assert!(
+            n_attempts > 0,
+            "The subquantizers should be optimized for at least one attempt."
+        );
+
+        let n_subquantizers = bits.len();
+        let parallelism = NestedParallelism::for_tasks(n_subquantizers);
+        let rngs = iter::repeat_with(|| {
+            XorShiftRng::from_rng(&mut rng).expect("Cannot seed subquantizer RNG")
+        })
+        .take(n_subquantizers)
+        .collect::<Vec<_>>();
+
+        let quantizers = bits
+            .par_iter()
+            .zip(subspaces)
+            .zip(rngs)
+            .with_min_len(parallelism.outer_min_len(n_subquantizers))
+            .enumerate()
+            .map(|(idx, ((&n_subquantizer_bits, subspace), mut rng))| {
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let sq_instances = instances.slice(s![.., subspace.clone()]);
+
+                let codebook_len = 2usize.pow(n_subquantizer_bits);
+                let config = SubquantizerConfig {
+                    subquantizer_idx: idx,
+                    n_subquantizers,
+                    codebook_len,
+                    n_iterations,
+                    n_attempts,
+                    parallelism,
+                };
+                let codebook = trainer.train_subquantizer(sq_instances, config, &mut rng);
+                assert_eq!(
+                    codebook.dim(),
+                    (codebook_len, subspace.len()),
+                    "Subquantizer trainer returned a codebook with an incorrect shape"
+                );
+                codebook
+            })
+            .collect();
+
+        MixedBitsPQ {
+            bits: bits.to_vec(),
+            quantizers,
+        }
+    }
+
+    /// Get the bit width of each subquantizer.
+    pub fn bits(&self) -> &[u32] {
+        &self.bits
+    }
+
+    /// Get the number of bits of a code.
+    pub fn code_bits(&self) -> u32 {
+        self.bits.iter().sum()
+    }
+
+    /// Get the subquantizer codebooks.
+    pub fn subquantizers(&self) -> &[Array2<A>] {
+        &self.quantizers
+    }
+
+    /// Get the subspace of each subquantizer.
+    pub fn subspaces(&self) -> Vec<Range<usize>> {
+        let mut offset = 0;
+        self.quantizers
+            .iter()
+            .map(|quantizer| {
+                offset += quantizer.ncols();
+                offset - quantizer.ncols()..offset
+            })
+            .collect()
+    }
+
+    fn check_index_type<I>(&self)
+    where
+        I: AsPrimitive<usize> + Bounded,
+        usize: AsPrimitive<I>,
+    {
+        let max_bits = self.bits.iter().cloned().max().unwrap_or(0);
+        assert!(
+            2usize.pow(max_bits) - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+    }
+}
+
+impl<A> QuantizeVector<A> for MixedBitsPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert!(
+            quantized.nrows() == x.nrows() && quantized.ncols() == self.quantized_len(),
+            "Quantized matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
+            x.nrows(),
+            self.quantized_len(),
+            quantized.nrows(),
+            quantized.ncols()
+        );
+        self.check_index_type::<I>();
+
+        let mut offset = 0;
+        for (quantizer, mut quantized) in
+            self.quantizers.iter().zip(quantized.axis_iter_mut(Axis(1)))
+        {
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_matrix = x.slice(s![.., offset..offset + quantizer.ncols()]);
+            let assignments = cluster_assignments(quantizer.view(), sub_matrix, Axis(0));
+            Zip::from(&mut quantized)
+                .and(&assignments)
+                .apply(|quantized, assignment| *quantized = assignment.as_());
+
+            offset += quantizer.ncols();
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.len(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        self.check_index_type::<I>();
+
+        let mut offset = 0;
+        self.quantizers
+            .iter()
+            .map(|quantizer| {
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let sub_vec = x.slice(s![offset..offset + quantizer.ncols()]);
+                offset += quantizer.ncols();
+                cluster_assignment(quantizer.view(), sub_vec).as_()
+            })
+            .collect()
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.quantizers.len()
+    }
+}
+
+impl<A> ReconstructVector<A> for MixedBitsPQ<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert!(
+            reconstructions.nrows() == quantized.nrows()
+                && reconstructions.ncols() == self.reconstructed_len(),
+            "Reconstructions matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
+            quantized.nrows(),
+            self.reconstructed_len(),
+            reconstructions.nrows(),
+            reconstructions.ncols()
+        );
+
+        for (quantized, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction.assign(&self.reconstruct_vector(quantized));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.len(),
+            self.quantized_len(),
+            "Quantization length does not match number of subquantizers"
+        );
+
+        let mut reconstruction = Vec::with_capacity(self.reconstructed_len());
+        for (&code, quantizer) in quantized.iter().zip(&self.quantizers) {
+            reconstruction.extend(quantizer.row(code.as_()));
+        }
+
+        Array1::from(reconstruction)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.quantizers.iter().map(Array2::ncols).sum()
+    }
+}
+
+/// Check that subspaces are non-empty and partition *[0, n_dims)*.
+fn check_subspaces(subspaces: &[Range<usize>], n_dims: usize) {
+    assert!(
+        !subspaces.is_empty(),
+        "The number of subquantizers should at least be 1."
+    );
+
+    let mut offset = 0;
+    for (idx, subspace) in subspaces.iter().enumerate() {
+        assert!(
+            subspace.start == offset && subspace.end > subspace.start,
+            "Subspace {} should be a non-empty range starting at {}, was: {:?}",
+            idx,
+            offset,
+            subspace
+        );
+        offset = subspace.end;
+    }
+
+    assert_eq!(
+        offset, n_dims,
+        "The subspaces should cover all {} dimensions, cover: {}",
+        n_dims, offset
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::MixedBitsPQ;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector};
+
+    fn mse(pq: &MixedBitsPQ<f32>, instances: &Array2<f32>) -> f32 {
+        let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+        let errors = instances - &pq.reconstruct_batch(quantized);
+        errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+    }
+
+    #[test]
+    fn mixed_bits_codebooks() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 6), Uniform::new(-1., 1.), &mut rng);
+
+        let pq = MixedBitsPQ::train_using(&[3, 2, 1], 5, 1, instances.view(), &mut rng);
+        assert_eq!(pq.bits(), &[3, 2, 1]);
+        assert_eq!(pq.code_bits(), 6);
+        let n_centroids = pq
+            .subquantizers()
+            .iter()
+            .map(|quantizer| quantizer.dim())
+            .collect::<Vec<_>>();
+        assert_eq!(n_centroids, vec![(8, 2), (4, 2), (2, 2)]);
+
+        let quantized: Array2<u16> = pq.quantize_batch(instances.view());
+        for (codes, &bits) in quantized.axis_iter(Axis(1)).zip(pq.bits()) {
+            assert!(codes.iter().all(|&code| code < 1 << bits));
+        }
+        assert_eq!(
+            quantized.row(3),
+            pq.quantize_vector::<u16, _>(instances.row(3))
+        );
+        assert_eq!(
+            MixedBitsPQ::new(pq.subquantizers().to_vec()).bits(),
+            pq.bits()
+        );
+    }
+
+    #[test]
+    fn bits_on_high_variance_subspace_reduce_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((512, 4), Uniform::new(-1., 1.), &mut rng);
+        instances.column_mut(0).mapv_inplace(|v| v * 10.);
+        instances.column_mut(1).mapv_inplace(|v| v * 10.);
+
+        let high = MixedBitsPQ::train_using(&[5, 1], 10, 1, instances.view(), &mut rng);
+        let low = MixedBitsPQ::train_using(&[1, 5], 10, 1, instances.view(), &mut rng);
+        assert!(mse(&high, &instances) < mse(&low, &instances));
+    }
+
+    #[test]
+    fn non_uniform_subspaces() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 10), Uniform::new(-1., 1.), &mut rng);
+
+        let pq = MixedBitsPQ::train_subspaces_using(
+            &[0..5, 5..8, 8..10],
+            &[3, 2, 2],
+            5,
+            1,
+            instances.view(),
+            &mut rng,
+        );
+        assert_eq!(pq.subspaces(), vec![0..5, 5..8, 8..10]);
+        assert_eq!(pq.reconstructed_len(), 10);
+        assert_eq!(
+            MixedBitsPQ::new(pq.subquantizers().to_vec()).subspaces(),
+            pq.subspaces()
+        );
+
+        let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+        let reconstructions = pq.reconstruct_batch(quantized.view());
+        for (subspace, (quantizer, codes)) in pq
+            .subspaces()
+            .into_iter()
+            .zip(pq.subquantizers().iter().zip(quantized.axis_iter(Axis(1))))
+        {
+            for (reconstruction, &code) in reconstructions.outer_iter().zip(codes) {
+                assert_eq!(
+                    reconstruction.slice(s![subspace.clone()]),
+                    quantizer.row(code as usize)
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn subspaces_should_cover_instances() {
+        let instances: Array2<f32> = Array2::zeros((16, 10));
+        MixedBitsPQ::train_subspaces_using(
+            &[0..5, 5..8],
+            &[2, 2],
+            5,
+            1,
+            instances.view(),
+            XorShiftRng::seed_from_u64(42),
+        );
+    }
+}
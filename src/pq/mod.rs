@@ -1,20 +1,159 @@
 //! Product quantization.
 
+mod anisotropic;
+
+mod bounds;
+pub use self::bounds::ErrorBounds;
+
+mod chunked;
+pub use self::chunked::{ChunkedCodes, DEFAULT_CHUNK_BYTES};
+
+mod codec;
+pub use self::codec::CodeBatch;
+
+mod dataset;
+pub use self::dataset::EncodedDataset;
+
+mod ecvq;
+
+mod entropy;
+pub use self::entropy::{EntropyDecoder, EntropyEncoder, EntropyModel};
+
+mod fingerprint;
+pub use self::fingerprint::Fingerprint;
+
+mod fixed;
+pub use self::fixed::{FixedPQ, PQ16, PQ8};
+
 #[cfg(feature = "opq-train")]
 mod gaussian_opq;
 #[cfg(feature = "opq-train")]
 pub use gaussian_opq::GaussianOPQ;
 
+mod grouping;
+
+mod iter;
+pub use self::iter::{AdcDistances, Reconstructions};
+
+mod kv;
+pub use self::kv::{KeyValueStore, KvCodeStore};
+
+mod manifest;
+pub use self::manifest::TrainingManifest;
+
+mod merge;
+
+mod mixed;
+pub use self::mixed::MixedBitsPQ;
+
+mod nibble;
+pub use self::nibble::NibbleCodes;
+
+mod normalization;
+pub use self::normalization::{Normalization, DEFAULT_NORM_TOLERANCE};
+
+mod online;
+pub use self::online::OnlinePQ;
+
 #[cfg(feature = "opq-train")]
 mod opq;
 #[cfg(feature = "opq-train")]
-pub use self::opq::OPQ;
+pub use self::opq::{OPQObjective, OPQSnapshot, OPQ};
 
-pub(crate) mod primitives;
+mod perturbation;
+pub(crate) use self::perturbation::standard_normal;
+pub use self::perturbation::Perturbation;
+
+mod polysemous;
 
 #[allow(clippy::module_inception)]
 mod pq;
+#[cfg(test)]
+pub(crate) use self::pq::tests::test_pq;
 pub use self::pq::PQ;
 
+pub(crate) mod primitives;
+
+mod proto;
+pub use self::proto::PROTOBUF_SCHEMA;
+
+mod quantized_table;
+pub use self::quantized_table::QuantizedTable;
+
+mod query_set;
+pub use self::query_set::QuerySet;
+
+mod refinement;
+pub use self::refinement::DriftRefinement;
+
+mod reorder;
+
+mod scoring;
+pub use self::scoring::ScoreTransform;
+
+mod sequences;
+pub use self::sequences::{PackedCodes, QuantizedSequences, SequenceBatch};
+
+mod storage;
+pub use self::storage::{CodeSink, CodeSource};
+
+mod trainer;
+pub use self::trainer::{
+    KMeansTrainer, SubquantizerConfig, SubquantizerTrainer, TrainingWatchdog,
+    DEFAULT_MAX_LOSS_GROWTH,
+};
+
 mod traits;
-pub use self::traits::{QuantizeVector, ReconstructVector, TrainPQ};
+pub use self::traits::{QuantizeVector, ReconstructVector, TrainPQ, TrainWeightedPQ};
+
+mod view;
+pub use self::view::PQView;
+
+mod wal;
+pub use self::wal::{CodeLog, LogRecord, LogWrite, MutableCodes};
+
+mod weighted;
+pub use self::weighted::WeightedKMeansTrainer;
+
+// Quantizers and code stores must be safe for lock-free shared reads,
+// e.g. when they are shared between the threads of a server in an `Arc`.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<ChunkedCodes>();
+    assert_send_sync::<crate::cq::CompositeQuantizer<f32>>();
+    assert_send_sync::<DriftRefinement<f32>>();
+    assert_send_sync::<EncodedDataset<u8>>();
+    assert_send_sync::<FixedPQ<f32, 8>>();
+    #[cfg(feature = "gpu")]
+    assert_send_sync::<crate::gpu::GpuQuantizer>();
+    assert_send_sync::<crate::fp16::Fp16Quantizer>();
+    assert_send_sync::<crate::lsh::HyperplaneLSH<f32>>();
+    assert_send_sync::<crate::ivfpq::IvfPQ<f32>>();
+    assert_send_sync::<KMeansTrainer>();
+    assert_send_sync::<KvCodeStore<std::collections::BTreeMap<Vec<u8>, Vec<u8>>>>();
+    assert_send_sync::<crate::lattice::LatticeQuantizer<f32>>();
+    assert_send_sync::<crate::lsq::LocalSearchQuantizer<f32>>();
+    assert_send_sync::<crate::lopq::LocallyOptimizedPQ<f32>>();
+    assert_send_sync::<MixedBitsPQ<f32>>();
+    assert_send_sync::<crate::msq::MultiScalePQ<f32>>();
+    assert_send_sync::<MutableCodes<Vec<u8>>>();
+    assert_send_sync::<NibbleCodes>();
+    assert_send_sync::<crate::neq::NormExplicitPQ<f32>>();
+    assert_send_sync::<OnlinePQ<f32>>();
+    assert_send_sync::<PQ<f32>>();
+    assert_send_sync::<PQView<f32>>();
+    assert_send_sync::<PackedCodes<u8>>();
+    assert_send_sync::<crate::sq::QuantileScalarQuantizer<f32>>();
+    assert_send_sync::<QuantizedSequences<u8>>();
+    assert_send_sync::<QuantizedTable<f32>>();
+    assert_send_sync::<QuerySet<f32>>();
+    assert_send_sync::<crate::rabitq::RaBitQ<f32>>();
+    assert_send_sync::<crate::rabitq::RaBitQCodes<f32>>();
+    assert_send_sync::<crate::rabitq::RaBitQQuery<f32>>();
+    assert_send_sync::<crate::rq::ResidualQuantizer<f32>>();
+    assert_send_sync::<crate::sq::ScalarQuantizer<f32>>();
+    assert_send_sync::<TrainingManifest>();
+    assert_send_sync::<crate::tree::TreeQuantizer<f32>>();
+    assert_send_sync::<WeightedKMeansTrainer<f32>>();
+};
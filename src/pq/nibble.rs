@@ -0,0 +1,258 @@
+use std::iter::Sum;
+
+use ndarray::{s, Array2, ArrayBase, ArrayView2, Axis, Data, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::{PQView, QuantizeVector, ReconstructVector, PQ};
+
+/// Number of vectors that are quantized at once before packing.
+const PACK_BLOCK_LEN: usize = 4096;
+
+/// Quantization codes of 4-bit subquantizers, packed two per byte.
+///
+/// Product quantizers with at most 16 centroids per subquantizer only
+/// need 4 bits per code. This container stores the codes of two
+/// subquantizers in a byte, halving the memory use compared to `u8`
+/// codes. The code of subquantizer *2j* is stored in the low nibble of
+/// byte *j* and the code of subquantizer *2j + 1* in the high nibble.
+/// With an odd number of subquantizers, the high nibble of the last
+/// byte is zero.
+///
+/// This is the storage layout for fast-scan style search, where the
+/// nibbles are used to look up distances in small tables.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NibbleCodes {
+    bytes: Array2<u8>,
+    n_subquantizers: usize,
+}
+
+impl NibbleCodes {
+    /// Pack codes with shape *(n_vectors, n_subquantizers)*.
+    ///
+    /// Panics when a code does not fit in 4 bits.
+    pub fn pack<I, S>(codes: ArrayBase<S, Ix2>) -> Self
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let n_subquantizers = codes.ncols();
+        let mut bytes = Array2::zeros((codes.nrows(), packed_len(n_subquantizers)));
+        for (codes, mut bytes) in codes.outer_iter().zip(bytes.outer_iter_mut()) {
+            for (sq, &code) in codes.iter().enumerate() {
+                let code = code.as_();
+                assert!(
+                    code < 16,
+                    "Code {} of subquantizer {} does not fit in 4 bits",
+                    code,
+                    sq
+                );
+                bytes[sq / 2] |= (code as u8) << (4 * (sq % 2));
+            }
+        }
+
+        NibbleCodes {
+            bytes,
+            n_subquantizers,
+        }
+    }
+
+    /// Construct codes from packed bytes.
+    ///
+    /// `bytes` has a row of *ceil(n_subquantizers / 2)* bytes per vector.
+    pub fn from_bytes(bytes: Array2<u8>, n_subquantizers: usize) -> Self {
+        assert_eq!(
+            bytes.ncols(),
+            packed_len(n_subquantizers),
+            "Packed codes of {} subquantizers should have {} bytes per vector",
+            n_subquantizers,
+            packed_len(n_subquantizers)
+        );
+        if n_subquantizers % 2 == 1 {
+            assert!(
+                bytes.column(bytes.ncols() - 1).iter().all(|&b| b >> 4 == 0),
+                "The unused high nibble of the last byte should be zero"
+            );
+        }
+
+        NibbleCodes {
+            bytes,
+            n_subquantizers,
+        }
+    }
+
+    /// Get the packed bytes, one row per vector.
+    pub fn bytes(&self) -> ArrayView2<u8> {
+        self.bytes.view()
+    }
+
+    /// Get the code of subquantizer `subquantizer` of vector `row`.
+    pub fn code(&self, row: usize, subquantizer: usize) -> u8 {
+        assert!(
+            subquantizer < self.n_subquantizers,
+            "Subquantizer {} is out of range, there are {} subquantizers",
+            subquantizer,
+            self.n_subquantizers
+        );
+
+        (self.bytes[(row, subquantizer / 2)] >> (4 * (subquantizer % 2))) & 0xf
+    }
+
+    /// Get the number of vectors.
+    pub fn len(&self) -> usize {
+        self.bytes.nrows()
+    }
+
+    /// Check whether there are no vectors.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of subquantizers.
+    pub fn n_subquantizers(&self) -> usize {
+        self.n_subquantizers
+    }
+
+    /// Unpack the codes into a matrix with one code per byte.
+    pub fn unpack(&self) -> Array2<u8> {
+        let mut codes = Array2::zeros((self.len(), self.n_subquantizers));
+        for (bytes, mut codes) in self.bytes.outer_iter().zip(codes.outer_iter_mut()) {
+            for (sq, code) in codes.iter_mut().enumerate() {
+                *code = (bytes[sq / 2] >> (4 * (sq % 2))) & 0xf;
+            }
+        }
+
+        codes
+    }
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors into 4-bit packed codes.
+    ///
+    /// The quantizer must have at most 16 centroids per subquantizer.
+    /// Vectors are quantized in blocks, so that the unpacked codes of
+    /// the whole batch are never stored.
+    pub fn quantize_batch_nibbles<S>(&self, x: ArrayBase<S, Ix2>) -> NibbleCodes
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            self.n_quantizer_centroids() <= 16,
+            "Cannot store codes of {} centroids in 4 bits",
+            self.n_quantizer_centroids()
+        );
+
+        let n_subquantizers = self.quantized_len();
+        let mut bytes = Array2::zeros((x.nrows(), packed_len(n_subquantizers)));
+        for (x, mut bytes) in x
+            .axis_chunks_iter(Axis(0), PACK_BLOCK_LEN)
+            .zip(bytes.axis_chunks_iter_mut(Axis(0), PACK_BLOCK_LEN))
+        {
+            let codes: Array2<u8> = self.quantize_batch(x);
+            bytes.assign(&NibbleCodes::pack(codes).bytes);
+        }
+
+        NibbleCodes {
+            bytes,
+            n_subquantizers,
+        }
+    }
+
+    /// Reconstruct a batch of vectors from 4-bit packed codes.
+    pub fn reconstruct_batch_nibbles(&self, codes: &NibbleCodes) -> Array2<A> {
+        assert_eq!(
+            codes.n_subquantizers(),
+            self.quantized_len(),
+            "Quantization length does not match number of subquantizers"
+        );
+
+        let mut reconstructions = Array2::zeros((codes.len(), self.reconstructed_len()));
+        for (block, mut reconstructions) in reconstructions
+            .axis_chunks_iter_mut(Axis(0), PACK_BLOCK_LEN)
+            .enumerate()
+        {
+            let offset = block * PACK_BLOCK_LEN;
+            let block_codes = NibbleCodes {
+                bytes: codes
+                    .bytes
+                    .slice(s![offset..offset + reconstructions.nrows(), ..])
+                    .to_owned(),
+                n_subquantizers: codes.n_subquantizers,
+            };
+            self.reconstruct_batch_into(block_codes.unpack(), reconstructions.view_mut());
+        }
+
+        reconstructions
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors into 4-bit packed codes.
+    ///
+    /// See `PQView::quantize_batch_nibbles`.
+    pub fn quantize_batch_nibbles<S>(&self, x: ArrayBase<S, Ix2>) -> NibbleCodes
+    where
+        S: Data<Elem = A>,
+    {
+        self.view().quantize_batch_nibbles(x)
+    }
+
+    /// Reconstruct a batch of vectors from 4-bit packed codes.
+    ///
+    /// See `PQView::reconstruct_batch_nibbles`.
+    pub fn reconstruct_batch_nibbles(&self, codes: &NibbleCodes) -> Array2<A> {
+        self.view().reconstruct_batch_nibbles(codes)
+    }
+}
+
+/// Number of bytes of the packed codes of a vector.
+fn packed_len(n_subquantizers: usize) -> usize {
+    n_subquantizers / 2 + n_subquantizers % 2
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::NibbleCodes;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    #[test]
+    fn nibble_codes_roundtrip() {
+        let codes = array![[1u8, 15, 7], [0, 3, 12]];
+        let packed = NibbleCodes::pack(codes.view());
+        assert_eq!(packed.bytes(), array![[0xf1, 0x07], [0x30, 0x0c]]);
+        assert_eq!(packed.code(1, 2), 12);
+        assert_eq!(packed.unpack(), codes);
+        assert_eq!(
+            NibbleCodes::from_bytes(packed.bytes().to_owned(), 3),
+            packed
+        );
+    }
+
+    #[test]
+    fn quantize_batch_nibbles_matches_unpacked() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 10), Uniform::new(-1., 1.), &mut rng);
+        let pq = PQ::train_pq_using(5, 4, 5, 1, instances.view(), &mut rng);
+
+        let codes = pq.quantize_batch_nibbles(instances.view());
+        assert_eq!(codes.bytes().dim(), (256, 3));
+        let unpacked: Array2<u8> = pq.quantize_batch(instances.view());
+        assert_eq!(codes.unpack(), unpacked);
+        assert_eq!(
+            pq.reconstruct_batch_nibbles(&codes),
+            pq.reconstruct_batch(unpacked)
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, Axis, Data, Ix2, NdFloat};
+
+use super::PQ;
+use crate::error::Error;
+
+/// Default tolerance of the L2 norm of normalized vectors.
+pub const DEFAULT_NORM_TOLERANCE: f64 = 1e-3;
+
+/// Input normalization of a quantizer.
+///
+/// Quantizers are only accurate for vectors that were normalized in the
+/// same way as the training data. Storing the normalization in the
+/// quantizer avoids serving unnormalized vectors to a quantizer that was
+/// trained on normalized vectors. See `PQ::with_normalization`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Normalization<A> {
+    /// Vectors are quantized as-is.
+    None,
+
+    /// Vectors are normalized to unit L2 norm.
+    L2,
+
+    /// The given mean is subtracted from vectors.
+    MeanCentering(Array1<A>),
+}
+
+impl<A> Normalization<A> {
+    /// Get the name of the normalization.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Normalization::None => "None",
+            Normalization::L2 => "L2",
+            Normalization::MeanCentering(_) => "MeanCentering",
+        }
+    }
+}
+
+impl<A> Normalization<A>
+where
+    A: NdFloat,
+{
+    /// Construct mean centering with the mean of `instances`.
+    pub fn mean_centering<S>(instances: ArrayBase<S, Ix2>) -> Self
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            instances.nrows() > 0,
+            "Cannot compute the mean without instances"
+        );
+
+        Normalization::MeanCentering(
+            instances.sum_axis(Axis(0)) / A::from(instances.nrows()).expect("Cannot convert count"),
+        )
+    }
+
+    /// Get the mean of mean centering.
+    pub fn mean(&self) -> Option<ArrayView1<A>> {
+        match self {
+            Normalization::MeanCentering(mean) => Some(mean.view()),
+            _ => None,
+        }
+    }
+
+    /// Normalize a batch of vectors.
+    ///
+    /// Without normalization, the vectors are copied. Vectors with a
+    /// zero norm are not changed by L2 normalization.
+    pub fn normalize_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        match self {
+            Normalization::None => x.to_owned(),
+            Normalization::L2 => {
+                let mut normalized = x.to_owned();
+                for mut row in normalized.outer_iter_mut() {
+                    let norm = row.dot(&row).sqrt();
+                    if norm > A::zero() {
+                        row /= norm;
+                    }
+                }
+                normalized
+            }
+            Normalization::MeanCentering(mean) => {
+                assert_eq!(mean.len(), x.ncols(), "Mean and vector length mismatch");
+                &x - mean
+            }
+        }
+    }
+
+    /// Check that a batch of vectors is normalized.
+    ///
+    /// For L2 normalization, every vector must have a norm within
+    /// `tolerance` of one. Mean centering is a property of a set of
+    /// vectors rather than of individual vectors, so it is not checked.
+    pub fn check_batch<S>(&self, x: ArrayBase<S, Ix2>, tolerance: A) -> Result<(), Error>
+    where
+        S: Data<Elem = A>,
+    {
+        if let Normalization::L2 = self {
+            for (row, v) in x.outer_iter().enumerate() {
+                let norm = v.dot(&v).sqrt();
+                if norm.is_nan() || (norm - A::one()).abs() > tolerance {
+                    return Err(Error::NotNormalized {
+                        row,
+                        reason: format!("L2 norm is {}, should be 1", norm),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat,
+{
+    /// Set the input normalization of the quantizer.
+    ///
+    /// All methods that quantize vectors or compute distances to
+    /// queries normalize their inputs, so that callers cannot forget to.
+    /// This includes the methods of views of the quantizer and the
+    /// quantizers that wrap it. Reconstructions approximate the
+    /// normalized vectors. The normalization is part of the fingerprint
+    /// of the quantizer.
+    pub fn with_normalization(mut self, normalization: Normalization<A>) -> Self {
+        if let Normalization::MeanCentering(mean) = &normalization {
+            assert_eq!(
+                mean.len(),
+                self.quantizers.len_of(Axis(0)) * self.quantizers.len_of(Axis(2)),
+                "Mean and quantizer length mismatch"
+            );
+        }
+
+        if let Some(manifest) = self.manifest.take() {
+            self.manifest = Some(manifest.with_normalization(&normalization));
+        }
+
+        self.normalization = normalization;
+        self
+    }
+
+    /// Get the input normalization of the quantizer.
+    pub fn normalization(&self) -> &Normalization<A> {
+        &self.normalization
+    }
+
+    /// Normalize a batch of vectors with the normalization of the
+    /// quantizer.
+    pub fn normalize_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.normalization.normalize_batch(x)
+    }
+
+    /// Check that a batch of vectors has the normalization of the
+    /// quantizer, with the tolerance `DEFAULT_NORM_TOLERANCE`.
+    ///
+    /// See `Normalization::check_batch`.
+    pub fn check_normalization<S>(&self, x: ArrayBase<S, Ix2>) -> Result<(), Error>
+    where
+        S: Data<Elem = A>,
+    {
+        self.normalization
+            .check_batch(x, A::from(DEFAULT_NORM_TOLERANCE).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+
+    use super::Normalization;
+    use crate::error::Error;
+    use crate::pq::{QuantizeVector, PQ};
+
+    #[test]
+    fn quantizer_applies_normalization() {
+        let pq = PQ::new(None, array![[[1f32, 0.], [0., 1.], [0., 0.]]]);
+        let x = array![[0.4f32, 0.], [0., 0.25]];
+
+        // Unnormalized, both vectors are closest to the zero centroid.
+        let quantized: Array2<u8> = pq.quantize_batch(x.view());
+        assert_eq!(quantized, array![[2], [2]]);
+
+        let l2 = pq.clone().with_normalization(Normalization::L2);
+        assert_eq!(l2.normalize_batch(x.view()), array![[1., 0.], [0., 1.]]);
+        let quantized: Array2<u8> = l2.quantize_batch(x.view());
+        assert_eq!(quantized, array![[0], [1]]);
+        assert_eq!(l2.quantize_vector::<u8, _>(x.row(1)), array![1]);
+        assert_eq!(
+            l2.check_normalization(x.view()),
+            Err(Error::NotNormalized {
+                row: 0,
+                reason: "L2 norm is 0.4, should be 1".to_string()
+            })
+        );
+        assert_eq!(l2.check_normalization(l2.normalize_batch(x.view())), Ok(()));
+
+        let centered = pq.with_normalization(Normalization::mean_centering(x.view()));
+        assert_eq!(
+            centered.normalization().mean(),
+            Some(array![0.2, 0.125].view())
+        );
+        let quantized: Array2<u8> = centered.quantize_batch(x.view());
+        assert_eq!(
+            quantized,
+            centered
+                .rotated()
+                .quantize_batch::<u8, _>(array![[0.2, -0.125], [-0.2, 0.125]])
+        );
+    }
+
+    #[test]
+    fn all_entry_points_apply_normalization() {
+        let l2 = PQ::new(None, array![[[1f32, 0.], [0., 1.], [0., 0.]]])
+            .with_normalization(Normalization::L2);
+        let x = array![[0.4f32, 0.], [0., 0.25]];
+        let expected = array![[0u8], [1]];
+
+        let view = l2.view();
+        assert_eq!(view.normalization(), &Normalization::L2);
+        assert_eq!(view.quantize_batch::<u8, _>(x.view()), expected);
+        assert_eq!(view.quantize_vector::<u8, _>(x.row(1)), array![1]);
+        assert_eq!(view.to_owned(), l2);
+        assert_eq!(l2.rotate_batch(x.view()), array![[1., 0.], [0., 1.]]);
+        assert_eq!(
+            l2.adc_tables_batch(x.view()),
+            l2.adc_tables_batch(array![[1., 0.], [0., 1.]])
+        );
+
+        // The int8 vectors are dequantized to [0.1, 0] and [0, 0.1].
+        let x_i8 = array![[1i8, 0], [0, 1]];
+        assert_eq!(l2.quantize_batch_i8::<u8, _>(x_i8, 0.1, 0), expected);
+
+        let mut sink = Vec::new();
+        l2.quantize_batch_into_sink(x.view(), &mut sink, 0).unwrap();
+        assert_eq!(sink, vec![0, 1]);
+    }
+}
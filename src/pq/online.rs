@@ -0,0 +1,294 @@
+use std::iter::Sum;
+use std::time::Instant;
+
+use ndarray::{s, Array2, ArrayBase, Axis, Data, Ix1, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::{ReconstructVector, TrainingManifest, PQ};
+use crate::kmeans::{cluster_assignments, sequential_update};
+
+/// Product quantizer with online codebook updates.
+///
+/// This wraps a trained product quantizer and adapts its subquantizers
+/// to a stream of instances using sequential k-means (see
+/// `kmeans::SequentialKMeans`). The projection matrix of an optimized
+/// product quantizer is not updated.
+#[derive(Clone, Debug)]
+pub struct OnlinePQ<A> {
+    pq: PQ<A>,
+    counts: Array2<usize>,
+    min_learning_rate: A,
+}
+
+impl<A> OnlinePQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Construct an online product quantizer.
+    ///
+    /// Each centroid is treated as if `initial_count` instances were
+    /// assigned to it. Centroids are updated with learning rate
+    /// *max(1 / (n + 1), min_learning_rate)*, where *n* is the number of
+    /// instances that were assigned to the centroid before.
+    pub fn new(pq: PQ<A>, initial_count: usize, min_learning_rate: A) -> Self {
+        assert!(
+            min_learning_rate >= A::zero() && min_learning_rate <= A::one(),
+            "The minimum learning rate should be in [0, 1]"
+        );
+
+        let counts = Array2::from_elem(
+            (pq.quantizers.len_of(Axis(0)), pq.n_quantizer_centroids()),
+            initial_count,
+        );
+
+        OnlinePQ {
+            pq,
+            counts,
+            min_learning_rate,
+        }
+    }
+
+    /// Get the product quantizer.
+    pub fn quantizer(&self) -> &PQ<A> {
+        &self.pq
+    }
+
+    /// Get the product quantizer, consuming the online quantizer.
+    pub fn into_inner(self) -> PQ<A> {
+        self.pq
+    }
+
+    /// Update the subquantizers with an instance.
+    ///
+    /// As with `PQ::update`, polysemous codes are discarded and the
+    /// update is recorded in an `OnlinePQ` manifest.
+    pub fn update<S>(&mut self, instance: ArrayBase<S, Ix1>)
+    where
+        S: Data<Elem = A>,
+    {
+        assert_eq!(
+            instance.len(),
+            self.pq.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+
+        let start = Instant::now();
+        let instance = self.pq.rotate_vector(instance);
+
+        let sq_dims = self.pq.quantizers.len_of(Axis(2));
+        for (sq, (quantizer, counts)) in self
+            .pq
+            .quantizers
+            .outer_iter_mut()
+            .zip(self.counts.outer_iter_mut())
+            .enumerate()
+        {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_instance = instance.slice(s![offset..offset + sq_dims]);
+            sequential_update(quantizer, counts, sub_instance, self.min_learning_rate);
+        }
+
+        self.pq.record_online_update((1, instance.len()), start);
+    }
+
+    /// Update the subquantizers with a batch of instances.
+    ///
+    /// The instances are the rows of `instances`, which are processed
+    /// in order.
+    pub fn update_batch<S>(&mut self, instances: ArrayBase<S, Ix2>)
+    where
+        S: Data<Elem = A>,
+    {
+        for instance in instances.outer_iter() {
+            self.update(instance);
+        }
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Update the subquantizers with a batch of instances.
+    ///
+    /// This is an incremental alternative to retraining for tracking a
+    /// drifting data distribution (mini-batch k-means, Sculley, 2010).
+    /// The instances are assigned to the nearest centroids of every
+    /// subquantizer. Each centroid with assigned instances then moves
+    /// towards the mean of its instances by `learning_rate`, so a
+    /// learning rate of one replaces the centroid by the mean. Centroids
+    /// without instances are not changed. The projection matrix of an
+    /// optimized product quantizer is not updated.
+    ///
+    /// Returns the mean squared quantization error of the instances
+    /// before the update, which can be used to monitor drift.
+    ///
+    /// Codes that were computed before the update refer to the updated
+    /// centroids, so stored codes should be recomputed when the
+    /// codebooks moved substantially. Polysemous codes (see
+    /// `PQ::polysemous_using`) are discarded, since they do not match
+    /// the updated codebooks. The training manifest is replaced by an
+    /// `OnlinePQ` manifest that counts the updates and their instances.
+    /// Use `OnlinePQ` to update the codebooks one instance at a time.
+    pub fn update<S>(&mut self, instances: ArrayBase<S, Ix2>, learning_rate: A) -> A
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            learning_rate > A::zero() && learning_rate <= A::one(),
+            "The learning rate should be in (0, 1], was: {}",
+            learning_rate
+        );
+        assert_eq!(
+            instances.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+
+        if instances.nrows() == 0 {
+            return A::zero();
+        }
+
+        let start = Instant::now();
+        let instances = self.rotate_batch(instances);
+        let sq_dims = self.quantizers.len_of(Axis(2));
+
+        let mut sq_error = A::zero();
+        for (sq, mut quantizer) in self.quantizers.outer_iter_mut().enumerate() {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_instances = instances.slice(s![.., offset..offset + sq_dims]);
+            let assignments = cluster_assignments(quantizer.view(), sub_instances, Axis(0));
+
+            let mut sums = Array2::<A>::zeros(quantizer.dim());
+            let mut counts = vec![0usize; quantizer.nrows()];
+            for (instance, &cluster) in sub_instances.outer_iter().zip(assignments.iter()) {
+                let diff = &instance - &quantizer.row(cluster);
+                sq_error += diff.dot(&diff);
+
+                let mut sum = sums.row_mut(cluster);
+                sum += &instance;
+                counts[cluster] += 1;
+            }
+
+            for ((mut centroid, sum), &count) in quantizer
+                .outer_iter_mut()
+                .zip(sums.outer_iter())
+                .zip(counts.iter())
+            {
+                if count > 0 {
+                    let mean = &sum / count.as_();
+                    centroid.zip_mut_with(&mean, |c, &m| *c += (m - *c) * learning_rate);
+                }
+            }
+        }
+
+        self.record_online_update(instances.dim(), start);
+
+        sq_error / instances.nrows().as_()
+    }
+
+    /// Record an online update of the codebooks.
+    ///
+    /// An existing `OnlinePQ` manifest is updated with the update
+    /// instances, any other manifest is replaced.
+    fn record_online_update(&mut self, shape: (usize, usize), start: Instant) {
+        self.polysemous = None;
+
+        match self.manifest.as_mut() {
+            Some(manifest) if manifest.quantizer == "OnlinePQ" => {
+                manifest.n_iterations += 1;
+                manifest.n_instances += shape.0;
+                manifest.duration += start.elapsed();
+            }
+            _ => {
+                self.manifest = Some(
+                    TrainingManifest::new(
+                        "OnlinePQ",
+                        self.quantizers.len_of(Axis(0)),
+                        self.n_quantizer_centroids()
+                            .next_power_of_two()
+                            .trailing_zeros(),
+                        1,
+                        1,
+                        shape,
+                        start,
+                    )
+                    .with_normalization(&self.normalization),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::OnlinePQ;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{TrainPQ, PQ};
+
+    #[test]
+    fn online_pq_adapts_centroids() {
+        let pq = PQ::new(None, array![[[0f64], [10.]], [[0.], [10.]]]);
+        let mut online = OnlinePQ::new(pq, 1, 0.);
+        online.update_batch(array![[2., 8.], [4., 14.]].view());
+        let pq = online.into_inner();
+        assert_eq!(
+            pq.subquantizers(),
+            array![[[2.], [10.]], [[0.], [32. / 3.]]]
+        );
+        assert_eq!(pq.manifest().unwrap().quantizer, "OnlinePQ");
+        assert_eq!(pq.manifest().unwrap().n_instances, 2);
+    }
+
+    #[test]
+    fn update_tracks_drifting_distribution() {
+        let mut pq = PQ::new(None, array![[[0f64], [10.]], [[0.], [10.]]]);
+        let error = pq.update(array![[2., 8.], [4., 14.]].view(), 0.5);
+        assert_eq!(error, (4. + 16. + 4. + 16.) / 2.);
+        assert_eq!(pq.subquantizers(), array![[[1.5], [10.]], [[0.], [10.5]]]);
+
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((512, 4), Uniform::new(-1., 1.), &mut rng);
+        let mut pq = PQ::train_pq_using(2, 3, 10, 1, instances.view(), &mut rng);
+
+        // Shifting the distribution increases the error, updates with
+        // batches of the shifted distribution reduce it again.
+        let shifted = instances.mapv(|v| v + 2.);
+        let initial_error = pq.update(shifted.view(), 0.5);
+        let mut error = initial_error;
+        for _ in 0..10 {
+            error = pq.update(shifted.view(), 0.5);
+        }
+        assert!(error < initial_error / 10.);
+
+        let manifest = pq.manifest().unwrap();
+        assert_eq!(manifest.quantizer, "OnlinePQ");
+        assert_eq!(manifest.n_iterations, 11);
+        assert_eq!(manifest.n_instances, 11 * 512);
+    }
+
+    #[test]
+    fn update_discards_polysemous_codes() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((512, 4), Uniform::new(-1., 1.), &mut rng);
+        let mut pq = PQ::train_pq_using(2, 3, 10, 1, instances.view(), &mut rng)
+            .polysemous_using(10, &mut rng);
+        assert!(pq.hamming_filterable());
+
+        pq.update(instances.view(), 0.5);
+        assert!(!pq.hamming_filterable());
+    }
+}
@@ -1,24 +1,31 @@
 //! Product quantization.
 
-use std::iter::Sum;
+use std::collections::BTreeMap;
+use std::iter::{self, Sum};
+use std::time::Instant;
 
 use lax::{Lapack, UPLO};
 use log::info;
 use ndarray::{
-    concatenate, s, Array2, ArrayBase, ArrayView2, ArrayViewMut2, ArrayViewMut3, Axis, Data, Ix1,
-    Ix2, NdFloat,
+    s, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, ArrayViewMut3, Axis, Data,
+    Ix1, Ix2, NdFloat,
 };
 use ndarray_linalg::{eigh::Eigh, svd::SVD, types::Scalar};
-use num_traits::AsPrimitive;
-use ordered_float::OrderedFloat;
+use num_traits::{AsPrimitive, ToPrimitive};
+use rand::seq::index;
 use rand::{Rng, RngCore};
 use rayon::prelude::*;
 
-use crate::kmeans::KMeansIteration;
-use crate::linalg::Covariance;
+use crate::float_ord::{float_cmp, min_by_float_key};
+use crate::kmeans::{kmeans_iteration_with_scratch, CentroidInitialization, KMeansScratch};
+use crate::linalg::{par_cross_product, Covariance};
 
+use super::anisotropic::{encode_anisotropic, update_codebooks};
 use super::primitives;
-use super::{TrainPQ, PQ};
+use super::{Normalization, TrainPQ, TrainingManifest, PQ};
+
+/// Number of rows per block of the OPQ rotation cross term.
+const ROTATION_BLOCK_LEN: usize = 4096;
 
 /// Optimized product quantizer (Ge et al., 2013).
 ///
@@ -37,6 +44,53 @@ use super::{TrainPQ, PQ};
 /// no effect.
 pub struct OPQ;
 
+/// Objective of OPQ training.
+///
+/// See `OPQ::train_pq_objective_using`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OPQObjective<A> {
+    /// Minimize the squared Euclidean reconstruction error.
+    Reconstruction,
+
+    /// Preserve inner products with the anisotropic loss.
+    ///
+    /// The parallel component of the quantization error is weighed
+    /// the given number of times as much as the orthogonal component
+    /// (see `PQ::train_anisotropic_using`).
+    InnerProduct(A),
+}
+
+/// Snapshot of OPQ training after an outer iteration.
+///
+/// See `OPQ::train_pq_with_snapshots_using`.
+#[derive(Clone, Debug)]
+pub struct OPQSnapshot<'a, A> {
+    /// The index of the outer iteration.
+    pub iteration: usize,
+
+    /// The mean squared error between the rotated instances and their
+    /// reconstructions in the iteration.
+    pub objective: A,
+
+    /// The current projection matrix.
+    pub projection: ArrayView2<'a, A>,
+
+    /// The current subquantizer centroids.
+    pub quantizers: ArrayView3<'a, A>,
+}
+
+impl<'a, A> OPQSnapshot<'a, A>
+where
+    A: NdFloat,
+{
+    /// Construct a quantizer from the snapshot.
+    ///
+    /// The quantizer has no training manifest.
+    pub fn to_pq(&self) -> PQ<A> {
+        PQ::new(Some(self.projection.to_owned()), self.quantizers.to_owned())
+    }
+}
+
 impl<A> TrainPQ<A> for OPQ
 where
     A: Lapack + NdFloat + Scalar + Sum,
@@ -49,12 +103,312 @@ where
         n_iterations: usize,
         _n_attempts: usize,
         instances: ArrayBase<S, Ix2>,
-        mut rng: R,
+        rng: R,
     ) -> PQ<A>
     where
         S: Sync + Data<Elem = A>,
         R: RngCore,
     {
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            instances.view(),
+            None,
+            OPQObjective::Reconstruction,
+            CentroidInitialization::RandomInstances,
+            &mut |_| true,
+            rng,
+        )
+    }
+}
+
+impl OPQ {
+    /// Train a product quantizer, updating the rotation on a subsample.
+    ///
+    /// The rotation update computes the *d × d* cross term of the
+    /// instances and their reconstructions and its SVD. For large
+    /// datasets, computing the cross term dominates training. This
+    /// constructor computes the cross term on `n_rotation_samples`
+    /// instances that are sampled once before training. The
+    /// subquantizers are still trained on all instances.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    pub fn train_pq_rotation_subsampled_using<A, S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        n_rotation_samples: usize,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_rotation_samples >= instances.ncols(),
+            "At least {} rotation samples are required, got: {}",
+            instances.ncols(),
+            n_rotation_samples
+        );
+
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            instances.view(),
+            Some(n_rotation_samples),
+            OPQObjective::Reconstruction,
+            CentroidInitialization::RandomInstances,
+            &mut |_| true,
+            rng,
+        )
+    }
+
+    /// Train a product quantizer, observing every outer iteration.
+    ///
+    /// `snapshot` is called after every outer iteration with the
+    /// current rotation, codebooks, and objective. This can be used to
+    /// visualize convergence or to keep the best intermediate
+    /// quantizer by a custom criterion (see `OPQSnapshot::to_pq`).
+    /// Training stops early when `snapshot` returns `false`, the
+    /// quantizer of the last iteration is then returned.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    pub fn train_pq_with_snapshots_using<A, S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut snapshot: impl FnMut(&OPQSnapshot<A>) -> bool,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            instances.view(),
+            None,
+            OPQObjective::Reconstruction,
+            CentroidInitialization::RandomInstances,
+            &mut snapshot,
+            rng,
+        )
+    }
+
+    /// Train a product quantizer with the given objective.
+    ///
+    /// With `OPQObjective::Reconstruction`, this is the same as
+    /// `TrainPQ::train_pq_using`. With `OPQObjective::InnerProduct`,
+    /// vectors are encoded and codebooks are updated with the anisotropic
+    /// loss in the rotated space, which is better suited to scoring by
+    /// inner product. The norms and the parallel components of vectors
+    /// are preserved by the rotation, so this is the same loss as in the
+    /// original space. The rotation is still updated to minimize the
+    /// Euclidean error for the current codes, so the objective is not
+    /// guaranteed to decrease in every iteration.
+    ///
+    /// The objective that is reported in snapshots and in the training
+    /// manifest is the mean anisotropic loss per instance. Quantize
+    /// vectors with `PQ::quantize_batch_anisotropic` to use the loss
+    /// during encoding as well.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    pub fn train_pq_objective_using<A, S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        objective: OPQObjective<A>,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        if let OPQObjective::InnerProduct(eta) = objective {
+            assert!(
+                eta >= A::one(),
+                "The parallel error weight should at least be 1, was: {}",
+                eta
+            );
+        }
+
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            instances.view(),
+            None,
+            objective,
+            CentroidInitialization::RandomInstances,
+            &mut |_| true,
+            rng,
+        )
+    }
+
+    /// Train a product quantizer with the given centroid initialization.
+    ///
+    /// The initial codebooks are picked with `initialization` from the
+    /// instances, rotated by the initial projection. The other
+    /// constructors use `CentroidInitialization::RandomInstances`.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    pub fn train_pq_initialized_using<A, S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        initialization: CentroidInitialization,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        Self::train(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            instances.view(),
+            None,
+            OPQObjective::Reconstruction,
+            initialization,
+            &mut |_| true,
+            rng,
+        )
+    }
+
+    /// Train the projection of a quantizer with fixed codebooks.
+    ///
+    /// This is the reverse of the usual training: the codebooks of `pq`
+    /// are kept fixed and only the rotation is optimized for
+    /// `n_iterations` iterations, starting from the projection of `pq`
+    /// (or the identity matrix). This is useful when the codebooks must
+    /// remain compatible with existing codes, but the vectors may be
+    /// rotated differently, e.g. when the distribution of the vectors
+    /// shifted.
+    ///
+    /// Since every iteration finds the optimal rotation for the current
+    /// codes, the objective does not increase over the iterations. The
+    /// objective of every iteration is stored in the training manifest.
+    ///
+    /// The instances are normalized with the normalization of `pq`
+    /// before fitting the rotation. The returned quantizer keeps the
+    /// normalization, metadata, and polysemous code assignment of `pq`,
+    /// since the codebooks do not change.
+    pub fn train_projection<A, S>(
+        pq: &PQ<A>,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        let start = Instant::now();
+
+        let n_dims = primitives::reconstructed_len(pq.quantizers.view());
+        assert_eq!(
+            instances.ncols(),
+            n_dims,
+            "Quantizer and instance length mismatch"
+        );
+        assert!(
+            n_iterations > 0,
+            "The projection should be optimized for at least one iteration."
+        );
+
+        let instances = pq.normalize_batch(instances);
+        let mut projection = pq.projection.clone().unwrap_or_else(|| Array2::eye(n_dims));
+        let quantizers = pq.quantizers.view();
+
+        let mut objective = Vec::with_capacity(n_iterations);
+        for i in 0..n_iterations {
+            let rx = instances.dot(&projection);
+            let quantized = primitives::quantize_batch::<_, usize, _>(quantizers, rx.view());
+            let mut reconstructed = Array2::zeros(rx.dim());
+            primitives::reconstruct_batch_into(quantizers, quantized, reconstructed.view_mut());
+
+            let loss = (&rx - &reconstructed).iter().map(|&v| v * v).sum::<A>() / rx.len().as_();
+            info!("Objective before iteration {}: {}", i, loss);
+            objective.push(ToPrimitive::to_f64(&loss).unwrap());
+
+            Self::update_projection(
+                projection.view_mut(),
+                instances.view(),
+                reconstructed.view(),
+                None,
+            );
+        }
+
+        let mut manifest = TrainingManifest::new(
+            "OPQ",
+            quantizers.len_of(Axis(0)),
+            pq.n_quantizer_centroids()
+                .next_power_of_two()
+                .trailing_zeros(),
+            n_iterations,
+            1,
+            instances.dim(),
+            start,
+        )
+        .with_normalization(&pq.normalization);
+        manifest.objective = objective;
+
+        PQ {
+            projection: Some(projection),
+            quantizers: pq.quantizers.clone(),
+            manifest: Some(manifest),
+            polysemous: pq.polysemous.clone(),
+            normalization: pq.normalization.clone(),
+            metadata: pq.metadata.clone(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn train<A, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        instances: ArrayView2<A>,
+        n_rotation_samples: Option<usize>,
+        objective_kind: OPQObjective<A>,
+        initialization: CentroidInitialization,
+        snapshot: &mut dyn FnMut(&OPQSnapshot<A>) -> bool,
+        mut rng: R,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        let start = Instant::now();
+
         PQ::check_quantizer_invariants(
             n_subquantizers,
             n_subquantizer_bits,
@@ -68,38 +422,90 @@ where
         let rx = instances.dot(&projection);
 
         // Pick centroids.
-        let centroids = Self::initial_centroids(
+        let mut quantizers = Self::initial_centroids(
             n_subquantizers,
             2usize.pow(n_subquantizer_bits),
             rx.view(),
+            initialization,
             &mut rng,
         );
 
-        let views = centroids
-            .iter()
-            .map(|c| c.view().insert_axis(Axis(0)))
-            .collect::<Vec<_>>();
-        let mut quantizers =
-            concatenate(Axis(0), &views).expect("Cannot concatenate subquantizers");
+        // Pick the instances that are used in rotation updates.
+        let rotation_samples = n_rotation_samples
+            .filter(|&n_samples| n_samples < instances.nrows())
+            .map(|n_samples| {
+                let mut samples = index::sample(&mut rng, instances.nrows(), n_samples).into_vec();
+                samples.sort_unstable();
+                samples
+            });
 
         // Iteratively refine the clusters and the projection matrix.
+        let mut objective = Vec::with_capacity(n_iterations);
+        let mut scratches = iter::repeat_with(KMeansScratch::new)
+            .take(n_subquantizers)
+            .collect::<Vec<_>>();
         for i in 0..n_iterations {
             info!("Train iteration {}", i);
-            Self::train_iteration(
+            let loss = Self::train_iteration(
                 projection.view_mut(),
                 quantizers.view_mut(),
                 instances.view(),
+                rotation_samples.as_deref(),
+                objective_kind,
+                &mut scratches,
             );
+            info!("Objective after iteration {}: {}", i, loss);
+            objective.push(ToPrimitive::to_f64(&loss).unwrap());
+
+            let proceed = snapshot(&OPQSnapshot {
+                iteration: i,
+                objective: loss,
+                projection: projection.view(),
+                quantizers: quantizers.view(),
+            });
+            if !proceed {
+                info!("Training stopped after iteration {}", i);
+                break;
+            }
         }
 
+        let name = match objective_kind {
+            OPQObjective::Reconstruction => "OPQ",
+            OPQObjective::InnerProduct(_) => "InnerProductOPQ",
+        };
+        // Record the iterations that were run, training may have been
+        // stopped early by the snapshot callback.
+        let mut manifest = TrainingManifest::new(
+            name,
+            n_subquantizers,
+            n_subquantizer_bits,
+            objective.len(),
+            1,
+            instances.dim(),
+            start,
+        )
+        .with_rng::<R>()
+        .with_setting("objective", format!("{:?}", objective_kind))
+        .with_setting("initialization", format!("{:?}", initialization))
+        .with_setting(
+            "n_rotation_samples",
+            match n_rotation_samples {
+                Some(n_samples) => n_samples.to_string(),
+                None => "all".to_string(),
+            },
+        );
+        manifest.objective = objective;
+
         PQ {
             projection: Some(projection),
             quantizers,
+            manifest: Some(manifest),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         }
     }
-}
 
-impl OPQ {
     pub(crate) fn create_projection_matrix<A>(
         instances: ArrayView2<A>,
         n_subquantizers: usize,
@@ -139,57 +545,133 @@ impl OPQ {
         n_subquantizers: usize,
         codebook_len: usize,
         instances: ArrayBase<S, Ix2>,
+        initialization: CentroidInitialization,
         rng: &mut impl Rng,
-    ) -> Vec<Array2<A>>
+    ) -> Array3<A>
     where
         S: Data<Elem = A>,
-        A: NdFloat,
+        A: NdFloat + Sum,
+        usize: AsPrimitive<A>,
     {
-        (0..n_subquantizers)
-            .map(|sq| {
-                PQ::subquantizer_initial_centroids(
-                    sq,
-                    n_subquantizers,
-                    codebook_len,
-                    instances.view(),
-                    rng,
-                )
-            })
-            .collect()
+        let mut centroids = Array3::zeros((
+            n_subquantizers,
+            codebook_len,
+            instances.ncols() / n_subquantizers,
+        ));
+
+        let sq_dims = instances.ncols() / n_subquantizers;
+        for (sq, mut sq_centroids) in centroids.outer_iter_mut().enumerate() {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sq_instances = instances.slice(s![.., offset..offset + sq_dims]);
+            sq_centroids.assign(&initialization.initial_centroids(
+                sq_instances,
+                Axis(0),
+                codebook_len,
+                rng,
+            ));
+        }
+
+        centroids
     }
 
+    /// Perform an outer training iteration.
+    ///
+    /// Returns the objective: the mean squared error between the
+    /// rotated instances and their reconstructions, or the mean
+    /// anisotropic loss for `OPQObjective::InnerProduct`.
     fn train_iteration<A>(
-        mut projection: ArrayViewMut2<A>,
+        projection: ArrayViewMut2<A>,
         mut centroids: ArrayViewMut3<A>,
         instances: ArrayView2<A>,
-    ) where
+        rotation_samples: Option<&[usize]>,
+        objective_kind: OPQObjective<A>,
+        scratches: &mut [KMeansScratch<A>],
+    ) -> A
+    where
         A: Lapack + NdFloat + Scalar + Sum,
         A::Real: NdFloat,
         usize: AsPrimitive<A>,
     {
         info!("Updating subquantizers");
 
-        // Perform one iteration of cluster updates, using regular k-means.
         let rx = instances.dot(&projection);
-        Self::update_subquantizers(centroids.view_mut(), rx.view());
+        let (quantized, anisotropic_loss) = match objective_kind {
+            OPQObjective::Reconstruction => {
+                // Perform one iteration of cluster updates, using regular k-means.
+                Self::update_subquantizers(centroids.view_mut(), rx.view(), scratches);
+                let quantized =
+                    primitives::quantize_batch::<_, usize, _>(centroids.view(), rx.view());
+                (quantized, None)
+            }
+            OPQObjective::InnerProduct(eta) => {
+                let mut quantized =
+                    primitives::quantize_batch::<_, usize, _>(centroids.view(), rx.view());
+                for (instance, mut codes) in rx.outer_iter().zip(quantized.outer_iter_mut()) {
+                    encode_anisotropic(
+                        centroids.view(),
+                        instance,
+                        codes.as_slice_mut().unwrap(),
+                        eta,
+                    );
+                }
+                let loss = update_codebooks(centroids.view_mut(), rx.view(), quantized.view(), eta);
+                (quantized, Some(loss))
+            }
+        };
 
         info!("Updating projection matrix");
 
-        // Do a quantization -> reconstruction roundtrip. We recycle the
-        // projection matrix to avoid (re)allocations.
-        let quantized = primitives::quantize_batch::<_, usize, _>(centroids.view(), rx.view());
-        let mut reconstructed = rx;
+        // Do a quantization -> reconstruction roundtrip.
+        let mut reconstructed = Array2::zeros(rx.dim());
         primitives::reconstruct_batch_into(centroids.view(), quantized, reconstructed.view_mut());
 
-        // Find the new projection matrix using the instances and their
-        // (projected) reconstructions. See (the text below) Eq 7 in
-        // Ge et al., 2013.
-        let (u, _, vt) = instances.t().dot(&reconstructed).svd(true, true).unwrap();
+        let objective = anisotropic_loss.unwrap_or_else(|| {
+            (&rx - &reconstructed).iter().map(|&v| v * v).sum::<A>() / rx.len().as_()
+        });
+
+        Self::update_projection(
+            projection,
+            instances,
+            reconstructed.view(),
+            rotation_samples,
+        );
+
+        objective
+    }
+
+    /// Update the projection matrix.
+    ///
+    /// Finds the new projection matrix using the instances and their
+    /// (projected) reconstructions. See (the text below) Eq 7 in Ge et
+    /// al., 2013.
+    fn update_projection<A>(
+        mut projection: ArrayViewMut2<A>,
+        instances: ArrayView2<A>,
+        reconstructed: ArrayView2<A>,
+        rotation_samples: Option<&[usize]>,
+    ) where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+    {
+        let cross = match rotation_samples {
+            Some(samples) => par_cross_product(
+                instances.select(Axis(0), samples).view(),
+                reconstructed.select(Axis(0), samples).view(),
+                ROTATION_BLOCK_LEN,
+            ),
+            None => par_cross_product(instances, reconstructed, ROTATION_BLOCK_LEN),
+        };
+        let (u, _, vt) = cross.svd(true, true).unwrap();
         projection.assign(&u.unwrap().dot(&vt.unwrap()));
     }
 
-    fn update_subquantizers<A, S>(mut centroids: ArrayViewMut3<A>, instances: ArrayBase<S, Ix2>)
-    where
+    fn update_subquantizers<A, S>(
+        mut centroids: ArrayViewMut3<A>,
+        instances: ArrayBase<S, Ix2>,
+        scratches: &mut [KMeansScratch<A>],
+    ) where
         A: NdFloat + Scalar + Sum,
         A::Real: NdFloat,
         usize: AsPrimitive<A>,
@@ -198,13 +680,19 @@ impl OPQ {
         centroids
             .axis_iter_mut(Axis(0))
             .into_par_iter()
+            .zip(scratches.par_iter_mut())
             .enumerate()
-            .for_each(|(sq, mut sq_centroids)| {
+            .for_each(|(sq, (mut sq_centroids, scratch))| {
                 let offset = sq * sq_centroids.ncols();
                 // ndarray#474
                 #[allow(clippy::deref_addrof)]
                 let sq_instances = instances.slice(s![.., offset..offset + sq_centroids.ncols()]);
-                sq_instances.kmeans_iteration(Axis(0), sq_centroids.view_mut());
+                kmeans_iteration_with_scratch(
+                    sq_instances,
+                    Axis(0),
+                    sq_centroids.view_mut(),
+                    scratch,
+                );
             });
     }
 }
@@ -229,8 +717,7 @@ where
     );
 
     let mut eigenvalue_indices: Vec<usize> = (0..eigenvalues.len()).collect();
-    eigenvalue_indices
-        .sort_unstable_by(|l, r| OrderedFloat(eigenvalues[*l]).cmp(&OrderedFloat(eigenvalues[*r])));
+    eigenvalue_indices.sort_unstable_by(|l, r| float_cmp(eigenvalues[*l], eigenvalues[*r]));
 
     // Only handle positive values, to switch to log-space. This is
     // ok for our purposes, since we only eigendecompose covariance
@@ -245,11 +732,7 @@ where
 
     // Make values positive, this is so that we can treat eigenvalues
     // (0,1] and [1,] in the same manner.
-    let smallest = eigenvalues
-        .iter()
-        .cloned()
-        .min_by_key(|&v| OrderedFloat(v))
-        .unwrap();
+    let smallest = min_by_float_key(eigenvalues.iter().cloned(), |&v| v).unwrap();
     eigenvalues.map_mut(|v| *v -= smallest);
 
     let mut assignments = vec![vec![]; n_buckets];
@@ -258,12 +741,14 @@ where
 
     while let Some(eigenvalue_idx) = eigenvalue_indices.pop() {
         // Find non-full bucket with the smallest product.
-        let (idx, _) = assignments
-            .iter()
-            .enumerate()
-            .filter(|(_, a)| a.len() < max_assignments)
-            .min_by_key(|(idx, _)| OrderedFloat(products[*idx]))
-            .unwrap();
+        let (idx, _) = min_by_float_key(
+            assignments
+                .iter()
+                .enumerate()
+                .filter(|(_, a)| a.len() < max_assignments),
+            |(idx, _)| products[*idx],
+        )
+        .unwrap();
 
         assignments[idx].push(eigenvalue_idx);
         products[idx] += eigenvalues[eigenvalue_idx];
@@ -274,13 +759,16 @@ where
 
 #[cfg(test)]
 mod tests {
-    use ndarray::{array, Array2, ArrayView2};
+    use ndarray::{array, Array1, Array2, ArrayView2};
     use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
 
-    use super::OPQ;
+    use super::{OPQObjective, OPQ};
+    use crate::kmeans::CentroidInitialization;
     use crate::linalg::EuclideanDistance;
     use crate::ndarray_rand::RandomExt;
-    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+    use crate::pq::{Normalization, QuantizeVector, ReconstructVector, TrainPQ, PQ};
 
     /// Calculate the average euclidean distances between the the given
     /// instances and the instances returned by quantizing and then
@@ -334,4 +822,175 @@ mod tests {
         // Loss is around 0.09.
         assert!(loss < 0.1);
     }
+
+    #[test]
+    fn quantize_with_rotation_subsampled_opq() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((256, 20), uniform);
+        let pq = OPQ::train_pq_rotation_subsampled_using(
+            10,
+            7,
+            10,
+            instances.view(),
+            128,
+            XorShiftRng::seed_from_u64(42),
+        );
+        let loss = avg_euclidean_loss(instances.view(), &pq);
+        assert!(loss < 0.12);
+    }
+
+    #[test]
+    fn quantize_with_initialized_opq() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((256, 20), uniform);
+        for &initialization in &[
+            CentroidInitialization::Quantiles,
+            CentroidInitialization::Bisecting(5),
+        ] {
+            let pq = OPQ::train_pq_initialized_using(
+                10,
+                7,
+                10,
+                initialization,
+                instances.view(),
+                XorShiftRng::seed_from_u64(42),
+            );
+            let loss = avg_euclidean_loss(instances.view(), &pq);
+            assert!(loss < 0.12);
+        }
+    }
+
+    #[test]
+    fn opq_reports_objective() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((256, 20), uniform);
+        let pq = OPQ::train_pq(10, 4, 5, 1, instances.view());
+        let objective = &pq.manifest().unwrap().objective;
+        assert_eq!(objective.len(), 5);
+        assert!(objective[4] <= objective[0]);
+    }
+
+    #[test]
+    fn train_projection_with_fixed_codebooks() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = Array2::random_using((256, 20), uniform, &mut rng);
+        let pq = PQ::train_pq_using(10, 4, 10, 1, instances.view(), &mut rng);
+
+        // Swap the halves of the instances, so that the codebooks no
+        // longer match the instances.
+        let mut permutation = Array2::zeros((20, 20));
+        for i in 0..20 {
+            permutation[((i + 10) % 20, i)] = 1f32;
+        }
+        let permuted = instances.dot(&permutation);
+
+        let rotated = OPQ::train_projection(&pq, 5, permuted.view());
+        assert_eq!(rotated.subquantizers(), pq.subquantizers());
+        assert!(
+            avg_euclidean_loss(permuted.view(), &rotated)
+                < avg_euclidean_loss(permuted.view(), &pq)
+        );
+
+        let objective = &rotated.manifest().unwrap().objective;
+        assert_eq!(objective.len(), 5);
+        assert!(objective[4] <= objective[0]);
+    }
+
+    #[test]
+    fn train_projection_keeps_normalization() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = Array2::random_using((256, 20), uniform, &mut rng);
+        let pq = PQ::train_pq_using(10, 4, 10, 1, instances.view(), &mut rng);
+
+        // Mean centering maps the shifted instances to the instances.
+        let shifted = &instances + 5.;
+        let centered = pq
+            .clone()
+            .with_normalization(Normalization::MeanCentering(Array1::from_elem(20, 5.)))
+            .with_metadata("model", "test");
+
+        let rotated = OPQ::train_projection(&centered, 5, shifted.view());
+        assert_eq!(rotated.normalization(), centered.normalization());
+        assert_eq!(rotated.metadata(), centered.metadata());
+
+        let expected = OPQ::train_projection(&pq, 5, instances.view());
+        for (&objective, &expected) in rotated
+            .manifest()
+            .unwrap()
+            .objective
+            .iter()
+            .zip(&expected.manifest().unwrap().objective)
+        {
+            assert!((objective - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn opq_snapshots_iterations() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = Array2::random_using((256, 20), uniform, &mut rng);
+
+        let mut snapshots = Vec::new();
+        let pq = OPQ::train_pq_with_snapshots_using(
+            10,
+            4,
+            10,
+            instances.view(),
+            |snapshot| {
+                snapshots.push((snapshot.objective, snapshot.to_pq()));
+                snapshot.iteration < 2
+            },
+            &mut rng,
+        );
+
+        // Training stops when the callback returns false.
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(pq.manifest().unwrap().objective.len(), 3);
+        assert_eq!(pq.manifest().unwrap().n_iterations, 3);
+        assert_eq!(pq.subquantizers(), snapshots[2].1.subquantizers());
+        assert_eq!(pq.projection(), snapshots[2].1.projection());
+        assert_eq!(pq.manifest().unwrap().objective[2], snapshots[2].0 as f64);
+    }
+
+    #[test]
+    fn inner_product_opq_reduces_parallel_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((512, 8), Uniform::new(-1., 1.), &mut rng);
+
+        // The mean squared error parallel to the instances.
+        let parallel_error = |quantized: Array2<u8>, pq: &PQ<f32>| {
+            let residuals = &instances - &pq.reconstruct_batch(quantized);
+            residuals
+                .outer_iter()
+                .zip(instances.outer_iter())
+                .map(|(r, x)| r.dot(&x).powi(2) / x.dot(&x))
+                .sum::<f32>()
+                / 512.
+        };
+
+        let opq = OPQ::train_pq_using(4, 3, 5, 1, instances.view(), &mut rng);
+        let opq_parallel = parallel_error(opq.quantize_batch(instances.view()), &opq);
+
+        let ip_opq = OPQ::train_pq_objective_using(
+            4,
+            3,
+            5,
+            OPQObjective::InnerProduct(8.),
+            instances.view(),
+            &mut rng,
+        );
+        let manifest = ip_opq.manifest().unwrap();
+        assert_eq!(manifest.objective.len(), 5);
+        assert!(ip_opq.projection().is_some());
+
+        let ip_parallel = parallel_error(
+            ip_opq.quantize_batch_anisotropic(instances.view(), 8.),
+            &ip_opq,
+        );
+        assert!(ip_parallel < opq_parallel);
+    }
 }
@@ -0,0 +1,154 @@
+use std::f64::consts::PI;
+use std::iter::Sum;
+
+use ndarray::{Axis, NdFloat};
+use num_traits::AsPrimitive;
+use rand::Rng;
+
+use super::PQ;
+
+/// Distortion caused by centroid perturbation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Perturbation<A> {
+    /// The standard deviation of the noise of each subquantizer.
+    pub noise_std: Vec<A>,
+
+    /// The mean squared displacement of the centroid coordinates.
+    pub centroid_mse: A,
+
+    /// The largest Euclidean displacement of a centroid.
+    pub max_centroid_displacement: A,
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Perturb the centroids with Gaussian noise.
+    ///
+    /// Returns a copy of the quantizer in which Gaussian noise was added
+    /// to every centroid coordinate, together with the resulting
+    /// distortion. This makes it possible to share a quantizer without
+    /// revealing the geometry of the training data exactly.
+    ///
+    /// The noise is calibrated per subquantizer: its standard deviation
+    /// is `relative_noise` times the standard deviation of the centroid
+    /// coordinates of the subquantizer. The projection matrix is not
+    /// perturbed. The training manifest is not retained, since the
+    /// perturbed quantizer is not the result of training.
+    pub fn perturb_centroids<R>(&self, relative_noise: A, rng: &mut R) -> (PQ<A>, Perturbation<A>)
+    where
+        R: Rng + ?Sized,
+    {
+        assert!(
+            relative_noise >= A::zero(),
+            "Relative noise should be non-negative, got: {}",
+            relative_noise
+        );
+
+        let mut quantizers = self.quantizers.clone();
+        let mut noise_std = Vec::with_capacity(quantizers.len_of(Axis(0)));
+        let mut squared_displacement = A::zero();
+        let mut max_centroid_displacement = A::zero();
+
+        for mut quantizer in quantizers.outer_iter_mut() {
+            let mean = quantizer.iter().cloned().sum::<A>() / quantizer.len().as_();
+            let variance = quantizer
+                .iter()
+                .map(|&v| (v - mean) * (v - mean))
+                .sum::<A>()
+                / quantizer.len().as_();
+            let std = relative_noise * variance.sqrt();
+            noise_std.push(std);
+
+            for mut centroid in quantizer.outer_iter_mut() {
+                let mut centroid_displacement = A::zero();
+                for v in centroid.iter_mut() {
+                    let noise = std * A::from(standard_normal(rng)).unwrap();
+                    *v += noise;
+                    centroid_displacement += noise * noise;
+                }
+
+                squared_displacement += centroid_displacement;
+                max_centroid_displacement =
+                    max_centroid_displacement.max(centroid_displacement.sqrt());
+            }
+        }
+
+        let perturbation = Perturbation {
+            noise_std,
+            centroid_mse: squared_displacement / quantizers.len().as_(),
+            max_centroid_displacement,
+        };
+
+        let pq = PQ {
+            projection: self.projection.clone(),
+            quantizers,
+            manifest: None,
+            polysemous: self.polysemous.clone(),
+            normalization: self.normalization.clone(),
+            metadata: self.metadata.clone(),
+        };
+
+        (pq, perturbation)
+    }
+}
+
+/// Sample from the standard normal distribution (Box-Muller).
+pub(crate) fn standard_normal<R>(rng: &mut R) -> f64
+where
+    R: Rng + ?Sized,
+{
+    // Avoid ln(0).
+    let u1 = 1. - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array2, Array3};
+    use rand::SeedableRng;
+    use rand_distr::Normal;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    #[test]
+    fn perturbation_is_calibrated() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let quantizers = Array3::random_using((4, 64, 8), Normal::new(0., 2.).unwrap(), &mut rng);
+        let pq = PQ::new(None, quantizers);
+
+        let (perturbed, perturbation) = pq.perturb_centroids(0.1, &mut rng);
+        assert_eq!(perturbation.noise_std.len(), 4);
+        for &std in &perturbation.noise_std {
+            assert!((std - 0.2f64).abs() < 0.03);
+        }
+        let expected_mse = perturbation
+            .noise_std
+            .iter()
+            .map(|std| std * std)
+            .sum::<f64>()
+            / 4.;
+        assert!((perturbation.centroid_mse - expected_mse).abs() < 0.1 * expected_mse);
+        assert!(perturbation.max_centroid_displacement > 0.);
+
+        // The perturbed quantizer remains usable.
+        let instances = Array2::random_using((16, 32), Normal::new(0., 2.).unwrap(), &mut rng);
+        let quantized = perturbed.quantize_batch::<u8, _>(instances.view());
+        assert_eq!(perturbed.reconstruct_batch(quantized).shape(), &[16, 32]);
+    }
+
+    #[test]
+    fn zero_noise_preserves_centroids() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let quantizers = Array3::random_using((2, 4, 3), Normal::new(0., 1.).unwrap(), &mut rng);
+        let pq = PQ::new(None, quantizers);
+        let (perturbed, perturbation) = pq.perturb_centroids(0., &mut rng);
+        assert_eq!(perturbed.subquantizers(), pq.subquantizers());
+        assert_eq!(perturbation.centroid_mse, 0.);
+    }
+}
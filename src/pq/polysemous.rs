@@ -0,0 +1,312 @@
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, ArrayView2, Axis, Data, Ix1, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+use rand::Rng;
+
+use super::reorder::permute_centroids;
+use super::PQ;
+use crate::linalg::SquaredEuclideanDistance;
+use crate::simd;
+
+/// Temperature of the first annealing iteration.
+const INITIAL_TEMPERATURE: f64 = 0.7;
+
+/// Temperature of the last annealing iteration.
+const FINAL_TEMPERATURE: f64 = 1e-3;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Learn polysemous codes (Douze et al., 2016).
+    ///
+    /// Returns a copy of the quantizer in which the centroids of every
+    /// subquantizer are reordered, such that the Hamming distance
+    /// between two centroid indices approximates the distance between
+    /// the centroids. The Hamming distance between two codes then
+    /// approximates the distance between the quantized vectors, so that
+    /// codes can be pre-filtered with cheap Hamming distances before
+    /// computing asymmetric distances.
+    ///
+    /// The order is optimized with simulated annealing of centroid
+    /// swaps for `n_iterations` iterations per subquantizer. Since only
+    /// the order of the centroids changes, reconstructions are the same
+    /// as those of this quantizer. Codes of this quantizer can be
+    /// converted with `polysemous_permutation`. The training manifest is
+    /// retained.
+    pub fn polysemous_using<R>(&self, n_iterations: usize, rng: &mut R) -> PQ<A>
+    where
+        R: Rng + ?Sized,
+    {
+        let n_centroids = self.n_quantizer_centroids();
+        assert!(
+            n_centroids.is_power_of_two(),
+            "Polysemous codes require a power of two centroids, was: {}",
+            n_centroids
+        );
+
+        let mut permutation = Array2::zeros((self.quantizers.len_of(Axis(0)), n_centroids));
+        for (quantizer, mut permutation) in self
+            .quantizers
+            .outer_iter()
+            .zip(permutation.outer_iter_mut())
+        {
+            permutation.assign(&anneal_permutation(quantizer, n_iterations, rng));
+        }
+        let quantizers = permute_centroids(self.quantizers.view(), permutation.view());
+
+        // Compose with an earlier permutation, so that the permutation
+        // always maps codes of the original quantizer.
+        if let Some(previous) = &self.polysemous {
+            let mut composed = previous.clone();
+            for (mut composed, permutation) in
+                composed.outer_iter_mut().zip(permutation.outer_iter())
+            {
+                composed.mapv_inplace(|code| permutation[code]);
+            }
+            permutation = composed;
+        }
+
+        PQ {
+            projection: self.projection.clone(),
+            quantizers,
+            manifest: self.manifest.clone(),
+            polysemous: Some(permutation),
+            normalization: self.normalization.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+impl<A> PQ<A> {
+    /// Check whether Hamming distances between codes are meaningful.
+    ///
+    /// This is the case for quantizers with polysemous codes, see
+    /// `PQ::polysemous_using`.
+    pub fn hamming_filterable(&self) -> bool {
+        self.polysemous.is_some()
+    }
+
+    /// Get the permutation of polysemous codes (if learned).
+    ///
+    /// The permutation has the shape *(n_subquantizers, n_centroids)*.
+    /// Element *(i, j)* is the polysemous code of centroid *j* of
+    /// subquantizer *i* of the quantizer that the polysemous codes were
+    /// learned from.
+    pub fn polysemous_permutation(&self) -> Option<ArrayView2<usize>> {
+        self.polysemous.as_ref().map(Array2::view)
+    }
+
+    /// Compute the Hamming distances between a code and a batch of codes.
+    ///
+    /// The distance is the number of differing bits, summed over the
+    /// subquantizers. This is only a useful approximation of distances
+    /// between vectors when the quantizer is `hamming_filterable`.
+    pub fn hamming_distances<I, S1, S2>(
+        &self,
+        code: ArrayBase<S1, Ix1>,
+        codes: ArrayBase<S2, Ix2>,
+    ) -> Array1<u32>
+    where
+        I: AsPrimitive<usize>,
+        S1: Data<Elem = I>,
+        S2: Data<Elem = I>,
+    {
+        assert_eq!(code.len(), codes.ncols(), "Code lengths do not match");
+
+        // Use the SIMD kernel for contiguous u8 codes.
+        let code_data = code.as_slice().and_then(simd::as_u8);
+        codes.map_axis(Axis(1), |other| {
+            match (code_data, other.as_slice().and_then(simd::as_u8)) {
+                (Some(code_data), Some(other_data)) => {
+                    simd::hamming_distance(code_data, other_data)
+                }
+                _ => code
+                    .iter()
+                    .zip(other.iter())
+                    .map(|(&a, &b)| (a.as_() ^ b.as_()).count_ones())
+                    .sum(),
+            }
+        })
+    }
+}
+
+/// Find a permutation of centroid indices by simulated annealing.
+///
+/// The cost of a permutation is the sum of the squared differences
+/// between the Hamming distances of the permuted indices of centroid
+/// pairs and their target distances.
+fn anneal_permutation<A, R>(
+    centroids: ArrayView2<A>,
+    n_iterations: usize,
+    rng: &mut R,
+) -> Array1<usize>
+where
+    A: NdFloat,
+    R: Rng + ?Sized,
+{
+    let n_centroids = centroids.nrows();
+    let mut permutation = (0..n_centroids).collect::<Array1<_>>();
+    let targets = match hamming_targets(centroids) {
+        Some(targets) if n_centroids > 2 => targets,
+        _ => return permutation,
+    };
+
+    let cost = |i: usize, code: usize, j: usize, other_code: usize| {
+        let hamming = (code ^ other_code).count_ones() as f64;
+        (hamming - targets[(i, j)]).powi(2)
+    };
+
+    // A swap changes the cost of 2 (n_centroids - 2) pairs, scale the
+    // temperature accordingly.
+    let mean_cost =
+        permutation_cost(targets.view(), permutation.view()) / (n_centroids * n_centroids) as f64;
+    let scale = mean_cost * 2. * (n_centroids - 2) as f64;
+
+    let decay = (FINAL_TEMPERATURE / INITIAL_TEMPERATURE).powf(1. / n_iterations.max(1) as f64);
+    let mut temperature = INITIAL_TEMPERATURE;
+    for _ in 0..n_iterations {
+        let a = rng.gen_range(0..n_centroids);
+        let b = (a + rng.gen_range(1..n_centroids)) % n_centroids;
+        let (code_a, code_b) = (permutation[a], permutation[b]);
+
+        let delta = (0..n_centroids)
+            .filter(|&k| k != a && k != b)
+            .map(|k| {
+                let code_k = permutation[k];
+                cost(a, code_b, k, code_k) + cost(b, code_a, k, code_k)
+                    - cost(a, code_a, k, code_k)
+                    - cost(b, code_b, k, code_k)
+            })
+            .sum::<f64>();
+
+        if delta < 0. || rng.gen::<f64>() < (-delta / (temperature * scale)).exp() {
+            permutation.swap(a, b);
+        }
+
+        temperature *= decay;
+    }
+
+    permutation
+}
+
+/// Compute the target Hamming distances of centroid pairs.
+///
+/// The targets are the Euclidean distances between the centroids,
+/// scaled such that the mean target is the mean Hamming distance of
+/// the centroid indices. Returns `None` when all centroids are equal.
+fn hamming_targets<A>(centroids: ArrayView2<A>) -> Option<Array2<f64>>
+where
+    A: NdFloat,
+{
+    let n_centroids = centroids.nrows();
+    let distances = centroids
+        .squared_euclidean_distance(centroids)
+        .mapv(|d| d.max(A::zero()).sqrt().to_f64().unwrap());
+    let mean_distance = distances.sum() / (n_centroids * (n_centroids - 1)) as f64;
+    if mean_distance <= 0. || mean_distance.is_nan() {
+        return None;
+    }
+
+    let n_bits = n_centroids.trailing_zeros() as f64;
+    Some(distances.mapv(|d| d * n_bits / 2. / mean_distance))
+}
+
+/// Compute the cost of a permutation of centroid indices.
+fn permutation_cost(targets: ArrayView2<f64>, permutation: ArrayView1<usize>) -> f64 {
+    targets
+        .indexed_iter()
+        .map(|((i, j), &target)| {
+            let hamming = (permutation[i] ^ permutation[j]).count_ones() as f64;
+            (hamming - target).powi(2)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array1, Array2, Array3, Axis};
+    use rand::distributions::Uniform;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{hamming_targets, permutation_cost};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    #[test]
+    fn polysemous_codes_preserve_reconstructions() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let quantizers = Array3::random_using((2, 16, 3), Uniform::new(-1f32, 1.), &mut rng);
+        let pq = PQ::new(None, quantizers);
+        assert!(!pq.hamming_filterable());
+
+        let polysemous = pq.polysemous_using(2000, &mut rng);
+        assert!(polysemous.hamming_filterable());
+        let permutation = polysemous.polysemous_permutation().unwrap();
+
+        let instances = Array2::random_using((64, 6), Uniform::new(-1f32, 1.), &mut rng);
+        let quantized: Array2<usize> = pq.quantize_batch(instances.view());
+        let polysemous_quantized: Array2<usize> = polysemous.quantize_batch(instances.view());
+        for (codes, polysemous_codes) in quantized
+            .outer_iter()
+            .zip(polysemous_quantized.outer_iter())
+        {
+            for (sq, (&code, &polysemous_code)) in codes.iter().zip(polysemous_codes).enumerate() {
+                assert_eq!(permutation[(sq, code)], polysemous_code);
+            }
+        }
+
+        assert_eq!(
+            polysemous.reconstruct_batch(polysemous_quantized),
+            pq.reconstruct_batch(quantized.view())
+        );
+
+        // Permutations are composed.
+        let twice = polysemous.polysemous_using(10, &mut rng);
+        let twice_quantized: Array2<usize> = twice.quantize_batch(instances.view());
+        let twice_permutation = twice.polysemous_permutation().unwrap();
+        assert_eq!(
+            twice_quantized[(0, 1)],
+            twice_permutation[(1, quantized[(0, 1)])]
+        );
+    }
+
+    #[test]
+    fn polysemous_codes_approximate_distances() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+
+        // Centroids on the corners of a cube, in a random order.
+        let mut corners = (0..8usize).collect::<Vec<_>>();
+        corners.shuffle(&mut rng);
+        let quantizers =
+            Array3::from_shape_fn((1, 8, 3), |(_, c, d)| ((corners[c] >> d) & 1) as f32);
+        let pq = PQ::new(None, quantizers);
+
+        let polysemous = pq.polysemous_using(5000, &mut rng);
+        let permutation = polysemous.polysemous_permutation().unwrap();
+        let targets = hamming_targets(pq.subquantizers().index_axis_move(Axis(0), 0)).unwrap();
+        let identity = (0..8).collect::<Array1<usize>>();
+        assert!(
+            permutation_cost(targets.view(), permutation.row(0))
+                < permutation_cost(targets.view(), identity.view())
+        );
+
+        // The optimal order maps the corners to codes with the same bits.
+        let centroids = polysemous.subquantizers().index_axis_move(Axis(0), 0);
+        let codes = Array2::from_shape_fn((8, 1), |(c, _)| c);
+        let distances = polysemous.hamming_distances(codes.row(0), codes.view());
+        for (centroid, &distance) in centroids.outer_iter().zip(distances.iter()) {
+            let l1 = (&centroid - &centroids.row(0)).mapv(f32::abs).sum();
+            assert_eq!(distance as f32, l1);
+        }
+
+        let codes = codes.mapv(|c| c as u8);
+        assert_eq!(
+            polysemous.hamming_distances(codes.row(0), codes.view()),
+            distances
+        );
+    }
+}
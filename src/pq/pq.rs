@@ -1,22 +1,37 @@
+use std::collections::BTreeMap;
 use std::iter;
 use std::iter::Sum;
+use std::mem;
+use std::time::Instant;
 
-use log::info;
+use log::{info, warn};
 use ndarray::{
-    concatenate, s, Array1, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, Axis,
-    Data, Ix1, Ix2, NdFloat,
+    s, Array1, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, Axis, Data, Ix1,
+    Ix2, NdFloat,
 };
 use num_traits::{AsPrimitive, Bounded, Zero};
-use ordered_float::OrderedFloat;
-use rand::{Rng, RngCore, SeedableRng};
+use rand::seq::index::{self, IndexVec};
+use rand::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
 use rayon::prelude::*;
 
 use super::primitives;
-use super::{QuantizeVector, ReconstructVector, TrainPQ};
+use super::{
+    CodeSink, CodeSource, KMeansTrainer, Normalization, PQView, QuantizeVector, ReconstructVector,
+    SubquantizerConfig, SubquantizerTrainer, TrainPQ, TrainingManifest,
+};
+use crate::error::Error;
 use crate::kmeans::{
-    InitialCentroids, KMeansWithCentroids, NIterationsCondition, RandomInstanceCentroids,
+    cluster_assignments, kmeans_with_centroids_scratch, KMeansScratch, NIterationsCondition,
 };
-use crate::rng::ReseedOnCloneRng;
+use crate::parallel::NestedParallelism;
+use crate::training_log::{log_record, TrainingRecord};
+
+/// Recommended minimum number of training instances per centroid.
+///
+/// With fewer instances, many clusters end up empty or fitted to a
+/// handful of instances.
+const MIN_INSTANCES_PER_CENTROID: usize = 39;
 
 /// Product quantizer (Jégou et al., 2011).
 ///
@@ -24,10 +39,36 @@ use crate::rng::ReseedOnCloneRng;
 /// assigns to the *i*-th slice the index of the nearest centroid of the
 /// *i*-th subquantizer. Vector reconstruction consists of concatenating
 /// the centroids that represent the slices.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// The codebooks of all subquantizers are stored in a single contiguous
+/// array with shape *(n_subquantizers, n_centroids, subquantizer_dims)*.
+///
+/// Quantizers are `Send + Sync` and quantization and reconstruction do
+/// not mutate the quantizer. A quantizer can therefore be shared between
+/// threads (e.g. using `Arc`) without locking.
+#[derive(Clone, Debug)]
 pub struct PQ<A> {
     pub(crate) projection: Option<Array2<A>>,
     pub(crate) quantizers: Array3<A>,
+    pub(crate) manifest: Option<TrainingManifest>,
+    pub(crate) polysemous: Option<Array2<usize>>,
+    pub(crate) normalization: Normalization<A>,
+    pub(crate) metadata: BTreeMap<String, String>,
+}
+
+/// The training manifest is not compared, it records the wall-clock
+/// duration of training and is not serialized.
+impl<A> PartialEq for PQ<A>
+where
+    A: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.projection == other.projection
+            && self.quantizers == other.quantizers
+            && self.polysemous == other.polysemous
+            && self.normalization == other.normalization
+            && self.metadata == other.metadata
+    }
 }
 
 impl<A> PQ<A>
@@ -35,27 +76,16 @@ where
     A: NdFloat,
 {
     pub fn new(projection: Option<Array2<A>>, quantizers: Array3<A>) -> Self {
-        assert!(
-            !quantizers.is_empty(),
-            "Attempted to construct a product quantizer without quantizers."
-        );
-
-        let reconstructed_len = primitives::reconstructed_len(quantizers.view());
-
-        if let Some(ref projection) = projection {
-            assert_eq!(
-                projection.shape(),
-                [reconstructed_len; 2],
-                "Incorrect projection matrix shape, was: {:?}, should be [{}, {}]",
-                projection.shape(),
-                reconstructed_len,
-                reconstructed_len
-            );
-        }
+        // Validate the quantizer shapes.
+        PQView::new(projection.as_ref().map(Array2::view), quantizers.view());
 
         PQ {
             projection,
             quantizers,
+            manifest: None,
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         }
     }
 
@@ -75,6 +105,11 @@ where
             n_subquantizer_bits > 0,
             "Number of quantizer bits should at least be one."
         );
+        assert!(
+            n_subquantizer_bits <= 32 && 2usize.checked_pow(n_subquantizer_bits).is_some(),
+            "Number of quantizer bits should at most be 32, was: {}",
+            n_subquantizer_bits
+        );
         assert!(
             instances.ncols() % n_subquantizers == 0,
             "The number of subquantizers should evenly divide each instance."
@@ -89,6 +124,95 @@ where
         );
     }
 
+    /// Log the codebook size and warn about too small training sets.
+    fn log_codebook_size(
+        n_instances: usize,
+        n_dims: usize,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        parallelism: NestedParallelism,
+    ) {
+        let codebook_len = 2usize.pow(n_subquantizer_bits);
+        if n_instances < codebook_len.saturating_mul(MIN_INSTANCES_PER_CENTROID) {
+            warn!(
+                "Training {} centroids per subquantizer on {} instances, at least {} instances are recommended",
+                codebook_len,
+                n_instances,
+                codebook_len.saturating_mul(MIN_INSTANCES_PER_CENTROID)
+            );
+        }
+
+        info!(
+            "Estimated memory use of training: {} MiB",
+            Self::training_memory_estimate(
+                n_instances,
+                n_dims,
+                n_subquantizers,
+                n_subquantizer_bits,
+                parallelism
+            ) / (1024 * 1024)
+        );
+    }
+
+    /// Estimate the peak memory use of training in bytes.
+    ///
+    /// The estimate covers the codebooks and the buffers of the
+    /// subquantizers that are trained concurrently, but not the training
+    /// instances. Since memory use grows linearly with the number of
+    /// centroids, it is worthwhile to check the estimate before training
+    /// large codebooks, such as 16-bit subquantizers with 65,536
+    /// centroids.
+    pub fn training_memory_estimate(
+        n_instances: usize,
+        n_dims: usize,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        parallelism: NestedParallelism,
+    ) -> usize {
+        let codebook_len = 2usize.pow(n_subquantizer_bits);
+        let codebook_size = codebook_len * (n_dims / n_subquantizers) * mem::size_of::<A>();
+
+        // Each concurrently trained subquantizer holds the codebook of the
+        // current attempt, the best codebook, and k-means buffers.
+        let n_concurrent = parallelism.outer().min(n_subquantizers);
+        let task_size = 2 * codebook_size
+            + KMeansScratch::<A>::memory_estimate(n_instances, codebook_len, parallelism);
+
+        n_subquantizers * codebook_size + n_concurrent * task_size
+    }
+
+    /// Get the manifest of the training run (if trained).
+    ///
+    /// The manifest is only available for quantizers that were trained
+    /// in this process, it is not available for quantizers that were
+    /// constructed using `PQ::new`.
+    pub fn manifest(&self) -> Option<&TrainingManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Attach a metadata entry to the quantizer.
+    ///
+    /// Metadata are arbitrary key-value pairs that describe the model,
+    /// such as the name of the training data set, the training date, or
+    /// the owner. Unlike the training manifest, metadata are preserved
+    /// by `PQ::to_protobuf` and `PQ::from_protobuf`. Metadata are not
+    /// part of the fingerprint. An existing entry with the same key is
+    /// replaced.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Get the metadata of the quantizer, ordered by key.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Get the metadata of the quantizer mutably.
+    pub fn metadata_mut(&mut self) -> &mut BTreeMap<String, String> {
+        &mut self.metadata
+    }
+
     /// Get the number of centroids per quantizer.
     pub fn n_quantizer_centroids(&self) -> usize {
         self.quantizers.len_of(Axis(1))
@@ -99,107 +223,283 @@ where
         self.projection.as_ref().map(Array2::view)
     }
 
-    /// Create initial centroids for a single quantizer.
+    /// Get the subquantizer centroids.
+    pub fn subquantizers(&self) -> ArrayView3<A> {
+        self.quantizers.view()
+    }
+
+    /// Get a view of this quantizer.
     ///
-    /// `subquantizer_idx` is the subquantizer index for which the initial
-    /// centroids should be picked. `subquantizer_idx < n_subquantizers`,
-    /// the total number of subquantizers.
-    pub(crate) fn subquantizer_initial_centroids<S>(
-        subquantizer_idx: usize,
-        n_subquantizers: usize,
-        codebook_len: usize,
-        instances: ArrayBase<S, Ix2>,
-        rng: &mut impl Rng,
-    ) -> Array2<A>
+    /// The view borrows the projection matrix, subquantizers, and input
+    /// normalization.
+    pub fn view(&self) -> PQView<A> {
+        PQView {
+            projection: self.projection(),
+            quantizers: self.subquantizers(),
+            normalization: Some(&self.normalization),
+        }
+    }
+
+    /// Get this quantizer in rotated space.
+    ///
+    /// See `PQView::rotated`.
+    pub fn rotated(&self) -> PQView<A> {
+        self.view().rotated()
+    }
+
+    /// Normalize and rotate a batch of vectors.
+    ///
+    /// See `PQView::rotate_batch`.
+    pub fn rotate_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
     where
         S: Data<Elem = A>,
     {
-        let sq_dims = instances.ncols() / n_subquantizers;
+        self.view().rotate_batch(x)
+    }
 
-        let mut random_centroids = RandomInstanceCentroids::new(rng);
+    /// Normalize and rotate a vector.
+    ///
+    /// See `PQView::rotate_vector`.
+    pub fn rotate_vector<S>(&self, x: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.view().rotate_vector(x)
+    }
+}
 
-        let offset = subquantizer_idx * sq_dims;
-        // ndarray#474
-        #[allow(clippy::deref_addrof)]
-        let sq_instances = instances.slice(s![.., offset..offset + sq_dims]);
-        random_centroids.initial_centroids(sq_instances, Axis(0), codebook_len)
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Compute asymmetric distance tables for a batch of queries.
+    ///
+    /// See `PQView::adc_tables_batch`.
+    pub fn adc_tables_batch<S>(&self, queries: ArrayBase<S, Ix2>) -> Array3<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.view().adc_tables_batch(queries)
+    }
+
+    /// Quantize a batch of int8 vectors.
+    ///
+    /// See `PQView::quantize_batch_i8`.
+    pub fn quantize_batch_i8<I, S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        scale: A,
+        zero_point: i8,
+    ) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = i8>,
+        usize: AsPrimitive<I>,
+    {
+        self.view().quantize_batch_i8(x, scale, zero_point)
     }
 
-    /// Train a subquantizer.
+    /// Quantize a batch of vectors into a code sink.
     ///
-    /// `subquantizer_idx` is the index of the subquantizer, where
-    /// `subquantizer_idx < n_subquantizers`, the overall number of
-    /// subquantizers. `codebook_len` is the code book size of the
-    /// quantizer.
-    fn train_subquantizer(
-        subquantizer_idx: usize,
+    /// See `PQView::quantize_batch_into_sink`.
+    pub fn quantize_batch_into_sink<S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        sink: &mut (impl CodeSink + ?Sized),
+        first_row: u64,
+    ) -> Result<(), Error>
+    where
+        S: Data<Elem = A>,
+    {
+        self.view().quantize_batch_into_sink(x, sink, first_row)
+    }
+
+    /// Reconstruct vectors from a code source.
+    ///
+    /// See `PQView::reconstruct_from_source`.
+    pub fn reconstruct_from_source(
+        &self,
+        source: &(impl CodeSource + ?Sized),
+        rows: impl IntoIterator<Item = u64>,
+    ) -> Result<Array2<A>, Error> {
+        self.view().reconstruct_from_source(source, rows)
+    }
+
+    /// Expand quantization codes into centroid embeddings.
+    ///
+    /// See `PQView::centroid_embeddings_batch`.
+    pub fn centroid_embeddings_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        self.view().centroid_embeddings_batch(quantized)
+    }
+
+    /// Expand quantization codes into one-hot features.
+    ///
+    /// See `PQView::one_hot_batch`.
+    pub fn one_hot_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        self.view().one_hot_batch(quantized)
+    }
+
+    /// Train a product quantizer with the given thread allocation.
+    ///
+    /// Subquantizers are trained in at most `parallelism.outer()`
+    /// parallel tasks, cluster assignment of each subquantizer in at
+    /// most `parallelism.inner()` tasks. `TrainPQ::train_pq_using` uses
+    /// `NestedParallelism::for_tasks(n_subquantizers)`. The trained
+    /// quantizer does not depend on the thread allocation.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_pq_parallel_using<S, R>(
         n_subquantizers: usize,
-        codebook_len: usize,
+        n_subquantizer_bits: u32,
         n_iterations: usize,
         n_attempts: usize,
-        instances: ArrayView2<A>,
-        mut rng: impl Rng,
-    ) -> Array2<A>
+        instances: ArrayBase<S, Ix2>,
+        parallelism: NestedParallelism,
+        rng: R,
+    ) -> PQ<A>
     where
-        A: Sum,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
         usize: AsPrimitive<A>,
     {
-        assert!(n_attempts > 0, "Cannot train a subquantizer in 0 attempts.");
-
-        info!("Training PQ subquantizer {}", subquantizer_idx);
-
-        let sq_dims = instances.ncols() / n_subquantizers;
-
-        let offset = subquantizer_idx * sq_dims;
-        // ndarray#474
-        #[allow(clippy::deref_addrof)]
-        let sq_instances = instances.slice(s![.., offset..offset + sq_dims]);
+        Self::train_with_trainer(
+            &KMeansTrainer::new(),
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            parallelism,
+            rng,
+        )
+    }
 
-        iter::repeat_with(|| {
-            let mut quantizer = PQ::subquantizer_initial_centroids(
-                subquantizer_idx,
-                n_subquantizers,
-                codebook_len,
-                instances,
-                &mut rng,
-            );
-            let loss = sq_instances.kmeans_with_centroids(
-                Axis(0),
-                quantizer.view_mut(),
-                NIterationsCondition(n_iterations),
-            );
-            (loss, quantizer)
-        })
-        .take(n_attempts)
-        .map(|(loss, quantizer)| (OrderedFloat(loss), quantizer))
-        .min_by_key(|attempt| attempt.0)
-        .unwrap()
-        .1
+    /// Train a product quantizer with a custom subquantizer trainer.
+    ///
+    /// The instances are sliced into the subspaces of the subquantizers
+    /// and the codebook of every subquantizer is trained with `trainer`.
+    /// See `SubquantizerTrainer` and `TrainPQ::train_pq_using` for a
+    /// description of the other arguments. `n_iterations` and
+    /// `n_attempts` are passed to the trainer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_pq_with_trainer_using<T, S, R>(
+        trainer: &T,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        T: SubquantizerTrainer<A> + Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        Self::train_with_trainer(
+            trainer,
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            NestedParallelism::for_tasks(n_subquantizers),
+            rng,
+        )
     }
 
-    /// Get the subquantizer centroids.
-    pub fn subquantizers(&self) -> ArrayView3<A> {
-        self.quantizers.view()
+    /// Train a product quantizer with a custom subquantizer trainer.
+    ///
+    /// This is the fallible counterpart of
+    /// `PQ::train_pq_with_trainer_using`, which returns the error of
+    /// `SubquantizerTrainer::try_train_subquantizer` when training of a
+    /// subquantizer fails. E.g. `KMeansTrainer` returns
+    /// `Error::TrainingDiverged` when its `TrainingWatchdog` detects a
+    /// diverging loss.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_train_pq_with_trainer_using<T, S, R>(
+        trainer: &T,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> Result<PQ<A>, Error>
+    where
+        T: SubquantizerTrainer<A> + Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        Self::try_train_with_trainer(
+            trainer,
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            NestedParallelism::for_tasks(n_subquantizers),
+            rng,
+        )
     }
-}
 
-impl<A> TrainPQ<A> for PQ<A>
-where
-    A: NdFloat + Sum,
-    usize: AsPrimitive<A>,
-{
-    fn train_pq_using<S, R>(
+    #[allow(clippy::too_many_arguments)]
+    fn train_with_trainer<T, S, R>(
+        trainer: &T,
         n_subquantizers: usize,
         n_subquantizer_bits: u32,
         n_iterations: usize,
         n_attempts: usize,
         instances: ArrayBase<S, Ix2>,
+        parallelism: NestedParallelism,
         rng: R,
     ) -> PQ<A>
     where
+        T: SubquantizerTrainer<A> + Sync,
         S: Sync + Data<Elem = A>,
-        R: RngCore + SeedableRng + Send,
+        R: RngCore,
     {
+        Self::try_train_with_trainer(
+            trainer,
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            parallelism,
+            rng,
+        )
+        .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn try_train_with_trainer<T, S, R>(
+        trainer: &T,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        parallelism: NestedParallelism,
+        mut rng: R,
+    ) -> Result<PQ<A>, Error>
+    where
+        T: SubquantizerTrainer<A> + Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        let start = Instant::now();
+
         Self::check_quantizer_invariants(
             n_subquantizers,
             n_subquantizer_bits,
@@ -207,37 +507,413 @@ where
             n_attempts,
             instances.view(),
         );
+        Self::log_codebook_size(
+            instances.nrows(),
+            instances.ncols(),
+            n_subquantizers,
+            n_subquantizer_bits,
+            parallelism,
+        );
 
-        let rng = ReseedOnCloneRng(rng);
+        // Subquantizers are trained in parallel, each subquantizer gets
+        // its own RNG that is seeded from the caller's RNG.
+        let rngs = iter::repeat_with(|| {
+            XorShiftRng::from_rng(&mut rng).expect("Cannot seed subquantizer RNG")
+        })
+        .take(n_subquantizers)
+        .collect::<Vec<_>>();
 
-        let rngs = iter::repeat_with(|| rng.clone())
-            .take(n_subquantizers)
-            .collect::<Vec<_>>();
+        let codebook_len = 2usize.pow(n_subquantizer_bits);
+        let sq_dims = instances.ncols() / n_subquantizers;
+        let mut quantizers = Array3::zeros((n_subquantizers, codebook_len, sq_dims));
 
-        let quantizers = rngs
+        quantizers
+            .axis_iter_mut(Axis(0))
             .into_par_iter()
+            .zip(rngs)
+            .with_min_len(parallelism.outer_min_len(n_subquantizers))
             .enumerate()
-            .map(|(idx, rng)| {
-                Self::train_subquantizer(
-                    idx,
+            .try_for_each(|(idx, (mut quantizer, mut rng))| {
+                let offset = idx * sq_dims;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let sq_instances = instances.slice(s![.., offset..offset + sq_dims]);
+
+                let config = SubquantizerConfig {
+                    subquantizer_idx: idx,
+                    n_subquantizers,
+                    codebook_len,
+                    n_iterations,
+                    n_attempts,
+                    parallelism,
+                };
+                let codebook = trainer.try_train_subquantizer(sq_instances, config, &mut rng)?;
+                assert_eq!(
+                    codebook.dim(),
+                    (codebook_len, sq_dims),
+                    "Subquantizer trainer returned a codebook with an incorrect shape"
+                );
+                quantizer.assign(&codebook);
+                Ok(())
+            })?;
+
+        log_record(|| TrainingRecord::new("quantizer", start.elapsed()));
+
+        Ok(PQ {
+            projection: None,
+            quantizers,
+            manifest: Some(
+                TrainingManifest::new(
+                    "PQ",
                     n_subquantizers,
-                    2usize.pow(n_subquantizer_bits),
+                    n_subquantizer_bits,
                     n_iterations,
                     n_attempts,
-                    instances.view(),
-                    rng,
+                    instances.dim(),
+                    start,
                 )
-                .insert_axis(Axis(0))
+                .with_rng::<R>()
+                .with_settings(trainer.settings()),
+            ),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
+        })
+    }
+
+    /// Refine the codebooks with coordinate descent on training data.
+    ///
+    /// This optional post-training pass performs `n_sweeps` sweeps over
+    /// the subquantizers. In every sweep, the training `instances` are
+    /// re-encoded and each centroid is refit to the mean of the
+    /// instances that are assigned to it. Centroids without assigned
+    /// instances are left unchanged. The pass never increases the
+    /// reconstruction error of the training data and is most useful
+    /// after training on a subsample or with few iterations. The
+    /// projection of an optimized product quantizer is not updated.
+    ///
+    /// Since the codebooks change, polysemous codes (see
+    /// `PQ::polysemous_using`) are discarded and the training manifest
+    /// is replaced by a manifest of the refinement.
+    ///
+    /// Returns the mean squared reconstruction error of the training
+    /// instances after the last sweep, when they are re-encoded with
+    /// the refined codebooks.
+    pub fn refine<S>(&mut self, instances: ArrayBase<S, Ix2>, n_sweeps: usize) -> A
+    where
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        assert!(n_sweeps > 0, "Refinement should at least use one sweep.");
+        assert_eq!(
+            instances.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert!(
+            instances.nrows() > 0,
+            "Cannot refine a quantizer without instances"
+        );
+
+        let start = Instant::now();
+        let instances = self.rotate_batch(instances);
+        let n_subquantizers = self.quantizers.len_of(Axis(0));
+        let sq_dims = instances.ncols() / n_subquantizers;
+        let parallelism = NestedParallelism::for_tasks(n_subquantizers);
+
+        let losses = self
+            .quantizers
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .with_min_len(parallelism.outer_min_len(n_subquantizers))
+            .enumerate()
+            .map(|(idx, mut quantizer)| {
+                let offset = idx * sq_dims;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let sq_instances = instances.slice(s![.., offset..offset + sq_dims]);
+
+                let mut scratch = KMeansScratch::new().with_parallelism(parallelism);
+                kmeans_with_centroids_scratch(
+                    sq_instances,
+                    Axis(0),
+                    quantizer.view_mut(),
+                    NIterationsCondition(n_sweeps),
+                    &mut scratch,
+                );
+
+                // The k-means loss is computed with the assignments
+                // before the last centroid update, so re-encode.
+                let assignments = cluster_assignments(quantizer.view(), sq_instances, Axis(0));
+                sq_instances
+                    .outer_iter()
+                    .zip(assignments.iter())
+                    .map(|(instance, &centroid)| {
+                        let diff = &instance - &quantizer.row(centroid);
+                        diff.dot(&diff)
+                    })
+                    .sum::<A>()
             })
             .collect::<Vec<_>>();
+        let loss = losses.into_iter().sum::<A>() / instances.nrows().as_();
+
+        let mut manifest = TrainingManifest::new(
+            "RefinedPQ",
+            n_subquantizers,
+            self.n_quantizer_centroids()
+                .next_power_of_two()
+                .trailing_zeros(),
+            n_sweeps,
+            1,
+            instances.dim(),
+            start,
+        )
+        .with_normalization(&self.normalization);
+        manifest.objective = vec![loss.to_f64().unwrap()];
+        self.manifest = Some(manifest);
+        self.polysemous = None;
+
+        loss
+    }
+
+    /// Train a product quantizer on a subset of instances per subquantizer.
+    ///
+    /// `subsets` contains for each subquantizer the indices of the rows
+    /// of `instances` that are used to train that subquantizer. Training
+    /// the subquantizers on independent samples decorrelates the errors
+    /// of the subquantizers and reduces training time on large data sets.
+    /// Every subset must contain more instances than there are centroids
+    /// per subquantizer.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_pq_subsets_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        subsets: &[Vec<usize>],
+        mut rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        let start = Instant::now();
+
+        Self::check_quantizer_invariants(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+        );
+        assert_eq!(
+            subsets.len(),
+            n_subquantizers,
+            "Expected an instance subset for each subquantizer"
+        );
+
+        let codebook_len = 2usize.pow(n_subquantizer_bits);
+        for (idx, subset) in subsets.iter().enumerate() {
+            assert!(
+                subset.len() > codebook_len,
+                "Subset {} has {} instances, but at least {} are required for {} centroids",
+                idx,
+                subset.len(),
+                codebook_len + 1,
+                codebook_len
+            );
+            if let Some(&instance) = subset
+                .iter()
+                .find(|&&instance| instance >= instances.nrows())
+            {
+                panic!(
+                    "Subset {} contains instance {}, but there are only {} instances",
+                    idx,
+                    instance,
+                    instances.nrows()
+                );
+            }
+        }
+
+        let rngs = iter::repeat_with(|| {
+            XorShiftRng::from_rng(&mut rng).expect("Cannot seed subquantizer RNG")
+        })
+        .take(n_subquantizers)
+        .collect::<Vec<_>>();
+
+        let sq_dims = instances.ncols() / n_subquantizers;
+        let mut quantizers = Array3::zeros((n_subquantizers, codebook_len, sq_dims));
+
+        let trainer = KMeansTrainer::new();
+        let parallelism = NestedParallelism::for_tasks(n_subquantizers);
+        Self::log_codebook_size(
+            subsets.iter().map(Vec::len).min().unwrap_or(0),
+            instances.ncols(),
+            n_subquantizers,
+            n_subquantizer_bits,
+            parallelism,
+        );
 
-        let views = quantizers.iter().map(|a| a.view()).collect::<Vec<_>>();
+        quantizers
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .zip(rngs)
+            .zip(subsets)
+            .with_min_len(parallelism.outer_min_len(n_subquantizers))
+            .enumerate()
+            .for_each(|(idx, ((mut quantizer, mut rng), subset))| {
+                // Only copy the subquantizer's dimensions of the subset.
+                let offset = idx * sq_dims;
+                // ndarray#474
+                #[allow(clippy::deref_addrof)]
+                let sq_instances = instances
+                    .slice(s![.., offset..offset + sq_dims])
+                    .select(Axis(0), subset);
+
+                let config = SubquantizerConfig {
+                    subquantizer_idx: idx,
+                    n_subquantizers,
+                    codebook_len,
+                    n_iterations,
+                    n_attempts,
+                    parallelism,
+                };
+                quantizer.assign(&trainer.train_subquantizer(
+                    sq_instances.view(),
+                    config,
+                    &mut rng,
+                ));
+            });
 
         PQ {
             projection: None,
-            quantizers: concatenate(Axis(0), &views).expect("Cannot concatenate subquantizers"),
+            quantizers,
+            manifest: Some(
+                TrainingManifest::new(
+                    "PQ",
+                    n_subquantizers,
+                    n_subquantizer_bits,
+                    n_iterations,
+                    n_attempts,
+                    instances.dim(),
+                    start,
+                )
+                .with_rng::<R>()
+                .with_settings(SubquantizerTrainer::<A>::settings(&trainer)),
+            ),
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         }
     }
+
+    /// Train a product quantizer on an independent sample per subquantizer.
+    ///
+    /// Each subquantizer is trained on `n_samples` instances that are
+    /// sampled without replacement from the rows of `instances`,
+    /// independently of the samples of the other subquantizers. See
+    /// `PQ::train_pq_subsets_using`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_pq_subsampled_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        n_samples: usize,
+        mut rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            n_samples <= instances.nrows(),
+            "Cannot sample more instances than available: {} instances, {} samples",
+            instances.nrows(),
+            n_samples
+        );
+
+        let subsets = iter::repeat_with(|| index::sample(&mut rng, instances.nrows(), n_samples))
+            .map(IndexVec::into_vec)
+            .take(n_subquantizers)
+            .collect::<Vec<_>>();
+
+        Self::train_pq_subsets_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            &subsets,
+            rng,
+        )
+    }
+
+    /// Reconstruct a batch of vectors, checking the quantization codes.
+    ///
+    /// Returns an error when a code does not refer to a centroid of its
+    /// subquantizer.
+    pub fn try_reconstruct_batch<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+    ) -> Result<Array2<A>, Error>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        self.view().try_reconstruct_batch(quantized)
+    }
+
+    /// Reconstruct a vector, checking the quantization codes.
+    ///
+    /// Returns an error when a code does not refer to a centroid of its
+    /// subquantizer.
+    pub fn try_reconstruct_vector<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix1>,
+    ) -> Result<Array1<A>, Error>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        self.view().try_reconstruct_vector(quantized)
+    }
+}
+
+impl<A> TrainPQ<A> for PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn train_pq_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        Self::train_pq_parallel_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            NestedParallelism::for_tasks(n_subquantizers),
+            rng,
+        )
+    }
 }
 
 impl<A> QuantizeVector<A> for PQ<A>
@@ -250,27 +926,17 @@ where
         S: Data<Elem = A>,
         usize: AsPrimitive<I>,
     {
-        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
-        self.quantize_batch_into(x, quantized.view_mut());
-        quantized
+        self.view().quantize_batch(x)
     }
 
     /// Quantize a batch of vectors into an existing matrix.
-    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, quantized: ArrayViewMut2<I>)
     where
         I: AsPrimitive<usize> + Bounded + Zero,
         S: Data<Elem = A>,
         usize: AsPrimitive<I>,
     {
-        match self.projection {
-            Some(ref projection) => {
-                let rx = x.dot(projection);
-                primitives::quantize_batch_into(self.quantizers.view(), rx, quantized.view_mut());
-            }
-            None => {
-                primitives::quantize_batch_into(self.quantizers.view(), x, quantized.view_mut());
-            }
-        }
+        self.view().quantize_batch_into(x, quantized)
     }
 
     fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
@@ -279,13 +945,7 @@ where
         S: Data<Elem = A>,
         usize: AsPrimitive<I>,
     {
-        match self.projection {
-            Some(ref projection) => {
-                let rx = x.dot(projection);
-                primitives::quantize(self.quantizers.view(), self.reconstructed_len(), rx)
-            }
-            None => primitives::quantize(self.quantizers.view(), self.reconstructed_len(), x),
-        }
+        self.view().quantize_vector(x)
     }
 
     fn quantized_len(&self) -> usize {
@@ -302,29 +962,19 @@ where
         I: AsPrimitive<usize>,
         S: Data<Elem = I>,
     {
-        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
-        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
-        reconstructions
+        self.view().reconstruct_batch(quantized)
     }
 
     fn reconstruct_batch_into<I, S>(
         &self,
         quantized: ArrayBase<S, Ix2>,
-        mut reconstructions: ArrayViewMut2<A>,
+        reconstructions: ArrayViewMut2<A>,
     ) where
         I: AsPrimitive<usize>,
         S: Data<Elem = I>,
     {
-        primitives::reconstruct_batch_into(
-            self.quantizers.view(),
-            quantized,
-            reconstructions.view_mut(),
-        );
-
-        if let Some(ref projection) = self.projection {
-            let projected_reconstruction = reconstructions.dot(&projection.t());
-            reconstructions.assign(&projected_reconstruction);
-        }
+        self.view()
+            .reconstruct_batch_into(quantized, reconstructions)
     }
 
     fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
@@ -332,27 +982,37 @@ where
         I: AsPrimitive<usize>,
         S: Data<Elem = I>,
     {
-        let reconstruction = primitives::reconstruct(self.quantizers.view(), quantized);
-        match self.projection {
-            Some(ref projection) => reconstruction.dot(&projection.t()),
-            None => reconstruction,
-        }
+        self.view().reconstruct_vector(quantized)
     }
 
     fn reconstructed_len(&self) -> usize {
         primitives::reconstructed_len(self.quantizers.view())
     }
+
+    fn normalize_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.normalization.normalize_batch(x)
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use ndarray::{array, Array1, Array2, Array3, ArrayView2};
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    use ndarray::{array, s, Array1, Array2, Array3, ArrayView2, Axis};
     use rand::distributions::Uniform;
+    use rand::{RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
 
     use super::PQ;
     use crate::linalg::EuclideanDistance;
     use crate::ndarray_rand::RandomExt;
-    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ};
+    use crate::parallel::NestedParallelism;
+    use crate::pq::{Normalization, QuantizeVector, ReconstructVector, TrainPQ};
 
     /// Calculate the average euclidean distances between the the given
     /// instances and the instances returned by quantizing and then
@@ -392,13 +1052,12 @@ mod tests {
         ]
     }
 
-    fn test_pq() -> PQ<f32> {
+    /// Product quantizer with two subquantizers of two centroids.
+    ///
+    /// This quantizer is shared by the unit tests of other modules.
+    pub(crate) fn test_pq() -> PQ<f32> {
         let quantizers = array![[[1., 0., 0.], [0., 1., 0.]], [[1., -1., 0.], [0., 1., 0.]],];
-
-        PQ {
-            projection: None,
-            quantizers,
-        }
+        PQ::new(None, quantizers)
     }
 
     #[test]
@@ -423,6 +1082,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn quantize_empty_batch() {
+        let mut projected = test_pq();
+        projected.projection = Some(Array2::eye(6));
+
+        for pq in &[test_pq(), projected] {
+            for &cols in &[0, 6] {
+                let quantized = pq.quantize_batch::<u8, _>(Array2::<f32>::zeros((0, cols)));
+                assert_eq!(quantized.shape(), &[0, 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn reconstruct_empty_batch() {
+        let mut projected = test_pq();
+        projected.projection = Some(Array2::eye(6));
+
+        for pq in &[test_pq(), projected] {
+            for &cols in &[0, 2] {
+                let reconstructions = pq.reconstruct_batch(Array2::<u8>::zeros((0, cols)));
+                assert_eq!(reconstructions.shape(), &[0, 6]);
+            }
+        }
+    }
+
     #[test]
     fn quantize_with_pq() {
         let uniform = Uniform::new(0f32, 1f32);
@@ -433,12 +1118,262 @@ mod tests {
         assert!(loss < 0.08);
     }
 
+    #[test]
+    fn train_pq_is_reproducible() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((64, 20), uniform);
+        let pq1 = PQ::train_pq_with_seed(10, 3, 5, 1, instances.view(), 42);
+        let pq2 = PQ::train_pq_with_seed(10, 3, 5, 1, instances.view(), 42);
+        assert_eq!(pq1.subquantizers(), pq2.subquantizers());
+
+        // The manifests differ in their durations.
+        assert_eq!(pq1, pq2);
+    }
+
+    #[test]
+    fn train_pq_on_column_instances() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        // Instances are the columns of this matrix.
+        let columns = Array2::random_using((20, 64), uniform, &mut rng);
+        let rows = columns.t().to_owned();
+
+        let pq_columns = PQ::train_pq_axis_using(
+            10,
+            3,
+            5,
+            1,
+            columns.view(),
+            Axis(1),
+            XorShiftRng::seed_from_u64(42),
+        );
+        let pq_rows = PQ::train_pq_using(10, 3, 5, 1, rows.view(), XorShiftRng::seed_from_u64(42));
+        assert_eq!(pq_columns.subquantizers(), pq_rows.subquantizers());
+
+        let quantized: Array2<u8> = pq_columns.quantize_batch_axis(columns.view(), Axis(1));
+        assert_eq!(quantized, pq_rows.quantize_batch::<u8, _>(rows.view()));
+        assert_eq!(
+            pq_rows.quantize_batch_axis::<u8, _>(rows.view(), Axis(0)),
+            quantized
+        );
+    }
+
+    #[test]
+    fn train_pq_using_dyn_rng() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((64, 20), uniform);
+        let mut rng = rand::thread_rng();
+        let rng: &mut dyn RngCore = &mut rng;
+        let pq = PQ::train_pq_using(10, 3, 5, 1, instances.view(), rng);
+        assert_eq!(pq.subquantizers().shape(), &[10, 8, 2]);
+    }
+
+    #[test]
+    fn train_pq_on_subsets() {
+        let instances = Array2::from_shape_fn((16, 2), |(row, _)| row as f32);
+        let subsets = vec![(0..8).collect(), (8..16).collect()];
+        let pq = PQ::train_pq_subsets_using(
+            2,
+            1,
+            5,
+            1,
+            instances.view(),
+            &subsets,
+            XorShiftRng::seed_from_u64(42),
+        );
+
+        let subquantizers = pq.subquantizers();
+        assert!(subquantizers.index_axis(Axis(0), 0).iter().all(|&v| v < 8.));
+        assert!(subquantizers
+            .index_axis(Axis(0), 1)
+            .iter()
+            .all(|&v| v >= 8.));
+    }
+
+    #[test]
+    #[should_panic(expected = "Subset 1 has 2 instances, but at least 3 are required")]
+    fn train_pq_on_subsets_rejects_small_subsets() {
+        let instances = Array2::from_shape_fn((16, 2), |(row, _)| row as f32);
+        let subsets = vec![(0..8).collect(), vec![8, 9]];
+        PQ::train_pq_subsets_using(
+            2,
+            1,
+            5,
+            1,
+            instances.view(),
+            &subsets,
+            XorShiftRng::seed_from_u64(42),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Subset 0 contains instance 16, but there are only 16 instances")]
+    fn train_pq_on_subsets_rejects_out_of_bounds_instances() {
+        let instances = Array2::from_shape_fn((16, 2), |(row, _)| row as f32);
+        let subsets = vec![(9..17).collect(), (0..8).collect()];
+        PQ::train_pq_subsets_using(
+            2,
+            1,
+            5,
+            1,
+            instances.view(),
+            &subsets,
+            XorShiftRng::seed_from_u64(42),
+        );
+    }
+
+    #[test]
+    fn train_pq_subsampled_is_reproducible() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((64, 20), uniform);
+        let train = || {
+            PQ::train_pq_subsampled_using(
+                10,
+                3,
+                5,
+                1,
+                instances.view(),
+                32,
+                XorShiftRng::seed_from_u64(42),
+            )
+        };
+        let pq = train();
+        assert_eq!(pq.subquantizers().shape(), &[10, 8, 2]);
+        assert_eq!(pq.subquantizers(), train().subquantizers());
+    }
+
+    #[test]
+    fn concurrent_quantization() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Arc::new(Array2::random((64, 20), uniform));
+        let pq = Arc::new(PQ::train_pq_with_seed(10, 3, 5, 1, instances.view(), 42));
+        let expected = pq.quantize_batch::<u8, _>(instances.view());
+
+        let handles = (0..4)
+            .map(|_| {
+                let pq = pq.clone();
+                let instances = instances.clone();
+                thread::spawn(move || {
+                    let quantized = pq.quantize_batch::<u8, _>(instances.view());
+                    let reconstructed = pq.reconstruct_batch(quantized.view());
+                    (quantized, reconstructed)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            let (quantized, reconstructed) = handle.join().unwrap();
+            assert_eq!(quantized, expected);
+            assert_eq!(reconstructed, pq.reconstruct_batch(expected.view()));
+        }
+    }
+
+    #[test]
+    fn train_pq_parallelism_is_deterministic() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((64, 20), uniform);
+        let train = |parallelism| {
+            PQ::train_pq_parallel_using(
+                10,
+                3,
+                5,
+                2,
+                instances.view(),
+                parallelism,
+                XorShiftRng::seed_from_u64(42),
+            )
+        };
+
+        let pq = train(NestedParallelism::sequential());
+        assert_eq!(
+            pq.subquantizers(),
+            train(NestedParallelism::new(2, 4)).subquantizers()
+        );
+        assert_eq!(
+            pq.subquantizers(),
+            PQ::train_pq_using(
+                10,
+                3,
+                5,
+                2,
+                instances.view(),
+                XorShiftRng::seed_from_u64(42)
+            )
+            .subquantizers()
+        );
+    }
+
+    #[test]
+    fn pq_training_manifest() {
+        let uniform = Uniform::new(0f32, 1f32);
+        let instances = Array2::random((64, 20), uniform);
+        let pq = PQ::train_pq_with_seed(10, 3, 5, 2, instances.view(), 42);
+        let manifest = pq.manifest().unwrap();
+        assert_eq!(manifest.quantizer, "PQ");
+        assert_eq!(manifest.n_subquantizers, 10);
+        assert_eq!(manifest.n_subquantizer_bits, 3);
+        assert_eq!(manifest.n_iterations, 5);
+        assert_eq!(manifest.n_attempts, 2);
+        assert_eq!(manifest.seed, Some(42));
+        assert_eq!((manifest.n_instances, manifest.n_dims), (64, 20));
+        assert_eq!(manifest.rng, Some("rand_xorshift::XorShiftRng"));
+        assert_eq!(manifest.normalization, "None");
+        assert_eq!(manifest.settings["initialization"], "RandomInstances");
+        assert_eq!(manifest.settings["kernel"], "Blocked");
+
+        let pq = pq.with_normalization(Normalization::L2);
+        assert_eq!(pq.manifest().unwrap().normalization, "L2");
+
+        assert!(test_pq().manifest().is_none());
+    }
+
+    #[test]
+    fn refine_reduces_training_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let uniform = Uniform::new(-1f32, 1f32);
+        let instances = Array2::random_using((256, 16), uniform, &mut rng);
+
+        let mut pq = PQ::train_pq_using(4, 4, 1, 1, instances.view(), &mut rng);
+        let loss = avg_euclidean_loss(instances.view(), &pq);
+
+        let mse = pq.refine(instances.view(), 5);
+        let refined_loss = avg_euclidean_loss(instances.view(), &pq);
+        assert!(refined_loss < loss);
+
+        // The returned error is the error after re-encoding.
+        let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+        let errors = &instances - &pq.reconstruct_batch(quantized);
+        assert!((errors.mapv(|v| v * v).sum() / 256. - mse).abs() < 1e-4);
+
+        let manifest = pq.manifest().unwrap();
+        assert_eq!(manifest.quantizer, "RefinedPQ");
+        assert_eq!(manifest.n_iterations, 5);
+        assert_eq!(manifest.objective, vec![f64::from(mse)]);
+    }
+
+    #[test]
+    fn refine_discards_polysemous_codes() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let uniform = Uniform::new(-1f32, 1f32);
+        let instances = Array2::random_using((256, 16), uniform, &mut rng);
+
+        let mut pq = PQ::train_pq_using(4, 4, 1, 1, instances.view(), &mut rng)
+            .polysemous_using(10, &mut rng);
+        assert!(pq.hamming_filterable());
+        pq.refine(instances.view(), 1);
+        assert!(!pq.hamming_filterable());
+    }
+
     #[test]
     fn quantize_with_type() {
         let uniform = Uniform::new(0f32, 1f32);
         let pq = PQ {
             projection: None,
             quantizers: Array3::random((1, 256, 10), uniform),
+            manifest: None,
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         };
         pq.quantize_vector::<u8, _>(Array1::random((10,), uniform));
     }
@@ -450,10 +1385,60 @@ mod tests {
         let pq = PQ {
             projection: None,
             quantizers: Array3::random((1, 257, 10), uniform),
+            manifest: None,
+            polysemous: None,
+            normalization: Normalization::None,
+            metadata: BTreeMap::new(),
         };
         pq.quantize_vector::<u8, _>(Array1::random((10,), uniform));
     }
 
+    #[test]
+    fn quantize_with_16_bit_subquantizers() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let uniform = Uniform::new(-1f32, 1f32);
+        let pq = PQ::new(None, Array3::random_using((2, 65536, 2), uniform, &mut rng));
+        let instances = Array2::random_using((10, 4), uniform, &mut rng);
+
+        let quantized: Array2<u16> = pq.quantize_batch(instances.view());
+        for (instance, codes) in instances.outer_iter().zip(quantized.outer_iter()) {
+            assert_eq!(pq.quantize_vector::<u16, _>(instance), codes);
+            for (sq, (quantizer, &code)) in pq.quantizers.outer_iter().zip(codes).enumerate() {
+                let sub_instance = instance.slice(s![sq * 2..(sq + 1) * 2]);
+                let min_dist = quantizer
+                    .outer_iter()
+                    .map(|centroid| centroid.euclidean_distance(sub_instance))
+                    .fold(f32::INFINITY, f32::min);
+                let code_dist = quantizer
+                    .index_axis(Axis(0), code as usize)
+                    .euclidean_distance(sub_instance);
+                assert!(code_dist - min_dist < 1e-5);
+            }
+        }
+
+        let reconstructions = pq.reconstruct_batch(quantized.view());
+        let codes32 = quantized.mapv(u32::from);
+        assert_eq!(pq.reconstruct_batch(codes32), reconstructions);
+    }
+
+    #[test]
+    fn training_memory_estimate_grows_with_codebook() {
+        let parallelism = NestedParallelism::new(4, 2);
+        let estimate_8 = PQ::<f32>::training_memory_estimate(100_000, 64, 8, 8, parallelism);
+        let estimate_16 = PQ::<f32>::training_memory_estimate(100_000, 64, 8, 16, parallelism);
+
+        // At least the 16-bit codebooks: 8 * 65,536 * 8 floats.
+        assert!(estimate_16 >= 8 * 65536 * 8 * 4);
+        assert!(estimate_16 > estimate_8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn train_pq_rejects_more_than_32_bits() {
+        let instances = Array2::<f32>::zeros((10, 4));
+        PQ::train_pq(2, 33, 1, 1, instances);
+    }
+
     #[test]
     fn quantizer_lens() {
         let quantizer = test_pq();
@@ -1,14 +1,19 @@
 use std::iter::Sum;
 
-#[cfg(feature = "opq-train")]
-use ndarray::Array2;
 use ndarray::{
-    s, Array1, ArrayBase, ArrayView3, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat, Zip,
+    s, Array, Array1, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, Axis, Data,
+    Dimension, Ix1, Ix2, NdFloat, Zip,
 };
 
 use num_traits::{AsPrimitive, Bounded, Zero};
+use rayon::prelude::*;
 
+use crate::error::Error;
 use crate::kmeans::{cluster_assignment, cluster_assignments};
+use crate::linalg::SquaredEuclideanDistance;
+use crate::parallel::BatchParallelism;
+use crate::pq::AdcDistances;
+use crate::simd;
 
 pub fn quantize<A, I, S>(
     quantizers: ArrayView3<A>,
@@ -47,7 +52,6 @@ where
     indices
 }
 
-#[cfg(feature = "opq-train")]
 pub fn quantize_batch<A, I, S>(quantizers: ArrayView3<A>, x: ArrayBase<S, Ix2>) -> Array2<I>
 where
     A: NdFloat + Sum,
@@ -61,21 +65,123 @@ where
 }
 
 pub fn quantize_batch_into<A, I, S>(
+    quantizers: ArrayView3<A>,
+    x: ArrayBase<S, Ix2>,
+    quantized: ArrayViewMut2<I>,
+) where
+    A: NdFloat + Sum,
+    I: 'static + AsPrimitive<usize> + Bounded + Copy + Zero,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<I>,
+{
+    quantize_batch_parallel_into(quantizers, x, quantized, BatchParallelism::default())
+}
+
+pub fn quantize_batch_parallel_into<A, I, S>(
     quantizers: ArrayView3<A>,
     x: ArrayBase<S, Ix2>,
     mut quantized: ArrayViewMut2<I>,
+    parallelism: BatchParallelism,
 ) where
     A: NdFloat + Sum,
     I: 'static + AsPrimitive<usize> + Bounded + Copy + Zero,
     S: Data<Elem = A>,
     usize: AsPrimitive<I>,
 {
+    assert!(
+        quantized.nrows() == x.nrows() && quantized.ncols() == quantizers.len_of(Axis(0)),
+        "Quantized matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
+        x.nrows(),
+        quantizers.len_of(Axis(0)),
+        quantized.nrows(),
+        quantized.ncols()
+    );
+
+    // Empty batches are accepted regardless of their number of columns.
+    if x.nrows() == 0 {
+        return;
+    }
+
     assert_eq!(
         reconstructed_len(quantizers.view()),
         x.ncols(),
         "Quantizer and vector length mismatch"
     );
 
+    let work_per_row = x.ncols() * quantizers.len_of(Axis(1));
+    if !parallelism.is_parallel(x.nrows(), work_per_row) {
+        quantize_batch_serial_into(quantizers, x.view(), quantized);
+        return;
+    }
+
+    // The codes type is not necessarily Send, so the parallel kernel
+    // assigns to a usize matrix, which is converted afterwards.
+    let chunk_len = parallelism.chunk_len(x.nrows());
+    let mut assignments = Array2::<usize>::zeros(quantized.dim());
+    x.axis_chunks_iter(Axis(0), chunk_len)
+        .into_par_iter()
+        .zip(assignments.axis_chunks_iter_mut(Axis(0), chunk_len))
+        .for_each(|(x, assignments)| {
+            quantize_batch_serial_into::<A, usize>(quantizers, x, assignments)
+        });
+
+    Zip::from(&mut quantized)
+        .and(&assignments)
+        .apply(|quantized, assignment| *quantized = assignment.as_());
+}
+
+fn quantize_batch_serial_into<A, I>(
+    quantizers: ArrayView3<A>,
+    x: ArrayView2<A>,
+    mut quantized: ArrayViewMut2<I>,
+) where
+    A: NdFloat + Sum,
+    I: 'static + AsPrimitive<usize> + Copy,
+    usize: AsPrimitive<I>,
+{
+    let mut offset = 0;
+    for (quantizer, mut quantized) in quantizers
+        .outer_iter()
+        .zip(quantized.axis_iter_mut(Axis(1)))
+    {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        let sub_matrix = x.slice(s![.., offset..offset + quantizer.ncols()]);
+        let assignments = cluster_assignments(quantizer.view(), sub_matrix, Axis(0));
+        Zip::from(&mut quantized)
+            .and(&assignments)
+            .apply(|quantized, assignment| *quantized = assignment.as_());
+
+        offset += quantizer.ncols();
+    }
+}
+
+/// Dequantize int8 values as *scale * (x - zero_point)*.
+pub fn dequantize_i8<A, S, D>(x: ArrayBase<S, D>, scale: A, zero_point: i8) -> Array<A, D>
+where
+    A: NdFloat,
+    S: Data<Elem = i8>,
+    D: Dimension,
+{
+    x.mapv(|v| scale * A::from(i16::from(v) - i16::from(zero_point)).unwrap())
+}
+
+/// Quantize a batch of int8 vectors.
+///
+/// The vectors are dequantized one subspace at a time, so that the
+/// batch is never dequantized as a whole.
+pub fn quantize_batch_i8_into<A, I, S>(
+    quantizers: ArrayView3<A>,
+    x: ArrayBase<S, Ix2>,
+    scale: A,
+    zero_point: i8,
+    mut quantized: ArrayViewMut2<I>,
+) where
+    A: NdFloat + Sum,
+    I: 'static + AsPrimitive<usize> + Bounded + Copy + Zero,
+    S: Data<Elem = i8>,
+    usize: AsPrimitive<I>,
+{
     assert!(
         quantized.nrows() == x.nrows() && quantized.ncols() == quantizers.len_of(Axis(0)),
         "Quantized matrix has incorrect shape, expected: ({}, {}), got: ({}, {})",
@@ -85,6 +191,16 @@ pub fn quantize_batch_into<A, I, S>(
         quantized.ncols()
     );
 
+    if x.nrows() == 0 {
+        return;
+    }
+
+    assert_eq!(
+        reconstructed_len(quantizers.view()),
+        x.ncols(),
+        "Quantizer and vector length mismatch"
+    );
+
     let mut offset = 0;
     for (quantizer, mut quantized) in quantizers
         .outer_iter()
@@ -92,8 +208,12 @@ pub fn quantize_batch_into<A, I, S>(
     {
         // ndarray#474
         #[allow(clippy::deref_addrof)]
-        let sub_matrix = x.slice(s![.., offset..offset + quantizer.ncols()]);
-        let assignments = cluster_assignments(quantizer.view(), sub_matrix, Axis(0));
+        let sub_matrix = dequantize_i8(
+            x.slice(s![.., offset..offset + quantizer.ncols()]),
+            scale,
+            zero_point,
+        );
+        let assignments = cluster_assignments(quantizer.view(), sub_matrix.view(), Axis(0));
         Zip::from(&mut quantized)
             .and(&assignments)
             .apply(|quantized, assignment| *quantized = assignment.as_());
@@ -102,6 +222,99 @@ pub fn quantize_batch_into<A, I, S>(
     }
 }
 
+/// Compute distance tables for a batch of queries.
+///
+/// Returns an array with shape *(n_queries, n_subquantizers,
+/// n_centroids)*, where *(i, j, k)* is the squared Euclidean distance
+/// between the *j*-th slice of query *i* and centroid *k* of
+/// subquantizer *j*. The distances of each subquantizer are computed
+/// for all queries using a single matrix multiplication.
+pub fn adc_tables_batch<A, S>(quantizers: ArrayView3<A>, queries: ArrayBase<S, Ix2>) -> Array3<A>
+where
+    A: NdFloat,
+    S: Data<Elem = A>,
+{
+    assert_eq!(
+        reconstructed_len(quantizers.view()),
+        queries.ncols(),
+        "Quantizer and vector length mismatch"
+    );
+
+    let mut tables = Array3::zeros((
+        queries.nrows(),
+        quantizers.len_of(Axis(0)),
+        quantizers.len_of(Axis(1)),
+    ));
+
+    let mut offset = 0;
+    for (quantizer, mut sq_tables) in quantizers.outer_iter().zip(tables.axis_iter_mut(Axis(1))) {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        let sub_queries = queries.slice(s![.., offset..offset + quantizer.ncols()]);
+        sq_tables.assign(&sub_queries.squared_euclidean_distance(quantizer));
+
+        offset += quantizer.ncols();
+    }
+
+    tables
+}
+
+/// Compute asymmetric distances for a matrix of codes.
+///
+/// `table` is the distance table of a query with shape
+/// *(n_subquantizers, n_centroids)*. The codes are processed in
+/// parallel when `parallelism` decides so.
+pub fn adc_distances<A, I>(
+    table: ArrayView2<A>,
+    codes: ArrayView2<I>,
+    parallelism: BatchParallelism,
+) -> Array1<A>
+where
+    A: NdFloat + Sum,
+    I: AsPrimitive<usize> + Sync,
+{
+    assert_eq!(
+        codes.ncols(),
+        table.nrows(),
+        "Quantization length does not match number of subquantizers"
+    );
+
+    if !parallelism.is_parallel(codes.nrows(), codes.ncols()) {
+        return adc_distances_serial(table, codes);
+    }
+
+    let chunk_len = parallelism.chunk_len(codes.nrows());
+    let mut distances = Array1::zeros(codes.nrows());
+    codes
+        .axis_chunks_iter(Axis(0), chunk_len)
+        .into_par_iter()
+        .zip(distances.axis_chunks_iter_mut(Axis(0), chunk_len))
+        .for_each(|(codes, mut distances)| distances.assign(&adc_distances_serial(table, codes)));
+
+    distances
+}
+
+fn adc_distances_serial<A, I>(table: ArrayView2<A>, codes: ArrayView2<I>) -> Array1<A>
+where
+    A: NdFloat + Sum,
+    I: AsPrimitive<usize>,
+{
+    // Use the SIMD kernel for contiguous f32 tables and u8 codes.
+    let mut distances = Array1::zeros(codes.nrows());
+    if let (Some(table_data), Some(codes_data), Some(distances_data)) = (
+        table.as_slice().and_then(simd::as_f32),
+        codes.as_slice().and_then(simd::as_u8),
+        distances.as_slice_mut().and_then(simd::as_f32_mut),
+    ) {
+        if !table_data.is_empty() {
+            simd::adc_distances(table_data, table.ncols(), codes_data, distances_data);
+            return distances;
+        }
+    }
+
+    AdcDistances::new(table, codes.outer_iter()).collect()
+}
+
 pub fn reconstructed_len<A>(quantizers: ArrayView3<A>) -> usize {
     quantizers.len_of(Axis(0)) * quantizers.len_of(Axis(2))
 }
@@ -119,17 +332,69 @@ where
     );
 
     let mut reconstruct = Vec::with_capacity(reconstructed_len(quantizers.view()));
-    for (&centroid, quantizer) in quantized.into_iter().zip(quantizers.outer_iter()) {
+    for (subquantizer, (&centroid, quantizer)) in quantized
+        .into_iter()
+        .zip(quantizers.outer_iter())
+        .enumerate()
+    {
+        debug_assert!(
+            centroid.as_() < quantizer.nrows(),
+            "Code {} of subquantizer {} is out of range, the subquantizer has {} centroids",
+            centroid.as_(),
+            subquantizer,
+            quantizer.nrows()
+        );
         reconstruct.extend(quantizer.index_axis(Axis(0), centroid.as_()));
     }
 
     Array1::from(reconstruct)
 }
 
+/// Check that all codes refer to a centroid of their subquantizer.
+pub fn check_codes<A, I, S>(
+    quantizers: ArrayView3<A>,
+    quantized: ArrayBase<S, Ix1>,
+) -> Result<(), Error>
+where
+    I: AsPrimitive<usize>,
+    S: Data<Elem = I>,
+{
+    let n_centroids = quantizers.len_of(Axis(1));
+    for (subquantizer, &code) in quantized.iter().enumerate() {
+        if code.as_() >= n_centroids {
+            return Err(Error::CodeOutOfRange {
+                subquantizer,
+                code: code.as_(),
+                n_centroids,
+            });
+        }
+    }
+
+    Ok(())
+}
+
 pub fn reconstruct_batch_into<A, I, S>(
+    quantizers: ArrayView3<A>,
+    quantized: ArrayBase<S, Ix2>,
+    reconstructions: ArrayViewMut2<A>,
+) where
+    A: NdFloat,
+    I: AsPrimitive<usize>,
+    S: Data<Elem = I>,
+{
+    reconstruct_batch_parallel_into(
+        quantizers,
+        quantized,
+        reconstructions,
+        BatchParallelism::default(),
+    )
+}
+
+pub fn reconstruct_batch_parallel_into<A, I, S>(
     quantizers: ArrayView3<A>,
     quantized: ArrayBase<S, Ix2>,
     mut reconstructions: ArrayViewMut2<A>,
+    parallelism: BatchParallelism,
 ) where
     A: NdFloat,
     I: AsPrimitive<usize>,
@@ -145,6 +410,32 @@ pub fn reconstruct_batch_into<A, I, S>(
         reconstructions.ncols()
     );
 
+    if !parallelism.is_parallel(quantized.nrows(), reconstructions.ncols()) {
+        reconstruct_batch_serial_into(quantizers, quantized.view(), reconstructions);
+        return;
+    }
+
+    // The codes type is not necessarily Sync, so the codes are
+    // converted before they are shared with the parallel kernel.
+    let quantized = quantized.mapv(AsPrimitive::<usize>::as_);
+    let chunk_len = parallelism.chunk_len(quantized.nrows());
+    quantized
+        .axis_chunks_iter(Axis(0), chunk_len)
+        .into_par_iter()
+        .zip(reconstructions.axis_chunks_iter_mut(Axis(0), chunk_len))
+        .for_each(|(quantized, reconstructions)| {
+            reconstruct_batch_serial_into(quantizers, quantized, reconstructions)
+        });
+}
+
+fn reconstruct_batch_serial_into<A, I>(
+    quantizers: ArrayView3<A>,
+    quantized: ArrayView2<I>,
+    mut reconstructions: ArrayViewMut2<A>,
+) where
+    A: NdFloat,
+    I: AsPrimitive<usize>,
+{
     for (quantized, mut reconstruction) in
         quantized.outer_iter().zip(reconstructions.outer_iter_mut())
     {
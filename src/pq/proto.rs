@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
+
+use ndarray::{Array1, Array2, Array3, ArrayView1};
+
+use super::codec::{read_varint, write_varint};
+use super::{CodeBatch, Fingerprint, Normalization, PQ};
+
+/// Protocol buffers schema of models and codes.
+///
+/// The schema can be used to generate decoders for other languages, so
+/// that codebooks and codes can be used without this crate. See
+/// `PQ::to_protobuf` and `CodeBatch::to_protobuf`.
+pub const PROTOBUF_SCHEMA: &str = include_str!("../../proto/reductive.proto");
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+impl PQ<f32> {
+    /// Encode the quantizer as a `ProductQuantizer` protobuf message.
+    ///
+    /// The metadata and the permutation of polysemous codes are part
+    /// of the message, the training manifest is not.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let shape = self.quantizers.shape();
+
+        let mut message = Vec::new();
+        write_uint(&mut message, 1, shape[0] as u64);
+        write_uint(&mut message, 2, shape[1] as u64);
+        write_uint(&mut message, 3, shape[2] as u64);
+        write_floats(&mut message, 4, self.quantizers.iter().copied());
+        if let Some(projection) = &self.projection {
+            write_floats(&mut message, 5, projection.iter().copied());
+        }
+        write_bytes(&mut message, 6, &self.fingerprint().to_bytes());
+        match &self.normalization {
+            Normalization::None => (),
+            Normalization::L2 => write_uint(&mut message, 7, 1),
+            Normalization::MeanCentering(mean) => {
+                write_uint(&mut message, 7, 2);
+                write_floats(&mut message, 8, mean.iter().copied());
+            }
+        }
+        for (key, value) in &self.metadata {
+            let mut entry = Vec::new();
+            write_bytes(&mut entry, 1, key.as_bytes());
+            write_bytes(&mut entry, 2, value.as_bytes());
+            write_bytes(&mut message, 9, &entry);
+        }
+        if let Some(permutation) = &self.polysemous {
+            write_packed(&mut message, 10, |buf| {
+                for &code in permutation {
+                    write_varint(buf, code as u64);
+                }
+            });
+        }
+
+        message
+    }
+
+    /// Decode a quantizer from a `ProductQuantizer` protobuf message.
+    ///
+    /// Returns an error when the message is malformed or when the
+    /// fingerprint of the message does not match the decoded quantizer.
+    pub fn from_protobuf(mut message: &[u8]) -> io::Result<Self> {
+        let mut shape = [0usize; 3];
+        let mut subquantizers = Vec::new();
+        let mut projection = Vec::new();
+        let mut fingerprint = None;
+        let mut normalization = 0;
+        let mut mean = Vec::new();
+        let mut metadata = BTreeMap::new();
+        let mut polysemous = Vec::new();
+
+        while let Some((field, wire_type)) = read_tag(&mut message)? {
+            match field {
+                1..=3 => shape[field as usize - 1] = read_uint(&mut message, wire_type)? as usize,
+                4 => read_floats(&mut message, wire_type, &mut subquantizers)?,
+                5 => read_floats(&mut message, wire_type, &mut projection)?,
+                6 => fingerprint = Some(read_fingerprint(&mut message, wire_type)?),
+                7 => normalization = read_uint(&mut message, wire_type)?,
+                8 => read_floats(&mut message, wire_type, &mut mean)?,
+                9 => {
+                    let (key, value) = read_metadata_entry(&mut message, wire_type)?;
+                    metadata.insert(key, value);
+                }
+                10 => read_uints(&mut message, wire_type, &mut polysemous)?,
+                _ => skip_field(&mut message, wire_type)?,
+            }
+        }
+
+        if shape.contains(&0) {
+            return Err(invalid("Product quantizer without subquantizers"));
+        }
+
+        let quantizers = Array3::from_shape_vec(shape, subquantizers)
+            .map_err(|_| invalid("Subquantizers do not match their shape"))?;
+        let projection = if projection.is_empty() {
+            None
+        } else {
+            let n_dims = shape[0] * shape[2];
+            Some(
+                Array2::from_shape_vec((n_dims, n_dims), projection)
+                    .map_err(|_| invalid("Projection does not match the quantizer shape"))?,
+            )
+        };
+
+        let normalization = match normalization {
+            0 => Normalization::None,
+            1 => Normalization::L2,
+            2 if mean.len() == shape[0] * shape[2] => {
+                Normalization::MeanCentering(Array1::from(mean))
+            }
+            2 => return Err(invalid("Mean does not match the quantizer shape")),
+            _ => return Err(invalid("Unknown normalization")),
+        };
+
+        let polysemous = if polysemous.is_empty() {
+            None
+        } else {
+            let permutation = Array2::from_shape_vec((shape[0], shape[1]), polysemous)
+                .map_err(|_| invalid("Polysemous permutation does not match the quantizer shape"))?
+                .mapv(|code| code as usize);
+            if !permutation.outer_iter().all(is_permutation) {
+                return Err(invalid("Polysemous codes are not a permutation"));
+            }
+            Some(permutation)
+        };
+
+        let mut pq = PQ::new(projection, quantizers).with_normalization(normalization);
+        pq.metadata = metadata;
+        pq.polysemous = polysemous;
+        if let Some(fingerprint) = fingerprint {
+            pq.verify_fingerprint(fingerprint)
+                .map_err(|err| invalid(err.to_string()))?;
+        }
+
+        Ok(pq)
+    }
+}
+
+impl CodeBatch {
+    /// Encode the batch as a `CodeBatch` protobuf message.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let codes = self.codes();
+
+        let mut message = Vec::new();
+        write_uint(&mut message, 1, codes.nrows() as u64);
+        write_uint(&mut message, 2, codes.ncols() as u64);
+        write_packed(&mut message, 3, |buf| {
+            for &code in codes {
+                write_varint(buf, u64::from(code));
+            }
+        });
+        if let Some(norms) = self.norms() {
+            write_floats(&mut message, 4, norms.iter().copied());
+        }
+        write_bytes(&mut message, 5, &self.fingerprint().to_bytes());
+
+        message
+    }
+
+    /// Decode a batch from a `CodeBatch` protobuf message.
+    pub fn from_protobuf(mut message: &[u8]) -> io::Result<Self> {
+        let mut shape = [0usize; 2];
+        let mut codes = Vec::new();
+        let mut norms = Vec::new();
+        let mut fingerprint = None;
+
+        while let Some((field, wire_type)) = read_tag(&mut message)? {
+            match field {
+                1 | 2 => shape[field as usize - 1] = read_uint(&mut message, wire_type)? as usize,
+                3 => read_uints(&mut message, wire_type, &mut codes)?,
+                4 => read_floats(&mut message, wire_type, &mut norms)?,
+                5 => fingerprint = Some(read_fingerprint(&mut message, wire_type)?),
+                _ => skip_field(&mut message, wire_type)?,
+            }
+        }
+
+        let fingerprint = fingerprint.ok_or_else(|| invalid("Code batch without fingerprint"))?;
+        let codes = Array2::from_shape_vec((shape[0], shape[1]), codes)
+            .map_err(|_| invalid("Codes do not match their shape"))?;
+        let batch = CodeBatch::new(fingerprint, codes);
+
+        if norms.is_empty() {
+            Ok(batch)
+        } else if norms.len() == shape[0] {
+            Ok(batch.with_norms(Array1::from(norms)))
+        } else {
+            Err(invalid(
+                "Number of norms does not match the number of vectors",
+            ))
+        }
+    }
+}
+
+/// Check that `codes` is a permutation of *0..codes.len()*.
+fn is_permutation(codes: ArrayView1<usize>) -> bool {
+    let mut seen = vec![false; codes.len()];
+    codes
+        .iter()
+        .all(|&code| code < seen.len() && !std::mem::replace(&mut seen[code], true))
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+fn write_tag(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, (field << 3) | wire_type);
+}
+
+fn write_uint(buf: &mut Vec<u8>, field: u64, v: u64) {
+    write_tag(buf, field, VARINT);
+    write_varint(buf, v);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_tag(buf, field, LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Write a packed repeated field, the values are written by `write_values`.
+fn write_packed(buf: &mut Vec<u8>, field: u64, write_values: impl FnOnce(&mut Vec<u8>)) {
+    let mut values = Vec::new();
+    write_values(&mut values);
+    if !values.is_empty() {
+        write_bytes(buf, field, &values);
+    }
+}
+
+fn write_floats(buf: &mut Vec<u8>, field: u64, values: impl Iterator<Item = f32>) {
+    write_packed(buf, field, |buf| {
+        for v in values {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    });
+}
+
+/// Read a field tag, returns `None` at the end of the message.
+fn read_tag(message: &mut &[u8]) -> io::Result<Option<(u64, u64)>> {
+    Ok(read_varint(message)?.map(|tag| (tag >> 3, tag & 0x7)))
+}
+
+fn read_varint_field(message: &mut &[u8]) -> io::Result<u64> {
+    read_varint(message)?.ok_or_else(|| invalid("Truncated protobuf message"))
+}
+
+fn read_uint(message: &mut &[u8], wire_type: u64) -> io::Result<u64> {
+    if wire_type != VARINT {
+        return Err(invalid(format!(
+            "Expected varint field, got wire type {}",
+            wire_type
+        )));
+    }
+
+    read_varint_field(message)
+}
+
+fn read_length_delimited<'a>(message: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = read_varint_field(message)? as usize;
+    if message.len() < len {
+        return Err(invalid("Truncated protobuf message"));
+    }
+
+    let (value, rest) = message.split_at(len);
+    *message = rest;
+    Ok(value)
+}
+
+/// Read a repeated `uint32` field, which can be packed or unpacked.
+fn read_uints(message: &mut &[u8], wire_type: u64, values: &mut Vec<u32>) -> io::Result<()> {
+    let mut push = |v: u64| {
+        values.push(u32::try_from(v).map_err(|_| invalid("Code does not fit in 32 bits"))?);
+        Ok(())
+    };
+
+    if wire_type == LENGTH_DELIMITED {
+        let mut packed = read_length_delimited(message)?;
+        while let Some(v) = read_varint(&mut packed)? {
+            push(v)?;
+        }
+        Ok(())
+    } else {
+        push(read_uint(message, wire_type)?)
+    }
+}
+
+/// Read a repeated `float` field, which can be packed or unpacked.
+fn read_floats(message: &mut &[u8], wire_type: u64, values: &mut Vec<f32>) -> io::Result<()> {
+    let bytes = match wire_type {
+        LENGTH_DELIMITED => read_length_delimited(message)?,
+        FIXED32 if message.len() >= 4 => {
+            let (value, rest) = message.split_at(4);
+            *message = rest;
+            value
+        }
+        FIXED32 => return Err(invalid("Truncated protobuf message")),
+        _ => {
+            return Err(invalid(format!(
+                "Expected float field, got wire type {}",
+                wire_type
+            )))
+        }
+    };
+
+    if bytes.len() % 4 != 0 {
+        return Err(invalid("Packed floats have an incorrect length"));
+    }
+
+    values.extend(
+        bytes
+            .chunks(4)
+            .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]])),
+    );
+
+    Ok(())
+}
+
+fn read_string(message: &mut &[u8], wire_type: u64) -> io::Result<String> {
+    if wire_type != LENGTH_DELIMITED {
+        return Err(invalid(format!(
+            "Expected string field, got wire type {}",
+            wire_type
+        )));
+    }
+
+    String::from_utf8(read_length_delimited(message)?.to_vec())
+        .map_err(|_| invalid("String is not valid UTF-8"))
+}
+
+/// Read an entry of a `map<string, string>` field.
+///
+/// Missing keys and values are empty strings.
+fn read_metadata_entry(message: &mut &[u8], wire_type: u64) -> io::Result<(String, String)> {
+    if wire_type != LENGTH_DELIMITED {
+        return Err(invalid(format!(
+            "Expected map entry, got wire type {}",
+            wire_type
+        )));
+    }
+
+    let mut entry = read_length_delimited(message)?;
+    let mut key = String::new();
+    let mut value = String::new();
+    while let Some((field, wire_type)) = read_tag(&mut entry)? {
+        match field {
+            1 => key = read_string(&mut entry, wire_type)?,
+            2 => value = read_string(&mut entry, wire_type)?,
+            _ => skip_field(&mut entry, wire_type)?,
+        }
+    }
+
+    Ok((key, value))
+}
+
+fn read_fingerprint(message: &mut &[u8], wire_type: u64) -> io::Result<Fingerprint> {
+    if wire_type != LENGTH_DELIMITED {
+        return Err(invalid(format!(
+            "Expected bytes field, got wire type {}",
+            wire_type
+        )));
+    }
+
+    let bytes = read_length_delimited(message)?;
+    if bytes.len() != 16 {
+        return Err(invalid(format!(
+            "Fingerprint should have 16 bytes, has {}",
+            bytes.len()
+        )));
+    }
+
+    let mut fingerprint = [0u8; 16];
+    fingerprint.copy_from_slice(bytes);
+    Ok(Fingerprint::from_bytes(fingerprint))
+}
+
+/// Skip a field that is not in the schema.
+fn skip_field(message: &mut &[u8], wire_type: u64) -> io::Result<()> {
+    let len = match wire_type {
+        VARINT => return read_varint_field(message).map(|_| ()),
+        FIXED64 => 8,
+        LENGTH_DELIMITED => return read_length_delimited(message).map(|_| ()),
+        FIXED32 => 4,
+        _ => return Err(invalid(format!("Unknown wire type {}", wire_type))),
+    };
+
+    if message.len() < len {
+        return Err(invalid("Truncated protobuf message"));
+    }
+
+    *message = &message[len..];
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Array3};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{write_tag, write_uint, FIXED32, VARINT};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{CodeBatch, Fingerprint, Normalization, QuantizeVector, PQ};
+
+    #[test]
+    fn product_quantizer_round_trip() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let uniform = Uniform::new(-1f32, 1f32);
+        let pq = PQ::new(None, Array3::random_using((2, 4, 3), uniform, &mut rng));
+        let opq = PQ::new(
+            Some(Array2::random_using((6, 6), uniform, &mut rng)),
+            pq.subquantizers().to_owned(),
+        );
+        let l2 = pq.clone().with_normalization(Normalization::L2);
+        let centered = pq
+            .clone()
+            .with_normalization(Normalization::MeanCentering(array![
+                0.5, -0.5, 0., 1., 2., 3.
+            ]));
+
+        let with_metadata = pq
+            .clone()
+            .with_metadata("dataset", "wikipedia-2021")
+            .with_metadata("owner", "søren")
+            .with_metadata("", "");
+
+        let polysemous = pq.polysemous_using(100, &mut rng);
+
+        for quantizer in &[pq, opq, l2, centered, with_metadata] {
+            let decoded = PQ::from_protobuf(&quantizer.to_protobuf()).unwrap();
+            assert_eq!(&decoded, quantizer);
+        }
+
+        let decoded = PQ::from_protobuf(&polysemous.to_protobuf()).unwrap();
+        assert!(decoded.hamming_filterable());
+        assert_eq!(decoded, polysemous);
+    }
+
+    #[test]
+    fn product_quantizer_rejects_invalid_polysemous_codes() {
+        let pq = PQ::new(None, array![[[1f32, 0.], [0., 1.]]]);
+        let mut message = pq.to_protobuf();
+        write_uint(&mut message, 10, 1);
+        write_uint(&mut message, 10, 1);
+        assert!(PQ::from_protobuf(&message).is_err());
+
+        let mut message = pq.to_protobuf();
+        write_uint(&mut message, 10, 1);
+        assert!(PQ::from_protobuf(&message).is_err());
+    }
+
+    #[test]
+    fn product_quantizer_rejects_incorrect_fingerprint() {
+        let pq = PQ::new(None, array![[[1f32, 0.], [0., 1.]]]);
+        let mut message = pq.to_protobuf();
+        // Corrupt the first centroid, which is written after the shape.
+        message[9] ^= 1;
+        assert!(PQ::from_protobuf(&message).is_err());
+    }
+
+    #[test]
+    fn code_batch_round_trip() {
+        let pq = PQ::new(None, array![[[1f32, 0.], [0., 1.]], [[0., 0.], [1., 1.]]]);
+        let quantized: Array2<u8> = pq.quantize_batch(array![[1f32, 0., 1., 1.], [0., 1., 0., 0.]]);
+
+        let batch = CodeBatch::new(pq.fingerprint(), quantized);
+        assert_eq!(
+            CodeBatch::from_protobuf(&batch.to_protobuf()).unwrap(),
+            batch
+        );
+
+        let with_norms = batch.with_norms(array![1.5, 2.5]);
+        assert_eq!(
+            CodeBatch::from_protobuf(&with_norms.to_protobuf()).unwrap(),
+            with_norms
+        );
+    }
+
+    #[test]
+    fn decode_unpacked_and_unknown_fields() {
+        let batch = CodeBatch::new(Fingerprint::from_bytes([1; 16]), array![[1u32, 300]]);
+
+        // Decoders should accept unpacked repeated fields and skip
+        // fields that are not in the schema.
+        let mut message = Vec::new();
+        write_uint(&mut message, 1, 1);
+        write_uint(&mut message, 2, 2);
+        write_uint(&mut message, 3, 1);
+        write_uint(&mut message, 3, 300);
+        write_uint(&mut message, 15, 42);
+        write_tag(&mut message, 16, FIXED32);
+        message.extend_from_slice(&[0; 4]);
+        write_tag(&mut message, 5, 2);
+        message.push(16);
+        message.extend_from_slice(&[1; 16]);
+        assert_eq!(CodeBatch::from_protobuf(&message).unwrap(), batch);
+
+        // A varint where floats are expected is an error.
+        let mut message = Vec::new();
+        write_tag(&mut message, 4, VARINT);
+        message.push(1);
+        assert!(CodeBatch::from_protobuf(&message).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use ndarray::{Array1, Array2, ArrayView2, Axis, NdFloat};
+use num_traits::AsPrimitive;
+
+use crate::simd;
+
+/// Distance table quantized to 16-bit fixed point.
+///
+/// Asymmetric distance computation looks up one table entry per
+/// subquantizer. Scans over large code sets are often bound by memory
+/// bandwidth rather than arithmetic, so this table stores its entries
+/// as `u16` rather than `f32` and accumulates them in `u32`. This halves
+/// the size of the table, which then fits in the L1 cache for more
+/// subquantizers.
+///
+/// The entries of subquantizer *j* are stored as *round((d - m_j) /
+/// scale)*, where *m_j* is the smallest entry of the subquantizer. A
+/// distance is then *bias + scale * sum*, where *bias* is the sum of the
+/// minima and *sum* is the accumulated entries. The error of a distance
+/// is at most `QuantizedTable::max_error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantizedTable<A> {
+    table: Array2<u16>,
+    bias: A,
+    scale: A,
+}
+
+impl<A> QuantizedTable<A>
+where
+    A: NdFloat,
+{
+    /// Quantize a distance table.
+    ///
+    /// `table` is the distance table of a query with shape
+    /// *(n_subquantizers, n_centroids)*, as returned by
+    /// `PQ::adc_tables_batch`.
+    pub fn new(table: ArrayView2<A>) -> Self {
+        assert!(
+            table.ncols() > 0,
+            "Distance table should have at least one centroid"
+        );
+        assert!(
+            table.nrows() <= (u32::MAX / u32::from(u16::MAX)) as usize,
+            "Cannot accumulate {} subquantizers in u32",
+            table.nrows()
+        );
+
+        let minima = table.map_axis(Axis(1), |row| {
+            row.iter().cloned().fold(A::infinity(), A::min)
+        });
+        let max_range = table
+            .outer_iter()
+            .zip(minima.iter())
+            .map(|(row, &min)| row.iter().cloned().fold(A::neg_infinity(), A::max) - min)
+            .fold(A::zero(), A::max);
+
+        let max_entry = A::from(u16::MAX).unwrap();
+        let scale = if max_range > A::zero() {
+            max_range / max_entry
+        } else {
+            A::one()
+        };
+
+        let mut quantized = Array2::zeros(table.dim());
+        for ((row, &min), mut quantized) in table
+            .outer_iter()
+            .zip(minima.iter())
+            .zip(quantized.outer_iter_mut())
+        {
+            quantized.zip_mut_with(&row, |q, &d| {
+                *q = ((d - min) / scale).round().min(max_entry).to_u16().unwrap();
+            });
+        }
+
+        QuantizedTable {
+            table: quantized,
+            bias: minima.sum(),
+            scale,
+        }
+    }
+
+    /// Get the quantized table entries.
+    pub fn table(&self) -> ArrayView2<u16> {
+        self.table.view()
+    }
+
+    /// Get the sum of the smallest entries of the subquantizers.
+    pub fn bias(&self) -> A {
+        self.bias
+    }
+
+    /// Get the step size of the quantized entries.
+    pub fn scale(&self) -> A {
+        self.scale
+    }
+
+    /// Get the largest difference between a distance of this table and
+    /// a distance of the original table.
+    ///
+    /// Each entry is rounded to the nearest step, so the error is at
+    /// most half a step per subquantizer.
+    pub fn max_error(&self) -> A {
+        self.scale * A::from(self.table.nrows()).unwrap() / A::from(2).unwrap()
+    }
+
+    /// Accumulate the quantized entries of a matrix of codes.
+    ///
+    /// Convert the sums to distances with `QuantizedTable::to_distance`.
+    /// Comparing sums rather than distances avoids converting to
+    /// floating point in scans that only need the nearest codes.
+    pub fn accumulate<I>(&self, codes: ArrayView2<I>) -> Array1<u32>
+    where
+        I: AsPrimitive<usize>,
+    {
+        assert_eq!(
+            codes.ncols(),
+            self.table.nrows(),
+            "Quantization length does not match number of subquantizers"
+        );
+
+        let n_centroids = self.table.ncols();
+        if let (Some(table), Some(codes_data)) = (
+            self.table.as_slice(),
+            codes.as_slice().and_then(simd::as_u8),
+        ) {
+            if !codes_data.is_empty() {
+                return codes_data
+                    .chunks_exact(codes.ncols())
+                    .map(|codes| {
+                        codes
+                            .iter()
+                            .enumerate()
+                            .map(|(sq, &code)| {
+                                assert!(
+                                    (code as usize) < n_centroids,
+                                    "Code {} of subquantizer {} is out of range",
+                                    code,
+                                    sq
+                                );
+                                u32::from(table[sq * n_centroids + code as usize])
+                            })
+                            .sum()
+                    })
+                    .collect();
+            }
+        }
+
+        codes.map_axis(Axis(1), |codes| {
+            codes
+                .iter()
+                .zip(self.table.outer_iter())
+                .map(|(&code, sq_table)| u32::from(sq_table[code.as_()]))
+                .sum()
+        })
+    }
+
+    /// Convert an accumulated sum to a distance.
+    pub fn to_distance(&self, sum: u32) -> A {
+        self.bias + self.scale * A::from(sum).unwrap()
+    }
+
+    /// Compute asymmetric distances for a matrix of codes.
+    pub fn distances<I>(&self, codes: ArrayView2<I>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+    {
+        self.accumulate(codes).mapv(|sum| self.to_distance(sum))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{s, Array1, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::QuantizedTable;
+    use crate::float_ord::min_by_float_key;
+    use crate::ndarray_rand::RandomExt;
+    use crate::parallel::BatchParallelism;
+    use crate::pq::{primitives, QuantizeVector, TrainPQ, PQ};
+
+    #[test]
+    fn quantized_table_matches_f32_reference() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((1024, 16), Uniform::new(-1., 1.), &mut rng);
+        let pq = PQ::train_pq_using(8, 5, 10, 1, instances.view(), &mut rng);
+        let codes: Array2<u8> = pq.quantize_batch(instances.view());
+        let wide_codes: Array2<usize> = pq.quantize_batch(instances.view());
+
+        let tables = pq.adc_tables_batch(instances.slice(s![..8, ..]));
+        for table in tables.outer_iter() {
+            let reference =
+                primitives::adc_distances(table, codes.view(), BatchParallelism::serial());
+            let quantized = QuantizedTable::new(table);
+            let distances = quantized.distances(codes.view());
+            assert_eq!(quantized.distances(wide_codes.view()), distances);
+
+            for (&distance, &expected) in distances.iter().zip(reference.iter()) {
+                assert!((distance - expected).abs() <= quantized.max_error() + 1e-5);
+            }
+
+            // The nearest code of the quantized table is at most twice
+            // the maximum error further away than the true nearest code.
+            assert!(quantized.max_error() < 1e-3);
+            let nearest = |distances: &Array1<f32>| {
+                min_by_float_key(0..distances.len(), |&idx| distances[idx]).unwrap()
+            };
+            assert!(
+                reference[nearest(&distances)] - reference[nearest(&reference)]
+                    <= 2. * quantized.max_error()
+            );
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::iter::Sum;
+
+use ndarray::{s, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, Axis, Data, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::{primitives, PQView, PQ};
+use crate::parallel::BatchParallelism;
+
+/// Standing set of queries with cached lookup tables.
+///
+/// Scoring a fixed set of queries, such as the weight vectors of a
+/// classifier, against batches of incoming codes repeats the same work
+/// for every batch: the queries are rotated with the projection of the
+/// quantizer and the lookup tables of the queries are computed. This
+/// set caches the rotated queries together with their squared distance
+/// and inner product tables, so that scoring a batch of codes only
+/// consists of table lookups.
+///
+/// Since the projection of an optimized product quantizer is
+/// orthogonal, inner products and distances in rotated space are the
+/// same as in the original space.
+#[derive(Clone, Debug)]
+pub struct QuerySet<A> {
+    rotated: Array2<A>,
+    distance_tables: Array3<A>,
+    inner_product_tables: Array3<A>,
+    parallelism: BatchParallelism,
+}
+
+impl<A> QuerySet<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a query set for a quantizer.
+    ///
+    /// `queries` has one query per row.
+    pub fn new<S>(quantizer: PQView<A>, queries: ArrayBase<S, Ix2>) -> Self
+    where
+        S: Data<Elem = A>,
+    {
+        let rotated = quantizer.rotate_batch(queries);
+        let distance_tables = primitives::adc_tables_batch(quantizer.quantizers, rotated.view());
+
+        let quantizers = quantizer.quantizers;
+        let sq_dims = quantizers.len_of(Axis(2));
+        let mut inner_product_tables = Array3::zeros(distance_tables.dim());
+        for (sq, (centroids, mut sq_tables)) in quantizers
+            .outer_iter()
+            .zip(inner_product_tables.axis_iter_mut(Axis(1)))
+            .enumerate()
+        {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sub_queries = rotated.slice(s![.., offset..offset + sq_dims]);
+            sq_tables.assign(&sub_queries.dot(&centroids.t()));
+        }
+
+        QuerySet {
+            rotated,
+            distance_tables,
+            inner_product_tables,
+            parallelism: BatchParallelism::default(),
+        }
+    }
+
+    /// Set the policy for scoring codes in parallel.
+    pub fn with_parallelism(mut self, parallelism: BatchParallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Get the number of queries.
+    pub fn len(&self) -> usize {
+        self.rotated.nrows()
+    }
+
+    /// Check whether the set has no queries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the queries, rotated with the projection of the quantizer.
+    pub fn rotated_queries(&self) -> ArrayView2<A> {
+        self.rotated.view()
+    }
+
+    /// Get the squared distance tables of the queries.
+    ///
+    /// See `PQView::adc_tables_batch`.
+    pub fn distance_tables(&self) -> ArrayView3<A> {
+        self.distance_tables.view()
+    }
+
+    /// Get the inner product tables of the queries.
+    ///
+    /// Element *(i, j, k)* is the inner product of the *j*-th slice of
+    /// rotated query *i* and centroid *k* of subquantizer *j*.
+    pub fn inner_product_tables(&self) -> ArrayView3<A> {
+        self.inner_product_tables.view()
+    }
+
+    /// Compute the approximate squared distances between the queries and
+    /// a batch of quantized vectors.
+    ///
+    /// Returns an array with shape *(n_queries, n_vectors)*.
+    pub fn squared_distances<I, S>(&self, codes: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize> + Sync,
+        S: Data<Elem = I>,
+    {
+        self.score(self.distance_tables.view(), codes.view())
+    }
+
+    /// Compute the approximate inner products between the queries and a
+    /// batch of quantized vectors.
+    ///
+    /// Returns an array with shape *(n_queries, n_vectors)*.
+    pub fn inner_products<I, S>(&self, codes: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize> + Sync,
+        S: Data<Elem = I>,
+    {
+        self.score(self.inner_product_tables.view(), codes.view())
+    }
+
+    fn score<I>(&self, tables: ArrayView3<A>, codes: ArrayView2<I>) -> Array2<A>
+    where
+        I: AsPrimitive<usize> + Sync,
+    {
+        let mut scores = Array2::zeros((self.len(), codes.nrows()));
+        for (table, mut scores) in tables.outer_iter().zip(scores.outer_iter_mut()) {
+            scores.assign(&primitives::adc_distances(table, codes, self.parallelism));
+        }
+
+        scores
+    }
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a standing query set with cached lookup tables.
+    ///
+    /// See `QuerySet`.
+    pub fn query_set<S>(&self, queries: ArrayBase<S, Ix2>) -> QuerySet<A>
+    where
+        S: Data<Elem = A>,
+    {
+        QuerySet::new(*self, queries)
+    }
+}
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a standing query set with cached lookup tables.
+    ///
+    /// See `QuerySet`.
+    pub fn query_set<S>(&self, queries: ArrayBase<S, Ix2>) -> QuerySet<A>
+    where
+        S: Data<Elem = A>,
+    {
+        QuerySet::new(self.view(), queries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Array3};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    #[test]
+    fn query_set_scores_codes() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let uniform = Uniform::new(-1f64, 1.);
+        // Orthogonal projection that swaps and negates dimensions.
+        let projection = array![
+            [0., 1., 0., 0.],
+            [-1., 0., 0., 0.],
+            [0., 0., 0., -1.],
+            [0., 0., 1., 0.]
+        ];
+        let pq = PQ::new(
+            Some(projection),
+            Array3::random_using((2, 4, 2), uniform, &mut rng),
+        );
+
+        let queries: Array2<f64> = Array2::random_using((3, 4), uniform, &mut rng);
+        let instances: Array2<f64> = Array2::random_using((16, 4), uniform, &mut rng);
+        let codes: Array2<u8> = pq.quantize_batch(instances.view());
+        let reconstructions = pq.reconstruct_batch(codes.view());
+
+        let query_set = pq.query_set(queries.view());
+        assert_eq!(query_set.len(), 3);
+        assert_eq!(
+            query_set.distance_tables(),
+            pq.adc_tables_batch(queries.view())
+        );
+
+        let inner_products = query_set.inner_products(codes.view());
+        assert!(inner_products.abs_diff_eq(&queries.dot(&reconstructions.t()), 1e-10));
+
+        let distances = query_set.squared_distances(codes.view());
+        for ((query_idx, idx), &distance) in distances.indexed_iter() {
+            let diff = &queries.row(query_idx) - &reconstructions.row(idx);
+            assert!((diff.dot(&diff) - distance).abs() < 1e-10);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+use std::iter::Sum;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use ndarray::{s, Array1, Array2, ArrayBase, ArrayView2, ArrayViewMut2, Axis, Data, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+
+use super::{QuantizeVector, ReconstructVector, TrainingManifest, PQ};
+use crate::float_ord::float_cmp;
+use crate::kmeans::{cluster_assignments, KMeansWithCentroids, NIterationsCondition};
+
+type RefineHook<A> = Box<dyn Fn(&PQ<A>, A) + Send + Sync>;
+
+/// Drift-triggered refinement of a product quantizer.
+///
+/// This policy monitors the reconstruction error of a product quantizer
+/// on recent traffic. The most recent `max_samples` instances that are
+/// observed are kept as samples. When the mean squared reconstruction
+/// error of the samples exceeds a threshold, the subquantizers are
+/// refined with k-means on the samples, starting from the current
+/// codebooks. Centroids that are not assigned any samples are
+/// re-seeded with the samples that have the largest quantization error,
+/// so that drifted traffic can use the full codebook. The refined
+/// quantizer then atomically replaces the current quantizer. Its
+/// training manifest describes the refinement. The projection matrix of
+/// an optimized product quantizer is not updated.
+///
+/// The policy can be shared between threads. Readers get the current
+/// quantizer through `DriftRefinement::quantizer` and are not blocked
+/// by refinement. Refinements are serialized, so that every refinement
+/// starts from the result of the previous refinement.
+pub struct DriftRefinement<A> {
+    quantizer: RwLock<Arc<PQ<A>>>,
+    refinement: Mutex<()>,
+    samples: Mutex<VecDeque<(Array1<A>, A)>>,
+    threshold: A,
+    max_samples: usize,
+    n_iterations: usize,
+    on_refine: Option<RefineHook<A>>,
+}
+
+impl<A> DriftRefinement<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Construct a refinement policy.
+    ///
+    /// Refinement is triggered when the mean squared reconstruction error
+    /// of the last `max_samples` observed instances exceeds `threshold`.
+    /// Refinement uses `n_iterations` k-means iterations per subquantizer.
+    pub fn new(pq: PQ<A>, threshold: A, max_samples: usize, n_iterations: usize) -> Self {
+        assert!(
+            max_samples >= pq.n_quantizer_centroids(),
+            "At least {} samples are required for refinement, got: {}",
+            pq.n_quantizer_centroids(),
+            max_samples
+        );
+        assert!(
+            n_iterations > 0,
+            "The subquantizers should be refined for at least one iteration."
+        );
+
+        DriftRefinement {
+            quantizer: RwLock::new(Arc::new(pq)),
+            refinement: Mutex::new(()),
+            samples: Mutex::new(VecDeque::with_capacity(max_samples)),
+            threshold,
+            max_samples,
+            n_iterations,
+            on_refine: None,
+        }
+    }
+
+    /// Set a hook that is called after refinement.
+    ///
+    /// The hook is called with the refined quantizer and the error that
+    /// triggered refinement, for instance to persist the new codebooks.
+    pub fn with_refine_hook(mut self, hook: impl Fn(&PQ<A>, A) + Send + Sync + 'static) -> Self {
+        self.on_refine = Some(Box::new(hook));
+        self
+    }
+
+    /// Get the current quantizer.
+    pub fn quantizer(&self) -> Arc<PQ<A>> {
+        self.quantizer.read().unwrap().clone()
+    }
+
+    /// Get the mean squared reconstruction error of the samples.
+    ///
+    /// Returns `None` if no instances were observed since the last
+    /// refinement.
+    pub fn error(&self) -> Option<A> {
+        let samples = self.samples.lock().unwrap();
+        Self::samples_error(&samples)
+    }
+
+    /// Observe a batch of instances.
+    ///
+    /// The instances are the rows of `instances`. Returns `true` when
+    /// the reconstruction error of the samples exceeds the threshold
+    /// and the policy holds enough samples for refinement.
+    pub fn observe<S>(&self, instances: ArrayBase<S, Ix2>) -> bool
+    where
+        S: Data<Elem = A>,
+    {
+        let pq = self.quantizer();
+        let quantized = pq.quantize_batch::<usize, _>(instances.view());
+        let reconstructions = pq.reconstruct_batch(quantized);
+
+        // Reconstructions approximate the normalized instances.
+        let normalized = pq.normalize_batch(instances.view());
+
+        let mut samples = self.samples.lock().unwrap();
+        for ((instance, normalized), reconstruction) in instances
+            .outer_iter()
+            .zip(normalized.outer_iter())
+            .zip(reconstructions.outer_iter())
+        {
+            let diff = &normalized - &reconstruction;
+            if samples.len() == self.max_samples {
+                samples.pop_front();
+            }
+            samples.push_back((instance.to_owned(), diff.dot(&diff) / instance.len().as_()));
+        }
+
+        self.refinement_due(&samples).is_some()
+    }
+
+    /// Observe a batch of instances and refine if necessary.
+    ///
+    /// Returns `true` if the quantizer was refined.
+    pub fn observe_and_refine<S>(&self, instances: ArrayBase<S, Ix2>) -> bool
+    where
+        S: Data<Elem = A>,
+    {
+        self.observe(instances) && self.refine()
+    }
+
+    /// Refine the quantizer if the error exceeds the threshold.
+    ///
+    /// The samples are cleared after refinement. The refine hook is
+    /// called before the next refinement starts. Returns `true` if the
+    /// quantizer was refined.
+    pub fn refine(&self) -> bool {
+        // Hold the refinement lock from reading the current quantizer
+        // until the refined quantizer has replaced it.
+        let _refinement = self.refinement.lock().unwrap();
+
+        let (samples, error) = {
+            let mut samples = self.samples.lock().unwrap();
+            let error = match self.refinement_due(&samples) {
+                Some(error) => error,
+                None => return false,
+            };
+
+            let n_dims = samples[0].0.len();
+            let mut sample_matrix = Array2::zeros((samples.len(), n_dims));
+            for ((sample, _), mut row) in samples.iter().zip(sample_matrix.outer_iter_mut()) {
+                row.assign(sample);
+            }
+            samples.clear();
+
+            (sample_matrix, error)
+        };
+
+        let refined = Arc::new(self.refined_quantizer(samples));
+        *self.quantizer.write().unwrap() = refined.clone();
+
+        if let Some(ref hook) = self.on_refine {
+            hook(&refined, error);
+        }
+
+        true
+    }
+
+    fn refined_quantizer(&self, samples: Array2<A>) -> PQ<A> {
+        let start = Instant::now();
+        let pq = self.quantizer();
+
+        let rx = pq.rotate_batch(samples);
+
+        let mut quantizers = pq.quantizers.clone();
+        let sq_dims = quantizers.len_of(Axis(2));
+        for (sq, mut quantizer) in quantizers.outer_iter_mut().enumerate() {
+            let offset = sq * sq_dims;
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            let sq_instances = rx.slice(s![.., offset..offset + sq_dims]);
+            sq_instances.kmeans_with_centroids(
+                Axis(0),
+                quantizer.view_mut(),
+                NIterationsCondition(self.n_iterations),
+            );
+
+            if reseed_empty_centroids(quantizer.view_mut(), sq_instances) {
+                sq_instances.kmeans_with_centroids(
+                    Axis(0),
+                    quantizer.view_mut(),
+                    NIterationsCondition(self.n_iterations),
+                );
+            }
+        }
+
+        let manifest = TrainingManifest::new(
+            "RefinedPQ",
+            quantizers.len_of(Axis(0)),
+            pq.n_quantizer_centroids()
+                .next_power_of_two()
+                .trailing_zeros(),
+            self.n_iterations,
+            1,
+            rx.dim(),
+            start,
+        )
+        .with_normalization(&pq.normalization);
+
+        PQ {
+            projection: pq.projection.clone(),
+            quantizers,
+            manifest: Some(manifest),
+            polysemous: None,
+            normalization: pq.normalization.clone(),
+            metadata: pq.metadata.clone(),
+        }
+    }
+
+    fn refinement_due(&self, samples: &VecDeque<(Array1<A>, A)>) -> Option<A> {
+        if samples.len() < self.quantizer().n_quantizer_centroids() {
+            return None;
+        }
+
+        Self::samples_error(samples).filter(|&error| error > self.threshold)
+    }
+
+    fn samples_error(samples: &VecDeque<(Array1<A>, A)>) -> Option<A> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        Some(samples.iter().map(|(_, error)| *error).sum::<A>() / samples.len().as_())
+    }
+}
+
+/// Re-seed centroids without assigned instances.
+///
+/// Each empty centroid is replaced by one of the instances with the
+/// largest distance to its nearest centroid. Returns `true` if a
+/// centroid was re-seeded.
+fn reseed_empty_centroids<A>(mut centroids: ArrayViewMut2<A>, instances: ArrayView2<A>) -> bool
+where
+    A: NdFloat + Sum,
+{
+    let assignments = cluster_assignments(centroids.view(), instances, Axis(0));
+    let mut counts = vec![0usize; centroids.nrows()];
+    for &cluster in &assignments {
+        counts[cluster] += 1;
+    }
+
+    let empty = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count == 0)
+        .map(|(cluster, _)| cluster)
+        .collect::<Vec<_>>();
+    if empty.is_empty() {
+        return false;
+    }
+
+    let distances = instances
+        .outer_iter()
+        .zip(assignments.iter())
+        .map(|(instance, &cluster)| {
+            let diff = &instance - &centroids.row(cluster);
+            diff.dot(&diff)
+        })
+        .collect::<Vec<_>>();
+    let mut farthest = (0..instances.nrows()).collect::<Vec<_>>();
+    farthest.sort_by(|&i, &j| float_cmp(distances[j], distances[i]));
+
+    for (cluster, instance) in empty.into_iter().zip(farthest) {
+        centroids.row_mut(cluster).assign(&instances.row(instance));
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use ndarray::{array, Array2};
+
+    use super::DriftRefinement;
+    use crate::pq::PQ;
+
+    #[test]
+    fn refines_on_drift() {
+        let pq = PQ::new(None, array![[[0f64], [1.]], [[0.], [1.]]]);
+        let n_refinements = Arc::new(AtomicUsize::new(0));
+        let hook_refinements = n_refinements.clone();
+        let policy = DriftRefinement::new(pq, 0.1, 4, 5).with_refine_hook(move |_, error| {
+            assert!(error > 0.1);
+            hook_refinements.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Traffic that the quantizer represents well.
+        assert!(!policy.observe_and_refine(array![[0., 1.], [1., 0.], [0., 0.], [1., 1.]].view()));
+        assert_eq!(policy.error(), Some(0.));
+
+        // Drifted traffic.
+        assert!(policy
+            .observe_and_refine(array![[10., 11.], [11., 10.], [10., 10.], [11., 11.]].view()));
+        assert_eq!(n_refinements.load(Ordering::SeqCst), 1);
+        assert_eq!(policy.error(), None);
+
+        // The first centroids are emptied by the drift and re-seeded
+        // with drifted instances.
+        let refined = policy.quantizer();
+        assert_eq!(
+            refined.subquantizers(),
+            array![[[10.], [11.]], [[11.], [10.]]]
+        );
+
+        let manifest = refined.manifest().unwrap();
+        assert_eq!(manifest.quantizer, "RefinedPQ");
+        assert_eq!(manifest.n_subquantizer_bits, 1);
+        assert_eq!(manifest.n_iterations, 5);
+        assert_eq!(manifest.n_instances, 4);
+
+        assert!(!policy.observe_and_refine(array![[10., 11.], [11., 10.]].view()));
+        assert_eq!(policy.error(), Some(0.));
+    }
+
+    #[test]
+    fn concurrent_refinements_are_serialized() {
+        let pq = PQ::new(None, array![[[0f64], [1.]], [[0.], [1.]]]);
+        let n_refinements = Arc::new(AtomicUsize::new(0));
+        let in_hook = Arc::new(AtomicBool::new(false));
+        let hook_refinements = n_refinements.clone();
+        let hook_in_hook = in_hook.clone();
+        // Refinements, including their hooks, must not overlap.
+        let policy = Arc::new(
+            DriftRefinement::new(pq, 0.1, 8, 5).with_refine_hook(move |_, _| {
+                assert!(!hook_in_hook.swap(true, Ordering::SeqCst));
+                thread::sleep(Duration::from_millis(10));
+                hook_refinements.fetch_add(1, Ordering::SeqCst);
+                hook_in_hook.store(false, Ordering::SeqCst);
+            }),
+        );
+
+        let threads = (0..8)
+            .map(|idx| {
+                let policy = policy.clone();
+                thread::spawn(move || {
+                    let offset = 10. * (idx + 1) as f64;
+                    let drifted = Array2::from_shape_fn((8, 2), |(row, col)| {
+                        offset + ((row + col) % 2) as f64
+                    });
+                    policy.observe_and_refine(drifted.view())
+                })
+            })
+            .collect::<Vec<_>>();
+        let n_refined = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|&refined| refined)
+            .count();
+
+        assert!(n_refined > 0);
+        assert_eq!(n_refinements.load(Ordering::SeqCst), n_refined);
+    }
+}
@@ -0,0 +1,173 @@
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, Array3, ArrayView2, ArrayView3, Axis, NdFloat};
+
+use super::PQ;
+use crate::float_ord::min_by_float_key;
+use crate::linalg::SquaredEuclideanDistance;
+
+impl<A> PQ<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Reorder centroids such that close codes have close centroids.
+    ///
+    /// Returns a copy of the quantizer in which the centroids of every
+    /// subquantizer are reordered along a path through the centroids.
+    /// The path starts at the centroid that is farthest from the mean
+    /// of the centroids and then repeatedly visits the nearest centroid
+    /// that was not visited yet. Since numerically close codes then
+    /// correspond to close centroids, vectors that are close tend to
+    /// get numerically close codes. This makes code streams of sorted
+    /// or clustered vectors more compressible with delta or entropy
+    /// coding.
+    ///
+    /// Also returns the permutation with the shape *(n_subquantizers,
+    /// n_centroids)*, element *(i, j)* is the new code of centroid *j*
+    /// of subquantizer *i*, which can be used to convert existing
+    /// codes. Reconstructions are not affected. Reordering invalidates
+    /// polysemous codes, so the result is not `hamming_filterable`.
+    pub fn locality_ordered(&self) -> (PQ<A>, Array2<usize>) {
+        let mut permutation = Array2::zeros((
+            self.quantizers.len_of(Axis(0)),
+            self.n_quantizer_centroids(),
+        ));
+        for (quantizer, mut permutation) in self
+            .quantizers
+            .outer_iter()
+            .zip(permutation.outer_iter_mut())
+        {
+            permutation.assign(&nearest_neighbor_path(quantizer));
+        }
+
+        let pq = PQ {
+            projection: self.projection.clone(),
+            quantizers: permute_centroids(self.quantizers.view(), permutation.view()),
+            manifest: self.manifest.clone(),
+            polysemous: None,
+            normalization: self.normalization.clone(),
+            metadata: self.metadata.clone(),
+        };
+
+        (pq, permutation)
+    }
+}
+
+/// Permute the centroids of every subquantizer.
+///
+/// Centroid *j* of subquantizer *i* is moved to index *permutation[(i, j)]*.
+pub(super) fn permute_centroids<A>(
+    quantizers: ArrayView3<A>,
+    permutation: ArrayView2<usize>,
+) -> Array3<A>
+where
+    A: NdFloat,
+{
+    let mut permuted = Array3::zeros(quantizers.dim());
+    for ((quantizer, mut permuted), permutation) in quantizers
+        .outer_iter()
+        .zip(permuted.outer_iter_mut())
+        .zip(permutation.outer_iter())
+    {
+        for (centroid, &code) in quantizer.outer_iter().zip(permutation.iter()) {
+            permuted.index_axis_mut(Axis(0), code).assign(&centroid);
+        }
+    }
+
+    permuted
+}
+
+/// Order centroids by a greedy nearest neighbor path.
+///
+/// Returns the position of every centroid in the path.
+fn nearest_neighbor_path<A>(centroids: ArrayView2<A>) -> Array1<usize>
+where
+    A: NdFloat + Sum,
+{
+    let n_centroids = centroids.nrows();
+    let distances = centroids.squared_euclidean_distance(centroids);
+    let mean = centroids.sum_axis(Axis(0)) / A::from(n_centroids).unwrap();
+    let from_mean = mean.squared_euclidean_distance(centroids);
+
+    let mut positions = Array1::zeros(n_centroids);
+    let mut visited = vec![false; n_centroids];
+    let mut current = min_by_float_key(0..n_centroids, |&idx| -from_mean[idx]).unwrap();
+    for position in 0..n_centroids {
+        positions[current] = position;
+        visited[current] = true;
+
+        if let Some(next) =
+            min_by_float_key((0..n_centroids).filter(|&idx| !visited[idx]), |&idx| {
+                distances[(current, idx)]
+            })
+        {
+            current = next;
+        }
+    }
+
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array2, Array3, Axis};
+    use rand::distributions::Uniform;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    #[test]
+    fn locality_ordered_codes_are_sorted_on_a_line() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut values = (0..16).map(|v| v as f32).collect::<Vec<_>>();
+        values.shuffle(&mut rng);
+        let pq = PQ::new(
+            None,
+            Array3::from_shape_fn((1, 16, 1), |(_, c, _)| values[c]),
+        );
+
+        let (ordered, permutation) = pq.locality_ordered();
+        let centroids = ordered.subquantizers().index_axis_move(Axis(0), 0);
+        let increasing = centroids
+            .column(0)
+            .windows(2)
+            .into_iter()
+            .all(|w| w[0] < w[1]);
+        let decreasing = centroids
+            .column(0)
+            .windows(2)
+            .into_iter()
+            .all(|w| w[0] > w[1]);
+        assert!(increasing || decreasing);
+
+        for (centroid, &code) in permutation.row(0).iter().enumerate() {
+            assert_eq!(centroids[(code, 0)], values[centroid]);
+        }
+    }
+
+    #[test]
+    fn locality_ordering_preserves_reconstructions() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let quantizers = Array3::random_using((3, 8, 2), Uniform::new(-1f32, 1.), &mut rng);
+        let pq = PQ::new(None, quantizers).polysemous_using(100, &mut rng);
+        let (ordered, permutation) = pq.locality_ordered();
+        assert!(!ordered.hamming_filterable());
+
+        let instances = Array2::random_using((32, 6), Uniform::new(-1f32, 1.), &mut rng);
+        let quantized: Array2<usize> = pq.quantize_batch(instances.view());
+        let ordered_quantized: Array2<usize> = ordered.quantize_batch(instances.view());
+        for (codes, ordered_codes) in quantized.outer_iter().zip(ordered_quantized.outer_iter()) {
+            for (sq, (&code, &ordered_code)) in codes.iter().zip(ordered_codes).enumerate() {
+                assert_eq!(permutation[(sq, code)], ordered_code);
+            }
+        }
+
+        assert_eq!(
+            ordered.reconstruct_batch(ordered_quantized),
+            pq.reconstruct_batch(quantized)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::sync::Arc;
+
+use ndarray::{Array1, NdFloat};
+
+/// Post-processing of approximate distances into scores.
+///
+/// A score transform maps the approximate squared distances of a search
+/// to calibrated similarities, so that downstream ranking does not
+/// depend on the scale of the distances of a particular quantizer.
+#[derive(Clone)]
+pub enum ScoreTransform {
+    /// Use the distances as scores.
+    Identity,
+
+    /// Logistic calibration (Platt scaling).
+    ///
+    /// The score of distance *d* is *1 / (1 + exp(slope * d +
+    /// intercept))*.
+    Logistic {
+        /// The slope of the logistic function.
+        slope: f64,
+
+        /// The intercept of the logistic function.
+        intercept: f64,
+    },
+
+    /// Temperature scaling.
+    ///
+    /// The score of distance *d* is *exp(-d / temperature)*.
+    Temperature(f64),
+
+    /// A custom transform.
+    Custom(Arc<dyn Fn(f64) -> f64 + Send + Sync>),
+}
+
+impl ScoreTransform {
+    /// Construct a custom transform.
+    pub fn custom(transform: impl Fn(f64) -> f64 + Send + Sync + 'static) -> Self {
+        ScoreTransform::Custom(Arc::new(transform))
+    }
+
+    /// Compute the score of a distance.
+    pub fn score(&self, distance: f64) -> f64 {
+        match self {
+            ScoreTransform::Identity => distance,
+            ScoreTransform::Logistic { slope, intercept } => {
+                1. / (1. + (slope * distance + intercept).exp())
+            }
+            ScoreTransform::Temperature(temperature) => (-distance / temperature).exp(),
+            ScoreTransform::Custom(transform) => transform(distance),
+        }
+    }
+
+    /// Compute the scores of distances.
+    pub fn scores<A>(&self, distances: Array1<A>) -> Array1<A>
+    where
+        A: NdFloat,
+    {
+        match self {
+            ScoreTransform::Identity => distances,
+            _ => distances.mapv_into(|d| A::from(self.score(d.to_f64().unwrap())).unwrap()),
+        }
+    }
+}
+
+impl fmt::Debug for ScoreTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScoreTransform::Identity => write!(f, "Identity"),
+            ScoreTransform::Logistic { slope, intercept } => f
+                .debug_struct("Logistic")
+                .field("slope", slope)
+                .field("intercept", intercept)
+                .finish(),
+            ScoreTransform::Temperature(temperature) => {
+                f.debug_tuple("Temperature").field(temperature).finish()
+            }
+            ScoreTransform::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Custom transforms are only equal if they share the same function.
+impl PartialEq for ScoreTransform {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ScoreTransform::Identity, ScoreTransform::Identity) => true,
+            (
+                ScoreTransform::Logistic { slope, intercept },
+                ScoreTransform::Logistic {
+                    slope: other_slope,
+                    intercept: other_intercept,
+                },
+            ) => slope == other_slope && intercept == other_intercept,
+            (ScoreTransform::Temperature(t), ScoreTransform::Temperature(other_t)) => t == other_t,
+            (ScoreTransform::Custom(f), ScoreTransform::Custom(other_f)) => Arc::ptr_eq(f, other_f),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::ScoreTransform;
+
+    #[test]
+    fn transform_scores() {
+        let distances = array![0f32, 1., 2.];
+        assert_eq!(
+            ScoreTransform::Identity.scores(distances.clone()),
+            distances
+        );
+
+        let logistic = ScoreTransform::Logistic {
+            slope: 1.,
+            intercept: -1.,
+        };
+        assert_eq!(logistic.score(1.), 0.5);
+        assert!(logistic.score(0.) > logistic.score(2.));
+
+        let temperature = ScoreTransform::Temperature(2.);
+        assert_eq!(temperature.score(0.), 1.);
+        assert!((temperature.score(2.) - (-1f64).exp()).abs() < 1e-12);
+
+        let custom = ScoreTransform::custom(|d| 10. - d);
+        assert_eq!(custom.scores(distances), array![10f32, 9., 8.]);
+        assert_eq!(custom, custom.clone());
+        assert_ne!(custom, ScoreTransform::custom(|d| 10. - d));
+    }
+}
@@ -0,0 +1,548 @@
+use std::iter::Sum;
+
+use ndarray::{s, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, Axis, Data, Ix2, NdFloat};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use super::{QuantizeVector, ReconstructVector};
+
+/// Padded batch of variable-length vector sequences.
+///
+/// The sequences are stored in an array with shape *(n_sequences,
+/// max_len, n_dims)*, where sequence *i* occupies the first
+/// `lengths[i]` rows of the *i*-th matrix. The remaining rows are
+/// padding and are always zero. This is the usual representation of
+/// e.g. token embeddings of a batch of sentences.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequenceBatch<A> {
+    data: Array3<A>,
+    lengths: Vec<usize>,
+}
+
+impl<A> SequenceBatch<A>
+where
+    A: NdFloat,
+{
+    /// Construct a batch from padded sequences.
+    ///
+    /// Padding rows of `data` are set to zero.
+    pub fn new(mut data: Array3<A>, lengths: Vec<usize>) -> Self {
+        check_lengths(data.len_of(Axis(0)), data.len_of(Axis(1)), &lengths);
+
+        for (mut sequence, &len) in data.outer_iter_mut().zip(&lengths) {
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            sequence.slice_mut(s![len.., ..]).fill(A::zero());
+        }
+
+        SequenceBatch { data, lengths }
+    }
+
+    /// Construct a batch from sequences.
+    ///
+    /// Each sequence is a matrix with shape *(len, n_dims)*. The
+    /// sequences are padded to the length of the longest sequence.
+    pub fn from_sequences<S>(sequences: &[ArrayBase<S, Ix2>]) -> Self
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            !sequences.is_empty(),
+            "Cannot construct a batch without sequences"
+        );
+
+        let n_dims = sequences[0].ncols();
+        let max_len = sequences.iter().map(|s| s.nrows()).max().unwrap_or(0);
+        let mut data = Array3::zeros((sequences.len(), max_len, n_dims));
+        for (sequence, mut padded) in sequences.iter().zip(data.outer_iter_mut()) {
+            assert_eq!(
+                sequence.ncols(),
+                n_dims,
+                "Sequences have different vector lengths"
+            );
+
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            padded
+                .slice_mut(s![..sequence.nrows(), ..])
+                .assign(sequence);
+        }
+
+        SequenceBatch {
+            data,
+            lengths: sequences.iter().map(|s| s.nrows()).collect(),
+        }
+    }
+
+    /// Get the padded sequences.
+    pub fn data(&self) -> ArrayView3<A> {
+        self.data.view()
+    }
+
+    /// Get the sequence lengths.
+    pub fn lengths(&self) -> &[usize] {
+        &self.lengths
+    }
+
+    /// Get the padding mask.
+    ///
+    /// Returns a matrix with shape *(n_sequences, max_len)*, which is
+    /// `true` for the rows that are part of a sequence.
+    pub fn mask(&self) -> Array2<bool> {
+        padding_mask(self.data.len_of(Axis(1)), &self.lengths)
+    }
+
+    /// Get the *i*-th sequence without padding.
+    pub fn sequence(&self, i: usize) -> ArrayView2<A> {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        self.data.slice(s![i, ..self.lengths[i], ..])
+    }
+
+    /// Quantize the sequences.
+    ///
+    /// All vectors of all sequences are quantized in a single batch.
+    /// Padding rows are not quantized and get zero codes.
+    pub fn quantize<I, Q>(&self, quantizer: &Q) -> QuantizedSequences<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        Q: QuantizeVector<A>,
+        usize: AsPrimitive<I>,
+    {
+        let n_dims = self.data.len_of(Axis(2));
+        let n_vectors = self.lengths.iter().sum();
+
+        let mut vectors = Array2::zeros((n_vectors, n_dims));
+        let mut offset = 0;
+        for i in 0..self.lengths.len() {
+            let len = self.lengths[i];
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            vectors
+                .slice_mut(s![offset..offset + len, ..])
+                .assign(&self.sequence(i));
+            offset += len;
+        }
+
+        let quantized: Array2<I> = quantizer.quantize_batch(vectors);
+
+        let mut codes = Array3::zeros((
+            self.lengths.len(),
+            self.data.len_of(Axis(1)),
+            quantizer.quantized_len(),
+        ));
+        let mut offset = 0;
+        for (mut sequence_codes, &len) in codes.outer_iter_mut().zip(&self.lengths) {
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            sequence_codes
+                .slice_mut(s![..len, ..])
+                .assign(&quantized.slice(s![offset..offset + len, ..]));
+            offset += len;
+        }
+
+        QuantizedSequences {
+            codes,
+            lengths: self.lengths.clone(),
+        }
+    }
+}
+
+/// Quantized batch of variable-length vector sequences.
+///
+/// The codes are stored in an array with shape *(n_sequences, max_len,
+/// n_subquantizers)*. The codes of padding rows are zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantizedSequences<I> {
+    codes: Array3<I>,
+    lengths: Vec<usize>,
+}
+
+impl<I> QuantizedSequences<I>
+where
+    I: AsPrimitive<usize>,
+{
+    /// Construct a quantized batch from padded codes.
+    pub fn new(codes: Array3<I>, lengths: Vec<usize>) -> Self {
+        check_lengths(codes.len_of(Axis(0)), codes.len_of(Axis(1)), &lengths);
+        QuantizedSequences { codes, lengths }
+    }
+
+    /// Get the padded codes.
+    pub fn codes(&self) -> ArrayView3<I> {
+        self.codes.view()
+    }
+
+    /// Get the sequence lengths.
+    pub fn lengths(&self) -> &[usize] {
+        &self.lengths
+    }
+
+    /// Get the padding mask.
+    ///
+    /// See `SequenceBatch::mask`.
+    pub fn mask(&self) -> Array2<bool> {
+        padding_mask(self.codes.len_of(Axis(1)), &self.lengths)
+    }
+
+    /// Get the codes of the *i*-th sequence without padding.
+    pub fn sequence(&self, i: usize) -> ArrayView2<I> {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        self.codes.slice(s![i, ..self.lengths[i], ..])
+    }
+
+    /// Reconstruct the sequences.
+    ///
+    /// Padding rows are not reconstructed and are zero.
+    pub fn reconstruct<A, Q>(&self, quantizer: &Q) -> SequenceBatch<A>
+    where
+        A: NdFloat,
+        Q: ReconstructVector<A>,
+    {
+        let mut data = Array3::zeros((
+            self.lengths.len(),
+            self.codes.len_of(Axis(1)),
+            quantizer.reconstructed_len(),
+        ));
+
+        for (i, mut sequence) in data.outer_iter_mut().enumerate() {
+            let len = self.lengths[i];
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            quantizer.reconstruct_batch_into(self.sequence(i), sequence.slice_mut(s![..len, ..]));
+        }
+
+        SequenceBatch {
+            data,
+            lengths: self.lengths.clone(),
+        }
+    }
+}
+
+impl<I> QuantizedSequences<I>
+where
+    I: AsPrimitive<usize> + Zero,
+{
+    /// Pack the codes without padding.
+    pub fn pack(&self) -> PackedCodes<I> {
+        let n_subquantizers = self.codes.len_of(Axis(2));
+        let offsets = sequence_offsets(&self.lengths);
+        let mut codes = Array2::zeros((offsets[offsets.len() - 1], n_subquantizers));
+        for (i, window) in offsets.windows(2).enumerate() {
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            codes
+                .slice_mut(s![window[0]..window[1], ..])
+                .assign(&self.sequence(i));
+        }
+
+        PackedCodes { codes, offsets }
+    }
+}
+
+/// Packed quantization codes of variable-length sequences.
+///
+/// The codes of all sequences are stored contiguously in a matrix with
+/// shape *(n_vectors, n_subquantizers)*, the codes of sequence *i* are
+/// rows `offsets[i]..offsets[i + 1]`. This is a compact representation
+/// for late-interaction retrieval in the style of ColBERT, where each
+/// document is represented by the vectors of its tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedCodes<I> {
+    codes: Array2<I>,
+    offsets: Vec<usize>,
+}
+
+impl<I> PackedCodes<I>
+where
+    I: AsPrimitive<usize>,
+{
+    /// Construct packed codes from codes and offsets.
+    ///
+    /// `offsets` has one element more than there are sequences. The
+    /// first offset must be zero and the last offset the number of rows
+    /// of `codes`.
+    pub fn new(codes: Array2<I>, offsets: Vec<usize>) -> Self {
+        assert!(
+            offsets.first() == Some(&0) && offsets.last() == Some(&codes.nrows()),
+            "Offsets should start at 0 and end at {}",
+            codes.nrows()
+        );
+        assert!(
+            offsets.windows(2).all(|w| w[0] <= w[1]),
+            "Offsets should be non-decreasing"
+        );
+
+        PackedCodes { codes, offsets }
+    }
+
+    /// Quantize and pack sequences.
+    ///
+    /// The vectors of all sequences are quantized in a single batch.
+    pub fn encode<A, Q, S>(quantizer: &Q, sequences: &[ArrayBase<S, Ix2>]) -> Self
+    where
+        A: NdFloat,
+        I: Bounded + Zero,
+        Q: QuantizeVector<A>,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let lengths = sequences.iter().map(|s| s.nrows()).collect::<Vec<_>>();
+        let offsets = sequence_offsets(&lengths);
+        let n_dims = sequences.first().map(|s| s.ncols()).unwrap_or(0);
+
+        let mut vectors = Array2::zeros((offsets[offsets.len() - 1], n_dims));
+        for (sequence, window) in sequences.iter().zip(offsets.windows(2)) {
+            assert_eq!(
+                sequence.ncols(),
+                n_dims,
+                "Sequences have different vector lengths"
+            );
+
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            vectors
+                .slice_mut(s![window[0]..window[1], ..])
+                .assign(sequence);
+        }
+
+        PackedCodes {
+            codes: quantizer.quantize_batch(vectors),
+            offsets,
+        }
+    }
+
+    /// Get the codes of all sequences.
+    pub fn codes(&self) -> ArrayView2<I> {
+        self.codes.view()
+    }
+
+    /// Get the sequence offsets.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// Get the number of sequences.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Check whether there are no sequences.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the codes of the *i*-th sequence.
+    pub fn sequence(&self, i: usize) -> ArrayView2<I> {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        self.codes
+            .slice(s![self.offsets[i]..self.offsets[i + 1], ..])
+    }
+
+    /// Reconstruct the vectors of the *i*-th sequence.
+    pub fn decode<A, Q>(&self, i: usize, quantizer: &Q) -> Array2<A>
+    where
+        A: NdFloat,
+        Q: ReconstructVector<A>,
+    {
+        quantizer.reconstruct_batch(self.sequence(i))
+    }
+
+    /// Compute the Chamfer distance between a query and a sequence.
+    ///
+    /// `query_tables` are the distance tables of the query vectors, as
+    /// returned by `PQ::adc_tables_batch`. Returns the sum over the
+    /// query vectors of the squared distance to the nearest vector of
+    /// sequence *i*. For unit vectors, this is *2 n_query_vectors -
+    /// 2 MaxSim*, so ranking by increasing distance is ranking by
+    /// ColBERT's late-interaction score. The distance is infinite for
+    /// an empty sequence.
+    pub fn chamfer_distance<A>(&self, i: usize, query_tables: ArrayView3<A>) -> A
+    where
+        A: NdFloat + Sum,
+    {
+        let sequence = self.sequence(i);
+        query_tables
+            .outer_iter()
+            .map(|table| {
+                sequence
+                    .outer_iter()
+                    .map(|codes| {
+                        codes
+                            .iter()
+                            .zip(table.outer_iter())
+                            .map(|(&code, sq_table)| sq_table[code.as_()])
+                            .sum::<A>()
+                    })
+                    .fold(A::infinity(), A::min)
+            })
+            .sum()
+    }
+}
+
+impl<I> PackedCodes<I>
+where
+    I: AsPrimitive<usize> + Zero,
+{
+    /// Unpack the codes into a padded batch.
+    pub fn unpack(&self) -> QuantizedSequences<I> {
+        let lengths = self
+            .offsets
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .collect::<Vec<_>>();
+        let max_len = lengths.iter().cloned().max().unwrap_or(0);
+
+        let mut codes = Array3::zeros((lengths.len(), max_len, self.codes.ncols()));
+        for (i, mut sequence_codes) in codes.outer_iter_mut().enumerate() {
+            // ndarray#474
+            #[allow(clippy::deref_addrof)]
+            sequence_codes
+                .slice_mut(s![..lengths[i], ..])
+                .assign(&self.sequence(i));
+        }
+
+        QuantizedSequences { codes, lengths }
+    }
+}
+
+fn sequence_offsets(lengths: &[usize]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(lengths.len() + 1);
+    offsets.push(0);
+    for &len in lengths {
+        offsets.push(offsets[offsets.len() - 1] + len);
+    }
+    offsets
+}
+
+fn check_lengths(n_sequences: usize, max_len: usize, lengths: &[usize]) {
+    assert_eq!(
+        n_sequences,
+        lengths.len(),
+        "Number of sequences ({}) and lengths ({}) do not match",
+        n_sequences,
+        lengths.len()
+    );
+
+    for &len in lengths {
+        assert!(
+            len <= max_len,
+            "Sequence length {} exceeds padded length {}",
+            len,
+            max_len
+        );
+    }
+}
+
+fn padding_mask(max_len: usize, lengths: &[usize]) -> Array2<bool> {
+    Array2::from_shape_fn((lengths.len(), max_len), |(i, j)| j < lengths[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, s, Array2, Array3};
+
+    use super::{PackedCodes, QuantizedSequences, SequenceBatch};
+    use crate::pq::{QuantizeVector, ReconstructVector, PQ};
+
+    fn sequence_pq() -> PQ<f32> {
+        let quantizers = array![[[1., 0.], [0., 1.]], [[1., -1.], [0., 1.]],];
+        PQ::new(None, quantizers)
+    }
+
+    #[test]
+    fn quantize_variable_length_sequences() {
+        let pq = sequence_pq();
+        let first = array![[0., 2., 0., -0.5], [1., -0.2, 0.5, 0.5]];
+        let second = array![[-0.2, 0.2, 0., -2.]];
+        let batch = SequenceBatch::from_sequences(&[first.view(), second.view()]);
+        assert_eq!(batch.lengths(), &[2, 1]);
+        assert_eq!(batch.mask(), array![[true, true], [true, false]]);
+        assert_eq!(batch.sequence(1), second);
+
+        let quantized = batch.quantize::<u8, _>(&pq);
+        assert_eq!(quantized.codes().shape(), &[2, 2, 2]);
+        assert_eq!(
+            quantized.sequence(0),
+            pq.quantize_batch::<u8, _>(first.view())
+        );
+        assert_eq!(
+            quantized.sequence(1),
+            pq.quantize_batch::<u8, _>(second.view())
+        );
+        assert_eq!(quantized.codes().slice(s![1, 1, ..]), array![0, 0]);
+
+        let reconstructed = quantized.reconstruct(&pq);
+        assert_eq!(reconstructed.mask(), batch.mask());
+        assert_eq!(
+            reconstructed.sequence(0),
+            pq.reconstruct_batch(quantized.sequence(0))
+        );
+        assert_eq!(
+            reconstructed.data().slice(s![1, 1, ..]),
+            array![0., 0., 0., 0.]
+        );
+    }
+
+    #[test]
+    fn packed_codes_round_trip() {
+        let pq = sequence_pq();
+        let first = array![[0., 2., 0., -0.5], [1., -0.2, 0.5, 0.5]];
+        let second = array![[-0.2, 0.2, 0., -2.]];
+        let packed = PackedCodes::<u8>::encode(&pq, &[first.view(), second.view()]);
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed.offsets(), &[0, 2, 3]);
+        assert_eq!(packed.sequence(0), pq.quantize_batch::<u8, _>(first.view()));
+        assert_eq!(
+            packed.decode(1, &pq),
+            pq.reconstruct_batch(pq.quantize_batch::<u8, _>(second.view()))
+        );
+
+        let batch = SequenceBatch::from_sequences(&[first.view(), second.view()]);
+        assert_eq!(packed.unpack(), batch.quantize(&pq));
+        assert_eq!(packed.unpack().pack(), packed);
+    }
+
+    #[test]
+    fn chamfer_distance_uses_nearest_vectors() {
+        let pq = sequence_pq();
+        let documents = [
+            array![[1., 0., 1., -1.], [0., 1., 0., 1.]],
+            array![[1., 0., 0., 1.]],
+        ];
+        let packed = PackedCodes::<u8>::encode(&pq, &[documents[0].view(), documents[1].view()]);
+
+        let query = array![[1f32, 0., 1., -1.], [0., 1., 0., 1.]];
+        let tables = pq.adc_tables_batch(query.view());
+        assert_eq!(packed.chamfer_distance(0, tables.view()), 0.);
+        // Squared distances of the query vectors to the only document
+        // vector: 0 + 5 and 2 + 0.
+        assert_eq!(packed.chamfer_distance(1, tables.view()), 7.);
+
+        let empty = PackedCodes::new(array![[0u8, 0]], vec![0, 1, 1]);
+        assert!(empty.chamfer_distance(1, tables.view()).is_infinite());
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_incorrect_offsets() {
+        PackedCodes::new(Array2::<u8>::zeros((2, 2)), vec![0, 3]);
+    }
+
+    #[test]
+    fn padding_is_cleared() {
+        let data = Array3::from_elem((2, 2, 2), 1f32);
+        let batch = SequenceBatch::new(data, vec![2, 0]);
+        assert_eq!(
+            batch.data(),
+            array![[[1., 1.], [1., 1.]], [[0., 0.], [0., 0.]]]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_too_long_sequences() {
+        QuantizedSequences::new(Array3::<u8>::zeros((1, 2, 2)), vec![3]);
+    }
+}
@@ -0,0 +1,283 @@
+use std::convert::TryFrom;
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, ArrayBase, Data, DataMut, Ix2, NdFloat};
+
+use super::{PQView, QuantizeVector, ReconstructVector};
+use crate::error::Error;
+
+/// Storage that quantization codes can be written to.
+///
+/// This trait makes it possible to stream codes into the page formats
+/// of custom storage engines. Each vector is stored as a row of `u8`
+/// codes, one code per subquantizer. Rows are identified by `u64`, so
+/// that storage can hold more than 2^32 vectors regardless of the
+/// platform.
+pub trait CodeSink {
+    /// Write the codes of the vector in row `row`.
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error>;
+}
+
+/// Storage that quantization codes can be read from.
+///
+/// See `CodeSink`.
+pub trait CodeSource {
+    /// Get the number of rows with codes of length `code_len`.
+    fn n_rows(&self, code_len: usize) -> u64;
+
+    /// Read the codes of the vector in row `row` into `codes`.
+    ///
+    /// The length of `codes` is the number of codes per vector.
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error>;
+}
+
+impl CodeSink for [u8] {
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error> {
+        let n_rows = self.len() / codes.len().max(1);
+        let offset = row_offset(row, codes.len(), n_rows as u64)?;
+        self[offset..offset + codes.len()].copy_from_slice(codes);
+        Ok(())
+    }
+}
+
+impl CodeSource for [u8] {
+    fn n_rows(&self, code_len: usize) -> u64 {
+        (self.len() / code_len.max(1)) as u64
+    }
+
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error> {
+        let offset = row_offset(row, codes.len(), self.n_rows(codes.len()))?;
+        codes.copy_from_slice(&self[offset..offset + codes.len()]);
+        Ok(())
+    }
+}
+
+/// Vectors grow to accommodate rows that are written.
+impl CodeSink for Vec<u8> {
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error> {
+        let end = usize::try_from(row)
+            .ok()
+            .and_then(|row| row.checked_add(1))
+            .and_then(|n_rows| n_rows.checked_mul(codes.len()))
+            .ok_or(Error::RowOutOfRange {
+                row,
+                n_rows: self.n_rows(codes.len()),
+            })?;
+        if self.len() < end {
+            self.resize(end, 0);
+        }
+
+        self.as_mut_slice().write_codes(row, codes)
+    }
+}
+
+impl CodeSource for Vec<u8> {
+    fn n_rows(&self, code_len: usize) -> u64 {
+        self.as_slice().n_rows(code_len)
+    }
+
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error> {
+        self.as_slice().read_codes(row, codes)
+    }
+}
+
+impl<S> CodeSink for ArrayBase<S, Ix2>
+where
+    S: DataMut<Elem = u8>,
+{
+    fn write_codes(&mut self, row: u64, codes: &[u8]) -> Result<(), Error> {
+        check_code_len(self.ncols(), codes.len())?;
+        let row = row_index(row, self.nrows() as u64)?;
+
+        for (code, &value) in self.row_mut(row).iter_mut().zip(codes) {
+            *code = value;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> CodeSource for ArrayBase<S, Ix2>
+where
+    S: Data<Elem = u8>,
+{
+    fn n_rows(&self, _code_len: usize) -> u64 {
+        self.nrows() as u64
+    }
+
+    fn read_codes(&self, row: u64, codes: &mut [u8]) -> Result<(), Error> {
+        check_code_len(self.ncols(), codes.len())?;
+        let row = row_index(row, self.nrows() as u64)?;
+
+        for (code, &value) in codes.iter_mut().zip(self.row(row)) {
+            *code = value;
+        }
+
+        Ok(())
+    }
+}
+
+fn check_code_len(expected: usize, actual: usize) -> Result<(), Error> {
+    if expected != actual {
+        return Err(Error::CodeLengthMismatch { expected, actual });
+    }
+
+    Ok(())
+}
+
+/// Convert a row identifier to an in-memory row index.
+fn row_index(row: u64, n_rows: u64) -> Result<usize, Error> {
+    if row >= n_rows {
+        return Err(Error::RowOutOfRange { row, n_rows });
+    }
+
+    // In-memory storage cannot have more rows than fit in usize.
+    Ok(row as usize)
+}
+
+fn row_offset(row: u64, code_len: usize, n_rows: u64) -> Result<usize, Error> {
+    Ok(row_index(row, n_rows)? * code_len)
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors into a code sink.
+    ///
+    /// The codes of the vectors are written to the rows starting at
+    /// `first_row`. Panics if the subquantizers have more than 256
+    /// centroids.
+    pub fn quantize_batch_into_sink<S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        sink: &mut (impl CodeSink + ?Sized),
+        first_row: u64,
+    ) -> Result<(), Error>
+    where
+        S: Data<Elem = A>,
+    {
+        let quantized = self.quantize_batch::<u8, _>(x);
+        for (idx, codes) in quantized.outer_iter().enumerate() {
+            sink.write_codes(
+                first_row + idx as u64,
+                codes
+                    .as_slice()
+                    .expect("Quantized matrix is not contiguous"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Reconstruct vectors from a code source.
+    ///
+    /// Reconstructs the vectors in rows `rows`. Returns an error when a
+    /// row is out of range or when a code does not refer to a centroid.
+    pub fn reconstruct_from_source(
+        &self,
+        source: &(impl CodeSource + ?Sized),
+        rows: impl IntoIterator<Item = u64>,
+    ) -> Result<Array2<A>, Error> {
+        let rows = rows.into_iter().collect::<Vec<_>>();
+        let mut codes = Array1::zeros(self.quantized_len());
+        let mut reconstructions = Array2::zeros((rows.len(), self.reconstructed_len()));
+        for (&row, mut reconstruction) in rows.iter().zip(reconstructions.outer_iter_mut()) {
+            source.read_codes(
+                row,
+                codes
+                    .as_slice_mut()
+                    .expect("Codes vector is not contiguous"),
+            )?;
+            reconstruction.assign(&self.try_reconstruct_vector(codes.view())?);
+        }
+
+        Ok(reconstructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, s, Array2, Axis};
+
+    use super::{CodeSink, CodeSource};
+    use crate::error::Error;
+    use crate::pq::{test_pq, QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn stream_codes_through_storage() {
+        let pq = test_pq();
+        let view = pq.view();
+        let instances: Array2<f32> = array![
+            [0., 2., 0., -0.5, 0., 0.],
+            [1., -0.2, 0., 0.5, 0.5, 0.],
+            [-0.2, 0.2, 0., 0., -2., 0.],
+        ];
+        let quantized = pq.quantize_batch::<u8, _>(instances.view());
+        let reconstructions = pq.reconstruct_batch(quantized.view());
+
+        let mut vec = Vec::new();
+        view.quantize_batch_into_sink(instances.view(), &mut vec, 0)
+            .unwrap();
+        assert_eq!(vec, quantized.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(vec.n_rows(2), 3);
+        assert_eq!(
+            view.reconstruct_from_source(&vec, 0..3),
+            Ok(reconstructions.clone())
+        );
+
+        let mut page = [0u8; 8];
+        assert_eq!(
+            view.quantize_batch_into_sink(instances.view(), &mut page[..], 2),
+            Err(Error::RowOutOfRange { row: 4, n_rows: 4 })
+        );
+        view.quantize_batch_into_sink(instances.slice(s![..2, ..]), &mut page[..], 2)
+            .unwrap();
+        assert_eq!(&page[4..], &vec[..4]);
+        assert_eq!(
+            view.reconstruct_from_source(&page[..], vec![3, 2]).unwrap(),
+            reconstructions.select(Axis(0), &[1, 0])
+        );
+
+        let mut array = Array2::zeros((3, 2));
+        view.quantize_batch_into_sink(instances.view(), &mut array, 0)
+            .unwrap();
+        assert_eq!(array, quantized);
+        assert_eq!(
+            view.reconstruct_from_source(&array, 0..3),
+            Ok(reconstructions)
+        );
+    }
+
+    #[test]
+    fn storage_errors() {
+        let mut array = Array2::<u8>::zeros((2, 2));
+        assert_eq!(
+            array.write_codes(0, &[1, 0, 1]),
+            Err(Error::CodeLengthMismatch {
+                expected: 2,
+                actual: 3
+            })
+        );
+        assert_eq!(
+            array.write_codes(2, &[1, 0]),
+            Err(Error::RowOutOfRange { row: 2, n_rows: 2 })
+        );
+
+        let mut codes = [0u8; 2];
+        assert_eq!(
+            vec![1u8, 0, 1].read_codes(1, &mut codes),
+            Err(Error::RowOutOfRange { row: 1, n_rows: 1 })
+        );
+
+        let pq = test_pq();
+        assert_eq!(
+            pq.view().reconstruct_from_source(&vec![0u8, 2], 0..1),
+            Err(Error::CodeOutOfRange {
+                subquantizer: 1,
+                code: 2,
+                n_centroids: 2
+            })
+        );
+    }
+}
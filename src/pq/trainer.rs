@@ -0,0 +1,450 @@
+use std::iter::Sum;
+use std::time::Instant;
+
+use log::{info, warn};
+use ndarray::{Array2, ArrayView2, Axis, NdFloat};
+use num_traits::AsPrimitive;
+use rand::RngCore;
+
+use crate::error::Error;
+use crate::float_ord::min_by_float_key;
+use crate::kmeans::{
+    kmeans_with_centroids_scratch, AssignmentKernel, CentroidInitialization, DivergenceCondition,
+    KMeansScratch, NIterationsCondition,
+};
+use crate::parallel::NestedParallelism;
+use crate::training_log::{log_record, LoggedIterations, TrainingRecord};
+
+/// Default maximum growth of the k-means loss during training.
+pub const DEFAULT_MAX_LOSS_GROWTH: f64 = 100.;
+
+/// Configuration of a subquantizer training task.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SubquantizerConfig {
+    /// The index of the subquantizer that is trained.
+    pub subquantizer_idx: usize,
+
+    /// The number of subquantizers of the product quantizer.
+    pub n_subquantizers: usize,
+
+    /// The number of centroids of the codebook.
+    pub codebook_len: usize,
+
+    /// The number of training iterations.
+    pub n_iterations: usize,
+
+    /// The number of training attempts.
+    pub n_attempts: usize,
+
+    /// The thread allocation of the task.
+    ///
+    /// A trainer can use up to `parallelism.inner()` threads.
+    pub parallelism: NestedParallelism,
+}
+
+/// Trainer of subquantizer codebooks.
+///
+/// Product quantizer training slices the (projected) training instances
+/// into subspaces and trains a codebook for every subspace. This trait
+/// abstracts over the training of a single codebook, so that other
+/// training methods (e.g. neural or external clustering) can be used
+/// with `PQ::train_pq_with_trainer_using`, while reusing the slicing,
+/// projection, and encoding of product quantizers. `KMeansTrainer` is
+/// the trainer that is used by `TrainPQ`.
+///
+/// Subquantizers are trained in parallel, so trainers must be `Sync`.
+pub trait SubquantizerTrainer<A> {
+    /// Train the codebook of a subquantizer.
+    ///
+    /// `instances` contains the instances restricted to the
+    /// subquantizer's subspace. Returns a codebook with the shape
+    /// *(config.codebook_len, instances.ncols())*. `rng` is a PRNG that
+    /// is seeded for this subquantizer, training should be
+    /// deterministic given its state.
+    fn train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        rng: &mut dyn RngCore,
+    ) -> Array2<A>;
+
+    /// Train the codebook of a subquantizer, reporting divergence.
+    ///
+    /// This method is used by `PQ::try_train_pq_with_trainer_using`.
+    /// The default implementation calls `train_subquantizer` and never
+    /// returns an error.
+    fn try_train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        rng: &mut dyn RngCore,
+    ) -> Result<Array2<A>, Error> {
+        Ok(self.train_subquantizer(instances, config, rng))
+    }
+
+    /// Get the settings of the trainer.
+    ///
+    /// The settings are recorded in the training manifest. The default
+    /// implementation has no settings.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+}
+
+/// Watchdog for diverging training runs.
+///
+/// Bad training data or numerical issues can make the k-means loss
+/// NaN or let it explode. The watchdog stops an attempt when its loss
+/// is not finite or grows to more than `max_loss_growth` times the loss
+/// of the first iteration. The attempt is then retried with new initial
+/// centroids, at most `n_reseeds` times per subquantizer. When all
+/// retries diverge, training fails with `Error::TrainingDiverged`.
+///
+/// Training instances with non-finite values always fail, since
+/// retrying does not help.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrainingWatchdog {
+    max_loss_growth: f64,
+    n_reseeds: usize,
+}
+
+impl TrainingWatchdog {
+    /// Construct a watchdog without retries.
+    pub fn new() -> Self {
+        TrainingWatchdog {
+            max_loss_growth: DEFAULT_MAX_LOSS_GROWTH,
+            n_reseeds: 0,
+        }
+    }
+
+    /// Set the maximum growth of the loss.
+    ///
+    /// Default: `DEFAULT_MAX_LOSS_GROWTH`
+    pub fn with_max_loss_growth(mut self, max_loss_growth: f64) -> Self {
+        assert!(
+            max_loss_growth >= 1.,
+            "The maximum loss growth should at least be 1, was: {}",
+            max_loss_growth
+        );
+
+        self.max_loss_growth = max_loss_growth;
+        self
+    }
+
+    /// Set the number of re-seeded retries of diverging attempts.
+    ///
+    /// Default: 0
+    pub fn with_reseeds(mut self, n_reseeds: usize) -> Self {
+        self.n_reseeds = n_reseeds;
+        self
+    }
+
+    /// Get the maximum growth of the loss.
+    pub fn max_loss_growth(&self) -> f64 {
+        self.max_loss_growth
+    }
+
+    /// Get the number of re-seeded retries of diverging attempts.
+    pub fn n_reseeds(&self) -> usize {
+        self.n_reseeds
+    }
+}
+
+impl Default for TrainingWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// k-means subquantizer trainer.
+///
+/// Each attempt initializes the centroids with random instances (see
+/// `KMeansTrainer::with_initialization` for other initializations) and
+/// optimizes them with `n_iterations` k-means iterations. The codebook
+/// of the attempt with the lowest loss is returned. Training is
+/// monitored by a `TrainingWatchdog`. `train_subquantizer` panics with
+/// a description of the divergence when training fails.
+///
+/// Instances are assigned to their nearest centroids with the blocked
+/// kernel by default. For very large codebooks, training can be sped up
+/// considerably with approximate assignment, see
+/// `AssignmentKernel::coarse`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KMeansTrainer {
+    initialization: CentroidInitialization,
+    kernel: AssignmentKernel,
+    watchdog: TrainingWatchdog,
+}
+
+impl KMeansTrainer {
+    /// Construct a k-means trainer with the default watchdog.
+    pub fn new() -> Self {
+        KMeansTrainer::default()
+    }
+
+    /// Set the training watchdog.
+    pub fn with_watchdog(mut self, watchdog: TrainingWatchdog) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// Get the training watchdog.
+    pub fn watchdog(&self) -> TrainingWatchdog {
+        self.watchdog
+    }
+
+    /// Set the initialization of the centroids.
+    ///
+    /// `CentroidInitialization::Quantiles` is deterministic, so all
+    /// attempts give the same codebook with this initialization.
+    ///
+    /// Default: `CentroidInitialization::RandomInstances`
+    pub fn with_initialization(mut self, initialization: CentroidInitialization) -> Self {
+        self.initialization = initialization;
+        self
+    }
+
+    /// Get the initialization of the centroids.
+    pub fn initialization(&self) -> CentroidInitialization {
+        self.initialization
+    }
+
+    /// Set the kernel for assigning instances to centroids.
+    pub fn with_assignment_kernel(mut self, kernel: AssignmentKernel) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    /// Get the kernel for assigning instances to centroids.
+    pub fn assignment_kernel(&self) -> AssignmentKernel {
+        self.kernel
+    }
+}
+
+impl<A> SubquantizerTrainer<A> for KMeansTrainer
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        rng: &mut dyn RngCore,
+    ) -> Array2<A> {
+        self.try_train_subquantizer(instances, config, rng)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        mut rng: &mut dyn RngCore,
+    ) -> Result<Array2<A>, Error> {
+        assert!(
+            config.n_attempts > 0,
+            "Cannot train a subquantizer in 0 attempts."
+        );
+
+        info!("Training PQ subquantizer {}", config.subquantizer_idx);
+        let start = Instant::now();
+
+        if let Some((instance, _)) = instances
+            .outer_iter()
+            .enumerate()
+            .find(|(_, instance)| instance.iter().any(|v| !v.is_finite()))
+        {
+            return Err(Error::TrainingDiverged {
+                subquantizer: config.subquantizer_idx,
+                iteration: 0,
+                reason: format!("instance {} has a non-finite value", instance),
+            });
+        }
+
+        // Buffers and instance norms are reused across iterations and
+        // attempts.
+        let mut scratch = KMeansScratch::new()
+            .with_parallelism(config.parallelism)
+            .with_kernel(self.kernel);
+        scratch.cache_instance_sqnorms(instances, Axis(0));
+
+        let mut n_reseeds = 0;
+        let mut attempts = Vec::with_capacity(config.n_attempts);
+        while attempts.len() < config.n_attempts {
+            let mut quantizer = self.initialization.initial_centroids(
+                instances,
+                Axis(0),
+                config.codebook_len,
+                &mut rng,
+            );
+            let mut condition = DivergenceCondition::new(
+                NIterationsCondition(config.n_iterations),
+                self.watchdog.max_loss_growth,
+            );
+            let loss = kmeans_with_centroids_scratch(
+                instances,
+                Axis(0),
+                quantizer.view_mut(),
+                LoggedIterations::new(&mut condition, config.subquantizer_idx),
+                &mut scratch,
+            );
+
+            match condition.divergence() {
+                None => attempts.push((loss, quantizer)),
+                Some((iteration, reason)) if n_reseeds < self.watchdog.n_reseeds => {
+                    warn!(
+                        "Training of subquantizer {} diverged in iteration {}: {}, retrying",
+                        config.subquantizer_idx, iteration, reason
+                    );
+                    n_reseeds += 1;
+                }
+                Some((iteration, reason)) => {
+                    return Err(Error::TrainingDiverged {
+                        subquantizer: config.subquantizer_idx,
+                        iteration,
+                        reason: reason.to_owned(),
+                    })
+                }
+            }
+        }
+
+        let (loss, quantizer) = min_by_float_key(attempts, |attempt| attempt.0).unwrap();
+        log_record(|| {
+            TrainingRecord::new("subquantizer", start.elapsed())
+                .with_subquantizer(config.subquantizer_idx)
+                .with_loss(loss.to_f64().unwrap_or(f64::NAN))
+        });
+
+        Ok(quantizer)
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("initialization", format!("{:?}", self.initialization)),
+            ("kernel", format!("{:?}", self.kernel)),
+            ("max_loss_growth", self.watchdog.max_loss_growth.to_string()),
+            ("n_reseeds", self.watchdog.n_reseeds.to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use ndarray::{s, Array2, ArrayView2};
+    use rand::distributions::Uniform;
+    use rand::{RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    use super::{KMeansTrainer, SubquantizerConfig, SubquantizerTrainer, TrainingWatchdog};
+    use crate::error::Error;
+    use crate::kmeans::CentroidInitialization;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    /// Trainer that uses the first instances as the codebook.
+    struct FirstInstancesTrainer;
+
+    impl SubquantizerTrainer<f32> for FirstInstancesTrainer {
+        fn train_subquantizer(
+            &self,
+            instances: ArrayView2<f32>,
+            config: SubquantizerConfig,
+            _rng: &mut dyn RngCore,
+        ) -> Array2<f32> {
+            instances.slice(s![..config.codebook_len, ..]).to_owned()
+        }
+    }
+
+    #[test]
+    fn train_with_custom_trainer() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> = Array2::random_using((64, 6), Uniform::new(-1., 1.), &mut rng);
+
+        let pq = PQ::train_pq_with_trainer_using(
+            &FirstInstancesTrainer,
+            3,
+            2,
+            1,
+            1,
+            instances.view(),
+            &mut rng,
+        );
+        for (sq, quantizer) in pq.subquantizers().outer_iter().enumerate() {
+            assert_eq!(quantizer, instances.slice(s![..4, sq * 2..(sq + 1) * 2]));
+        }
+    }
+
+    #[test]
+    fn train_pq_uses_kmeans_trainer() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> = Array2::random_using((64, 6), Uniform::new(-1., 1.), &mut rng);
+
+        let pq = PQ::train_pq_using(3, 2, 5, 2, instances.view(), XorShiftRng::seed_from_u64(1));
+        let kmeans = PQ::train_pq_with_trainer_using(
+            &KMeansTrainer::new(),
+            3,
+            2,
+            5,
+            2,
+            instances.view(),
+            XorShiftRng::seed_from_u64(1),
+        );
+        assert_eq!(kmeans.subquantizers(), pq.subquantizers());
+    }
+
+    #[test]
+    fn train_with_initializations() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> = Array2::random_using((64, 6), Uniform::new(-1., 1.), &mut rng);
+
+        // Quantile initialization does not depend on the RNG.
+        let trainer = KMeansTrainer::new().with_initialization(CentroidInitialization::Quantiles);
+        let train = |seed| {
+            PQ::train_pq_with_trainer_using(
+                &trainer,
+                3,
+                2,
+                5,
+                1,
+                instances.view(),
+                XorShiftRng::seed_from_u64(seed),
+            )
+        };
+        assert_eq!(train(1), train(2));
+
+        // Bisecting initialization works with fewer distinct instances
+        // than centroids.
+        let duplicated = Array2::from_shape_fn((64, 6), |(row, col)| instances[(row % 2, col)]);
+        let trainer =
+            KMeansTrainer::new().with_initialization(CentroidInitialization::Bisecting(5));
+        let pq = PQ::train_pq_with_trainer_using(&trainer, 3, 2, 5, 1, duplicated.view(), &mut rng);
+        let reconstructions = pq.reconstruct_batch(pq.quantize_batch::<u8, _>(duplicated.view()));
+        assert_abs_diff_eq!(reconstructions, duplicated, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn watchdog_reports_non_finite_instances() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((64, 6), Uniform::new(-1., 1.), &mut rng);
+        instances[(7, 3)] = f32::NAN;
+
+        let trainer = KMeansTrainer::new().with_watchdog(TrainingWatchdog::new().with_reseeds(2));
+        let result =
+            PQ::try_train_pq_with_trainer_using(&trainer, 3, 2, 5, 1, instances.view(), &mut rng);
+        match result {
+            Err(Error::TrainingDiverged {
+                subquantizer,
+                iteration,
+                reason,
+            }) => {
+                assert_eq!(subquantizer, 1);
+                assert_eq!(iteration, 0);
+                assert_eq!(reason, "instance 7 has a non-finite value");
+            }
+            _ => panic!("Training with NaN instances should fail"),
+        }
+    }
+}
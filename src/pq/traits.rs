@@ -1,4 +1,4 @@
-use ndarray::{Array1, Array2, ArrayBase, ArrayViewMut2, Data, Ix1, Ix2};
+use ndarray::{Array1, Array2, ArrayBase, ArrayViewMut2, Axis, Data, Ix1, Ix2};
 use num_traits::{AsPrimitive, Bounded, Zero};
 use rand::{RngCore, SeedableRng};
 use rand_xorshift::XorShiftRng;
@@ -17,6 +17,12 @@ pub trait TrainPQ<A> {
     /// centroids.  The subquantizers are trained with `n_iterations`
     /// k-means iterations. Each subquantizer is trained `n_attempts`
     /// times, where the best clustering is used.
+    ///
+    /// At most 32 bits per subquantizer are supported. Codes of
+    /// subquantizers with more than 8 bits should be stored in a wider
+    /// integer type, e.g. `u16` for 16-bit subquantizers. See
+    /// `PQ::training_memory_estimate` for the memory use of training
+    /// large codebooks.
     fn train_pq<S>(
         n_subquantizers: usize,
         n_subquantizer_bits: u32,
@@ -37,6 +43,37 @@ pub trait TrainPQ<A> {
         )
     }
 
+    /// Train a product quantizer with the xorshift PRNG and a seed.
+    ///
+    /// This method is equivalent to `train_pq`, but seeds the PRNG
+    /// with `seed`. The seed is recorded in the training manifest.
+    fn train_pq_with_seed<S>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        seed: u64,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+    {
+        let mut pq = Self::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            XorShiftRng::seed_from_u64(seed),
+        );
+
+        if let Some(manifest) = pq.manifest.as_mut() {
+            manifest.seed = Some(seed);
+        }
+
+        pq
+    }
+
     /// Train a product quantizer.
     ///
     /// Train a product quantizer with `n_subquantizers` subquantizers
@@ -46,7 +83,9 @@ pub trait TrainPQ<A> {
     /// times, where the best clustering is used.
     ///
     /// `rng` is used for picking the initial cluster centroids of
-    /// each subquantizer.
+    /// each subquantizer. Any RNG can be used, including trait objects
+    /// such as `&mut dyn RngCore`. Training is deterministic given the
+    /// state of `rng`.
     fn train_pq_using<S, R>(
         n_subquantizers: usize,
         n_subquantizer_bits: u32,
@@ -57,12 +96,92 @@ pub trait TrainPQ<A> {
     ) -> PQ<A>
     where
         S: Sync + Data<Elem = A>,
-        R: RngCore + SeedableRng + Send;
+        R: RngCore;
+
+    /// Train a product quantizer on instances along an axis.
+    ///
+    /// This method is equivalent to `train_pq_using`, but trains on
+    /// the instances along `instance_axis`. With `Axis(1)`, the
+    /// instances are the columns of the matrix, e.g. when the data
+    /// comes from a column-oriented store. The instances are not copied
+    /// to a row-major layout.
+    fn train_pq_axis_using<S, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        instance_axis: Axis,
+        rng: R,
+    ) -> PQ<A>
+    where
+        A: Sync,
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        let instances = match instance_axis {
+            Axis(0) => instances.view(),
+            Axis(1) => instances.view().reversed_axes(),
+            _ => panic!(
+                "Instance axis should be 0 or 1, was: {}",
+                instance_axis.index()
+            ),
+        };
+
+        Self::train_pq_using(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            rng,
+        )
+    }
+}
+
+/// Training trait for product quantizers with instance weights.
+///
+/// `OPQ` does not implement this trait, since its rotation updates do
+/// not support weights.
+pub trait TrainWeightedPQ<A>: TrainPQ<A> {
+    /// Train a product quantizer with instance weights.
+    ///
+    /// The codebook of every subquantizer is trained with weighted
+    /// k-means (see `WeightedKMeansTrainer`), which minimizes the
+    /// weighted squared error *Σ_i w_i |x_i - c(x_i)|²*. Instances with
+    /// a large weight, such as the embeddings of frequent words, are
+    /// then reconstructed more accurately. Each subquantizer is trained
+    /// `n_attempts` times, the clustering with the smallest weighted
+    /// error is used.
+    ///
+    /// `weights` contains one finite, non-negative weight per instance.
+    /// At least 2^`n_subquantizer_bits` instances must have a positive
+    /// weight.
+    ///
+    /// See `TrainPQ::train_pq_using` for a description of the other
+    /// arguments.
+    #[allow(clippy::too_many_arguments)]
+    fn train_pq_weighted_using<S, W, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        weights: ArrayBase<W, Ix1>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        W: Data<Elem = A>,
+        R: RngCore;
 }
 
 /// Vector quantization.
 pub trait QuantizeVector<A> {
     /// Quantize a batch of vectors.
+    ///
+    /// Quantizing a batch without rows results in a matrix without
+    /// rows, regardless of the number of columns of the batch.
     fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
     where
         I: AsPrimitive<usize> + Bounded + Zero,
@@ -83,6 +202,27 @@ pub trait QuantizeVector<A> {
         S: Data<Elem = A>,
         usize: AsPrimitive<I>;
 
+    /// Quantize a batch of vectors along an axis.
+    ///
+    /// Quantizes the vectors along `instance_axis` of `x`. With
+    /// `Axis(1)`, the vectors are the columns of `x`. The quantized
+    /// vectors are always the rows of the returned matrix.
+    fn quantize_batch_axis<I, S>(&self, x: ArrayBase<S, Ix2>, instance_axis: Axis) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        match instance_axis {
+            Axis(0) => self.quantize_batch(x),
+            Axis(1) => self.quantize_batch(x.reversed_axes()),
+            _ => panic!(
+                "Instance axis should be 0 or 1, was: {}",
+                instance_axis.index()
+            ),
+        }
+    }
+
     /// Get the length of a vector after quantization.
     fn quantized_len(&self) -> usize;
 }
@@ -92,6 +232,8 @@ pub trait ReconstructVector<A> {
     /// Reconstruct a batch of vectors.
     ///
     /// The vectors are reconstructed from the quantization indices.
+    /// Reconstructing a batch without rows results in a matrix without
+    /// rows.
     fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
     where
         I: AsPrimitive<usize>,
@@ -118,4 +260,18 @@ pub trait ReconstructVector<A> {
 
     /// Get the length of a vector after reconstruction.
     fn reconstructed_len(&self) -> usize;
+
+    /// Normalize a batch of vectors.
+    ///
+    /// Quantizers with an input normalization (see
+    /// `PQ::with_normalization`) reconstruct the normalized vectors, so
+    /// reconstructions should be compared to the vectors returned by
+    /// this method. The default implementation copies the vectors.
+    fn normalize_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        A: Clone,
+        S: Data<Elem = A>,
+    {
+        x.to_owned()
+    }
 }
@@ -0,0 +1,696 @@
+use std::collections::BTreeMap;
+use std::iter::Sum;
+
+use ndarray::{
+    Array1, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, Axis, CowArray, Data,
+    Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use super::primitives;
+use super::{Normalization, QuantizeVector, ReconstructVector, PQ};
+use crate::error::Error;
+use crate::parallel::BatchParallelism;
+
+/// Product quantizer view.
+///
+/// This is a product quantizer that borrows its projection matrix and
+/// subquantizers, for instance from a memory-mapped file or from an
+/// owned `PQ`. This makes it possible to share the codebooks of one
+/// quantizer between many objects without copying.
+///
+/// A view of a `PQ` also borrows its input normalization. All methods
+/// that quantize vectors or compute distances to queries normalize
+/// their inputs before projecting them.
+#[derive(Clone, Copy, Debug)]
+pub struct PQView<'a, A> {
+    pub(crate) projection: Option<ArrayView2<'a, A>>,
+    pub(crate) quantizers: ArrayView3<'a, A>,
+    pub(crate) normalization: Option<&'a Normalization<A>>,
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat,
+{
+    /// Construct a product quantizer view.
+    ///
+    /// `quantizers` has the shape *(n_subquantizers, n_centroids,
+    /// subquantizer_dims)*. If a `projection` is given, it must be a
+    /// square matrix with the size of the reconstructed vectors.
+    pub fn new(projection: Option<ArrayView2<'a, A>>, quantizers: ArrayView3<'a, A>) -> Self {
+        assert!(
+            !quantizers.is_empty(),
+            "Attempted to construct a product quantizer without quantizers."
+        );
+
+        let reconstructed_len = primitives::reconstructed_len(quantizers);
+
+        if let Some(projection) = projection {
+            assert_eq!(
+                projection.shape(),
+                [reconstructed_len; 2],
+                "Incorrect projection matrix shape, was: {:?}, should be [{}, {}]",
+                projection.shape(),
+                reconstructed_len,
+                reconstructed_len
+            );
+        }
+
+        PQView {
+            projection,
+            quantizers,
+            normalization: None,
+        }
+    }
+
+    /// Set the input normalization of the quantizer.
+    ///
+    /// See `PQ::with_normalization`.
+    pub fn with_normalization(mut self, normalization: &'a Normalization<A>) -> Self {
+        if let Normalization::MeanCentering(mean) = normalization {
+            assert_eq!(
+                mean.len(),
+                primitives::reconstructed_len(self.quantizers),
+                "Mean and quantizer length mismatch"
+            );
+        }
+
+        self.normalization = Some(normalization);
+        self
+    }
+
+    /// Get the input normalization of the quantizer.
+    pub fn normalization(&self) -> &'a Normalization<A> {
+        self.normalization.unwrap_or(&Normalization::None)
+    }
+
+    /// Get the number of centroids per quantizer.
+    pub fn n_quantizer_centroids(&self) -> usize {
+        self.quantizers.len_of(Axis(1))
+    }
+
+    /// Get the projection matrix (if used).
+    pub fn projection(&self) -> Option<ArrayView2<'a, A>> {
+        self.projection
+    }
+
+    /// Get the subquantizer centroids.
+    pub fn subquantizers(&self) -> ArrayView3<'a, A> {
+        self.quantizers
+    }
+
+    /// Copy the borrowed codebooks into an owned product quantizer.
+    pub fn to_owned(&self) -> PQ<A> {
+        PQ {
+            projection: self.projection.map(|p| p.to_owned()),
+            quantizers: self.quantizers.to_owned(),
+            manifest: None,
+            polysemous: None,
+            normalization: self.normalization().clone(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Get this quantizer in rotated space.
+    ///
+    /// The returned view quantizes vectors that were already normalized
+    /// and rotated with `rotate_batch` or `rotate_vector` and does not
+    /// project reconstructions back. Since the projection of an optimized
+    /// product quantizer is orthogonal, distances between rotated
+    /// queries and rotated reconstructions are the same as distances
+    /// in the original space. Rotating queries is much cheaper than
+    /// back-projecting every reconstruction in distance computations.
+    ///
+    /// Without a projection, this is the quantizer itself.
+    pub fn rotated(&self) -> PQView<'a, A> {
+        PQView {
+            projection: None,
+            quantizers: self.quantizers,
+            normalization: None,
+        }
+    }
+
+    /// Normalize and rotate a batch of vectors.
+    ///
+    /// The vectors are normalized with the input normalization and then
+    /// rotated with the projection matrix. Without normalization and
+    /// projection, the vectors are copied.
+    pub fn rotate_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = A>,
+    {
+        let x = self.normalize(x.view());
+        match self.projection {
+            Some(projection) => x.dot(&projection),
+            None => x.into_owned(),
+        }
+    }
+
+    /// Normalize and rotate a vector.
+    ///
+    /// See `PQView::rotate_batch`.
+    pub fn rotate_vector<S>(&self, x: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        S: Data<Elem = A>,
+    {
+        self.rotate_batch(x.insert_axis(Axis(0)))
+            .index_axis_move(Axis(0), 0)
+    }
+
+    /// Check whether the quantizer normalizes its inputs.
+    pub(crate) fn normalizes(&self) -> bool {
+        match self.normalization {
+            None | Some(Normalization::None) => false,
+            Some(_) => true,
+        }
+    }
+
+    /// Normalize a batch of vectors, borrowing them when the quantizer
+    /// does not normalize.
+    pub(crate) fn normalize<'b>(&self, x: ArrayView2<'b, A>) -> CowArray<'b, A, Ix2> {
+        match self.normalization {
+            Some(normalization) if self.normalizes() => {
+                CowArray::from(normalization.normalize_batch(x))
+            }
+            _ => CowArray::from(x),
+        }
+    }
+}
+
+impl<'a, A> PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    /// Compute asymmetric distance tables for a batch of queries.
+    ///
+    /// Returns an array with shape *(n_queries, n_subquantizers,
+    /// n_centroids)*, where *(i, j, k)* is the squared Euclidean
+    /// distance between the *j*-th slice of (projected) query *i* and
+    /// centroid *k* of subquantizer *j*. The squared distance between a
+    /// query and a quantized vector is approximated by summing the
+    /// table entries of the vector's codes.
+    pub fn adc_tables_batch<S>(&self, queries: ArrayBase<S, Ix2>) -> Array3<A>
+    where
+        S: Data<Elem = A>,
+    {
+        let queries = self.normalize(queries.view());
+        match self.projection {
+            Some(projection) => {
+                primitives::adc_tables_batch(self.quantizers, queries.dot(&projection))
+            }
+            None => primitives::adc_tables_batch(self.quantizers, queries),
+        }
+    }
+
+    /// Expand quantization codes into centroid embeddings.
+    ///
+    /// Each row of codes is replaced by the concatenation of the
+    /// corresponding centroids. Unlike `reconstruct_batch`, the result
+    /// is not projected back, so for OPQ the embeddings are in rotated
+    /// space. This is cheaper than reconstruction when the embeddings
+    /// are used as features for a downstream model.
+    pub fn centroid_embeddings_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut embeddings = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        primitives::reconstruct_batch_into(self.quantizers, quantized, embeddings.view_mut());
+        embeddings
+    }
+
+    /// Expand quantization codes into one-hot features.
+    ///
+    /// Returns an array with shape *(n_vectors, n_subquantizers *
+    /// n_centroids)*. The features of subquantizer *j* occupy the
+    /// columns *j * n_centroids..(j + 1) * n_centroids*, where the
+    /// column of the vector's code is one and all other columns are
+    /// zero.
+    pub fn one_hot_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.quantized_len(),
+            "Quantization codes have incorrect length"
+        );
+
+        let n_centroids = self.n_quantizer_centroids();
+        let mut one_hot = Array2::zeros((quantized.nrows(), quantized.ncols() * n_centroids));
+        for (codes, mut features) in quantized.outer_iter().zip(one_hot.outer_iter_mut()) {
+            for (sq, code) in codes.iter().enumerate() {
+                let code = code.as_();
+                assert!(
+                    code < n_centroids,
+                    "Code {} of subquantizer {} is out of range",
+                    code,
+                    sq
+                );
+                features[sq * n_centroids + code] = A::one();
+            }
+        }
+
+        one_hot
+    }
+
+    /// Quantize a batch of int8 vectors.
+    ///
+    /// This method quantizes vectors that were quantized to int8, for
+    /// instance by a quantized neural network. The int8 values are
+    /// dequantized as *scale * (x - zero_point)*. Without a projection
+    /// and normalization, dequantization is done per subspace, so that
+    /// the batch is never dequantized as a whole.
+    pub fn quantize_batch_i8<I, S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        scale: A,
+        zero_point: i8,
+    ) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = i8>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+
+        if (self.projection.is_some() || self.normalizes()) && x.nrows() != 0 {
+            let rx = self.rotate_batch(primitives::dequantize_i8(x, scale, zero_point));
+            primitives::quantize_batch_into(self.quantizers, rx, quantized.view_mut());
+        } else {
+            primitives::quantize_batch_i8_into(
+                self.quantizers,
+                x,
+                scale,
+                zero_point,
+                quantized.view_mut(),
+            );
+        }
+
+        quantized
+    }
+
+    /// Quantize a batch of vectors into an existing matrix.
+    ///
+    /// `parallelism` decides whether the vectors are quantized in
+    /// parallel. `quantize_batch_into` uses `BatchParallelism::default`,
+    /// which only parallelizes batches with enough work.
+    pub fn quantize_batch_parallel_into<I, S>(
+        &self,
+        x: ArrayBase<S, Ix2>,
+        mut quantized: ArrayViewMut2<I>,
+        parallelism: BatchParallelism,
+    ) where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let x = self.normalize(x.view());
+        match self.projection {
+            Some(projection) if x.nrows() != 0 => {
+                let rx = x.dot(&projection);
+                primitives::quantize_batch_parallel_into(
+                    self.quantizers,
+                    rx,
+                    quantized.view_mut(),
+                    parallelism,
+                );
+            }
+            _ => {
+                primitives::quantize_batch_parallel_into(
+                    self.quantizers,
+                    x,
+                    quantized.view_mut(),
+                    parallelism,
+                );
+            }
+        }
+    }
+
+    /// Reconstruct a batch of vectors into an existing matrix.
+    ///
+    /// `parallelism` decides whether the vectors are reconstructed in
+    /// parallel. `reconstruct_batch_into` uses
+    /// `BatchParallelism::default`.
+    pub fn reconstruct_batch_parallel_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+        parallelism: BatchParallelism,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        primitives::reconstruct_batch_parallel_into(
+            self.quantizers,
+            quantized,
+            reconstructions.view_mut(),
+            parallelism,
+        );
+
+        if let Some(projection) = self.projection {
+            let projected_reconstruction = reconstructions.dot(&projection.t());
+            reconstructions.assign(&projected_reconstruction);
+        }
+    }
+
+    /// Reconstruct a batch of vectors, checking the quantization codes.
+    ///
+    /// Returns an error when a code does not refer to a centroid of its
+    /// subquantizer.
+    pub fn try_reconstruct_batch<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+    ) -> Result<Array2<A>, Error>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        for codes in quantized.outer_iter() {
+            primitives::check_codes(self.quantizers, codes)?;
+        }
+
+        Ok(self.reconstruct_batch(quantized))
+    }
+
+    /// Reconstruct a vector, checking the quantization codes.
+    ///
+    /// Returns an error when a code does not refer to a centroid of its
+    /// subquantizer.
+    pub fn try_reconstruct_vector<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix1>,
+    ) -> Result<Array1<A>, Error>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        primitives::check_codes(self.quantizers, quantized.view())?;
+        Ok(self.reconstruct_vector(quantized))
+    }
+}
+
+impl<'a, A> QuantizeVector<A> for PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        self.quantize_batch_parallel_into(x, quantized, BatchParallelism::default())
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        if self.normalizes() {
+            return primitives::quantize(
+                self.quantizers,
+                self.reconstructed_len(),
+                self.rotate_vector(x),
+            );
+        }
+
+        match self.projection {
+            Some(projection) => {
+                let rx = x.dot(&projection);
+                primitives::quantize(self.quantizers, self.reconstructed_len(), rx)
+            }
+            None => primitives::quantize(self.quantizers, self.reconstructed_len(), x),
+        }
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.quantizers.len_of(Axis(0))
+    }
+}
+
+impl<'a, A> ReconstructVector<A> for PQView<'a, A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        self.reconstruct_batch_parallel_into(
+            quantized,
+            reconstructions,
+            BatchParallelism::default(),
+        )
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let reconstruction = primitives::reconstruct(self.quantizers, quantized);
+        match self.projection {
+            Some(projection) => reconstruction.dot(&projection.t()),
+            None => reconstruction,
+        }
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        primitives::reconstructed_len(self.quantizers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, s, Array2, Axis};
+
+    use super::PQView;
+    use crate::error::Error;
+    use crate::parallel::BatchParallelism;
+    use crate::pq::{test_pq, QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn view_shares_codebooks() {
+        let pq = test_pq();
+        let view = pq.view();
+        assert_eq!(
+            view.subquantizers().as_ptr(),
+            pq.subquantizers().as_ptr(),
+            "View should borrow the codebooks"
+        );
+        assert_eq!(view.to_owned(), pq);
+    }
+
+    #[test]
+    fn view_quantizes_like_owned() {
+        let pq = test_pq();
+        let instances: Array2<f32> = array![
+            [0., 2., 0., -0.5, 0., 0.],
+            [1., -0.2, 0., 0.5, 0.5, 0.],
+            [-0.2, 0.2, 0., 0., -2., 0.],
+        ];
+
+        let view = PQView::new(None, pq.subquantizers());
+        let quantized = view.quantize_batch::<u8, _>(instances.view());
+        assert_eq!(quantized, pq.quantize_batch::<u8, _>(instances.view()));
+        assert_eq!(
+            view.reconstruct_batch(quantized.view()),
+            pq.reconstruct_batch(quantized.view())
+        );
+    }
+
+    #[test]
+    fn parallel_kernels_match_serial() {
+        let pq = test_pq();
+        let instances = Array2::from_shape_fn((37, 6), |(i, j)| ((i * 7 + j * 3) % 11) as f32 - 5.);
+
+        let mut serial = Array2::<u8>::zeros((37, 2));
+        let mut parallel = Array2::<u8>::zeros((37, 2));
+        pq.view().quantize_batch_parallel_into(
+            instances.view(),
+            serial.view_mut(),
+            BatchParallelism::serial(),
+        );
+        pq.view().quantize_batch_parallel_into(
+            instances.view(),
+            parallel.view_mut(),
+            BatchParallelism::parallel(),
+        );
+        assert_eq!(serial, parallel);
+
+        let mut serial_reconstructions = Array2::zeros((37, 6));
+        let mut parallel_reconstructions = Array2::zeros((37, 6));
+        pq.view().reconstruct_batch_parallel_into(
+            serial.view(),
+            serial_reconstructions.view_mut(),
+            BatchParallelism::serial(),
+        );
+        pq.view().reconstruct_batch_parallel_into(
+            serial.view(),
+            parallel_reconstructions.view_mut(),
+            BatchParallelism::parallel(),
+        );
+        assert_eq!(serial_reconstructions, parallel_reconstructions);
+        assert_eq!(serial_reconstructions, pq.reconstruct_batch(serial.view()));
+    }
+
+    #[test]
+    fn adc_tables_batch_distances() {
+        let pq = test_pq();
+        let queries: Array2<f32> = array![[0., 2., 0., -0.5, 0., 0.], [1., -0.2, 0., 0.5, 0.5, 0.]];
+        let tables = pq.view().adc_tables_batch(queries.view());
+        assert_eq!(tables.shape(), &[2, 2, 2]);
+
+        for (query, query_tables) in queries.outer_iter().zip(tables.outer_iter()) {
+            for (sq, sq_table) in query_tables.outer_iter().enumerate() {
+                let sub_query = query.slice(s![sq * 3..(sq + 1) * 3]);
+                for (centroid, &dist) in pq
+                    .subquantizers()
+                    .index_axis(Axis(0), sq)
+                    .outer_iter()
+                    .zip(sq_table)
+                {
+                    let diff = &sub_query - &centroid;
+                    assert!((diff.dot(&diff) - dist).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn try_reconstruct_rejects_out_of_range_codes() {
+        let pq = test_pq();
+        let view = pq.view();
+
+        assert_eq!(
+            view.try_reconstruct_vector(array![1u8, 0].view()),
+            Ok(array![0., 1., 0., 1., -1., 0.])
+        );
+        assert_eq!(
+            view.try_reconstruct_vector(array![1u8, 2].view()),
+            Err(Error::CodeOutOfRange {
+                subquantizer: 1,
+                code: 2,
+                n_centroids: 2
+            })
+        );
+        assert_eq!(
+            view.try_reconstruct_batch(array![[0u8, 1], [3, 0]].view()),
+            Err(Error::CodeOutOfRange {
+                subquantizer: 0,
+                code: 3,
+                n_centroids: 2
+            })
+        );
+    }
+
+    #[test]
+    fn expand_codes_to_features() {
+        let pq = test_pq();
+        let view = pq.view();
+        let quantized = array![[1u8, 0], [0, 1]];
+
+        assert_eq!(
+            view.one_hot_batch(quantized.view()),
+            array![[0., 1., 1., 0.], [1., 0., 0., 1.]]
+        );
+        assert_eq!(
+            view.centroid_embeddings_batch(quantized.view()),
+            pq.reconstruct_batch(quantized.view())
+        );
+    }
+
+    #[test]
+    fn quantize_i8_like_dequantized() {
+        let pq = test_pq();
+        let instances = array![[0i8, 4, 0, -2, 0, 0], [4, -1, 0, 2, 2, 0]];
+        let dequantized = instances.mapv(|v| 0.5 * f32::from(v - 2));
+
+        let view = pq.view();
+        assert_eq!(
+            view.quantize_batch_i8::<u8, _>(instances.view(), 0.5, 2),
+            view.quantize_batch::<u8, _>(dequantized.view())
+        );
+
+        let projection = Array2::eye(6);
+        let view = PQView::new(Some(projection.view()), pq.subquantizers());
+        assert_eq!(
+            view.quantize_batch_i8::<u8, _>(instances.view(), 0.5, 2),
+            view.quantize_batch::<u8, _>(dequantized.view())
+        );
+    }
+
+    #[test]
+    fn rotated_distances_match_original_space() {
+        let pq = test_pq();
+        // Orthogonal projection: rotate every pair of dimensions by 90 degrees.
+        let mut projection = Array2::zeros((6, 6));
+        for i in (0..6).step_by(2) {
+            projection[[i, i + 1]] = 1f32;
+            projection[[i + 1, i]] = -1f32;
+        }
+        let view = PQView::new(Some(projection.view()), pq.subquantizers());
+
+        let queries: Array2<f32> = array![[0., 2., 0., -0.5, 0., 0.], [1., -0.2, 0., 0.5, 0.5, 0.]];
+        let quantized = view.quantize_batch::<u8, _>(queries.view());
+        let rotated = view.rotated();
+        let rotated_queries = view.rotate_batch(queries.view());
+        assert_eq!(
+            rotated.quantize_batch::<u8, _>(rotated_queries.view()),
+            quantized
+        );
+
+        let reconstructions = view.reconstruct_batch(quantized.view());
+        let rotated_reconstructions = rotated.reconstruct_batch(quantized.view());
+        for ((query, rotated_query), (reconstruction, rotated_reconstruction)) in
+            queries.outer_iter().zip(rotated_queries.outer_iter()).zip(
+                reconstructions
+                    .outer_iter()
+                    .zip(rotated_reconstructions.outer_iter()),
+            )
+        {
+            assert_eq!(view.rotate_vector(query), rotated_query);
+            let diff = &query - &reconstruction;
+            let rotated_diff = &rotated_query - &rotated_reconstruction;
+            assert!((diff.dot(&diff) - rotated_diff.dot(&rotated_diff)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn view_rejects_incorrect_projection() {
+        let pq = test_pq();
+        let projection = Array2::eye(3);
+        PQView::new(Some(projection.view()), pq.subquantizers());
+    }
+}
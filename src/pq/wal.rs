@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+
+const ADD_TAG: u8 = 1;
+const REMOVE_TAG: u8 = 2;
+
+/// Record of the code log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogRecord {
+    /// The codes of a vector were added or replaced.
+    Add {
+        /// The identifier of the vector.
+        id: u64,
+
+        /// The codes of the vector.
+        codes: Vec<u8>,
+    },
+
+    /// A vector was removed.
+    Remove {
+        /// The identifier of the vector.
+        id: u64,
+    },
+}
+
+impl LogRecord {
+    fn write_to(&self, mut write: impl Write) -> io::Result<()> {
+        let too_long = |_| io::Error::new(ErrorKind::InvalidInput, "Code log record is too long");
+
+        let mut payload = Vec::new();
+        match self {
+            LogRecord::Add { id, codes } => {
+                payload.push(ADD_TAG);
+                payload.extend_from_slice(&id.to_le_bytes());
+                payload.extend_from_slice(
+                    &u32::try_from(codes.len()).map_err(too_long)?.to_le_bytes(),
+                );
+                payload.extend_from_slice(codes);
+            }
+            LogRecord::Remove { id } => {
+                payload.push(REMOVE_TAG);
+                payload.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+
+        write.write_all(
+            &u32::try_from(payload.len())
+                .map_err(too_long)?
+                .to_le_bytes(),
+        )?;
+        write.write_all(&payload)?;
+        write.write_all(&checksum(&payload).to_le_bytes())
+    }
+
+    /// Read a record and its length in bytes.
+    ///
+    /// Returns `None` at the end of the log and for a record that was
+    /// not completely written, e.g. due to a crash during an append.
+    fn read_from(mut read: impl Read) -> io::Result<Option<(Self, u64)>> {
+        let mut len = [0u8; 4];
+        if !read_exact_or_eof(&mut read, &mut len)? {
+            return Ok(None);
+        }
+
+        // The length of a corrupt record can be arbitrarily large, so
+        // the payload buffer only grows with the data that is read.
+        let len = u64::from(u32::from_le_bytes(len));
+        let mut payload = Vec::new();
+        read.by_ref().take(len).read_to_end(&mut payload)?;
+        let mut record_checksum = [0u8; 4];
+        if payload.len() as u64 != len
+            || !read_exact_or_eof(&mut read, &mut record_checksum)?
+            || u32::from_le_bytes(record_checksum) != checksum(&payload)
+        {
+            return Ok(None);
+        }
+        let record_len = len + 8;
+
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "Invalid code log record");
+        let id = payload
+            .get(1..9)
+            .map(|id| u64::from_le_bytes([id[0], id[1], id[2], id[3], id[4], id[5], id[6], id[7]]))
+            .ok_or_else(invalid)?;
+        match payload.first() {
+            Some(&ADD_TAG) => {
+                let codes_len = payload
+                    .get(9..13)
+                    .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]))
+                    .ok_or_else(invalid)?;
+                let codes = &payload[13..];
+                if u64::from(codes_len) != codes.len() as u64 {
+                    return Err(invalid());
+                }
+
+                Ok(Some((
+                    LogRecord::Add {
+                        id,
+                        codes: codes.to_owned(),
+                    },
+                    record_len,
+                )))
+            }
+            Some(&REMOVE_TAG) => Ok(Some((LogRecord::Remove { id }, record_len))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Storage of a code log.
+///
+/// Besides writing records, the storage of a log must be able to make
+/// the records durable and to discard the invalid tail of a log after
+/// a crash.
+pub trait LogWrite: Write {
+    /// Flush written records to durable storage.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Truncate the log to `len` bytes.
+    ///
+    /// Subsequent writes are appended at `len`.
+    fn truncate_log(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl LogWrite for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_data()
+    }
+
+    fn truncate_log(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)?;
+        self.seek(SeekFrom::Start(len)).map(|_| ())
+    }
+}
+
+/// In-memory logs are not durable.
+impl LogWrite for Vec<u8> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate_log(&mut self, len: u64) -> io::Result<()> {
+        if len < self.len() as u64 {
+            self.truncate(len as usize);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W> LogWrite for BufWriter<W>
+where
+    W: LogWrite,
+{
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().sync()
+    }
+
+    fn truncate_log(&mut self, len: u64) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().truncate_log(len)
+    }
+}
+
+/// Append-only log of code store updates.
+///
+/// Every update is appended to the log before it is applied, so that
+/// an in-process store can be recovered after a crash by replaying the
+/// log with `CodeLog::replay`. Each record has a checksum, a record
+/// that was not completely written is ignored during replay.
+pub struct CodeLog<W> {
+    write: W,
+}
+
+impl<W> CodeLog<W>
+where
+    W: LogWrite,
+{
+    /// Construct a log that appends to `write`.
+    pub fn new(write: W) -> Self {
+        CodeLog { write }
+    }
+
+    /// Append a record and sync it to durable storage.
+    ///
+    /// The record is durable when this method returns successfully.
+    pub fn append(&mut self, record: &LogRecord) -> io::Result<()> {
+        record.write_to(&mut self.write)?;
+        self.write.sync()
+    }
+
+    /// Unwrap the writer of the log.
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+
+    /// Read the records of a log.
+    ///
+    /// Reading stops at the first record that was not completely
+    /// written or that has an incorrect checksum.
+    pub fn replay(read: impl Read) -> io::Result<Vec<LogRecord>> {
+        Self::replay_valid(read).map(|(records, _)| records)
+    }
+
+    /// Read the records of a log and the length of its valid prefix.
+    ///
+    /// The length is the offset of the first byte after the last valid
+    /// record. Anything after that offset is a torn or corrupt tail.
+    pub fn replay_valid(mut read: impl Read) -> io::Result<(Vec<LogRecord>, u64)> {
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        while let Some((record, record_len)) = LogRecord::read_from(&mut read)? {
+            records.push(record);
+            valid_len += record_len;
+        }
+
+        Ok((records, valid_len))
+    }
+}
+
+/// Mutable in-memory store of quantization codes.
+///
+/// The store maps vector identifiers to codes. Updates are optionally
+/// written to a `CodeLog`, so that the store can be recovered after a
+/// crash without re-quantizing all vectors.
+pub struct MutableCodes<W> {
+    codes: BTreeMap<u64, Vec<u8>>,
+    log: Option<CodeLog<W>>,
+}
+
+impl<W> MutableCodes<W>
+where
+    W: LogWrite,
+{
+    /// Construct an empty store without a log.
+    pub fn new() -> Self {
+        MutableCodes {
+            codes: BTreeMap::new(),
+            log: None,
+        }
+    }
+
+    /// Construct an empty store that logs its updates.
+    pub fn with_log(log: CodeLog<W>) -> Self {
+        MutableCodes {
+            codes: BTreeMap::new(),
+            log: Some(log),
+        }
+    }
+
+    /// Recover a store from a log.
+    ///
+    /// The records of `read` are replayed. Subsequent updates are
+    /// appended to `log`, which would typically write to the same file.
+    /// `log` is truncated to the valid prefix of `read`, so that a torn
+    /// or corrupt tail does not hide the records that are appended
+    /// after recovery.
+    pub fn recover(read: impl Read, mut log: CodeLog<W>) -> io::Result<Self> {
+        let (records, valid_len) = CodeLog::<W>::replay_valid(read)?;
+        log.write.truncate_log(valid_len)?;
+
+        let mut store = Self::with_log(log);
+        for record in records {
+            store.apply(record);
+        }
+
+        Ok(store)
+    }
+
+    /// Add or replace the codes of a vector.
+    pub fn add(&mut self, id: u64, codes: Vec<u8>) -> io::Result<()> {
+        self.log_and_apply(LogRecord::Add { id, codes })
+    }
+
+    /// Remove a vector.
+    pub fn remove(&mut self, id: u64) -> io::Result<()> {
+        self.log_and_apply(LogRecord::Remove { id })
+    }
+
+    /// Get the codes of a vector.
+    pub fn get(&self, id: u64) -> Option<&[u8]> {
+        self.codes.get(&id).map(Vec::as_slice)
+    }
+
+    /// Iterate over the identifiers and codes in identifier order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.codes.iter().map(|(&id, codes)| (id, codes.as_slice()))
+    }
+
+    /// Get the number of vectors.
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    /// Check whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    /// Get the log (if used).
+    pub fn log(&self) -> Option<&CodeLog<W>> {
+        self.log.as_ref()
+    }
+
+    fn log_and_apply(&mut self, record: LogRecord) -> io::Result<()> {
+        if let Some(log) = self.log.as_mut() {
+            log.append(&record)?;
+        }
+
+        self.apply(record);
+
+        Ok(())
+    }
+
+    fn apply(&mut self, record: LogRecord) {
+        match record {
+            LogRecord::Add { id, codes } => {
+                self.codes.insert(id, codes);
+            }
+            LogRecord::Remove { id } => {
+                self.codes.remove(&id);
+            }
+        }
+    }
+}
+
+impl<W> Default for MutableCodes<W>
+where
+    W: LogWrite,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a checksum.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Fill `buf`, returning `false` if the reader ends before `buf` is full.
+fn read_exact_or_eof(mut read: impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match read.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{checksum, CodeLog, LogRecord, MutableCodes, ADD_TAG};
+
+    #[test]
+    fn recover_from_log() {
+        let mut store = MutableCodes::with_log(CodeLog::new(Vec::new()));
+        store.add(1, vec![1, 2]).unwrap();
+        store.add(2, vec![3, 4]).unwrap();
+        store.add(1, vec![5, 6]).unwrap();
+        store.remove(2).unwrap();
+        store.add(3, vec![7, 8]).unwrap();
+
+        let log = store.log().unwrap().write.clone();
+        assert_eq!(
+            CodeLog::<Vec<u8>>::replay(log.as_slice()).unwrap()[3],
+            LogRecord::Remove { id: 2 }
+        );
+
+        let recovered = MutableCodes::recover(log.as_slice(), CodeLog::new(Vec::new())).unwrap();
+        assert_eq!(
+            recovered.iter().collect::<Vec<_>>(),
+            vec![(1, &[5u8, 6][..]), (3, &[7, 8][..])]
+        );
+    }
+
+    #[test]
+    fn ignore_torn_record() {
+        let mut store = MutableCodes::with_log(CodeLog::new(Vec::new()));
+        store.add(1, vec![1, 2]).unwrap();
+        let complete_len = store.log().unwrap().write.len();
+        store.add(2, vec![3, 4]).unwrap();
+        let log = store.log().unwrap().write.clone();
+
+        for len in complete_len..log.len() {
+            let recovered = MutableCodes::recover(&log[..len], CodeLog::new(Vec::new())).unwrap();
+            assert_eq!(recovered.len(), 1);
+            assert_eq!(recovered.get(1), Some(&[1u8, 2][..]));
+        }
+
+        // A corrupted record is ignored.
+        let mut corrupted = log.clone();
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 1;
+        let recovered =
+            MutableCodes::recover(corrupted.as_slice(), CodeLog::new(Vec::new())).unwrap();
+        assert_eq!(recovered.get(2), None);
+    }
+
+    #[test]
+    fn recovery_truncates_invalid_tail() {
+        let mut store = MutableCodes::with_log(CodeLog::new(Vec::new()));
+        store.add(1, vec![1, 2]).unwrap();
+        let complete_len = store.log().unwrap().write.len();
+        store.add(2, vec![3, 4]).unwrap();
+
+        // A torn second record.
+        let mut log = store.log().unwrap().write.clone();
+        log.truncate(log.len() - 1);
+        let (records, valid_len) = CodeLog::<Vec<u8>>::replay_valid(log.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(valid_len, complete_len as u64);
+
+        // Updates after recovery are appended after the valid prefix.
+        let mut recovered =
+            MutableCodes::recover(log.as_slice(), CodeLog::new(log.clone())).unwrap();
+        recovered.add(3, vec![5, 6]).unwrap();
+        let log = recovered.log().unwrap().write.clone();
+        assert_eq!(
+            MutableCodes::recover(log.as_slice(), CodeLog::new(Vec::new()))
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![(1, &[1u8, 2][..]), (3, &[5, 6][..])]
+        );
+
+        // A corrupt length prefix does not allocate the claimed length.
+        let mut corrupt = log[..complete_len].to_vec();
+        corrupt.extend_from_slice(&u32::MAX.to_le_bytes());
+        corrupt.push(ADD_TAG);
+        let (records, valid_len) = CodeLog::<Vec<u8>>::replay_valid(corrupt.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(valid_len, complete_len as u64);
+    }
+    #[test]
+    fn reject_inconsistent_code_length() {
+        // A record with a valid checksum that claims three codes.
+        let mut payload = vec![ADD_TAG];
+        payload.extend_from_slice(&1u64.to_le_bytes());
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.extend_from_slice(&[1, 2]);
+        let mut log = (payload.len() as u32).to_le_bytes().to_vec();
+        log.extend_from_slice(&payload);
+        log.extend_from_slice(&checksum(&payload).to_le_bytes());
+
+        assert_eq!(
+            CodeLog::<Vec<u8>>::replay(log.as_slice())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
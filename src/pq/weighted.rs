@@ -0,0 +1,261 @@
+use std::iter::Sum;
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, ArrayView2, Axis, Data, Ix1, Ix2, NdFloat};
+use num_traits::AsPrimitive;
+use rand::seq::index;
+use rand::RngCore;
+
+use super::{SubquantizerConfig, SubquantizerTrainer, TrainWeightedPQ, PQ};
+use crate::error::Error;
+use crate::float_ord::min_by_float_key;
+use crate::kmeans::weighted_kmeans;
+
+/// Weighted k-means subquantizer trainer.
+///
+/// The codebook is trained with weighted k-means, which minimizes the
+/// weighted squared error *Σ_i w_i |x_i - c(x_i)|²*. Initial centroids
+/// are sampled with probabilities that are proportional to the
+/// weights. The codebook of the attempt with the smallest weighted
+/// error is returned.
+///
+/// The trainer has one weight per training instance, so it can be used
+/// with `PQ::train_pq_with_trainer_using`, which passes all training
+/// instances to the trainer in their original order.
+/// `train_subquantizer` panics when training diverges.
+#[derive(Clone, Debug)]
+pub struct WeightedKMeansTrainer<A> {
+    weights: Array1<A>,
+    sampling_weights: Vec<f64>,
+}
+
+impl<A> WeightedKMeansTrainer<A>
+where
+    A: NdFloat,
+{
+    /// Construct a trainer with the given instance weights.
+    ///
+    /// `weights` contains one finite, non-negative weight per instance.
+    pub fn new<S>(weights: ArrayBase<S, Ix1>) -> Self
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(
+            weights.iter().all(|&w| w.is_finite() && w >= A::zero()),
+            "Weights should be finite and non-negative"
+        );
+
+        let sampling_weights = weights
+            .iter()
+            .map(|w| w.to_f64().expect("Cannot convert weight"))
+            .collect();
+
+        WeightedKMeansTrainer {
+            weights: weights.to_owned(),
+            sampling_weights,
+        }
+    }
+
+    /// Get the instance weights.
+    pub fn weights(&self) -> ArrayView1<A> {
+        self.weights.view()
+    }
+}
+
+impl<A> SubquantizerTrainer<A> for WeightedKMeansTrainer<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        rng: &mut dyn RngCore,
+    ) -> Array2<A> {
+        self.try_train_subquantizer(instances, config, rng)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_train_subquantizer(
+        &self,
+        instances: ArrayView2<A>,
+        config: SubquantizerConfig,
+        rng: &mut dyn RngCore,
+    ) -> Result<Array2<A>, Error> {
+        assert!(
+            config.n_attempts > 0,
+            "Cannot train a subquantizer in 0 attempts."
+        );
+        assert_eq!(
+            self.weights.len(),
+            instances.nrows(),
+            "The number of weights should be equal to the number of instances"
+        );
+        assert!(
+            self.weights.iter().filter(|&&w| w > A::zero()).count() >= config.codebook_len,
+            "At least {} instances should have a positive weight",
+            config.codebook_len
+        );
+
+        if let Some((instance, _)) = instances
+            .outer_iter()
+            .enumerate()
+            .find(|(_, instance)| instance.iter().any(|v| !v.is_finite()))
+        {
+            return Err(Error::TrainingDiverged {
+                subquantizer: config.subquantizer_idx,
+                iteration: 0,
+                reason: format!("instance {} has a non-finite value", instance),
+            });
+        }
+
+        let mut attempts = Vec::with_capacity(config.n_attempts);
+        for _ in 0..config.n_attempts {
+            let initial = index::sample_weighted(
+                rng,
+                instances.nrows(),
+                |idx| self.sampling_weights[idx],
+                config.codebook_len,
+            )
+            .expect("Cannot sample initial centroids")
+            .into_vec();
+            let mut centroids = instances.select(Axis(0), &initial);
+            let loss = weighted_kmeans(
+                centroids.view_mut(),
+                instances,
+                self.weights.view(),
+                config.n_iterations,
+            );
+
+            // The loss can only be compared when it is finite.
+            if !loss.is_finite() {
+                return Err(Error::TrainingDiverged {
+                    subquantizer: config.subquantizer_idx,
+                    iteration: config.n_iterations,
+                    reason: format!("the weighted loss is {}", loss),
+                });
+            }
+
+            attempts.push((loss, centroids));
+        }
+
+        let (_, centroids) = min_by_float_key(attempts, |attempt| attempt.0).unwrap();
+
+        Ok(centroids)
+    }
+}
+
+impl<A> TrainWeightedPQ<A> for PQ<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn train_pq_weighted_using<S, W, R>(
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        weights: ArrayBase<W, Ix1>,
+        rng: R,
+    ) -> PQ<A>
+    where
+        S: Sync + Data<Elem = A>,
+        W: Data<Elem = A>,
+        R: RngCore,
+    {
+        assert_eq!(
+            weights.len(),
+            instances.nrows(),
+            "The number of weights should be equal to the number of instances"
+        );
+
+        let mut pq = PQ::train_pq_with_trainer_using(
+            &WeightedKMeansTrainer::new(weights),
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            rng,
+        );
+
+        if let Some(manifest) = pq.manifest.as_mut() {
+            manifest.quantizer = "WeightedPQ";
+        }
+
+        pq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array1, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::WeightedKMeansTrainer;
+    use crate::error::Error;
+    use crate::ndarray_rand::RandomExt;
+    use crate::parallel::NestedParallelism;
+    use crate::pq::{
+        QuantizeVector, ReconstructVector, SubquantizerConfig, SubquantizerTrainer, TrainPQ,
+        TrainWeightedPQ, PQ,
+    };
+
+    #[test]
+    fn weighted_training_favors_heavy_instances() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((1024, 4), Uniform::new(-1., 1.), &mut rng);
+        // Zipfian weights.
+        let weights = Array1::from_shape_fn(1024, |idx| 1. / (idx + 1) as f32);
+
+        // The weighted and unweighted mean squared errors.
+        let errors = |pq: &PQ<f32>| {
+            let quantized: Array2<u8> = pq.quantize_batch(instances.view());
+            let errors = &instances - &pq.reconstruct_batch(quantized);
+            let sq_errors = errors.map_axis(Axis(1), |e| e.dot(&e));
+            (
+                sq_errors.dot(&weights) / weights.sum(),
+                sq_errors.sum() / 1024.,
+            )
+        };
+
+        let pq = PQ::train_pq_using(2, 3, 10, 1, instances.view(), &mut rng);
+        let weighted =
+            PQ::train_pq_weighted_using(2, 3, 10, 2, instances.view(), weights.view(), &mut rng);
+        assert_eq!(weighted.manifest().unwrap().quantizer, "WeightedPQ");
+
+        // Weighted training trades unweighted error for weighted error.
+        let (weighted_mse, mse) = errors(&weighted);
+        let (pq_weighted_mse, pq_mse) = errors(&pq);
+        assert!(weighted_mse < 0.95 * pq_weighted_mse);
+        assert!(mse > pq_mse);
+    }
+    #[test]
+    fn weighted_trainer_rejects_non_finite_loss() {
+        // The sum of the weights overflows, so the loss is NaN.
+        let trainer = WeightedKMeansTrainer::new(array![f32::MAX, f32::MAX]);
+        let config = SubquantizerConfig {
+            subquantizer_idx: 1,
+            n_subquantizers: 2,
+            codebook_len: 1,
+            n_iterations: 0,
+            n_attempts: 2,
+            parallelism: NestedParallelism::for_tasks(2),
+        };
+        let mut rng = XorShiftRng::seed_from_u64(42);
+
+        let instances = array![[0f32], [2.]];
+        assert!(matches!(
+            trainer.try_train_subquantizer(instances.view(), config, &mut rng),
+            Err(Error::TrainingDiverged {
+                subquantizer: 1,
+                iteration: 0,
+                ..
+            })
+        ));
+    }
+}
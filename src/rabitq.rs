@@ -0,0 +1,342 @@
+//! Binary quantization with error correction factors.
+
+use ndarray::{Array1, Array2, ArrayBase, ArrayView1, ArrayView2, Axis, Data, Ix1, Ix2, NdFloat};
+use rand::Rng;
+
+use crate::pq::standard_normal;
+
+/// Binary quantizer with per-vector correction factors (Gao & Long, 2024).
+///
+/// RaBitQ encodes a vector with one bit per dimension. A vector is
+/// first centered on the centroid of the data and normalized. The
+/// normalized residual is then randomly rotated and bit *i* is set when
+/// dimension *i* of the rotated residual is positive. So, the residual
+/// is quantized to the nearest vertex of a randomly rotated hypercube.
+///
+/// Besides the bits, the norm of the residual and a correction factor
+/// are stored for every vector. The correction factor is the inner
+/// product of the normalized residual and its quantized counterpart.
+/// Dividing the inner product of a query and a quantized residual by
+/// this factor gives an unbiased estimate of the inner product of the
+/// query and the residual, from which squared Euclidean distances are
+/// estimated. See `RaBitQQuery`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaBitQ<A> {
+    centroid: Array1<A>,
+    rotation: Array2<A>,
+}
+
+impl<A> RaBitQ<A>
+where
+    A: NdFloat,
+{
+    /// Construct a quantizer from a centroid and a rotation.
+    ///
+    /// The rows of `rotation` must form an orthonormal basis.
+    pub fn new(centroid: Array1<A>, rotation: Array2<A>) -> Self {
+        assert!(
+            !centroid.is_empty(),
+            "The quantizer should have at least one dimension"
+        );
+        assert_eq!(
+            rotation.dim(),
+            (centroid.len(), centroid.len()),
+            "The rotation should be a square matrix of the centroid length"
+        );
+
+        RaBitQ { centroid, rotation }
+    }
+
+    /// Train a quantizer.
+    ///
+    /// The centroid is the mean of `instances`. The rotation is sampled
+    /// with `rng`, so training only requires a pass over the instances.
+    pub fn train_using<S, R>(instances: ArrayBase<S, Ix2>, rng: &mut R) -> Self
+    where
+        S: Data<Elem = A>,
+        R: Rng + ?Sized,
+    {
+        assert!(
+            instances.nrows() > 0,
+            "Cannot train quantizer without instances"
+        );
+
+        let centroid =
+            instances.sum_axis(Axis(0)) / A::from(instances.nrows()).expect("Cannot convert count");
+
+        RaBitQ::new(centroid, random_rotation(instances.ncols(), rng))
+    }
+
+    /// Get the centroid.
+    pub fn centroid(&self) -> ArrayView1<A> {
+        self.centroid.view()
+    }
+
+    /// Get the random rotation.
+    pub fn rotation(&self) -> ArrayView2<A> {
+        self.rotation.view()
+    }
+
+    /// Get the number of dimensions of the vectors.
+    pub fn n_dims(&self) -> usize {
+        self.centroid.len()
+    }
+
+    /// Get the number of bytes of a packed code.
+    pub fn packed_len(&self) -> usize {
+        self.n_dims() / 8 + usize::from(self.n_dims() % 8 != 0)
+    }
+
+    /// Quantize a batch of vectors.
+    pub fn quantize_batch<S>(&self, x: ArrayBase<S, Ix2>) -> RaBitQCodes<A>
+    where
+        S: Data<Elem = A>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.n_dims(),
+            "Quantizer and vector length mismatch"
+        );
+
+        let scale = A::one() / A::from(self.n_dims()).unwrap().sqrt();
+        let rotated = (&x - &self.centroid).dot(&self.rotation.t());
+
+        let mut bits = Array2::zeros((x.nrows(), self.packed_len()));
+        let mut norms = Array1::zeros(x.nrows());
+        let mut corrections = Array1::zeros(x.nrows());
+        for (((rotated, mut bits), norm), correction) in rotated
+            .outer_iter()
+            .zip(bits.outer_iter_mut())
+            .zip(norms.iter_mut())
+            .zip(corrections.iter_mut())
+        {
+            for (idx, &v) in rotated.iter().enumerate() {
+                bits[idx / 8] |= u8::from(v > A::zero()) << (idx % 8);
+            }
+
+            *norm = rotated.dot(&rotated).sqrt();
+            *correction = if *norm > A::zero() {
+                rotated
+                    .iter()
+                    .map(|v| v.abs())
+                    .fold(A::zero(), |a, b| a + b)
+                    * scale
+                    / *norm
+            } else {
+                // The residual is zero, so its inner products are zero
+                // regardless of the correction.
+                A::one()
+            };
+        }
+
+        RaBitQCodes {
+            bits,
+            norms,
+            corrections,
+        }
+    }
+
+    /// Prepare a query for distance estimation.
+    pub fn query<S>(&self, query: ArrayBase<S, Ix1>) -> RaBitQQuery<A>
+    where
+        S: Data<Elem = A>,
+    {
+        assert_eq!(
+            query.len(),
+            self.n_dims(),
+            "Quantizer and query length mismatch"
+        );
+
+        let mut rotated = self.rotation.dot(&(&query - &self.centroid));
+        let norm = rotated.dot(&rotated).sqrt();
+        if norm > A::zero() {
+            rotated /= norm;
+        }
+
+        let scale = A::one() / A::from(self.n_dims()).unwrap().sqrt();
+        let sum = rotated.sum();
+
+        RaBitQQuery {
+            rotated,
+            norm,
+            scale,
+            sum,
+        }
+    }
+}
+
+/// Binary codes with correction factors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaBitQCodes<A> {
+    bits: Array2<u8>,
+    norms: Array1<A>,
+    corrections: Array1<A>,
+}
+
+impl<A> RaBitQCodes<A>
+where
+    A: NdFloat,
+{
+    /// Get the packed bits, one row per vector.
+    ///
+    /// Bit *i* of a code is stored in bit *i % 8* of byte *i / 8*.
+    /// Unused bits of the last byte are zero.
+    pub fn bits(&self) -> ArrayView2<u8> {
+        self.bits.view()
+    }
+
+    /// Get the norms of the residuals.
+    pub fn norms(&self) -> ArrayView1<A> {
+        self.norms.view()
+    }
+
+    /// Get the correction factors.
+    pub fn corrections(&self) -> ArrayView1<A> {
+        self.corrections.view()
+    }
+
+    /// Get the number of vectors.
+    pub fn len(&self) -> usize {
+        self.bits.nrows()
+    }
+
+    /// Check whether there are no vectors.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Query for estimating distances to RaBitQ codes.
+///
+/// The query is not quantized, so estimates are asymmetric.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaBitQQuery<A> {
+    rotated: Array1<A>,
+    norm: A,
+    scale: A,
+    sum: A,
+}
+
+impl<A> RaBitQQuery<A>
+where
+    A: NdFloat,
+{
+    /// Estimate the inner products of the normalized query residual and
+    /// the normalized residuals of the codes.
+    ///
+    /// The estimates are unbiased over the random rotation.
+    pub fn estimate_cosines(&self, codes: &RaBitQCodes<A>) -> Array1<A> {
+        assert_eq!(
+            codes.bits.ncols(),
+            self.rotated.len() / 8 + usize::from(self.rotated.len() % 8 != 0),
+            "Code and query length mismatch"
+        );
+
+        let two = A::from(2).unwrap();
+        codes
+            .bits
+            .outer_iter()
+            .zip(codes.corrections.iter())
+            .map(|(bits, &correction)| {
+                let positive = self
+                    .rotated
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| (bits[idx / 8] >> (idx % 8)) & 1 == 1)
+                    .fold(A::zero(), |sum, (_, &v)| sum + v);
+
+                // The quantized residual has the value 1/sqrt(D) for set
+                // bits and -1/sqrt(D) for unset bits.
+                (two * positive - self.sum) * self.scale / correction
+            })
+            .collect()
+    }
+
+    /// Estimate the squared Euclidean distances of the query to the
+    /// vectors of the codes.
+    pub fn estimate_squared_distances(&self, codes: &RaBitQCodes<A>) -> Array1<A> {
+        let two = A::from(2).unwrap();
+        let mut distances = self.estimate_cosines(codes);
+        for (distance, &norm) in distances.iter_mut().zip(codes.norms.iter()) {
+            *distance = norm * norm + self.norm * self.norm - two * norm * self.norm * *distance;
+        }
+
+        distances
+    }
+}
+
+/// Sample a random rotation.
+///
+/// The rows of a matrix with standard normal entries are
+/// orthonormalized with the modified Gram-Schmidt process.
+fn random_rotation<A, R>(n_dims: usize, rng: &mut R) -> Array2<A>
+where
+    A: NdFloat,
+    R: Rng + ?Sized,
+{
+    let mut rotation = Array2::from_shape_fn((n_dims, n_dims), |_| standard_normal(rng));
+
+    for idx in 0..n_dims {
+        let (done, mut rest) = rotation.view_mut().split_at(Axis(0), idx);
+        let mut row = rest.row_mut(0);
+        for basis in done.outer_iter() {
+            let projection = basis.dot(&row);
+            row.scaled_add(-projection, &basis);
+        }
+        let norm = row.dot(&row).sqrt();
+        row /= norm;
+    }
+
+    rotation.mapv(|v| A::from(v).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array1, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{random_rotation, RaBitQ};
+    use crate::ndarray_rand::RandomExt;
+
+    #[test]
+    fn random_rotation_is_orthonormal() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let rotation = random_rotation::<f64, _>(10, &mut rng);
+        let identity = rotation.dot(&rotation.t());
+        assert!(identity.abs_diff_eq(&Array2::eye(10), 1e-10));
+    }
+
+    #[test]
+    fn distance_estimates_are_unbiased() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f64> =
+            Array2::random_using((512, 64), Uniform::new(-1., 1.), &mut rng);
+        let quantizer = RaBitQ::train_using(instances.view(), &mut rng);
+        let codes = quantizer.quantize_batch(instances.view());
+        assert_eq!(codes.bits().dim(), (512, 8));
+        assert_eq!(codes.len(), 512);
+
+        let mut errors = Vec::new();
+        for (query_idx, query) in instances.outer_iter().enumerate().take(16) {
+            let estimates = quantizer.query(query).estimate_squared_distances(&codes);
+            let diffs = &instances - &query;
+            let distances: Array1<f64> = (&diffs * &diffs).sum_axis(Axis(1));
+            errors.extend(
+                estimates
+                    .iter()
+                    .zip(distances.iter())
+                    .enumerate()
+                    .filter(|&(idx, _)| idx != query_idx)
+                    .map(|(_, (estimate, distance))| (estimate - distance) / distance),
+            );
+        }
+
+        let n_errors = errors.len() as f64;
+        let bias = errors.iter().sum::<f64>() / n_errors;
+        let mean_abs = errors.iter().map(|e| e.abs()).sum::<f64>() / n_errors;
+        assert!(bias.abs() < 0.02, "bias: {}", bias);
+        assert!(mean_abs < 0.15, "mean absolute error: {}", mean_abs);
+    }
+}
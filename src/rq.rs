@@ -0,0 +1,596 @@
+//! Residual quantization.
+
+use std::iter;
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    Array1, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut2, Axis, Data, Ix1, Ix2,
+    NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use crate::float_ord::min_by_float_key;
+use crate::kmeans::{
+    cluster_assignment, cluster_assignments, kmeans_iteration_with_scratch,
+    kmeans_with_centroids_scratch, InitialCentroids, KMeansScratch, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::parallel::NestedParallelism;
+use crate::pq::{QuantizeVector, ReconstructVector};
+
+/// Training trait for residual quantizers.
+///
+/// This trait specifies the training functions for residual
+/// quantizers, analogous to `TrainPQ`.
+pub trait TrainRQ<A> {
+    /// Train a residual quantizer with the xorshift PRNG.
+    ///
+    /// Train a residual quantizer with `n_stages` stages on
+    /// `instances`. Each stage has 2^`n_stage_bits` centroids. The
+    /// stages are trained with `n_iterations` k-means iterations. Each
+    /// stage is trained `n_attempts` times, where the best clustering
+    /// is used.
+    fn train_rq<S>(
+        n_stages: usize,
+        n_stage_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+    ) -> ResidualQuantizer<A>
+    where
+        S: Sync + Data<Elem = A>,
+    {
+        Self::train_rq_using(
+            n_stages,
+            n_stage_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            XorShiftRng::from_entropy(),
+        )
+    }
+
+    /// Train a residual quantizer with the xorshift PRNG and a seed.
+    ///
+    /// This method is equivalent to `train_rq`, but seeds the PRNG
+    /// with `seed`.
+    fn train_rq_with_seed<S>(
+        n_stages: usize,
+        n_stage_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        seed: u64,
+    ) -> ResidualQuantizer<A>
+    where
+        S: Sync + Data<Elem = A>,
+    {
+        Self::train_rq_using(
+            n_stages,
+            n_stage_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            XorShiftRng::seed_from_u64(seed),
+        )
+    }
+
+    /// Train a residual quantizer.
+    ///
+    /// See `train_rq` for a description of the arguments. `rng` is
+    /// used for picking the initial cluster centroids of each stage.
+    /// Training is deterministic given the state of `rng`.
+    fn train_rq_using<S, R>(
+        n_stages: usize,
+        n_stage_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> ResidualQuantizer<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore;
+}
+
+/// Residual quantizer.
+///
+/// A residual quantizer consists of a sequence of codebooks (stages)
+/// over the full vector space. The first stage quantizes a vector, each
+/// subsequent stage quantizes the residual of the previous stages.
+/// Vector reconstruction consists of summing the centroids of all
+/// stages. At the same code length, residual quantization often has a
+/// lower reconstruction error than product quantization, at the cost
+/// of more expensive quantization.
+///
+/// The codebooks are stored in a single contiguous array with shape
+/// *(n_stages, n_centroids, n_dims)*.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResidualQuantizer<A> {
+    codebooks: Array3<A>,
+}
+
+impl<A> ResidualQuantizer<A>
+where
+    A: NdFloat,
+{
+    /// Construct a residual quantizer from its codebooks.
+    pub fn new(codebooks: Array3<A>) -> Self {
+        assert!(
+            codebooks.len_of(Axis(0)) > 0,
+            "A residual quantizer should have at least one stage."
+        );
+        assert!(
+            codebooks.len_of(Axis(1)) > 0,
+            "The stages of a residual quantizer should have at least one centroid."
+        );
+
+        ResidualQuantizer { codebooks }
+    }
+
+    /// Get the codebooks of the stages.
+    pub fn codebooks(&self) -> ArrayView3<A> {
+        self.codebooks.view()
+    }
+
+    /// Get the number of stages.
+    pub fn n_stages(&self) -> usize {
+        self.codebooks.len_of(Axis(0))
+    }
+
+    /// Get the number of centroids per stage.
+    pub fn n_stage_centroids(&self) -> usize {
+        self.codebooks.len_of(Axis(1))
+    }
+
+    /// Compute the residuals of a batch of vectors after quantization.
+    pub fn residuals_batch<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        A: Sum,
+        S: Data<Elem = A>,
+    {
+        let quantized: Array2<usize> = self.quantize_batch(x.view());
+        x.to_owned() - self.reconstruct_batch(quantized)
+    }
+}
+
+impl<A> ResidualQuantizer<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    /// Train a residual quantizer with the given thread allocation.
+    ///
+    /// The stages are trained sequentially, since each stage depends on
+    /// the residuals of the previous stages. Cluster assignment is
+    /// parallelized over at most `parallelism.inner()` tasks.
+    ///
+    /// See `TrainRQ::train_rq_using` for a description of the other
+    /// arguments.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_rq_parallel_using<S, R>(
+        n_stages: usize,
+        n_stage_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        parallelism: NestedParallelism,
+        mut rng: R,
+    ) -> Self
+    where
+        S: Data<Elem = A>,
+        R: RngCore,
+    {
+        assert!(n_stages > 0, "The number of stages should at least be 1.");
+        assert!(
+            n_stage_bits > 0,
+            "Number of quantizer bits should at least be one."
+        );
+        assert!(
+            n_iterations > 0,
+            "The stages should be optimized for at least one iteration."
+        );
+        assert!(
+            n_attempts > 0,
+            "The stages should be optimized for at least one attempt."
+        );
+
+        let codebook_len = 2usize.pow(n_stage_bits);
+        let mut codebooks = Array3::zeros((n_stages, codebook_len, instances.ncols()));
+        let mut residuals = instances.to_owned();
+
+        // Buffers are reused across stages, iterations, and attempts.
+        let mut scratch = KMeansScratch::new().with_parallelism(parallelism);
+
+        for (stage, mut codebook) in codebooks.outer_iter_mut().enumerate() {
+            info!("Training RQ stage {}", stage);
+
+            codebook.assign(&Self::train_stage(
+                codebook_len,
+                n_iterations,
+                n_attempts,
+                residuals.view(),
+                &mut scratch,
+                &mut rng,
+            ));
+
+            let assignments = cluster_assignments(codebook.view(), residuals.view(), Axis(0));
+            for (mut residual, &assignment) in residuals.outer_iter_mut().zip(assignments.iter()) {
+                residual -= &codebook.index_axis(Axis(0), assignment);
+            }
+        }
+
+        ResidualQuantizer { codebooks }
+    }
+
+    /// Refine the codebooks with coordinate descent on training data.
+    ///
+    /// This optional post-training pass encodes the training `instances`
+    /// and then performs `n_sweeps` sweeps over the stages. For every
+    /// stage, the codes of the stage are re-assigned given the codes of
+    /// all other stages, after which the centroids of the stage are refit
+    /// to the mean of their assigned residuals. Unlike product
+    /// quantization, where re-encoding is exact, this improves the codes
+    /// beyond greedy encoding, similar to local search in additive
+    /// quantization.
+    ///
+    /// Returns the mean squared reconstruction error of the refined
+    /// training codes. Since vectors are quantized greedily, the error
+    /// of re-quantized instances can be slightly higher.
+    pub fn refine<S>(&mut self, instances: ArrayBase<S, Ix2>, n_sweeps: usize) -> A
+    where
+        S: Data<Elem = A>,
+    {
+        assert!(n_sweeps > 0, "Refinement should at least use one sweep.");
+        assert!(
+            instances.nrows() > 0,
+            "Cannot refine a quantizer without instances"
+        );
+
+        let mut codes = self.quantize_batch::<usize, _>(instances.view());
+        let mut reconstructions = self.reconstruct_batch(codes.view());
+        let mut scratch = KMeansScratch::new().with_parallelism(NestedParallelism::for_tasks(1));
+
+        for _ in 0..n_sweeps {
+            for (mut codebook, mut stage_codes) in self
+                .codebooks
+                .outer_iter_mut()
+                .zip(codes.axis_iter_mut(Axis(1)))
+            {
+                // Residuals of the instances given all other stages.
+                let mut residuals = &instances - &reconstructions;
+                for (mut residual, &code) in residuals.outer_iter_mut().zip(stage_codes.iter()) {
+                    residual += &codebook.index_axis(Axis(0), code);
+                }
+
+                kmeans_iteration_with_scratch(
+                    residuals.view(),
+                    Axis(0),
+                    codebook.view_mut(),
+                    &mut scratch,
+                );
+
+                for (((mut reconstruction, instance), residual), (code, &assignment)) in
+                    reconstructions
+                        .outer_iter_mut()
+                        .zip(instances.outer_iter())
+                        .zip(residuals.outer_iter())
+                        .zip(stage_codes.iter_mut().zip(scratch.assignments()))
+                {
+                    *code = assignment as usize;
+                    reconstruction.assign(&(&instance - &residual));
+                    reconstruction += &codebook.index_axis(Axis(0), *code);
+                }
+            }
+        }
+
+        let errors = &instances - &reconstructions;
+        errors.iter().map(|&v| v * v).sum::<A>() / instances.nrows().as_()
+    }
+
+    fn train_stage(
+        codebook_len: usize,
+        n_iterations: usize,
+        n_attempts: usize,
+        residuals: ArrayView2<A>,
+        scratch: &mut KMeansScratch<A>,
+        rng: &mut impl RngCore,
+    ) -> Array2<A> {
+        // The residuals only change after the stage is trained.
+        scratch.cache_instance_sqnorms(residuals, Axis(0));
+
+        let attempts = iter::repeat_with(|| {
+            let mut codebook = RandomInstanceCentroids::new(&mut *rng).initial_centroids(
+                residuals,
+                Axis(0),
+                codebook_len,
+            );
+            let loss = kmeans_with_centroids_scratch(
+                residuals,
+                Axis(0),
+                codebook.view_mut(),
+                NIterationsCondition(n_iterations),
+                scratch,
+            );
+            (loss, codebook)
+        })
+        .take(n_attempts);
+
+        let codebook = min_by_float_key(attempts, |attempt| attempt.0).unwrap().1;
+        scratch.clear_instance_sqnorms();
+        codebook
+    }
+}
+
+/// Quantize vectors greedily with a sequence of additive codebooks.
+///
+/// Each codebook quantizes the residuals of the previous codebooks.
+pub(crate) fn greedy_quantize_into<A, I>(
+    codebooks: ArrayView3<A>,
+    x: ArrayView2<A>,
+    mut quantized: ArrayViewMut2<I>,
+) where
+    A: NdFloat + Sum,
+    I: Copy + 'static,
+    usize: AsPrimitive<I>,
+{
+    let mut residuals = x.to_owned();
+    for (codebook, mut codes) in codebooks.outer_iter().zip(quantized.axis_iter_mut(Axis(1))) {
+        let assignments = cluster_assignments(codebook, residuals.view(), Axis(0));
+        for ((mut residual, code), &assignment) in residuals
+            .outer_iter_mut()
+            .zip(codes.iter_mut())
+            .zip(assignments.iter())
+        {
+            residual -= &codebook.index_axis(Axis(0), assignment);
+            *code = assignment.as_();
+        }
+    }
+}
+
+impl<A> TrainRQ<A> for ResidualQuantizer<A>
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    fn train_rq_using<S, R>(
+        n_stages: usize,
+        n_stage_bits: u32,
+        n_iterations: usize,
+        n_attempts: usize,
+        instances: ArrayBase<S, Ix2>,
+        rng: R,
+    ) -> ResidualQuantizer<A>
+    where
+        S: Sync + Data<Elem = A>,
+        R: RngCore,
+    {
+        Self::train_rq_parallel_using(
+            n_stages,
+            n_stage_bits,
+            n_iterations,
+            n_attempts,
+            instances,
+            NestedParallelism::for_tasks(1),
+            rng,
+        )
+    }
+}
+
+impl<A> QuantizeVector<A> for ResidualQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Quantize a batch of vectors.
+    ///
+    /// Each stage assigns the residuals of all vectors to their nearest
+    /// centroids (greedy encoding).
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_stage_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        greedy_quantize_into(self.codebooks.view(), x.view(), quantized);
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.len(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert!(
+            self.n_stage_centroids() - 1 <= I::max_value().as_(),
+            "Cannot store centroids in quantizer index type"
+        );
+
+        let mut residual = x.to_owned();
+        let mut quantized = Array1::zeros(self.quantized_len());
+        for (codebook, code) in self.codebooks.outer_iter().zip(quantized.iter_mut()) {
+            let assignment = cluster_assignment(codebook, residual.view());
+            residual -= &codebook.index_axis(Axis(0), assignment);
+            *code = assignment.as_();
+        }
+
+        quantized
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_stages()
+    }
+}
+
+impl<A> ReconstructVector<A> for ResidualQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            reconstruction.assign(&self.reconstruct_vector(codes));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.len(),
+            self.quantized_len(),
+            "Quantization length does not match number of stages"
+        );
+
+        let mut reconstruction = Array1::zeros(self.reconstructed_len());
+        for (codebook, &code) in self.codebooks.outer_iter().zip(quantized.iter()) {
+            reconstruction += &codebook.index_axis(Axis(0), code.as_());
+        }
+
+        reconstruction
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.codebooks.len_of(Axis(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{ResidualQuantizer, TrainRQ};
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ, PQ};
+
+    fn mean_squared_error<Q>(quantizer: &Q, instances: &Array2<f32>) -> f32
+    where
+        Q: QuantizeVector<f32> + ReconstructVector<f32>,
+    {
+        let quantized: Array2<u8> = quantizer.quantize_batch(instances.view());
+        let errors = instances - &quantizer.reconstruct_batch(quantized);
+        errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+    }
+
+    #[test]
+    fn quantize_and_reconstruct() {
+        let rq = ResidualQuantizer::new(array![
+            [[1., 0.], [0., 1.]],
+            [[0.5, 0.], [0., 0.]],
+            [[0., 0.], [0., -0.25]]
+        ]);
+
+        let quantized: Array2<u8> = rq.quantize_batch(array![[1.5, -0.25], [0., 1.]]);
+        assert_eq!(quantized, array![[0, 0, 1], [1, 1, 0]]);
+        assert_eq!(
+            rq.quantize_vector::<u8, _>(array![1.5f32, -0.25]),
+            array![0, 0, 1]
+        );
+        assert_eq!(
+            rq.reconstruct_batch(quantized),
+            array![[1.5, -0.25], [0., 1.]]
+        );
+        assert_eq!(rq.residuals_batch(array![[1.5, 0.], [0., 1.]]).sum(), 0.);
+    }
+
+    #[test]
+    fn residual_stages_reduce_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(0., 1.), &mut rng);
+
+        let rq = ResidualQuantizer::train_rq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        assert_eq!(rq.n_stages(), 4);
+        assert_eq!(rq.n_stage_centroids(), 16);
+
+        let mut prev_mse = f32::INFINITY;
+        for n_stages in 1..=4 {
+            let prefix = ResidualQuantizer::new(
+                rq.codebooks()
+                    .slice(ndarray::s![..n_stages, .., ..])
+                    .to_owned(),
+            );
+            let mse = mean_squared_error(&prefix, &instances);
+            assert!(mse < prev_mse);
+            prev_mse = mse;
+        }
+
+        // Residual quantization should be more accurate than product
+        // quantization with the same code length.
+        let pq = PQ::train_pq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        assert!(prev_mse < mean_squared_error(&pq, &instances));
+    }
+
+    #[test]
+    fn refine_reduces_training_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(0., 1.), &mut rng);
+
+        let mut rq = ResidualQuantizer::train_rq_using(4, 4, 10, 1, instances.view(), &mut rng);
+        let mse = mean_squared_error(&rq, &instances);
+        assert!(rq.refine(instances.view(), 3) < mse);
+    }
+}
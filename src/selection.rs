@@ -0,0 +1,915 @@
+//! Utilities for choosing quantizer hyperparameters.
+
+use std::iter::Sum;
+#[cfg(feature = "opq-train")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "opq-train")]
+use lax::Lapack;
+#[cfg(feature = "opq-train")]
+use log::info;
+
+use ndarray::{s, Array2, ArrayBase, ArrayView2, Axis, Data, Ix2, NdFloat};
+#[cfg(feature = "opq-train")]
+use ndarray_linalg::types::Scalar;
+use num_traits::{AsPrimitive, FromPrimitive};
+use rand::seq::index;
+use rand::Rng;
+#[cfg(feature = "opq-train")]
+use rand::SeedableRng;
+#[cfg(feature = "opq-train")]
+use rand_xorshift::XorShiftRng;
+use rayon::prelude::*;
+
+use crate::kmeans::{KMeans, NIterationsCondition, RandomInstanceCentroids};
+use crate::linalg::Covariance;
+use crate::metrics::rand_index;
+#[cfg(feature = "opq-train")]
+use crate::pq::{GaussianOPQ, OPQ, PQ};
+use crate::pq::{QuantizeVector, ReconstructVector, TrainPQ};
+use crate::split::k_fold_splits;
+#[cfg(feature = "opq-train")]
+use crate::split::train_validation_split;
+
+/// The number of k-means attempts per loss of `inertia_curve` and
+/// `gap_statistic`.
+///
+/// A single k-means run from random instances often ends in a local
+/// minimum where two centroids share a cluster, which distorts the
+/// loss curve.
+const KMEANS_ATTEMPTS: usize = 10;
+
+/// Gap statistic for a number of clusters (Tibshirani et al., 2001).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gap<A> {
+    /// The number of clusters.
+    pub k: usize,
+
+    /// The mean squared error of the clustering of the data.
+    pub loss: A,
+
+    /// The gap between the expected log-loss on reference data and
+    /// the log-loss on the data.
+    pub gap: A,
+
+    /// The standard error of the expected log-loss on reference data.
+    pub std_err: A,
+}
+
+/// Quantizer hyperparameters.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PQConfig {
+    /// The number of subquantizers.
+    pub n_subquantizers: usize,
+
+    /// The number of bits per subquantizer.
+    pub n_subquantizer_bits: u32,
+
+    /// The number of training iterations.
+    pub n_iterations: usize,
+
+    /// The number of training attempts per subquantizer.
+    pub n_attempts: usize,
+}
+
+/// Cross-validation result of a quantizer configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrossValidation<A> {
+    /// The evaluated configuration.
+    pub config: PQConfig,
+
+    /// The mean squared reconstruction error on each held-out fold.
+    pub fold_losses: Vec<A>,
+
+    /// The mean of the fold reconstruction errors.
+    pub loss_mean: A,
+
+    /// The standard deviation of the fold reconstruction errors.
+    pub loss_std: A,
+}
+
+/// Correlation between the subspaces of a product quantizer.
+///
+/// Product quantization assumes that the subspaces are independent.
+/// High correlations between dimensions of different subspaces indicate
+/// that this assumption is violated and that a rotation (OPQ) is likely
+/// to reduce the quantization error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubspaceCorrelation<A> {
+    /// Mean absolute correlations between subspaces.
+    ///
+    /// *(i, j)* is the mean absolute Pearson correlation between the
+    /// dimensions of subspace *i* and the dimensions of subspace *j*.
+    /// The diagonal contains the correlations within each subspace,
+    /// excluding the correlation of a dimension with itself.
+    pub correlations: Array2<A>,
+
+    /// Mean absolute correlation between dimensions of different subspaces.
+    pub mean_cross_correlation: A,
+
+    /// Maximum absolute correlation between dimensions of different subspaces.
+    pub max_cross_correlation: A,
+}
+
+impl<A> SubspaceCorrelation<A>
+where
+    A: NdFloat,
+{
+    /// Check whether OPQ is recommended over PQ.
+    ///
+    /// Returns `true` when the mean absolute correlation between
+    /// dimensions of different subspaces exceeds `threshold`.
+    pub fn recommend_opq(&self, threshold: A) -> bool {
+        self.mean_cross_correlation > threshold
+    }
+}
+
+/// Stability of quantizer training across random seeds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeedStability<A> {
+    /// The seeds that were used for training.
+    pub seeds: Vec<u64>,
+
+    /// The mean squared reconstruction error of each trained quantizer.
+    pub losses: Vec<A>,
+
+    /// The mean of the reconstruction errors.
+    pub loss_mean: A,
+
+    /// The variance of the reconstruction errors.
+    pub loss_variance: A,
+
+    /// The mean Rand index of the subquantizer assignments.
+    ///
+    /// This is the Rand index of the assignments of each subquantizer,
+    /// averaged over all subquantizers and pairs of trained quantizers.
+    /// A value of one indicates that all seeds result in the same
+    /// partitioning of the data.
+    pub mean_agreement: A,
+}
+
+/// Train a quantizer configuration with multiple seeds.
+///
+/// A quantizer of type `T` is trained on `instances` for each seed in
+/// `seeds`, using the xorshift PRNG and the given hyperparameters (see
+/// `TrainPQ::train_pq`). The returned report contains the variance of
+/// the reconstruction error and the agreement of the assignments
+/// between the trained quantizers.
+///
+/// If the variance is low and the agreement high, training is stable
+/// and a single attempt likely suffices.
+pub fn seed_stability<T, A, S>(
+    n_subquantizers: usize,
+    n_subquantizer_bits: u32,
+    n_iterations: usize,
+    n_attempts: usize,
+    instances: ArrayBase<S, Ix2>,
+    seeds: impl IntoIterator<Item = u64>,
+) -> SeedStability<A>
+where
+    T: TrainPQ<A>,
+    A: NdFloat + Sum,
+    S: Sync + Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let seeds = seeds.into_iter().collect::<Vec<_>>();
+    assert!(
+        seeds.len() > 1,
+        "At least two seeds are required to measure stability."
+    );
+
+    let mut losses = Vec::with_capacity(seeds.len());
+    let mut quantizations = Vec::with_capacity(seeds.len());
+    for &seed in &seeds {
+        let pq = T::train_pq_with_seed(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            n_attempts,
+            instances.view(),
+            seed,
+        );
+
+        let quantized = pq.quantize_batch::<usize, _>(instances.view());
+        losses.push(codes_loss(&pq, instances.view(), quantized.view()));
+        quantizations.push(quantized);
+    }
+
+    let n_seeds: A = seeds.len().as_();
+    let loss_mean = losses.iter().cloned().sum::<A>() / n_seeds;
+    let loss_variance = losses
+        .iter()
+        .map(|&loss| (loss - loss_mean) * (loss - loss_mean))
+        .sum::<A>()
+        / n_seeds;
+
+    let mut agreement_sum = A::zero();
+    let mut n_comparisons = 0usize;
+    for (idx, quantized1) in quantizations.iter().enumerate() {
+        for quantized2 in &quantizations[idx + 1..] {
+            for (sq1, sq2) in quantized1
+                .axis_iter(Axis(1))
+                .zip(quantized2.axis_iter(Axis(1)))
+            {
+                agreement_sum += rand_index(sq1, sq2);
+                n_comparisons += 1;
+            }
+        }
+    }
+
+    SeedStability {
+        seeds,
+        losses,
+        loss_mean,
+        loss_variance,
+        mean_agreement: agreement_sum / n_comparisons.as_(),
+    }
+}
+
+/// Evaluate quantizer configurations using k-fold cross-validation.
+///
+/// The rows of `instances` are shuffled and split into `n_folds`
+/// folds (see `split::k_fold_splits`). For each configuration in `configs` and each fold, a
+/// quantizer of type `T` is trained on the remaining folds and its
+/// mean squared reconstruction error is measured on the held-out fold.
+/// The folds are processed in parallel.
+///
+/// Shuffling and training are seeded with `seed`, so that the results
+/// are reproducible. Returns one result per configuration, in the order
+/// of `configs`.
+pub fn cross_validate<T, A, S>(
+    instances: ArrayBase<S, Ix2>,
+    configs: impl IntoIterator<Item = PQConfig>,
+    n_folds: usize,
+    seed: u64,
+) -> Vec<CrossValidation<A>>
+where
+    T: TrainPQ<A>,
+    A: NdFloat + Sum,
+    S: Sync + Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let folds = k_fold_splits(instances.nrows(), n_folds, seed)
+        .iter()
+        .map(|split| split.select(&instances))
+        .collect::<Vec<_>>();
+
+    configs
+        .into_iter()
+        .map(|config| {
+            let fold_losses = folds
+                .par_iter()
+                .enumerate()
+                .map(|(fold, (train, held_out))| {
+                    let pq = T::train_pq_with_seed(
+                        config.n_subquantizers,
+                        config.n_subquantizer_bits,
+                        config.n_iterations,
+                        config.n_attempts,
+                        train.view(),
+                        seed.wrapping_add(fold as u64),
+                    );
+                    reconstruction_loss(&pq, held_out.view())
+                })
+                .collect::<Vec<_>>();
+
+            let n_folds: A = n_folds.as_();
+            let loss_mean = fold_losses.iter().cloned().sum::<A>() / n_folds;
+            let loss_std = (fold_losses
+                .iter()
+                .map(|&loss| (loss - loss_mean) * (loss - loss_mean))
+                .sum::<A>()
+                / n_folds)
+                .sqrt();
+
+            CrossValidation {
+                config,
+                fold_losses,
+                loss_mean,
+                loss_std,
+            }
+        })
+        .collect()
+}
+
+/// Measure the correlation between subspaces before training.
+///
+/// The instances are the rows of `instances`, which are split into
+/// `n_subquantizers` subspaces of equal size, as done by product
+/// quantization. Dimensions without variance are considered to be
+/// uncorrelated to all other dimensions.
+pub fn subspace_correlation<A, S>(
+    instances: ArrayBase<S, Ix2>,
+    n_subquantizers: usize,
+) -> SubspaceCorrelation<A>
+where
+    A: FromPrimitive + NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    assert!(
+        n_subquantizers > 1 && instances.ncols() % n_subquantizers == 0,
+        "At least two subquantizers are required, which should divide {} dimensions, got: {}",
+        instances.ncols(),
+        n_subquantizers
+    );
+
+    let cov = instances.view().covariance(Axis(0));
+    let std_devs = cov.diag().mapv(|v| v.max(A::zero()).sqrt());
+    let abs_corr = Array2::from_shape_fn(cov.dim(), |(i, j)| {
+        let norm = std_devs[i] * std_devs[j];
+        if norm > A::zero() {
+            (cov[(i, j)] / norm).abs()
+        } else {
+            A::zero()
+        }
+    });
+
+    let sq_dims = instances.ncols() / n_subquantizers;
+    let mut correlations = Array2::zeros((n_subquantizers, n_subquantizers));
+    let mut cross_sum = A::zero();
+    let mut max_cross_correlation = A::zero();
+    for ((sq1, sq2), correlation) in correlations.indexed_iter_mut() {
+        // ndarray#474
+        #[allow(clippy::deref_addrof)]
+        let block = abs_corr.slice(s![
+            sq1 * sq_dims..(sq1 + 1) * sq_dims,
+            sq2 * sq_dims..(sq2 + 1) * sq_dims
+        ]);
+
+        if sq1 == sq2 {
+            if sq_dims > 1 {
+                let off_diag_sum = block.sum() - block.diag().sum();
+                *correlation = off_diag_sum / (sq_dims * (sq_dims - 1)).as_();
+            }
+        } else {
+            let block_sum = block.sum();
+            *correlation = block_sum / (sq_dims * sq_dims).as_();
+            cross_sum += block_sum;
+            max_cross_correlation = block.fold(max_cross_correlation, |acc, &v| acc.max(v));
+        }
+    }
+
+    let n_cross = instances.ncols() * instances.ncols() - n_subquantizers * sq_dims * sq_dims;
+
+    SubspaceCorrelation {
+        correlations,
+        mean_cross_correlation: cross_sum / n_cross.as_(),
+        max_cross_correlation,
+    }
+}
+
+/// Train a quantizer, choosing between PQ, OPQ and GaussianOPQ.
+///
+/// This function is intended for users who do not want to choose a
+/// quantizer type themselves. `n_samples` instances are sampled from
+/// the rows of `instances`. The sample is used to measure the
+/// correlation between subspaces (see `subspace_correlation`) and how
+/// Gaussian the variables are. Based on these measurements, candidate
+/// quantizers are ordered from most to least promising, trained on 80%
+/// of the sample, and evaluated on the remaining 20%.
+///
+/// Candidates are trained until `time_budget` is exhausted, but at
+/// least one candidate is trained. The candidate with the lowest
+/// reconstruction error is returned. Its manifest records the quantizer
+/// type, which can be used to train a quantizer of the same type on
+/// all instances.
+#[cfg(feature = "opq-train")]
+pub fn auto_train<A, S>(
+    n_subquantizers: usize,
+    n_subquantizer_bits: u32,
+    n_iterations: usize,
+    instances: ArrayBase<S, Ix2>,
+    n_samples: usize,
+    time_budget: Duration,
+    seed: u64,
+) -> PQ<A>
+where
+    A: FromPrimitive + Lapack + NdFloat + Scalar + Sum,
+    A::Real: NdFloat,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let start = Instant::now();
+
+    let mut rng = XorShiftRng::seed_from_u64(seed);
+    let sample = subsample(instances.view(), n_samples, &mut rng);
+    let (train, validation) = train_validation_split(sample.nrows(), 0.2, seed).select(&sample);
+
+    let candidates = auto_train_candidates(sample.view(), n_subquantizers);
+    let mut best: Option<(A, PQ<A>)> = None;
+    for candidate in candidates {
+        if best.is_some() && start.elapsed() >= time_budget {
+            break;
+        }
+
+        let pq = candidate.train_pq(
+            n_subquantizers,
+            n_subquantizer_bits,
+            n_iterations,
+            train.view(),
+            seed,
+        );
+
+        let loss = reconstruction_loss(&pq, validation.view());
+        info!("Candidate {:?}, validation loss: {}", candidate, loss);
+
+        if best
+            .as_ref()
+            .map(|(best_loss, _)| loss < *best_loss)
+            .unwrap_or(true)
+        {
+            best = Some((loss, pq));
+        }
+    }
+
+    best.expect("No candidate quantizer was trained").1
+}
+
+/// Quantizer types that are candidates of `auto_train`.
+#[cfg(feature = "opq-train")]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum QuantizerKind {
+    PQ,
+    OPQ,
+    GaussianOPQ,
+}
+
+#[cfg(feature = "opq-train")]
+impl QuantizerKind {
+    /// Train a quantizer of this type.
+    fn train_pq<A>(
+        self,
+        n_subquantizers: usize,
+        n_subquantizer_bits: u32,
+        n_iterations: usize,
+        instances: ArrayView2<A>,
+        seed: u64,
+    ) -> PQ<A>
+    where
+        A: Lapack + NdFloat + Scalar + Sum,
+        A::Real: NdFloat,
+        usize: AsPrimitive<A>,
+    {
+        match self {
+            QuantizerKind::PQ => PQ::train_pq_with_seed(
+                n_subquantizers,
+                n_subquantizer_bits,
+                n_iterations,
+                1,
+                instances,
+                seed,
+            ),
+            QuantizerKind::OPQ => OPQ::train_pq_with_seed(
+                n_subquantizers,
+                n_subquantizer_bits,
+                n_iterations,
+                1,
+                instances,
+                seed,
+            ),
+            QuantizerKind::GaussianOPQ => GaussianOPQ::train_pq_with_seed(
+                n_subquantizers,
+                n_subquantizer_bits,
+                n_iterations,
+                1,
+                instances,
+                seed,
+            ),
+        }
+    }
+}
+
+/// Order the candidate quantizer types of `auto_train`.
+#[cfg(feature = "opq-train")]
+fn auto_train_candidates<A>(sample: ArrayView2<A>, n_subquantizers: usize) -> Vec<QuantizerKind>
+where
+    A: FromPrimitive + NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    let correlated = n_subquantizers > 1
+        && subspace_correlation(sample, n_subquantizers).recommend_opq(A::from(0.1).unwrap());
+    let gaussian = mean_abs_excess_kurtosis(sample) < A::one();
+
+    match (correlated, gaussian) {
+        (false, _) => vec![
+            QuantizerKind::PQ,
+            QuantizerKind::GaussianOPQ,
+            QuantizerKind::OPQ,
+        ],
+        (true, true) => vec![
+            QuantizerKind::GaussianOPQ,
+            QuantizerKind::OPQ,
+            QuantizerKind::PQ,
+        ],
+        (true, false) => vec![
+            QuantizerKind::OPQ,
+            QuantizerKind::GaussianOPQ,
+            QuantizerKind::PQ,
+        ],
+    }
+}
+
+/// Compute the mean absolute excess kurtosis of the columns.
+///
+/// The excess kurtosis of a Gaussian variable is zero. Columns without
+/// variance are ignored.
+#[cfg(feature = "opq-train")]
+fn mean_abs_excess_kurtosis<A>(instances: ArrayView2<A>) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    let n_instances: A = instances.nrows().as_();
+    let three = A::from(3).unwrap();
+
+    let kurtoses = instances
+        .axis_iter(Axis(1))
+        .filter_map(|column| {
+            let mean = column.sum() / n_instances;
+            let m2 = column.iter().map(|&v| (v - mean).powi(2)).sum::<A>() / n_instances;
+            let m4 = column.iter().map(|&v| (v - mean).powi(4)).sum::<A>() / n_instances;
+            if m2 > A::zero() {
+                Some((m4 / (m2 * m2) - three).abs())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if kurtoses.is_empty() {
+        return A::zero();
+    }
+
+    kurtoses.iter().cloned().sum::<A>() / kurtoses.len().as_()
+}
+
+/// Compute the k-means loss for a range of cluster counts.
+///
+/// For each *k* in `ks`, k-means clustering is performed on the rows
+/// of `instances` for `n_iterations` iterations. The lowest loss of
+/// several clusterings from different initial centroids is used. If
+/// `n_samples` is smaller than the number of instances, clustering is
+/// performed on a random subsample of `n_samples` instances.
+///
+/// Returns the pairs *(k, loss)*, where *loss* is the mean squared
+/// error of the clustering. Plotting this curve can be used to find
+/// the elbow point. Since a subquantizer with *b* bits has *2^b*
+/// centroids, `ks` will typically be powers of two when choosing the
+/// number of subquantizer bits.
+pub fn inertia_curve<A, S>(
+    instances: ArrayBase<S, Ix2>,
+    ks: impl IntoIterator<Item = usize>,
+    n_iterations: usize,
+    n_samples: usize,
+    rng: &mut impl Rng,
+) -> Vec<(usize, A)>
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    let instances = subsample(instances.view(), n_samples, rng);
+
+    ks.into_iter()
+        .map(|k| (k, kmeans_loss(instances.view(), k, n_iterations, rng)))
+        .collect()
+}
+
+/// Compute the gap statistic for a range of cluster counts.
+///
+/// For each *k* in `ks`, the loss of k-means clustering on `instances`
+/// is compared to the expected loss on `n_references` reference data
+/// sets. As in `inertia_curve`, the lowest loss of several clusterings
+/// is used. The reference data sets are drawn uniformly from the bounding
+/// box of `instances`. If `n_samples` is smaller than the number of
+/// instances, a random subsample of `n_samples` instances is used.
+///
+/// Use `gap_optimal_k` to pick the number of clusters from the result.
+pub fn gap_statistic<A, S>(
+    instances: ArrayBase<S, Ix2>,
+    ks: impl IntoIterator<Item = usize>,
+    n_iterations: usize,
+    n_samples: usize,
+    n_references: usize,
+    rng: &mut impl Rng,
+) -> Vec<Gap<A>>
+where
+    A: NdFloat + Sum,
+    S: Data<Elem = A>,
+    usize: AsPrimitive<A>,
+{
+    assert!(
+        n_references > 0,
+        "At least one reference data set is required."
+    );
+
+    let instances = subsample(instances.view(), n_samples, rng);
+    let references = (0..n_references)
+        .map(|_| uniform_reference(instances.view(), rng))
+        .collect::<Vec<_>>();
+
+    ks.into_iter()
+        .map(|k| {
+            let loss = kmeans_loss(instances.view(), k, n_iterations, rng);
+
+            let ref_log_losses = references
+                .iter()
+                .map(|reference| log_loss(kmeans_loss(reference.view(), k, n_iterations, rng)))
+                .collect::<Vec<_>>();
+            let n_refs: A = n_references.as_();
+            let ref_mean = ref_log_losses.iter().cloned().sum::<A>() / n_refs;
+            let ref_std = (ref_log_losses
+                .iter()
+                .map(|&v| (v - ref_mean) * (v - ref_mean))
+                .sum::<A>()
+                / n_refs)
+                .sqrt();
+
+            Gap {
+                k,
+                loss,
+                gap: ref_mean - log_loss(loss),
+                std_err: ref_std * (A::one() + A::one() / n_refs).sqrt(),
+            }
+        })
+        .collect()
+}
+
+/// Pick the number of clusters using the gap statistic.
+///
+/// Returns the smallest *k* such that *Gap(k) >= Gap(k') - s(k')*,
+/// where *k'* is the next number of clusters in `gaps` and *s(k')* its
+/// standard error. `gaps` should be ordered by the number of clusters.
+/// Returns `None` if there is no such *k*.
+pub fn gap_optimal_k<A>(gaps: &[Gap<A>]) -> Option<usize>
+where
+    A: NdFloat,
+{
+    gaps.windows(2)
+        .find(|w| w[0].gap >= w[1].gap - w[1].std_err)
+        .map(|w| w[0].k)
+}
+
+fn reconstruction_loss<A, Q>(quantizer: &Q, instances: ArrayView2<A>) -> A
+where
+    A: NdFloat + Sum,
+    Q: QuantizeVector<A> + ReconstructVector<A>,
+    usize: AsPrimitive<A>,
+{
+    let quantized = quantizer.quantize_batch::<usize, _>(instances);
+    codes_loss(quantizer, instances, quantized.view())
+}
+
+/// Compute the reconstruction loss of already-quantized instances.
+fn codes_loss<A, Q>(quantizer: &Q, instances: ArrayView2<A>, quantized: ArrayView2<usize>) -> A
+where
+    A: NdFloat + Sum,
+    Q: ReconstructVector<A>,
+    usize: AsPrimitive<A>,
+{
+    let mut errors = quantizer.reconstruct_batch(quantized);
+    errors -= &quantizer.normalize_batch(instances);
+    errors.iter().map(|&v| v * v).sum::<A>() / instances.len().as_()
+}
+
+/// Compute the lowest loss of `KMEANS_ATTEMPTS` k-means clusterings.
+fn kmeans_loss<A>(instances: ArrayView2<A>, k: usize, n_iterations: usize, rng: &mut impl Rng) -> A
+where
+    A: NdFloat + Sum,
+    usize: AsPrimitive<A>,
+{
+    (0..KMEANS_ATTEMPTS)
+        .map(|_| {
+            instances
+                .k_means(
+                    Axis(0),
+                    k,
+                    RandomInstanceCentroids::new(&mut *rng),
+                    NIterationsCondition(n_iterations),
+                )
+                .1
+        })
+        .fold(A::infinity(), A::min)
+}
+
+/// Compute the logarithm of a loss.
+///
+/// The loss is zero when there are as many clusters as distinct
+/// instances, so it is offset by epsilon to keep the logarithm finite.
+fn log_loss<A>(loss: A) -> A
+where
+    A: NdFloat,
+{
+    (loss + A::epsilon()).ln()
+}
+
+pub(crate) fn subsample<A>(
+    instances: ArrayView2<A>,
+    n_samples: usize,
+    rng: &mut impl Rng,
+) -> Array2<A>
+where
+    A: Clone,
+{
+    if n_samples >= instances.nrows() {
+        return instances.to_owned();
+    }
+
+    let indices = index::sample(rng, instances.nrows(), n_samples).into_vec();
+    instances.select(Axis(0), &indices)
+}
+
+fn uniform_reference<A>(instances: ArrayView2<A>, rng: &mut impl Rng) -> Array2<A>
+where
+    A: NdFloat,
+{
+    let mins = instances.fold_axis(Axis(0), A::infinity(), |&a, &b| a.min(b));
+    let maxs = instances.fold_axis(Axis(0), A::neg_infinity(), |&a, &b| a.max(b));
+
+    Array2::from_shape_fn(instances.dim(), |(_, col)| {
+        let u = A::from(rng.gen::<f64>()).unwrap();
+        mins[col] + (maxs[col] - mins[col]) * u
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, concatenate, Array2, Axis};
+    use rand::SeedableRng;
+    use rand_distr::Normal;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{
+        cross_validate, gap_optimal_k, gap_statistic, inertia_curve, reconstruction_loss,
+        seed_stability, subspace_correlation, Gap, PQConfig,
+    };
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{Normalization, PQ};
+
+    fn gaussian_spheres(rng: &mut XorShiftRng) -> Array2<f64> {
+        let centers = array![[0., 0.], [1., 0.], [1., 1.]];
+        let spheres = centers
+            .outer_iter()
+            .map(|center| {
+                let mut sphere = Array2::random_using((20, 2), Normal::new(0., 0.01).unwrap(), rng);
+                sphere += &center;
+                sphere
+            })
+            .collect::<Vec<_>>();
+        let views = spheres.iter().map(Array2::view).collect::<Vec<_>>();
+        concatenate(Axis(0), &views).unwrap()
+    }
+
+    #[test]
+    fn inertia_curve_decreases() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = gaussian_spheres(&mut rng);
+        let curve = inertia_curve(instances.view(), 1..=3, 10, 100, &mut rng);
+        assert_eq!(curve.iter().map(|p| p.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(curve[0].1 > curve[1].1);
+        assert!(curve[1].1 > curve[2].1);
+    }
+
+    #[test]
+    fn gap_statistic_finds_spheres() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = gaussian_spheres(&mut rng);
+        let gaps = gap_statistic(instances.view(), 1..=5, 10, 60, 5, &mut rng);
+
+        assert_eq!(gap_optimal_k(&gaps), Some(3));
+    }
+
+    #[test]
+    fn gap_statistic_zero_loss_is_finite() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = array![[0f64, 0.], [0., 0.], [1., 1.], [1., 1.]];
+        let gaps = gap_statistic(instances.view(), 1..=2, 10, 4, 3, &mut rng);
+
+        assert_eq!(gaps[1].loss, 0.);
+        assert!(gaps.iter().all(|gap| gap.gap.is_finite()));
+    }
+
+    #[test]
+    fn seed_stability_report() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = gaussian_spheres(&mut rng);
+        let report = seed_stability::<PQ<f64>, _, _>(1, 1, 10, 1, instances.view(), 0..4);
+        assert_eq!(report.seeds, vec![0, 1, 2, 3]);
+        assert_eq!(report.losses.len(), 4);
+        assert!(report.loss_variance >= 0.);
+        assert!(report.mean_agreement > 0. && report.mean_agreement <= 1.);
+    }
+
+    #[test]
+    fn seed_stability_seeds_change_training() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances =
+            Array2::<f64>::random_using((100, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+
+        // The same seed should give the same quantizer.
+        let same = seed_stability::<PQ<f64>, _, _>(2, 2, 10, 1, instances.view(), vec![7, 7]);
+        assert_eq!(same.losses[0], same.losses[1]);
+        assert_eq!(same.mean_agreement, 1.);
+
+        // Different seeds should start from different centroids.
+        let different = seed_stability::<PQ<f64>, _, _>(2, 2, 10, 1, instances.view(), 0..4);
+        assert!(different
+            .losses
+            .iter()
+            .any(|&loss| loss != different.losses[0]));
+        assert!(different.loss_variance > 0.);
+        assert!(different.mean_agreement < 1.);
+    }
+
+    #[test]
+    fn reconstruction_loss_is_normalized() {
+        // The quantizer reconstructs the centered instances exactly.
+        let instances = array![[9f64, 11.], [11., 9.]];
+        let pq = PQ::new(None, array![[[-1., 1.], [1., -1.]]])
+            .with_normalization(Normalization::MeanCentering(array![10., 10.]));
+        assert_eq!(reconstruction_loss(&pq, instances.view()), 0.);
+
+        let pq = PQ::new(None, array![[[1f64], [0.]], [[0.], [1.]]])
+            .with_normalization(Normalization::L2);
+        assert_eq!(reconstruction_loss(&pq, array![[2., 0.]].view()), 0.);
+    }
+
+    #[test]
+    fn cross_validate_configs() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances = gaussian_spheres(&mut rng);
+        let config = |n_subquantizer_bits| PQConfig {
+            n_subquantizers: 1,
+            n_subquantizer_bits,
+            n_iterations: 10,
+            n_attempts: 1,
+        };
+
+        let results =
+            cross_validate::<PQ<f64>, _, _>(instances.view(), vec![config(1), config(3)], 3, 42);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].config, config(1));
+        assert_eq!(results[0].fold_losses.len(), 3);
+
+        // More centroids should give a lower held-out error.
+        assert!(results[1].loss_mean < results[0].loss_mean);
+
+        assert_eq!(
+            results,
+            cross_validate::<PQ<f64>, _, _>(instances.view(), vec![config(1), config(3)], 3, 42)
+        );
+    }
+
+    #[test]
+    fn subspace_correlation_detects_dependence() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let noise = Array2::<f64>::random_using((100, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+
+        let independent = subspace_correlation(noise.view(), 2);
+        assert_eq!(independent.correlations.dim(), (2, 2));
+        assert!(!independent.recommend_opq(0.2));
+
+        // Copy the first dimension into the second subspace.
+        let mut dependent = noise;
+        let first = dependent.column(0).to_owned();
+        dependent.column_mut(2).assign(&first);
+        let report = subspace_correlation(dependent.view(), 2);
+        assert!((report.max_cross_correlation - 1.).abs() < 1e-6);
+        assert!(report.correlations[(0, 1)] > independent.correlations[(0, 1)]);
+        assert!(report.mean_cross_correlation > independent.mean_cross_correlation);
+    }
+
+    #[cfg(feature = "opq-train")]
+    #[test]
+    fn auto_train_picks_quantizer() {
+        use std::time::Duration;
+
+        use super::auto_train;
+
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances =
+            Array2::<f64>::random_using((200, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+
+        let pq = auto_train(2, 2, 5, instances.view(), 100, Duration::from_secs(60), 42);
+        let manifest = pq.manifest().unwrap();
+        assert!(["PQ", "OPQ", "GaussianOPQ"].contains(&manifest.quantizer));
+        assert_eq!(manifest.n_instances, 80);
+    }
+
+    #[test]
+    fn gap_optimal_k_picks_smallest() {
+        let gap = |k, gap| Gap {
+            k,
+            loss: 0.,
+            gap,
+            std_err: 0.1,
+        };
+        assert_eq!(
+            gap_optimal_k(&[gap(1, 0.1), gap(2, 0.5), gap(4, 0.55), gap(8, 0.9)]),
+            Some(2)
+        );
+        assert_eq!(gap_optimal_k(&[gap(1, 0.1), gap(2, 0.5)]), None);
+    }
+}
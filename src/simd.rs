@@ -0,0 +1,533 @@
+//! SIMD kernels with runtime dispatch.
+//!
+//! The kernels in this module select an implementation based on the
+//! instruction sets that the CPU supports at run time, so that a single
+//! binary uses AVX-512 or AVX2/FMA on x86_64 machines that support them
+//! and NEON on aarch64. Every kernel has a scalar fallback.
+
+use std::any::TypeId;
+use std::slice;
+
+/// Reinterpret a slice as an `f32` slice if `A` is `f32`.
+pub(crate) fn as_f32<A: 'static>(values: &[A]) -> Option<&[f32]> {
+    if TypeId::of::<A>() == TypeId::of::<f32>() {
+        // Safety: A is f32.
+        Some(unsafe { slice::from_raw_parts(values.as_ptr() as *const f32, values.len()) })
+    } else {
+        None
+    }
+}
+
+/// Reinterpret a mutable slice as an `f32` slice if `A` is `f32`.
+pub(crate) fn as_f32_mut<A: 'static>(values: &mut [A]) -> Option<&mut [f32]> {
+    if TypeId::of::<A>() == TypeId::of::<f32>() {
+        // Safety: A is f32.
+        Some(unsafe { slice::from_raw_parts_mut(values.as_mut_ptr() as *mut f32, values.len()) })
+    } else {
+        None
+    }
+}
+
+/// Reinterpret a slice as a `u8` slice if `I` is `u8`.
+pub(crate) fn as_u8<I: 'static>(values: &[I]) -> Option<&[u8]> {
+    if TypeId::of::<I>() == TypeId::of::<u8>() {
+        // Safety: I is u8.
+        Some(unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, values.len()) })
+    } else {
+        None
+    }
+}
+
+/// Compute the squared Euclidean distance between two vectors.
+pub(crate) fn squared_euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(
+        a.len(),
+        b.len(),
+        "Cannot compute (squared) euclidean distance of vectors with different lengths."
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512f") {
+            // Safety: the required CPU features are available.
+            return unsafe { x86::squared_euclidean_distance_avx512(a, b) };
+        }
+
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // Safety: the required CPU features are available.
+            return unsafe { x86::squared_euclidean_distance(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // Safety: the required CPU features are available.
+            return unsafe { aarch64::squared_euclidean_distance(a, b) };
+        }
+    }
+
+    scalar::squared_euclidean_distance(a, b)
+}
+
+/// Scan quantization codes with an asymmetric distance table.
+///
+/// `table` is a row-major table with shape *(n_subquantizers,
+/// n_centroids)*, `codes` a row-major matrix with shape *(n_vectors,
+/// n_subquantizers)*. The distance of each vector is written to
+/// `distances`.
+pub(crate) fn adc_distances(
+    table: &[f32],
+    n_centroids: usize,
+    codes: &[u8],
+    distances: &mut [f32],
+) {
+    let n_subquantizers = table.len() / n_centroids;
+    assert_eq!(
+        codes.len(),
+        distances.len() * n_subquantizers,
+        "Quantization length does not match number of subquantizers"
+    );
+    assert!(
+        codes.iter().all(|&code| (code as usize) < n_centroids),
+        "Code does not refer to a centroid"
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512f") && table.len() <= i32::MAX as usize {
+            // Safety: the required CPU features are available and all
+            // codes are valid table indices.
+            return unsafe { x86::adc_distances_avx512(table, n_centroids, codes, distances) };
+        }
+
+        if is_x86_feature_detected!("avx2") && table.len() <= i32::MAX as usize {
+            // Safety: the required CPU features are available and all
+            // codes are valid table indices.
+            return unsafe { x86::adc_distances(table, n_centroids, codes, distances) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // Safety: the required CPU features are available and all
+            // codes are valid table indices.
+            return unsafe { aarch64::adc_distances(table, n_centroids, codes, distances) };
+        }
+    }
+
+    scalar::adc_distances(table, n_centroids, codes, distances)
+}
+
+/// Compute the Hamming distance between two bit vectors.
+///
+/// The distance is the number of differing bits of the byte slices.
+/// For codes of polysemous product quantizers with `u8` codes, this is
+/// the sum of the Hamming distances of the codes.
+pub(crate) fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+    assert_eq!(
+        a.len(),
+        b.len(),
+        "Cannot compute Hamming distance of vectors with different lengths."
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("popcnt") {
+            // Safety: the required CPU features are available.
+            return unsafe { x86::hamming_distance(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // Safety: the required CPU features are available.
+            return unsafe { aarch64::hamming_distance(a, b) };
+        }
+    }
+
+    scalar::hamming_distance(a, b)
+}
+
+mod scalar {
+    pub fn squared_euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| {
+                let diff = a - b;
+                diff * diff
+            })
+            .sum()
+    }
+
+    pub fn adc_distances(table: &[f32], n_centroids: usize, codes: &[u8], distances: &mut [f32]) {
+        let n_subquantizers = table.len() / n_centroids;
+        for (distance, codes) in distances
+            .iter_mut()
+            .zip(codes.chunks_exact(n_subquantizers))
+        {
+            *distance = codes
+                .iter()
+                .zip(table.chunks_exact(n_centroids))
+                .map(|(&code, sq_table)| sq_table[code as usize])
+                .sum();
+        }
+    }
+
+    // Inlined, so that count_ones uses popcnt in the x86 kernel.
+    #[inline(always)]
+    pub fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+        let a_words = a.chunks_exact(8);
+        let b_words = b.chunks_exact(8);
+        let tail = a_words
+            .remainder()
+            .iter()
+            .zip(b_words.remainder())
+            .map(|(&a, &b)| (a ^ b).count_ones())
+            .sum::<u32>();
+
+        a_words
+            .zip(b_words)
+            .map(|(a, b)| (word(a) ^ word(b)).count_ones())
+            .sum::<u32>()
+            + tail
+    }
+
+    #[inline(always)]
+    fn word(bytes: &[u8]) -> u64 {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(bytes);
+        u64::from_ne_bytes(word)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::scalar;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn squared_euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+        let n_blocked = a.len() / 8 * 8;
+        let mut sums = _mm256_setzero_ps();
+        for i in (0..n_blocked).step_by(8) {
+            let diff = _mm256_sub_ps(
+                _mm256_loadu_ps(a.as_ptr().add(i)),
+                _mm256_loadu_ps(b.as_ptr().add(i)),
+            );
+            sums = _mm256_fmadd_ps(diff, diff, sums);
+        }
+
+        horizontal_sum(sums) + scalar::squared_euclidean_distance(&a[n_blocked..], &b[n_blocked..])
+    }
+
+    /// Scan eight vectors at a time, gathering their table entries.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn adc_distances(
+        table: &[f32],
+        n_centroids: usize,
+        codes: &[u8],
+        distances: &mut [f32],
+    ) {
+        let n_subquantizers = table.len() / n_centroids;
+        let n_blocked = distances.len() / 8 * 8;
+        for row in (0..n_blocked).step_by(8) {
+            let block = &codes[row * n_subquantizers..(row + 8) * n_subquantizers];
+            let mut sums = _mm256_setzero_ps();
+            for sq in 0..n_subquantizers {
+                let code = |i: usize| block[i * n_subquantizers + sq] as i32;
+                let indices = _mm256_add_epi32(
+                    _mm256_setr_epi32(
+                        code(0),
+                        code(1),
+                        code(2),
+                        code(3),
+                        code(4),
+                        code(5),
+                        code(6),
+                        code(7),
+                    ),
+                    _mm256_set1_epi32((sq * n_centroids) as i32),
+                );
+                sums = _mm256_add_ps(sums, _mm256_i32gather_ps::<4>(table.as_ptr(), indices));
+            }
+
+            _mm256_storeu_ps(distances.as_mut_ptr().add(row), sums);
+        }
+
+        scalar::adc_distances(
+            table,
+            n_centroids,
+            &codes[n_blocked * n_subquantizers..],
+            &mut distances[n_blocked..],
+        );
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn squared_euclidean_distance_avx512(a: &[f32], b: &[f32]) -> f32 {
+        let mut sums = _mm512_setzero_ps();
+        for i in (0..a.len()).step_by(16) {
+            // Mask out the lanes past the end of the vectors.
+            let mask = (u32::MAX >> (32 - (a.len() - i).min(16))) as __mmask16;
+            let diff = _mm512_sub_ps(
+                _mm512_maskz_loadu_ps(mask, a.as_ptr().add(i)),
+                _mm512_maskz_loadu_ps(mask, b.as_ptr().add(i)),
+            );
+            sums = _mm512_fmadd_ps(diff, diff, sums);
+        }
+
+        _mm512_reduce_add_ps(sums)
+    }
+
+    /// Scan sixteen vectors at a time, gathering their table entries.
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn adc_distances_avx512(
+        table: &[f32],
+        n_centroids: usize,
+        codes: &[u8],
+        distances: &mut [f32],
+    ) {
+        let n_subquantizers = table.len() / n_centroids;
+        let n_blocked = distances.len() / 16 * 16;
+        let mut block_codes = [0i32; 16];
+        for row in (0..n_blocked).step_by(16) {
+            let block = &codes[row * n_subquantizers..(row + 16) * n_subquantizers];
+            let mut sums = _mm512_setzero_ps();
+            for sq in 0..n_subquantizers {
+                for (i, code) in block_codes.iter_mut().enumerate() {
+                    *code = block[i * n_subquantizers + sq] as i32;
+                }
+                let indices = _mm512_add_epi32(
+                    _mm512_loadu_si512(block_codes.as_ptr() as *const _),
+                    _mm512_set1_epi32((sq * n_centroids) as i32),
+                );
+                sums = _mm512_add_ps(sums, _mm512_i32gather_ps::<4>(indices, table.as_ptr()));
+            }
+
+            _mm512_storeu_ps(distances.as_mut_ptr().add(row), sums);
+        }
+
+        scalar::adc_distances(
+            table,
+            n_centroids,
+            &codes[n_blocked * n_subquantizers..],
+            &mut distances[n_blocked..],
+        );
+    }
+
+    #[target_feature(enable = "popcnt")]
+    pub unsafe fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+        scalar::hamming_distance(a, b)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn horizontal_sum(v: __m256) -> f32 {
+        let sums = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps::<1>(v));
+        let sums = _mm_add_ps(sums, _mm_movehl_ps(sums, sums));
+        let sums = _mm_add_ss(sums, _mm_shuffle_ps::<0b01>(sums, sums));
+        _mm_cvtss_f32(sums)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use std::arch::aarch64::*;
+
+    use super::scalar;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn squared_euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+        let n_blocked = a.len() / 4 * 4;
+        let mut sums = vdupq_n_f32(0.);
+        for i in (0..n_blocked).step_by(4) {
+            let diff = vsubq_f32(vld1q_f32(a.as_ptr().add(i)), vld1q_f32(b.as_ptr().add(i)));
+            sums = vfmaq_f32(sums, diff, diff);
+        }
+
+        vaddvq_f32(sums) + scalar::squared_euclidean_distance(&a[n_blocked..], &b[n_blocked..])
+    }
+
+    /// Scan four vectors at a time, loading their table entries into lanes.
+    ///
+    /// NEON has no gather instruction, but the lanes are summed in the
+    /// same order as the scalar kernel, so the distances are identical.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn adc_distances(
+        table: &[f32],
+        n_centroids: usize,
+        codes: &[u8],
+        distances: &mut [f32],
+    ) {
+        let n_subquantizers = table.len() / n_centroids;
+        let n_blocked = distances.len() / 4 * 4;
+        let mut entries = [0f32; 4];
+        for row in (0..n_blocked).step_by(4) {
+            let block = &codes[row * n_subquantizers..(row + 4) * n_subquantizers];
+            let mut sums = vdupq_n_f32(0.);
+            for (sq, sq_table) in table.chunks_exact(n_centroids).enumerate() {
+                for (i, entry) in entries.iter_mut().enumerate() {
+                    // The caller guarantees that codes are valid indices.
+                    *entry = *sq_table.get_unchecked(block[i * n_subquantizers + sq] as usize);
+                }
+                sums = vaddq_f32(sums, vld1q_f32(entries.as_ptr()));
+            }
+
+            vst1q_f32(distances.as_mut_ptr().add(row), sums);
+        }
+
+        scalar::adc_distances(
+            table,
+            n_centroids,
+            &codes[n_blocked * n_subquantizers..],
+            &mut distances[n_blocked..],
+        );
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+        let n_blocked = a.len() / 16 * 16;
+        let mut count = 0;
+        for i in (0..n_blocked).step_by(16) {
+            let diff = veorq_u8(vld1q_u8(a.as_ptr().add(i)), vld1q_u8(b.as_ptr().add(i)));
+            // At most 16 * 8 = 128 bits differ, so the sum fits in a byte.
+            count += u32::from(vaddvq_u8(vcntq_u8(diff)));
+        }
+
+        count + scalar::hamming_distance(&a[n_blocked..], &b[n_blocked..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Uniform;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    use super::{adc_distances, hamming_distance, scalar, squared_euclidean_distance};
+
+    type DistanceKernel = unsafe fn(&[f32], &[f32]) -> f32;
+
+    type AdcKernel = unsafe fn(&[f32], usize, &[u8], &mut [f32]);
+
+    type HammingKernel = unsafe fn(&[u8], &[u8]) -> u32;
+
+    /// Get the dispatched kernels and the kernels of every instruction set
+    /// that the CPU supports.
+    fn kernels() -> (Vec<DistanceKernel>, Vec<AdcKernel>, Vec<HammingKernel>) {
+        let mut distance: Vec<DistanceKernel> = vec![squared_euclidean_distance];
+        let mut adc: Vec<AdcKernel> = vec![adc_distances];
+        let mut hamming: Vec<HammingKernel> = vec![hamming_distance];
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use super::x86;
+
+            if is_x86_feature_detected!("avx512f") {
+                distance.push(x86::squared_euclidean_distance_avx512);
+                adc.push(x86::adc_distances_avx512);
+            }
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+                distance.push(x86::squared_euclidean_distance);
+                adc.push(x86::adc_distances);
+            }
+            if is_x86_feature_detected!("popcnt") {
+                hamming.push(x86::hamming_distance);
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            use super::aarch64;
+
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                distance.push(aarch64::squared_euclidean_distance);
+                adc.push(aarch64::adc_distances);
+                hamming.push(aarch64::hamming_distance);
+            }
+        }
+
+        (distance, adc, hamming)
+    }
+
+    #[test]
+    fn kernels_match_scalar() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let (distance_kernels, adc_kernels, hamming_kernels) = kernels();
+
+        for &len in &[0, 3, 8, 19, 96] {
+            let a: Vec<f32> = (&mut rng)
+                .sample_iter(Uniform::new(-1., 1.))
+                .take(len)
+                .collect();
+            let b: Vec<f32> = (&mut rng)
+                .sample_iter(Uniform::new(-1., 1.))
+                .take(len)
+                .collect();
+            let expected = scalar::squared_euclidean_distance(&a, &b);
+            for kernel in &distance_kernels {
+                // Safety: the kernels are supported by the CPU.
+                assert!((unsafe { kernel(&a, &b) } - expected).abs() < 1e-4);
+            }
+        }
+
+        let n_subquantizers = 4;
+        let n_centroids = 16;
+        let table: Vec<f32> = (&mut rng)
+            .sample_iter(Uniform::new(0., 1.))
+            .take(n_subquantizers * n_centroids)
+            .collect();
+        for &n_vectors in &[0, 7, 19, 35] {
+            let codes: Vec<u8> = (&mut rng)
+                .sample_iter(Uniform::new(0, n_centroids as u8))
+                .take(n_vectors * n_subquantizers)
+                .collect();
+            let mut expected = vec![0f32; n_vectors];
+            scalar::adc_distances(&table, n_centroids, &codes, &mut expected);
+            for kernel in &adc_kernels {
+                let mut distances = vec![0f32; n_vectors];
+                // Safety: the kernels are supported by the CPU and the
+                // codes are valid.
+                unsafe { kernel(&table, n_centroids, &codes, &mut distances) };
+                for (distance, expected) in distances.iter().zip(&expected) {
+                    assert!((distance - expected).abs() < 1e-5);
+                }
+            }
+        }
+
+        for &len in &[0, 3, 8, 19, 40] {
+            let a: Vec<u8> = (&mut rng)
+                .sample_iter(Uniform::new_inclusive(0, 255))
+                .take(len)
+                .collect();
+            let b: Vec<u8> = (&mut rng)
+                .sample_iter(Uniform::new_inclusive(0, 255))
+                .take(len)
+                .collect();
+            let expected = a
+                .iter()
+                .zip(&b)
+                .map(|(&a, &b)| (a ^ b).count_ones())
+                .sum::<u32>();
+            for kernel in &hamming_kernels {
+                // Safety: the kernels are supported by the CPU.
+                assert_eq!(unsafe { kernel(&a, &b) }, expected);
+            }
+        }
+
+        let mut a = vec![0b1011, 0, 0, 0, 0, 0, 0, 0];
+        a.extend_from_slice(&[u8::MAX; 9]);
+        let mut b = vec![0b0110; 1];
+        b.resize(17, 0);
+        assert_eq!(hamming_distance(&a, &b), 75);
+    }
+
+    #[test]
+    #[should_panic]
+    fn adc_distances_rejects_invalid_codes() {
+        let mut distances = [0f32];
+        adc_distances(&[0., 1., 2., 3.], 2, &[0, 2], &mut distances);
+    }
+}
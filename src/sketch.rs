@@ -0,0 +1,210 @@
+//! Streaming quantile sketches.
+
+use std::collections::BTreeMap;
+
+/// Streaming quantile sketch with relative accuracy (DDSketch).
+///
+/// The sketch summarizes a stream of non-negative values, such as the
+/// distances that are observed during search, in logarithmically-sized
+/// buckets. Quantile estimates have a relative error of at most the
+/// relative accuracy of the sketch. The memory use grows with the
+/// logarithm of the range of the values rather than with the number of
+/// values. This makes it possible to calibrate thresholds, e.g. *match
+/// if the distance is below the 5th percentile*, from production
+/// traffic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantileSketch {
+    gamma: f64,
+    buckets: BTreeMap<i32, u64>,
+    n_zeros: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl QuantileSketch {
+    /// Construct a sketch with the given relative accuracy.
+    ///
+    /// The relative accuracy must be in *(0, 1)*.
+    pub fn new(relative_accuracy: f64) -> Self {
+        assert!(
+            relative_accuracy > 0. && relative_accuracy < 1.,
+            "Relative accuracy should be in (0, 1), was: {}",
+            relative_accuracy
+        );
+
+        QuantileSketch {
+            gamma: (1. + relative_accuracy) / (1. - relative_accuracy),
+            buckets: BTreeMap::new(),
+            n_zeros: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Add a value to the sketch.
+    ///
+    /// Panics if the value is negative or NaN.
+    pub fn insert(&mut self, value: f64) {
+        assert!(
+            value >= 0.,
+            "Only non-negative values can be added to the sketch, got: {}",
+            value
+        );
+
+        // Values below the smallest positive normal value are treated
+        // as zero to keep bucket indices in range.
+        if value < f64::MIN_POSITIVE {
+            self.n_zeros += 1;
+        } else {
+            *self.buckets.entry(self.bucket(value)).or_insert(0) += 1;
+        }
+
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Merge another sketch into this sketch.
+    ///
+    /// Both sketches must have the same relative accuracy.
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        assert!(
+            (self.gamma - other.gamma).abs() < 1e-12,
+            "Cannot merge sketches with different relative accuracies"
+        );
+
+        for (&bucket, &count) in &other.buckets {
+            *self.buckets.entry(bucket).or_insert(0) += count;
+        }
+
+        self.n_zeros += other.n_zeros;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Get the number of values in the sketch.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Check whether the sketch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Get the smallest value in the sketch.
+    pub fn min(&self) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.min)
+        }
+    }
+
+    /// Get the largest value in the sketch.
+    pub fn max(&self) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    /// Estimate the *q*-quantile of the values.
+    ///
+    /// `q` must be in *[0, 1]*. The extreme quantiles are exact. Returns
+    /// `None` if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        assert!(
+            (0. ..=1.).contains(&q),
+            "Quantile should be in [0, 1], was: {}",
+            q
+        );
+
+        if self.is_empty() {
+            return None;
+        }
+
+        // Zero-based rank of the quantile.
+        let rank = (q * (self.count - 1) as f64).floor() as u64;
+        if rank == 0 {
+            return Some(self.min);
+        } else if rank == self.count - 1 {
+            return Some(self.max);
+        } else if rank < self.n_zeros {
+            return Some(0.);
+        }
+
+        let mut seen = self.n_zeros;
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+            if seen > rank {
+                let estimate = 2. * self.gamma.powi(bucket) / (self.gamma + 1.);
+                return Some(estimate.max(self.min).min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    fn bucket(&self, value: f64) -> i32 {
+        (value.ln() / self.gamma.ln()).ceil() as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuantileSketch;
+
+    #[test]
+    fn quantiles_are_relatively_accurate() {
+        let mut sketch = QuantileSketch::new(0.01);
+        let values = (1..=10_000).map(|v| v as f64 / 100.).collect::<Vec<_>>();
+        for &v in values.iter().rev() {
+            sketch.insert(v);
+        }
+
+        assert_eq!(sketch.count(), 10_000);
+        assert_eq!(sketch.min(), Some(0.01));
+        assert_eq!(sketch.max(), Some(100.));
+        for &q in &[0., 0.05, 0.5, 0.95, 1.] {
+            let exact = values[(q * 9_999.) as usize];
+            let estimate = sketch.quantile(q).unwrap();
+            assert!(
+                (estimate - exact).abs() <= 0.01 * exact + 1e-12,
+                "q: {}, exact: {}, estimate: {}",
+                q,
+                exact,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn merge_and_zeros() {
+        let mut left = QuantileSketch::new(0.05);
+        let mut right = QuantileSketch::new(0.05);
+        assert_eq!(left.quantile(0.5), None);
+
+        left.insert(0.);
+        left.insert(0.);
+        right.insert(4.);
+        right.insert(8.);
+        left.merge(&right);
+
+        assert_eq!(left.count(), 4);
+        assert_eq!(left.quantile(0.), Some(0.));
+        assert_eq!(left.quantile(0.3), Some(0.));
+        assert!((left.quantile(0.7).unwrap() - 4.).abs() <= 0.2);
+        assert_eq!(left.quantile(1.), Some(8.));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_negative_values() {
+        QuantileSketch::new(0.01).insert(-1.);
+    }
+}
@@ -0,0 +1,187 @@
+//! Seeded shuffling and splitting of data sets.
+//!
+//! The functions in this module operate on instance indices, so that
+//! splits can be made without copying the data. The instances of a
+//! split can be gathered with `Split::select`.
+
+use ndarray::{Array2, ArrayBase, ArrayViewMut2, Axis, Data, Ix2};
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+/// Split of a data set into training and validation instances.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Split {
+    /// Indices of the training instances.
+    pub train: Vec<usize>,
+
+    /// Indices of the validation instances.
+    pub validation: Vec<usize>,
+}
+
+impl Split {
+    /// Copy the training and validation instances.
+    ///
+    /// Instances are the rows of `instances`. Returns the training and
+    /// validation instances, in the order of the split's indices.
+    pub fn select<A, S>(&self, instances: &ArrayBase<S, Ix2>) -> (Array2<A>, Array2<A>)
+    where
+        A: Clone,
+        S: Data<Elem = A>,
+    {
+        (
+            instances.select(Axis(0), &self.train),
+            instances.select(Axis(0), &self.validation),
+        )
+    }
+}
+
+/// Get a random permutation of the indices *0..n_instances*.
+pub fn shuffled_indices(n_instances: usize, seed: u64) -> Vec<usize> {
+    let mut rng = XorShiftRng::seed_from_u64(seed);
+    index::sample(&mut rng, n_instances, n_instances).into_vec()
+}
+
+/// Shuffle the rows of a matrix in-place.
+pub fn shuffle_rows<A>(mut instances: ArrayViewMut2<A>, seed: u64) {
+    let mut rng = XorShiftRng::seed_from_u64(seed);
+
+    // Fisher-Yates shuffle.
+    for i in (1..instances.nrows()).rev() {
+        let j = rng.gen_range(0..=i);
+        if i != j {
+            for col in 0..instances.ncols() {
+                instances.swap((i, col), (j, col));
+            }
+        }
+    }
+}
+
+/// Randomly split a data set into training and validation instances.
+///
+/// `validation_fraction` is the fraction of the `n_instances` instances
+/// that is used for validation, rounded down.
+pub fn train_validation_split(n_instances: usize, validation_fraction: f64, seed: u64) -> Split {
+    assert!(
+        (0.0..=1.0).contains(&validation_fraction),
+        "Validation fraction should be in [0, 1], was: {}",
+        validation_fraction
+    );
+
+    let mut indices = shuffled_indices(n_instances, seed);
+    let n_validation = (n_instances as f64 * validation_fraction) as usize;
+    let validation = indices.split_off(n_instances - n_validation);
+
+    Split {
+        train: indices,
+        validation,
+    }
+}
+
+/// Randomly split a data set into folds for k-fold cross-validation.
+///
+/// Returns a split for each of the `n_folds` folds, in which the fold
+/// is used for validation and the remaining folds for training. Folds
+/// differ at most one instance in size.
+pub fn k_fold_splits(n_instances: usize, n_folds: usize, seed: u64) -> Vec<Split> {
+    assert!(n_folds > 1, "At least two folds are required.");
+    assert!(
+        n_folds <= n_instances,
+        "Cannot split {} instances into {} folds",
+        n_instances,
+        n_folds
+    );
+
+    let permutation = shuffled_indices(n_instances, seed);
+
+    (0..n_folds)
+        .map(|fold| {
+            let fold_start = fold * n_instances / n_folds;
+            let fold_end = (fold + 1) * n_instances / n_folds;
+            Split {
+                train: permutation[..fold_start]
+                    .iter()
+                    .chain(&permutation[fold_end..])
+                    .cloned()
+                    .collect(),
+                validation: permutation[fold_start..fold_end].to_vec(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array2, Axis};
+
+    use super::{k_fold_splits, shuffle_rows, shuffled_indices, train_validation_split};
+
+    fn sorted(mut indices: Vec<usize>) -> Vec<usize> {
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn k_fold_splits_partition_instances() {
+        let splits = k_fold_splits(10, 3, 42);
+        assert_eq!(splits.len(), 3);
+
+        let mut validation = Vec::new();
+        for split in &splits {
+            assert!(split.validation.len() == 3 || split.validation.len() == 4);
+            assert_eq!(
+                sorted(
+                    split
+                        .train
+                        .iter()
+                        .chain(&split.validation)
+                        .cloned()
+                        .collect()
+                ),
+                (0..10).collect::<Vec<_>>()
+            );
+            validation.extend_from_slice(&split.validation);
+        }
+        assert_eq!(sorted(validation), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shuffle_rows_is_seeded() {
+        let instances = Array2::from_shape_fn((10, 2), |(row, col)| (row * 2 + col) as u32);
+
+        let mut shuffled = instances.clone();
+        shuffle_rows(shuffled.view_mut(), 42);
+        assert_eq!(
+            shuffled,
+            instances.select(
+                Axis(0),
+                &shuffled.column(0).mapv(|v| v as usize / 2).to_vec()
+            )
+        );
+        assert_ne!(shuffled, instances);
+
+        let mut shuffled_again = instances;
+        shuffle_rows(shuffled_again.view_mut(), 42);
+        assert_eq!(shuffled, shuffled_again);
+    }
+
+    #[test]
+    fn train_validation_split_sizes() {
+        let split = train_validation_split(10, 0.25, 42);
+        assert_eq!(split.train.len(), 8);
+        assert_eq!(split.validation.len(), 2);
+        assert_eq!(
+            sorted(
+                split
+                    .train
+                    .iter()
+                    .chain(&split.validation)
+                    .cloned()
+                    .collect()
+            ),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(split, train_validation_split(10, 0.25, 42));
+        assert_eq!(sorted(shuffled_indices(5, 1)), vec![0, 1, 2, 3, 4]);
+    }
+}
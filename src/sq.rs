@@ -0,0 +1,929 @@
+//! Scalar quantization.
+
+use ndarray::{
+    Array1, Array2, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+
+use crate::float_ord::float_cmp;
+use crate::pq::{QuantizeVector, ReconstructVector};
+
+/// Range of values that is covered by the codes of a dimension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalarRange {
+    /// Cover the range between the minimum and maximum values.
+    MinMax,
+
+    /// Cover the mean plus or minus the given number of standard
+    /// deviations.
+    ///
+    /// Values outside this range are clamped, which gives a finer
+    /// resolution to the bulk of the values when a dimension has
+    /// outliers.
+    MeanStd(f64),
+}
+
+/// Number of bits of the code of a dimension.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScalarBits {
+    /// 4-bit codes, two dimensions are packed into a byte.
+    Four,
+
+    /// 6-bit codes, four dimensions are packed into three bytes.
+    Six,
+
+    /// 8-bit codes, one byte per dimension.
+    #[default]
+    Eight,
+}
+
+impl ScalarBits {
+    /// Get the number of bits.
+    pub fn n_bits(self) -> usize {
+        match self {
+            ScalarBits::Four => 4,
+            ScalarBits::Six => 6,
+            ScalarBits::Eight => 8,
+        }
+    }
+
+    /// Get the largest code.
+    pub fn max_code(self) -> usize {
+        (1 << self.n_bits()) - 1
+    }
+
+    /// Get the number of bytes of `n_dims` packed codes.
+    pub fn packed_len(self, n_dims: usize) -> usize {
+        let n_bits = n_dims * self.n_bits();
+        n_bits / 8 + usize::from(n_bits % 8 != 0)
+    }
+}
+
+/// Trainer for scalar quantizers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SQTrainer {
+    range: ScalarRange,
+    bits: ScalarBits,
+}
+
+impl Default for SQTrainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SQTrainer {
+    /// Construct a trainer for 8-bit codes that cover the
+    /// minimum/maximum range.
+    pub fn new() -> Self {
+        SQTrainer {
+            range: ScalarRange::MinMax,
+            bits: ScalarBits::Eight,
+        }
+    }
+
+    /// Set the number of bits of the code of a dimension.
+    pub fn with_bits(mut self, bits: ScalarBits) -> Self {
+        self.bits = bits;
+        self
+    }
+
+    /// Get the number of bits of the code of a dimension.
+    pub fn bits(&self) -> ScalarBits {
+        self.bits
+    }
+
+    /// Set the range that is covered by the codes of a dimension.
+    pub fn with_range(mut self, range: ScalarRange) -> Self {
+        if let ScalarRange::MeanStd(n_std) = range {
+            assert!(
+                n_std > 0.,
+                "The number of standard deviations should be positive, was: {}",
+                n_std
+            );
+        }
+
+        self.range = range;
+        self
+    }
+
+    /// Get the range that is covered by the codes of a dimension.
+    pub fn range(&self) -> ScalarRange {
+        self.range
+    }
+
+    /// Train a scalar quantizer.
+    ///
+    /// Learns the range of each dimension of `instances`, where the
+    /// instances are the rows of the matrix.
+    pub fn train<A, S>(&self, instances: ArrayBase<S, Ix2>) -> ScalarQuantizer<A>
+    where
+        A: NdFloat,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            instances.nrows() > 0,
+            "Cannot train a scalar quantizer without instances"
+        );
+
+        let (lower, upper) = match self.range {
+            ScalarRange::MinMax => min_max(instances.view()),
+            ScalarRange::MeanStd(n_std) => {
+                let n_std = A::from(n_std).unwrap();
+                let (mean, std) = mean_std(instances.view());
+                (&mean - &(&std * n_std), &mean + &(&std * n_std))
+            }
+        };
+
+        let scales = (&upper - &lower) / self.bits.max_code().as_();
+
+        ScalarQuantizer::new(lower, scales).with_bits(self.bits)
+    }
+
+    /// Train a quantile scalar quantizer.
+    ///
+    /// Learns the bin edges of each dimension of `instances` from the
+    /// empirical quantiles of the dimension, so that every code is used
+    /// by roughly the same number of training instances. The value of a
+    /// code is the mean of the training values in its bin. The range of
+    /// the trainer is not used.
+    pub fn train_quantiles<A, S>(&self, instances: ArrayBase<S, Ix2>) -> QuantileScalarQuantizer<A>
+    where
+        A: NdFloat,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            instances.nrows() > 0,
+            "Cannot train a scalar quantizer without instances"
+        );
+
+        let n_codes = self.bits.max_code() + 1;
+        let mut edges = Array2::zeros((instances.ncols(), n_codes - 1));
+        let mut values = Array2::zeros((instances.ncols(), n_codes));
+        for ((column, mut edges), mut values) in instances
+            .axis_iter(Axis(1))
+            .zip(edges.outer_iter_mut())
+            .zip(values.outer_iter_mut())
+        {
+            let mut column = column.to_vec();
+            column.sort_unstable_by(|&a, &b| float_cmp(a, b));
+
+            for (idx, edge) in edges.iter_mut().enumerate() {
+                *edge = column[(idx + 1) * column.len() / n_codes];
+            }
+
+            // Use the mean of the values in a bin as its value. Bins can
+            // be empty when values are repeated, these get the value of
+            // their lower edge.
+            let mut sums = vec![A::zero(); n_codes];
+            let mut counts = vec![0usize; n_codes];
+            for &v in &column {
+                let code = bin(edges.view(), v);
+                sums[code] += v;
+                counts[code] += 1;
+            }
+            for (code, value) in values.iter_mut().enumerate() {
+                *value = if counts[code] != 0 {
+                    sums[code] / counts[code].as_()
+                } else {
+                    edges[code - 1]
+                };
+            }
+        }
+
+        QuantileScalarQuantizer {
+            edges,
+            values,
+            bits: self.bits,
+        }
+    }
+}
+
+/// Get the bin of a value given the edges of the bins.
+///
+/// Values on an edge belong to the bin above the edge.
+fn bin<A>(edges: ArrayView1<A>, v: A) -> usize
+where
+    A: NdFloat,
+{
+    edges
+        .as_slice()
+        .expect("Bin edges are not contiguous")
+        .partition_point(|&edge| edge <= v)
+}
+
+/// Per-dimension minima and maxima of the rows.
+fn min_max<A>(instances: ArrayView2<A>) -> (Array1<A>, Array1<A>)
+where
+    A: NdFloat,
+{
+    let mut lower = instances.index_axis(Axis(0), 0).to_owned();
+    let mut upper = lower.clone();
+    for instance in instances.outer_iter() {
+        for ((lower, upper), &v) in lower.iter_mut().zip(upper.iter_mut()).zip(instance) {
+            *lower = lower.min(v);
+            *upper = upper.max(v);
+        }
+    }
+
+    (lower, upper)
+}
+
+/// Per-dimension means and standard deviations of the rows.
+fn mean_std<A>(instances: ArrayView2<A>) -> (Array1<A>, Array1<A>)
+where
+    A: NdFloat,
+    usize: AsPrimitive<A>,
+{
+    let n_instances: A = instances.nrows().as_();
+    let mean = instances.sum_axis(Axis(0)) / n_instances;
+    let mut variance = Array1::zeros(instances.ncols());
+    for instance in instances.outer_iter() {
+        let diff = &instance - &mean;
+        variance += &(&diff * &diff);
+    }
+    variance /= n_instances;
+
+    (mean, variance.mapv(A::sqrt))
+}
+
+/// Scalar quantizer.
+///
+/// A scalar quantizer quantizes each dimension of a vector separately
+/// to a 4, 6, or 8-bit code. The codes of a dimension are evenly spaced
+/// over the range of the dimension that was learned during training.
+///
+/// The `QuantizeVector` and `ReconstructVector` implementations use one
+/// code per dimension. `quantize_batch_packed` and
+/// `reconstruct_batch_packed` pack the codes of narrower bit widths
+/// into bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalarQuantizer<A> {
+    offsets: Array1<A>,
+    scales: Array1<A>,
+    bits: ScalarBits,
+}
+
+impl<A> ScalarQuantizer<A>
+where
+    A: NdFloat,
+{
+    /// Construct an 8-bit scalar quantizer.
+    ///
+    /// The value of code *c* of dimension *d* is
+    /// `offsets[d] + c * scales[d]`.
+    pub fn new(offsets: Array1<A>, scales: Array1<A>) -> Self {
+        assert_eq!(
+            offsets.len(),
+            scales.len(),
+            "Number of offsets and scales differ"
+        );
+        assert!(
+            scales.iter().all(|&scale| scale >= A::zero()),
+            "Scales should be non-negative"
+        );
+
+        ScalarQuantizer {
+            offsets,
+            scales,
+            bits: ScalarBits::Eight,
+        }
+    }
+
+    /// Use codes with the given number of bits.
+    pub fn with_bits(mut self, bits: ScalarBits) -> Self {
+        self.bits = bits;
+        self
+    }
+
+    /// Get the number of bits of the code of a dimension.
+    pub fn bits(&self) -> ScalarBits {
+        self.bits
+    }
+
+    /// Get the per-dimension offsets.
+    pub fn offsets(&self) -> ArrayView1<A> {
+        self.offsets.view()
+    }
+
+    /// Get the number of dimensions.
+    pub fn n_dims(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Get the per-dimension scales.
+    pub fn scales(&self) -> ArrayView1<A> {
+        self.scales.view()
+    }
+
+    fn quantize_value(&self, dim: usize, value: A) -> usize
+    where
+        usize: AsPrimitive<A>,
+        A: AsPrimitive<usize>,
+    {
+        let scale = self.scales[dim];
+        if scale == A::zero() {
+            return 0;
+        }
+
+        let code = ((value - self.offsets[dim]) / scale).round();
+        num_traits::clamp(code, A::zero(), self.bits.max_code().as_()).as_()
+    }
+
+    /// Get the length of a vector after quantization and packing.
+    pub fn packed_len(&self) -> usize {
+        self.bits.packed_len(self.n_dims())
+    }
+
+    /// Quantize a batch of vectors into packed codes.
+    ///
+    /// The codes of a vector are packed into `packed_len` bytes, where
+    /// the code of the first dimension is stored in the least
+    /// significant bits of the first byte.
+    pub fn quantize_batch_packed<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<u8>
+    where
+        A: AsPrimitive<usize>,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<A>,
+    {
+        pack_batch(self.quantize_batch::<u8, _>(x).view(), self.bits)
+    }
+
+    /// Reconstruct a batch of vectors from packed codes.
+    pub fn reconstruct_batch_packed<S>(&self, packed: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = u8>,
+        usize: AsPrimitive<A>,
+    {
+        self.reconstruct_batch(unpack_batch(packed.view(), self.bits, self.n_dims()))
+    }
+}
+
+/// Pack the codes of each vector of a batch.
+fn pack_batch(codes: ArrayView2<u8>, bits: ScalarBits) -> Array2<u8> {
+    let mut packed = Array2::zeros((codes.nrows(), bits.packed_len(codes.ncols())));
+    for (codes, mut packed) in codes.outer_iter().zip(packed.outer_iter_mut()) {
+        pack_codes(codes.iter().copied(), bits, packed.as_slice_mut().unwrap());
+    }
+
+    packed
+}
+
+/// Unpack the codes of each vector of a batch.
+fn unpack_batch(packed: ArrayView2<u8>, bits: ScalarBits, n_dims: usize) -> Array2<u8> {
+    assert_eq!(
+        packed.ncols(),
+        bits.packed_len(n_dims),
+        "Packed length does not match number of dimensions"
+    );
+
+    let mut codes = Array2::zeros((packed.nrows(), n_dims));
+    for (packed, mut codes) in packed.outer_iter().zip(codes.outer_iter_mut()) {
+        for (code, unpacked) in codes
+            .iter_mut()
+            .zip(unpack_codes(packed.iter().copied(), bits))
+        {
+            *code = unpacked;
+        }
+    }
+
+    codes
+}
+
+/// Quantile scalar quantizer.
+///
+/// A quantile scalar quantizer quantizes each dimension of a vector
+/// separately, like `ScalarQuantizer`. However, the bins of a dimension
+/// are learned from the empirical quantiles of the training data rather
+/// than spaced evenly. This assigns more codes to dense regions of a
+/// dimension, which reduces the typical quantization error of
+/// heavy-tailed distributions. Since the outermost bins are wide, the
+/// error of values in the tails is larger than with evenly-spaced bins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantileScalarQuantizer<A> {
+    edges: Array2<A>,
+    values: Array2<A>,
+    bits: ScalarBits,
+}
+
+impl<A> QuantileScalarQuantizer<A>
+where
+    A: NdFloat,
+{
+    /// Get the bin edges.
+    ///
+    /// Row *d* contains the ascending edges between the bins of
+    /// dimension *d*. A value *v* gets the code of the number of edges
+    /// that are smaller than or equal to *v*.
+    pub fn edges(&self) -> ArrayView2<A> {
+        self.edges.view()
+    }
+
+    /// Get the values of the codes.
+    ///
+    /// Row *d* contains the values of the codes of dimension *d*.
+    pub fn values(&self) -> ArrayView2<A> {
+        self.values.view()
+    }
+
+    /// Get the number of bits of the code of a dimension.
+    pub fn bits(&self) -> ScalarBits {
+        self.bits
+    }
+
+    /// Get the number of dimensions.
+    pub fn n_dims(&self) -> usize {
+        self.values.nrows()
+    }
+
+    /// Get the length of a vector after quantization and packing.
+    pub fn packed_len(&self) -> usize {
+        self.bits.packed_len(self.n_dims())
+    }
+
+    /// Quantize a batch of vectors into packed codes.
+    ///
+    /// See `ScalarQuantizer::quantize_batch_packed`.
+    pub fn quantize_batch_packed<S>(&self, x: ArrayBase<S, Ix2>) -> Array2<u8>
+    where
+        S: Data<Elem = A>,
+    {
+        pack_batch(self.quantize_batch::<u8, _>(x).view(), self.bits)
+    }
+
+    /// Reconstruct a batch of vectors from packed codes.
+    pub fn reconstruct_batch_packed<S>(&self, packed: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        S: Data<Elem = u8>,
+    {
+        self.reconstruct_batch(unpack_batch(packed.view(), self.bits, self.n_dims()))
+    }
+}
+
+impl<A> QuantizeVector<A> for QuantileScalarQuantizer<A>
+where
+    A: NdFloat,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.n_dims(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.n_dims()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.bits.max_code() <= I::max_value().as_(),
+            "Cannot store codes in quantizer index type"
+        );
+
+        for (instance, mut codes) in x.outer_iter().zip(quantized.outer_iter_mut()) {
+            for ((&v, code), edges) in instance
+                .iter()
+                .zip(codes.iter_mut())
+                .zip(self.edges.outer_iter())
+            {
+                *code = bin(edges, v).as_();
+            }
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_dims()
+    }
+}
+
+impl<A> ReconstructVector<A> for QuantileScalarQuantizer<A>
+where
+    A: NdFloat,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.n_dims(),
+            "Quantization length does not match number of dimensions"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            for ((v, &code), values) in reconstruction
+                .iter_mut()
+                .zip(codes.iter())
+                .zip(self.values.outer_iter())
+            {
+                *v = values[code.as_()];
+            }
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.n_dims()
+    }
+}
+
+/// Pack codes into a little-endian bit stream.
+fn pack_codes(codes: impl Iterator<Item = u8>, bits: ScalarBits, packed: &mut [u8]) {
+    let n_bits = bits.n_bits();
+    let mut buffer = 0u32;
+    let mut n_buffered = 0;
+    let mut packed = packed.iter_mut();
+
+    for code in codes {
+        buffer |= u32::from(code) << n_buffered;
+        n_buffered += n_bits;
+        while n_buffered >= 8 {
+            *packed.next().expect("Packed codes do not fit") = buffer as u8;
+            buffer >>= 8;
+            n_buffered -= 8;
+        }
+    }
+
+    if n_buffered > 0 {
+        *packed.next().expect("Packed codes do not fit") = buffer as u8;
+    }
+}
+
+/// Unpack codes from a little-endian bit stream.
+///
+/// The iterator yields codes for all full codes in the bit stream, the
+/// caller should take the number of codes that were packed.
+fn unpack_codes(packed: impl Iterator<Item = u8>, bits: ScalarBits) -> impl Iterator<Item = u8> {
+    let n_bits = bits.n_bits();
+    let mask = bits.max_code() as u32;
+    let mut buffer = 0u32;
+    let mut n_buffered = 0;
+    let mut packed = packed;
+
+    std::iter::from_fn(move || {
+        while n_buffered < n_bits {
+            buffer |= u32::from(packed.next()?) << n_buffered;
+            n_buffered += 8;
+        }
+
+        let code = (buffer & mask) as u8;
+        buffer >>= n_bits;
+        n_buffered -= n_bits;
+        Some(code)
+    })
+}
+
+impl<A> QuantizeVector<A> for ScalarQuantizer<A>
+where
+    A: NdFloat + AsPrimitive<usize>,
+    usize: AsPrimitive<A>,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), self.quantized_len()));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), self.quantized_len()),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.bits.max_code() <= I::max_value().as_(),
+            "Cannot store codes in quantizer index type"
+        );
+
+        for (instance, mut codes) in x.outer_iter().zip(quantized.outer_iter_mut()) {
+            for (dim, (&v, code)) in instance.iter().zip(codes.iter_mut()).enumerate() {
+                *code = self.quantize_value(dim, v).as_();
+            }
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, self.quantized_len()));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        self.n_dims()
+    }
+}
+
+impl<A> ReconstructVector<A> for ScalarQuantizer<A>
+where
+    A: NdFloat,
+    usize: AsPrimitive<A>,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            self.n_dims(),
+            "Quantization length does not match number of dimensions"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        for (codes, mut reconstruction) in
+            quantized.outer_iter().zip(reconstructions.outer_iter_mut())
+        {
+            for (((v, &code), &offset), &scale) in reconstruction
+                .iter_mut()
+                .zip(codes.iter())
+                .zip(self.offsets.iter())
+                .zip(self.scales.iter())
+            {
+                *v = offset + code.as_().as_() * scale;
+            }
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.n_dims()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2, Axis};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_distr::Normal;
+    use rand_xorshift::XorShiftRng;
+
+    use super::{
+        pack_codes, unpack_codes, QuantileScalarQuantizer, SQTrainer, ScalarBits, ScalarQuantizer,
+        ScalarRange,
+    };
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn quantize_and_reconstruct() {
+        let sq = ScalarQuantizer::new(array![0., -1., 2.], array![1., 0.5, 0.]);
+
+        let quantized: Array2<u8> = sq.quantize_batch(array![[3., -0.4, 7.], [-1., 200., 2.]]);
+        assert_eq!(quantized, array![[3, 1, 0], [0, 255, 0]]);
+        assert_eq!(
+            sq.quantize_vector::<u8, _>(array![3., -0.4, 7.]),
+            array![3, 1, 0]
+        );
+        assert_eq!(
+            sq.reconstruct_batch(quantized),
+            array![[3., -0.5, 2.], [0., 126.5, 2.]]
+        );
+        assert_eq!(
+            sq.reconstruct_vector(array![3u8, 1, 0]),
+            array![3., -0.5, 2.]
+        );
+    }
+
+    #[test]
+    fn min_max_error_is_bounded_by_half_step() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 16), Uniform::new(-3., 5.), &mut rng);
+
+        let sq: ScalarQuantizer<f32> = SQTrainer::new().train(instances.view());
+        let quantized: Array2<u8> = sq.quantize_batch(instances.view());
+        let errors = &instances - &sq.reconstruct_batch(quantized);
+        for (errors, &scale) in errors.axis_iter(Axis(1)).zip(sq.scales()) {
+            assert!(errors.iter().all(|e| e.abs() <= scale / 2. + 1e-6));
+        }
+    }
+
+    #[test]
+    fn pack_and_unpack_codes() {
+        for &bits in &[ScalarBits::Four, ScalarBits::Six, ScalarBits::Eight] {
+            let codes: Vec<u8> = (0..7)
+                .map(|i| (i * 5) as u8 & bits.max_code() as u8)
+                .collect();
+            let mut packed = vec![0; bits.packed_len(codes.len())];
+            pack_codes(codes.iter().copied(), bits, &mut packed);
+            let unpacked: Vec<u8> = unpack_codes(packed.iter().copied(), bits)
+                .take(codes.len())
+                .collect();
+            assert_eq!(unpacked, codes);
+        }
+
+        let mut packed = [0; 3];
+        pack_codes([1, 2, 3, 4].iter().copied(), ScalarBits::Six, &mut packed);
+        assert_eq!(packed, [0b1000_0001, 0b0011_0000, 0b0001_0000]);
+        assert_eq!(ScalarBits::Four.packed_len(3), 2);
+        assert_eq!(ScalarBits::Six.packed_len(5), 4);
+    }
+
+    #[test]
+    fn narrow_codes_trade_accuracy_for_memory() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((256, 15), Uniform::new(-3., 5.), &mut rng);
+
+        let mut prev_mse = f32::INFINITY;
+        for &bits in &[ScalarBits::Four, ScalarBits::Six, ScalarBits::Eight] {
+            let sq: ScalarQuantizer<f32> = SQTrainer::new().with_bits(bits).train(instances.view());
+            assert_eq!(sq.packed_len(), bits.packed_len(15));
+
+            let packed = sq.quantize_batch_packed(instances.view());
+            assert_eq!(packed.ncols(), sq.packed_len());
+            let reconstructions = sq.reconstruct_batch_packed(packed);
+            let quantized: Array2<u8> = sq.quantize_batch(instances.view());
+            assert!(quantized
+                .iter()
+                .all(|&code| code as usize <= bits.max_code()));
+            assert_eq!(reconstructions, sq.reconstruct_batch(quantized));
+
+            let errors = &instances - &reconstructions;
+            for (errors, &scale) in errors.axis_iter(Axis(1)).zip(sq.scales()) {
+                assert!(errors.iter().all(|e| e.abs() <= scale / 2. + 1e-6));
+            }
+
+            let mse = errors.mapv(|e| e * e).mean().unwrap();
+            assert!(mse < prev_mse);
+            prev_mse = mse;
+        }
+    }
+
+    #[test]
+    fn mean_std_clamps_outliers() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let mut instances: Array2<f32> =
+            Array2::random_using((1000, 4), Normal::new(0., 1.).unwrap(), &mut rng);
+        instances[(0, 0)] = 1000.;
+
+        let min_max: ScalarQuantizer<f32> = SQTrainer::new().train(instances.view());
+        let mean_std: ScalarQuantizer<f32> = SQTrainer::new()
+            .with_range(ScalarRange::MeanStd(3.))
+            .train(instances.view());
+        assert!(mean_std.scales()[0] < min_max.scales()[0]);
+
+        let quantized: Array2<u8> = mean_std.quantize_batch(instances.view());
+        assert_eq!(quantized[(0, 0)], 255);
+    }
+
+    #[test]
+    fn quantile_bins_are_balanced() {
+        let instances = Array2::from_shape_fn((64, 2), |(row, col)| (row * (col + 1)) as f32);
+        let sq: QuantileScalarQuantizer<f32> = SQTrainer::new()
+            .with_bits(ScalarBits::Four)
+            .train_quantiles(instances.view());
+        assert_eq!(
+            sq.edges().row(0).to_vec(),
+            (1..16).map(|i| (4 * i) as f32).collect::<Vec<_>>()
+        );
+
+        let quantized: Array2<u8> = sq.quantize_batch(instances.view());
+        for codes in quantized.axis_iter(Axis(1)) {
+            let mut counts = [0; 16];
+            codes.iter().for_each(|&code| counts[code as usize] += 1);
+            assert!(counts.iter().all(|&count| count == 4));
+        }
+
+        // Codes are reconstructed to the means of their bins.
+        assert_eq!(sq.reconstruct_vector(array![0u8, 15]), array![1.5, 123.]);
+        assert_eq!(
+            sq.reconstruct_batch_packed(sq.quantize_batch_packed(instances.view())),
+            sq.reconstruct_batch(quantized)
+        );
+    }
+
+    #[test]
+    fn quantiles_reduce_typical_error_for_heavy_tails() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((2000, 8), Normal::new(0., 1.).unwrap(), &mut rng)
+                .mapv(|v: f32| v * v * v);
+
+        let trainer = SQTrainer::new().with_bits(ScalarBits::Four);
+        let uniform: ScalarQuantizer<f32> = trainer.train(instances.view());
+        let quantile: QuantileScalarQuantizer<f32> = trainer.train_quantiles(instances.view());
+
+        // Quantile bins are narrow where most values are, which reduces
+        // the typical error at the cost of the error in the tails.
+        let mean_abs_error = |reconstructions: Array2<f32>| {
+            (&instances - &reconstructions)
+                .mapv(f32::abs)
+                .mean()
+                .unwrap()
+        };
+        let uniform_error = mean_abs_error(
+            uniform.reconstruct_batch(uniform.quantize_batch::<u8, _>(instances.view())),
+        );
+        let quantile_error = mean_abs_error(
+            quantile.reconstruct_batch(quantile.quantize_batch::<u8, _>(instances.view())),
+        );
+        assert!(quantile_error < 0.5 * uniform_error);
+    }
+}
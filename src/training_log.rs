@@ -0,0 +1,187 @@
+//! Structured logging of training milestones.
+//!
+//! Training emits a record for every k-means iteration of a
+//! subquantizer, for every trained subquantizer, and for every trained
+//! quantizer. The records are logged as JSON objects on a single line
+//! using the `log` crate with the target `TRAINING_LOG_TARGET` at the
+//! info level. Records are only formatted when this target is enabled,
+//! so that training jobs can route them to a separate sink (e.g. to
+//! monitor loss plateaus) without affecting other log output.
+//!
+//! A record has the following fields, fields without a value are
+//! omitted:
+//!
+//! * `phase`: the training phase, `kmeans_iteration`, `subquantizer`,
+//!   or `quantizer`.
+//! * `subquantizer`: the index of the subquantizer.
+//! * `iteration`: the iteration within the phase.
+//! * `loss`: the loss, `null` when the loss is not finite.
+//! * `duration_secs`: the time since the start of the phase.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use log::{info, log_enabled, Level};
+use ndarray::NdFloat;
+
+use crate::kmeans::StopCondition;
+
+/// Log target of training records.
+pub const TRAINING_LOG_TARGET: &str = "reductive::training";
+
+/// Record of a training milestone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainingRecord {
+    phase: &'static str,
+    subquantizer: Option<usize>,
+    iteration: Option<usize>,
+    loss: Option<f64>,
+    duration: Duration,
+}
+
+impl TrainingRecord {
+    /// Construct a record of a training phase.
+    pub fn new(phase: &'static str, duration: Duration) -> Self {
+        TrainingRecord {
+            phase,
+            subquantizer: None,
+            iteration: None,
+            loss: None,
+            duration,
+        }
+    }
+
+    /// Set the subquantizer of the record.
+    pub fn with_subquantizer(mut self, subquantizer: usize) -> Self {
+        self.subquantizer = Some(subquantizer);
+        self
+    }
+
+    /// Set the iteration of the record.
+    pub fn with_iteration(mut self, iteration: usize) -> Self {
+        self.iteration = Some(iteration);
+        self
+    }
+
+    /// Set the loss of the record.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = Some(loss);
+        self
+    }
+
+    /// Get the training phase.
+    pub fn phase(&self) -> &'static str {
+        self.phase
+    }
+
+    /// Get the subquantizer (if any).
+    pub fn subquantizer(&self) -> Option<usize> {
+        self.subquantizer
+    }
+
+    /// Get the iteration (if any).
+    pub fn iteration(&self) -> Option<usize> {
+        self.iteration
+    }
+
+    /// Get the loss (if any).
+    pub fn loss(&self) -> Option<f64> {
+        self.loss
+    }
+
+    /// Get the time since the start of the phase.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Format the record as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        // Phases are identifiers, so they do not need escaping.
+        let mut json = format!("{{\"phase\":\"{}\"", self.phase);
+        if let Some(subquantizer) = self.subquantizer {
+            write!(json, ",\"subquantizer\":{}", subquantizer).unwrap();
+        }
+        if let Some(iteration) = self.iteration {
+            write!(json, ",\"iteration\":{}", iteration).unwrap();
+        }
+        match self.loss {
+            Some(loss) if loss.is_finite() => write!(json, ",\"loss\":{:?}", loss).unwrap(),
+            Some(_) => json.push_str(",\"loss\":null"),
+            None => (),
+        }
+        write!(
+            json,
+            ",\"duration_secs\":{:?}}}",
+            self.duration.as_secs_f64()
+        )
+        .unwrap();
+
+        json
+    }
+}
+
+/// Log a training record if the training log target is enabled.
+pub(crate) fn log_record(record: impl FnOnce() -> TrainingRecord) {
+    if log_enabled!(target: TRAINING_LOG_TARGET, Level::Info) {
+        info!(target: TRAINING_LOG_TARGET, "{}", record().to_json());
+    }
+}
+
+/// Stop condition that logs the loss of every k-means iteration.
+pub(crate) struct LoggedIterations<C> {
+    condition: C,
+    subquantizer: usize,
+    start: Instant,
+}
+
+impl<C> LoggedIterations<C> {
+    pub(crate) fn new(condition: C, subquantizer: usize) -> Self {
+        LoggedIterations {
+            condition,
+            subquantizer,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<A, C> StopCondition<A> for LoggedIterations<C>
+where
+    A: NdFloat,
+    C: StopCondition<A>,
+{
+    fn should_stop(&mut self, iteration: usize, loss: A) -> bool {
+        log_record(|| {
+            TrainingRecord::new("kmeans_iteration", self.start.elapsed())
+                .with_subquantizer(self.subquantizer)
+                .with_iteration(iteration)
+                .with_loss(loss.to_f64().unwrap_or(f64::NAN))
+        });
+
+        self.condition.should_stop(iteration, loss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TrainingRecord;
+
+    #[test]
+    fn training_record_json() {
+        let record = TrainingRecord::new("kmeans_iteration", Duration::from_millis(1500))
+            .with_subquantizer(2)
+            .with_iteration(7)
+            .with_loss(0.25);
+        assert_eq!(
+            record.to_json(),
+            r#"{"phase":"kmeans_iteration","subquantizer":2,"iteration":7,"loss":0.25,"duration_secs":1.5}"#
+        );
+
+        let record = TrainingRecord::new("quantizer", Duration::from_secs(3)).with_loss(f64::NAN);
+        assert_eq!(
+            record.to_json(),
+            r#"{"phase":"quantizer","loss":null,"duration_secs":3.0}"#
+        );
+    }
+}
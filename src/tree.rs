@@ -0,0 +1,407 @@
+//! Tree-structured vector quantization.
+
+use std::iter::Sum;
+
+use log::info;
+use ndarray::{
+    s, Array1, Array2, ArrayBase, ArrayView2, ArrayViewMut2, Axis, Data, Ix1, Ix2, NdFloat,
+};
+use num_traits::{AsPrimitive, Bounded, Zero};
+use rand::RngCore;
+
+use crate::kmeans::{
+    cluster_assignment, InitialCentroids, KMeansWithCentroids, NIterationsCondition,
+    RandomInstanceCentroids,
+};
+use crate::pq::{QuantizeVector, ReconstructVector};
+
+/// Tree-structured vector quantizer.
+///
+/// This quantizer organizes its centroids in a tree in which every
+/// inner node has `branching_factor` children. A vector is quantized by
+/// descending from the root to a leaf, at each level picking the child
+/// that is nearest to the vector. The code of a vector is the index of
+/// its leaf. Since only `branching_factor` centroids are compared per
+/// level, quantization takes logarithmic time in the number of leaves.
+/// This makes very large codebooks (e.g. 2^16 leaves or more) practical,
+/// at the cost of a higher error than exhaustive search in a flat
+/// codebook of the same size.
+///
+/// The tree is trained with recursive k-means: the instances of a node
+/// are clustered into the children of the node. The centroids of level
+/// *l* are stored in an array with *branching_factor^(l + 1)* rows,
+/// where the children of node *i* are the rows *i * branching_factor..(i
+/// + 1) * branching_factor* of the next level.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeQuantizer<A> {
+    branching_factor: usize,
+    levels: Vec<Array2<A>>,
+}
+
+impl<A> TreeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    /// Construct a tree quantizer from the centroids of its levels.
+    pub fn new(branching_factor: usize, levels: Vec<Array2<A>>) -> Self {
+        assert!(
+            branching_factor > 1,
+            "The branching factor should at least be 2, was: {}",
+            branching_factor
+        );
+        assert!(!levels.is_empty(), "A tree should have at least one level.");
+
+        let n_dims = levels[0].ncols();
+        let mut n_nodes = 1usize;
+        for (level, centroids) in levels.iter().enumerate() {
+            n_nodes = n_nodes
+                .checked_mul(branching_factor)
+                .expect("The number of leaves does not fit in usize");
+            assert_eq!(
+                centroids.dim(),
+                (n_nodes, n_dims),
+                "Level {} has an incorrect shape",
+                level
+            );
+        }
+
+        TreeQuantizer {
+            branching_factor,
+            levels,
+        }
+    }
+
+    /// Train a tree quantizer with recursive k-means.
+    ///
+    /// The tree has `n_levels` levels with `branching_factor` children
+    /// per node, so it has *branching_factor^n_levels* leaves. The
+    /// clustering of each node is optimized for `n_iterations` k-means
+    /// iterations. Nodes with no more instances than children use their
+    /// instances as centroids and fill the remaining children with their
+    /// own centroid.
+    pub fn train_using<S, R>(
+        branching_factor: usize,
+        n_levels: usize,
+        n_iterations: usize,
+        instances: ArrayBase<S, Ix2>,
+        mut rng: R,
+    ) -> Self
+    where
+        S: Data<Elem = A>,
+        R: RngCore,
+        usize: AsPrimitive<A>,
+    {
+        assert!(
+            branching_factor > 1,
+            "The branching factor should at least be 2, was: {}",
+            branching_factor
+        );
+        assert!(n_levels > 0, "A tree should have at least one level.");
+        assert!(
+            n_iterations > 0,
+            "The nodes should be optimized for at least one iteration."
+        );
+        assert!(
+            instances.nrows() > 0,
+            "Cannot train a tree quantizer without instances"
+        );
+
+        let mut levels = Vec::with_capacity(n_levels);
+        let mut parents =
+            (instances.sum_axis(Axis(0)) / instances.nrows().as_()).insert_axis(Axis(0));
+        let mut nodes = vec![0; instances.nrows()];
+
+        for level in 0..n_levels {
+            info!("Training tree level {}", level);
+
+            let mut centroids = Array2::zeros((
+                parents
+                    .nrows()
+                    .checked_mul(branching_factor)
+                    .expect("The number of leaves does not fit in usize"),
+                instances.ncols(),
+            ));
+
+            let mut node_instances = vec![Vec::new(); parents.nrows()];
+            for (idx, &node) in nodes.iter().enumerate() {
+                node_instances[node].push(idx);
+            }
+
+            for (node, (parent, node_instances)) in
+                parents.outer_iter().zip(node_instances.iter()).enumerate()
+            {
+                let children = centroids.slice_mut(s![
+                    node * branching_factor..(node + 1) * branching_factor,
+                    ..
+                ]);
+                Self::train_node(
+                    parent.to_owned(),
+                    instances.select(Axis(0), node_instances),
+                    children,
+                    n_iterations,
+                    &mut rng,
+                );
+            }
+
+            for (instance, node) in instances.outer_iter().zip(nodes.iter_mut()) {
+                *node = Self::child(&centroids, branching_factor, *node, instance);
+            }
+
+            levels.push(centroids.clone());
+            parents = centroids;
+        }
+
+        TreeQuantizer {
+            branching_factor,
+            levels,
+        }
+    }
+
+    fn train_node(
+        parent: Array1<A>,
+        instances: Array2<A>,
+        mut children: ArrayViewMut2<A>,
+        n_iterations: usize,
+        rng: &mut impl RngCore,
+    ) where
+        usize: AsPrimitive<A>,
+    {
+        let branching_factor = children.nrows();
+        if instances.nrows() <= branching_factor {
+            for (idx, mut child) in children.outer_iter_mut().enumerate() {
+                match instances.outer_iter().nth(idx) {
+                    Some(instance) => child.assign(&instance),
+                    None => child.assign(&parent),
+                }
+            }
+            return;
+        }
+
+        let mut initial = RandomInstanceCentroids::new(rng).initial_centroids(
+            instances.view(),
+            Axis(0),
+            branching_factor,
+        );
+        instances.kmeans_with_centroids(
+            Axis(0),
+            initial.view_mut(),
+            NIterationsCondition(n_iterations),
+        );
+        children.assign(&initial);
+    }
+
+    /// Get the child of `node` that is nearest to `instance`.
+    fn child<S>(
+        centroids: &Array2<A>,
+        branching_factor: usize,
+        node: usize,
+        instance: ArrayBase<S, Ix1>,
+    ) -> usize
+    where
+        S: Data<Elem = A>,
+    {
+        let first = node * branching_factor;
+        let children = centroids.slice(s![first..first + branching_factor, ..]);
+        first + cluster_assignment(children, instance)
+    }
+
+    /// Get the branching factor of the tree.
+    pub fn branching_factor(&self) -> usize {
+        self.branching_factor
+    }
+
+    /// Get the centroids of a level of the tree.
+    pub fn level(&self, level: usize) -> ArrayView2<A> {
+        self.levels[level].view()
+    }
+
+    /// Get the number of levels of the tree.
+    pub fn n_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Get the number of leaves of the tree.
+    pub fn n_leaves(&self) -> usize {
+        self.leaves().nrows()
+    }
+
+    /// Get the centroids of the leaves.
+    pub fn leaves(&self) -> ArrayView2<A> {
+        self.levels.last().unwrap().view()
+    }
+
+    fn quantize_leaf<S>(&self, x: ArrayBase<S, Ix1>) -> usize
+    where
+        S: Data<Elem = A>,
+    {
+        self.levels.iter().fold(0, |node, centroids| {
+            Self::child(centroids, self.branching_factor, node, x.view())
+        })
+    }
+}
+
+impl<A> QuantizeVector<A> for TreeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn quantize_batch<I, S>(&self, x: ArrayBase<S, Ix2>) -> Array2<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((x.nrows(), 1));
+        self.quantize_batch_into(x, quantized.view_mut());
+        quantized
+    }
+
+    fn quantize_batch_into<I, S>(&self, x: ArrayBase<S, Ix2>, mut quantized: ArrayViewMut2<I>)
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        assert_eq!(
+            x.ncols(),
+            self.reconstructed_len(),
+            "Quantizer and vector length mismatch"
+        );
+        assert_eq!(
+            quantized.dim(),
+            (x.nrows(), 1),
+            "Quantized matrix has incorrect shape"
+        );
+        assert!(
+            self.n_leaves() - 1 <= I::max_value().as_(),
+            "Cannot store leaves in quantizer index type"
+        );
+
+        for (instance, code) in x.outer_iter().zip(quantized.iter_mut()) {
+            *code = self.quantize_leaf(instance).as_();
+        }
+    }
+
+    fn quantize_vector<I, S>(&self, x: ArrayBase<S, Ix1>) -> Array1<I>
+    where
+        I: AsPrimitive<usize> + Bounded + Zero,
+        S: Data<Elem = A>,
+        usize: AsPrimitive<I>,
+    {
+        let mut quantized = Array2::zeros((1, 1));
+        self.quantize_batch_into(x.insert_axis(Axis(0)), quantized.view_mut());
+        quantized.index_axis_move(Axis(0), 0)
+    }
+
+    fn quantized_len(&self) -> usize {
+        1
+    }
+}
+
+impl<A> ReconstructVector<A> for TreeQuantizer<A>
+where
+    A: NdFloat + Sum,
+{
+    fn reconstruct_batch<I, S>(&self, quantized: ArrayBase<S, Ix2>) -> Array2<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstructions = Array2::zeros((quantized.nrows(), self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized, reconstructions.view_mut());
+        reconstructions
+    }
+
+    fn reconstruct_batch_into<I, S>(
+        &self,
+        quantized: ArrayBase<S, Ix2>,
+        mut reconstructions: ArrayViewMut2<A>,
+    ) where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        assert_eq!(
+            quantized.ncols(),
+            1,
+            "Quantization length of a tree quantizer should be 1"
+        );
+        assert_eq!(
+            reconstructions.dim(),
+            (quantized.nrows(), self.reconstructed_len()),
+            "Reconstructions matrix has incorrect shape"
+        );
+
+        let leaves = self.leaves();
+        for (&code, mut reconstruction) in quantized.iter().zip(reconstructions.outer_iter_mut()) {
+            reconstruction.assign(&leaves.row(code.as_()));
+        }
+    }
+
+    fn reconstruct_vector<I, S>(&self, quantized: ArrayBase<S, Ix1>) -> Array1<A>
+    where
+        I: AsPrimitive<usize>,
+        S: Data<Elem = I>,
+    {
+        let mut reconstruction = Array2::zeros((1, self.reconstructed_len()));
+        self.reconstruct_batch_into(quantized.insert_axis(Axis(0)), reconstruction.view_mut());
+        reconstruction.index_axis_move(Axis(0), 0)
+    }
+
+    fn reconstructed_len(&self) -> usize {
+        self.levels[0].ncols()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{array, Array2};
+    use rand::distributions::Uniform;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    use super::TreeQuantizer;
+    use crate::ndarray_rand::RandomExt;
+    use crate::pq::{QuantizeVector, ReconstructVector};
+
+    #[test]
+    fn quantize_by_descending_the_tree() {
+        let tree = TreeQuantizer::new(
+            2,
+            vec![
+                array![[0f32, 0.], [10., 0.]],
+                array![[-1., 0.], [1., 0.], [9., 0.], [11., 0.]],
+            ],
+        );
+        assert_eq!(tree.n_leaves(), 4);
+
+        // The leaf [1, 0] is nearest to [4, 0], but [4, 0] is nearer to
+        // the root [0, 0] than to [10, 0].
+        let quantized: Array2<u8> = tree.quantize_batch(array![[4f32, 0.], [6., 0.], [12., 1.]]);
+        assert_eq!(quantized, array![[1], [2], [3]]);
+        assert_eq!(
+            tree.reconstruct_batch(quantized),
+            array![[1., 0.], [9., 0.], [11., 0.]]
+        );
+        assert_eq!(tree.quantize_vector::<u8, _>(array![-3f32, 0.]), array![0]);
+    }
+
+    #[test]
+    fn deeper_trees_reduce_error() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let instances: Array2<f32> =
+            Array2::random_using((1024, 8), Uniform::new(-1., 1.), &mut rng);
+
+        let mut mse = |n_levels| {
+            let tree = TreeQuantizer::train_using(4, n_levels, 5, instances.view(), &mut rng);
+            assert_eq!(tree.n_leaves(), 4usize.pow(n_levels as u32));
+            let quantized: Array2<u16> = tree.quantize_batch(instances.view());
+            let errors = &instances - &tree.reconstruct_batch(quantized);
+            errors.mapv(|v| v * v).sum() / instances.nrows() as f32
+        };
+
+        let mse1 = mse(1);
+        let mse3 = mse(3);
+        let mse6 = mse(6);
+        assert!(mse3 < mse1);
+        assert!(mse6 < mse3);
+    }
+}